@@ -247,9 +247,18 @@ void changeWhiteList({Function()? callback}) async {
                     r"^(25[0-5]|2[0-4][0-9]|1[0-9][0-9]|[1-9][0-9]?|0)\.(25[0-5]|2[0-4][0-9]|1[0-9][0-9]|[1-9][0-9]?|0)\.(25[0-5]|2[0-4][0-9]|1[0-9][0-9]|[1-9][0-9]?|0)\.(25[0-5]|2[0-4][0-9]|1[0-9][0-9]|[1-9][0-9]?|0)(\/([1-9]|[1-2][0-9]|3[0-2])){0,1}$");
                 final ipv6Match = RegExp(
                     r"^(((?:[0-9A-Fa-f]{1,4}))*((?::[0-9A-Fa-f]{1,4}))*::((?:[0-9A-Fa-f]{1,4}))*((?::[0-9A-Fa-f]{1,4}))*|((?:[0-9A-Fa-f]{1,4}))((?::[0-9A-Fa-f]{1,4})){7})(\/([1-9]|[1-9][0-9]|1[0-1][0-9]|12[0-8])){0,1}$");
-                for (final ip in ips) {
-                  if (!ipMatch.hasMatch(ip) && !ipv6Match.hasMatch(ip)) {
-                    msg = "${translate("Invalid IP")} $ip";
+                // [!]target[@modifier]..., see `Rule` in src/server/whitelist.rs
+                final ruleMatch = RegExp(
+                    r"^!?([^@!]+)(@(view-only|no-keyboard|no-clipboard|no-file|no-audio|no-tunnel|\d{1,2}:\d{2}-\d{1,2}:\d{2}))*$");
+                final hostMatch = RegExp(
+                    r"^(\*|[A-Za-z0-9]([A-Za-z0-9-]*[A-Za-z0-9])?(\.[A-Za-z0-9]([A-Za-z0-9-]*[A-Za-z0-9])?)*)$");
+                for (final rule in ips) {
+                  final ip = ruleMatch.firstMatch(rule)?.group(1) ?? '';
+                  if (!ipMatch.hasMatch(ip) &&
+                      !ipv6Match.hasMatch(ip) &&
+                      (!hostMatch.hasMatch(ip) ||
+                          RegExp(r"^[\d.]+$").hasMatch(ip))) {
+                    msg = "${translate("Invalid IP")} $rule";
                     setState(() {
                       isInProgress = false;
                     });
@@ -468,6 +477,7 @@ class Dialog2FaField extends ValidationField {
     this.errorText,
     this.readyCallback,
     this.onChanged,
+    this.lengths = const [6],
   }) : super(key: key);
 
   final TextEditingController controller;
@@ -478,6 +488,8 @@ class Dialog2FaField extends ValidationField {
   final String? errorText;
   final VoidCallback? readyCallback;
   final VoidCallback? onChanged;
+  // Accepted code lengths, `readyCallback` is only called at the longest.
+  final List<int> lengths;
   final errMsg = translate('2FA code must be 6 digits.');
 
   @override
@@ -501,8 +513,10 @@ class Dialog2FaField extends ValidationField {
   String get text => controller.text;
   bool get isAllDigits => text.codeUnits.every((e) => e >= 48 && e <= 57);
 
+  int get maxLength => lengths.reduce((a, b) => a > b ? a : b);
+
   @override
-  bool get isReady => text.length == 6 && isAllDigits;
+  bool get isReady => lengths.contains(text.length) && isAllDigits;
 
   @override
   String? validate() => isReady ? null : errMsg;
@@ -510,7 +524,7 @@ class Dialog2FaField extends ValidationField {
   _onChanged(StateSetter setState, SimpleWrapper<String?> errText) {
     onChanged?.call();
 
-    if (text.length > 6) {
+    if (text.length > maxLength) {
       setState(() => errText.value = errMsg);
       return;
     }
@@ -520,7 +534,7 @@ class Dialog2FaField extends ValidationField {
       return;
     }
 
-    if (isReady) {
+    if (isReady && text.length == maxLength) {
       readyCallback?.call();
       return;
     }
@@ -1883,6 +1897,9 @@ void change2fa({Function()? callback}) async {
   var new2fa = (await bind.mainGenerate2Fa());
   final secretRegex = RegExp(r'secret=([^&]+)');
   final secret = secretRegex.firstMatch(new2fa)?.group(1);
+  final digits = int.tryParse(
+          RegExp(r'digits=(\d+)').firstMatch(new2fa)?.group(1) ?? '') ??
+      6;
   String? errorText;
   final controller = TextEditingController();
   gFFI.dialogManager.show((setState, close, context) {
@@ -1900,6 +1917,7 @@ void change2fa({Function()? callback}) async {
       errorText: errorText,
       onChanged: () => setState(() => errorText = null),
       title: translate('Verification code'),
+      lengths: [digits],
       readyCallback: () {
         onVerify();
         setState(() {});
@@ -1940,6 +1958,35 @@ void change2fa({Function()? callback}) async {
   });
 }
 
+void show2FaRecoveryCodes() {
+  CommonConfirmDialog(gFFI.dialogManager, translate('2fa-recovery-codes-tip'),
+      () async {
+    final codes = await bind.mainGenerate2FaRecoveryCodes();
+    if (codes.isEmpty) {
+      showToast(translate('Failed'));
+      return;
+    }
+    gFFI.dialogManager.show((setState, close, context) {
+      copy() {
+        Clipboard.setData(ClipboardData(text: codes));
+        showToast(translate('Copied'));
+      }
+
+      return CustomAlertDialog(
+        title: Text(translate('Recovery codes')),
+        content: SelectableText(codes,
+            style: const TextStyle(fontFamily: 'monospace', fontSize: 15)),
+        actions: [
+          dialogButton('Copy to clipboard', onPressed: copy, isOutline: true),
+          dialogButton('Close', onPressed: close),
+        ],
+        onSubmit: close,
+        onCancel: close,
+      );
+    });
+  });
+}
+
 void enter2FaDialog(
     SessionID sessionId, OverlayDialogManager dialogManager) async {
   final controller = TextEditingController();
@@ -1965,6 +2012,8 @@ void enter2FaDialog(
     codeField = Dialog2FaField(
       controller: controller,
       title: translate('Verification code'),
+      // 6 or 8 digits TOTP codes, or a 20 digits recovery code
+      lengths: const [6, 8, 20],
       onChanged: () => submitReady.value = codeField.isReady,
     );
 
@@ -390,6 +390,11 @@ Future<List<TRadioMenu<String>>> toolbarImageQuality(
         value: kRemoteImageQualityLow,
         groupValue: groupValue,
         onChanged: onChanged),
+    TRadioMenu<String>(
+        child: Text(translate('Lossless text')),
+        value: kRemoteImageQualityLossless,
+        groupValue: groupValue,
+        onChanged: onChanged),
     TRadioMenu<String>(
       child: Text(translate('Custom')),
       value: kRemoteImageQualityCustom,
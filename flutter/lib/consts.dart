@@ -326,6 +326,9 @@ const kRemoteImageQualityBalanced = 'balanced';
 /// [kRemoteImageQualityLow] Low image quality, better performance.
 const kRemoteImageQualityLow = 'low';
 
+/// [kRemoteImageQualityLossless] Lossless screen content, AV1 only.
+const kRemoteImageQualityLossless = 'lossless';
+
 /// [kRemoteImageQualityCustom] Custom image quality.
 const kRemoteImageQualityCustom = 'custom';
 
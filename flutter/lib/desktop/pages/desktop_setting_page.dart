@@ -914,8 +914,16 @@ class _SafetyState extends State<_Safety> with AutomaticKeepAliveClientMixin {
         ],
       ).marginOnly(left: 30);
 
+      final recovery = Row(
+        children: [
+          ElevatedButton(
+              onPressed: locked ? null : show2FaRecoveryCodes,
+              child: Text(translate('Recovery codes'))),
+        ],
+      ).marginOnly(left: 30);
+
       return Column(
-        children: [tfa, bot, trust],
+        children: [tfa, bot, trust, recovery],
       );
     }
 
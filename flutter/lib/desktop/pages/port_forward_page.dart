@@ -256,7 +256,15 @@ class _PortForwardPageState extends State<PortForwardPage>
                   : const Color(0xFFF4F5F6)
               : Theme.of(context).colorScheme.background),
       child: Row(children: [
-        text(pf.localPort.toString()),
+        Expanded(
+            child: Column(
+          crossAxisAlignment: CrossAxisAlignment.start,
+          mainAxisAlignment: MainAxisAlignment.center,
+          children: [
+            Text(pf.localPort.toString(), style: const TextStyle(fontSize: 20)),
+            buildTunnelStats(pf.localPort),
+          ],
+        ).marginOnly(left: _kTextLeftMargin)),
         const SizedBox(width: _kColumn1Width),
         text(pf.remoteHost),
         text(pf.remotePort.toString()),
@@ -275,6 +283,21 @@ class _PortForwardPageState extends State<PortForwardPage>
     );
   }
 
+  /// Connections and traffic of the forward on [localPort] in this session.
+  Widget buildTunnelStats(int localPort) {
+    return Obx(() {
+      final stats = _ffi.portForwardStats[localPort];
+      if (stats == null) return const Offstage();
+      final sent = readableFileSize((stats['bytes_sent'] as int).toDouble());
+      final received =
+          readableFileSize((stats['bytes_received'] as int).toDouble());
+      return Text(
+        '${stats['active']}/${stats['connections']}  ↑ $sent  ↓ $received',
+        style: const TextStyle(fontSize: 11, color: MyTheme.darkGray),
+      );
+    });
+  }
+
   void refreshTunnelConfig() async {
     String peer = bind.mainGetPeerSync(id: widget.id);
     Map<String, dynamic> config = jsonDecode(peer);
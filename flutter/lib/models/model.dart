@@ -339,6 +339,8 @@ class FfiModel with ChangeNotifier {
         parent.target?.serverModel.onClientRemove(evt);
       } else if (name == 'update_quality_status') {
         parent.target?.qualityMonitorModel.updateQualityStatus(evt);
+      } else if (name == 'update_port_forward_stats') {
+        updatePortForwardStats(evt);
       } else if (name == 'update_block_input_state') {
         updateBlockInputState(evt, peerId);
       } else if (name == 'update_privacy_mode') {
@@ -1413,6 +1415,18 @@ class FfiModel with ChangeNotifier {
     }
   }
 
+  updatePortForwardStats(Map<String, dynamic> evt) {
+    try {
+      final stats = jsonDecode(evt['stats']) as List<dynamic>;
+      parent.target?.portForwardStats.value = {
+        for (final s in stats)
+          s['local_port'] as int: s as Map<String, dynamic>
+      };
+    } catch (e) {
+      debugPrint('Failed to decode port forward stats: $e');
+    }
+  }
+
   updateBlockInputState(Map<String, dynamic> evt, String peerId) {
     _inputBlocked = evt['input_state'] == 'on';
     notifyListeners();
@@ -2843,6 +2857,9 @@ class FFI {
   var closed = false;
   var auditNote = '';
 
+  /// Traffic of the forwards of a port forward session, by local port.
+  final portForwardStats = RxMap<int, Map<String, dynamic>>();
+
   /// dialogManager use late to ensure init after main page binding [globalKey]
   late final dialogManager = OverlayDialogManager();
 
@@ -1731,6 +1731,10 @@ class RustdeskImpl {
     throw UnimplementedError("mainVerify2Fa");
   }
 
+  Future<String> mainGenerate2FaRecoveryCodes({dynamic hint}) {
+    throw UnimplementedError("mainGenerate2FaRecoveryCodes");
+  }
+
   bool mainHasValid2FaSync({dynamic hint}) {
     throw UnimplementedError("mainHasValid2FaSync");
   }
@@ -42,6 +42,18 @@ impl Enigo {
         &mut self.custom_mouse
     }
 
+    /// Create an instance bound to the given X display instead of `$DISPLAY`,
+    /// e.g. a headless Xvfb server. Keys go through xdo only.
+    pub fn new_for_x11_display(display: &str) -> Self {
+        Self {
+            xdo: EnigoXdo::with_display(display),
+            is_x11: true,
+            tfc: None,
+            custom_keyboard: None,
+            custom_mouse: None,
+        }
+    }
+
     /// Clear remapped keycodes
     pub fn tfc_clear_remapped(&mut self) {
         if let Some(tfc) = &mut self.tfc {
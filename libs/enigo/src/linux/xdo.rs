@@ -79,6 +79,17 @@ impl Default for EnigoXdo {
     }
 }
 impl EnigoXdo {
+    /// Create a new EnigoXdo instance connected to the given X display, e.g. ":1".
+    pub fn with_display(display: &str) -> Self {
+        let xdo = match CString::new(display) {
+            Ok(display) => unsafe { xdo_new(display.as_ptr()) },
+            Err(_) => ptr::null(),
+        };
+        Self {
+            xdo,
+            delay: DEFAULT_DELAY,
+        }
+    }
     /// Get the delay per keypress.
     /// Default value is 12000.
     /// This is Linux-specific.
@@ -173,6 +173,7 @@ fn test_av1(
         height: height as _,
         quality,
         keyframe_interval: None,
+        lossless: false,
     });
     let mut encoder = AomEncoder::new(config, i444).unwrap();
     let start = Instant::now();
@@ -7,6 +7,7 @@
 include!(concat!(env!("OUT_DIR"), "/aom_ffi.rs"));
 
 use crate::codec::{base_bitrate, codec_thread_num};
+use crate::region::{Region, RegionHints};
 use crate::{codec::EncoderApi, EncodeFrame, STRIDE_ALIGN};
 use crate::{common::GoogleImage, generate_call_macro, generate_call_ptr_macro, Error, Result};
 use crate::{EncodeInput, EncodeYuvFormat, Pixfmt};
@@ -47,6 +48,8 @@ pub struct AomEncoderConfig {
     pub height: u32,
     pub quality: f32,
     pub keyframe_interval: Option<usize>,
+    /// Lossless screen content coding, the quality only sets the target bitrate.
+    pub lossless: bool,
 }
 
 pub struct AomEncoder {
@@ -54,7 +57,11 @@ pub struct AomEncoder {
     width: usize,
     height: usize,
     i444: bool,
+    lossless: bool,
     yuvfmt: EncodeYuvFormat,
+    active_map: Vec<u8>,
+    // Forced on the next frame, with all blocks active.
+    key_frame: bool,
 }
 
 // https://webrtc.googlesource.com/src/+/refs/heads/main/modules/video_coding/codecs/av1/libaom_av1_encoder.cc
@@ -110,7 +117,11 @@ mod webrtc {
         } else {
             c.kf_mode = aom_kf_mode::AOM_KF_DISABLED;
         }
-        let (q_min, q_max) = AomEncoder::calc_q_values(cfg.quality);
+        let (q_min, q_max) = if cfg.lossless {
+            (0, 0)
+        } else {
+            AomEncoder::calc_q_values(cfg.quality)
+        };
         c.rc_min_quantizer = q_min;
         c.rc_max_quantizer = q_max;
         c.rc_target_bitrate = AomEncoder::bitrate(cfg.width as _, cfg.height as _, cfg.quality);
@@ -132,7 +143,11 @@ mod webrtc {
         Ok(c)
     }
 
-    pub fn set_controls(ctx: *mut aom_codec_ctx_t, cfg: &aom_codec_enc_cfg) -> ResultType<()> {
+    pub fn set_controls(
+        ctx: *mut aom_codec_ctx_t,
+        cfg: &aom_codec_enc_cfg,
+        lossless: bool,
+    ) -> ResultType<()> {
         use aom_tune_content::*;
         use aome_enc_control_id::*;
         macro_rules! call_ctl {
@@ -194,6 +209,14 @@ mod webrtc {
         call_ctl!(ctx, AV1E_SET_ENABLE_SMOOTH_INTERINTRA, 0);
         call_ctl!(ctx, AV1E_SET_ENABLE_TX64, 0);
         call_ctl!(ctx, AV1E_SET_MAX_REFERENCE_FRAMES, 3);
+        if lossless {
+            // Thin text and sharp edges: no quantization, intra block copy and paeth
+            // for repeated glyphs, no aq segments as every block is lossless.
+            call_ctl!(ctx, AV1E_SET_LOSSLESS, 1);
+            call_ctl!(ctx, AV1E_SET_AQ_MODE, 0);
+            call_ctl!(ctx, AV1E_SET_ENABLE_INTRABC, 1);
+            call_ctl!(ctx, AV1E_SET_ENABLE_PAETH_INTRA, 1);
+        }
 
         Ok(())
     }
@@ -219,13 +242,16 @@ impl EncoderApi for AomEncoder {
                     flags,
                     AOM_ENCODER_ABI_VERSION as _
                 ));
-                webrtc::set_controls(&mut ctx, &c)?;
+                webrtc::set_controls(&mut ctx, &c, config.lossless)?;
                 Ok(Self {
                     ctx,
                     width: config.width as _,
                     height: config.height as _,
                     i444,
+                    lossless: config.lossless,
                     yuvfmt: Self::get_yuvfmt(config.width, config.height, i444),
+                    active_map: vec![],
+                    key_frame: false,
                 })
             }
             _ => Err(anyhow!("encoder type mismatch")),
@@ -258,9 +284,11 @@ impl EncoderApi for AomEncoder {
 
     fn set_quality(&mut self, ratio: f32) -> ResultType<()> {
         let mut c = unsafe { *self.ctx.config.enc.to_owned() };
-        let (q_min, q_max) = Self::calc_q_values(ratio);
-        c.rc_min_quantizer = q_min;
-        c.rc_max_quantizer = q_max;
+        if !self.lossless {
+            let (q_min, q_max) = Self::calc_q_values(ratio);
+            c.rc_min_quantizer = q_min;
+            c.rc_max_quantizer = q_max;
+        }
         c.rc_target_bitrate = Self::bitrate(self.width as _, self.height as _, ratio);
         call_aom!(aom_codec_enc_config_set(&mut self.ctx, &c));
         Ok(())
@@ -284,6 +312,40 @@ impl EncoderApi for AomEncoder {
     }
 
     fn disable(&self) {}
+
+    // Static blocks are inactive, aom ignores the active map on key frames.
+    // Focus is not hinted, the segments are used by the cyclic refresh aq.
+    fn set_region_hints(&mut self, hints: &RegionHints) -> ResultType<()> {
+        if !hints.fits(self.width, self.height) {
+            return Ok(());
+        }
+        let mut active_map: Vec<u8> = hints
+            .map
+            .iter()
+            .map(|r| (self.key_frame || *r != Region::Static) as u8)
+            .collect();
+        if active_map == self.active_map {
+            return Ok(());
+        }
+        let mut active: aom_active_map_t = unsafe { std::mem::zeroed() };
+        active.rows = hints.rows as _;
+        active.cols = hints.cols as _;
+        if active_map.iter().any(|a| *a == 0) {
+            active.active_map = active_map.as_mut_ptr();
+        }
+        call_aom!(aom_codec_control(
+            &mut self.ctx,
+            aome_enc_control_id::AOME_SET_ACTIVEMAP as i32,
+            &mut active as *mut aom_active_map_t
+        ));
+        self.active_map = active_map;
+        Ok(())
+    }
+
+    fn request_key_frame(&mut self) -> bool {
+        self.key_frame = true;
+        true
+    }
 }
 
 impl AomEncoder {
@@ -309,12 +371,17 @@ impl AomEncoder {
         ));
         let pts = webrtc::kTimeBaseDen / 1000 * ms;
         let duration = webrtc::kTimeBaseDen / 1000;
+        let flags = if std::mem::take(&mut self.key_frame) {
+            AOM_EFLAG_FORCE_KF
+        } else {
+            0
+        };
         call_aom!(aom_codec_encode(
             &mut self.ctx,
             &image,
             pts as _,
             duration as _, // Duration
-            0,             // Flags
+            flags as _,
         ));
 
         Ok(EncodeFrames {
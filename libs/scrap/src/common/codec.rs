@@ -14,6 +14,7 @@ use crate::vram::*;
 use crate::{
     aom::{self, AomDecoder, AomEncoder, AomEncoderConfig},
     common::GoogleImage,
+    region::RegionHints,
     vpxcodec::{self, VpxDecoder, VpxDecoderConfig, VpxEncoder, VpxEncoderConfig, VpxVideoCodecId},
     CodecFormat, EncodeInput, EncodeYuvFormat, ImageRgb, ImageTexture,
 };
@@ -73,6 +74,17 @@ pub trait EncoderApi {
     fn is_hardware(&self) -> bool;
 
     fn disable(&self);
+
+    /// Per block hints for the next frame, ignored by encoders without region control.
+    fn set_region_hints(&mut self, _hints: &RegionHints) -> ResultType<()> {
+        Ok(())
+    }
+
+    /// Make the next frame a key frame, `false` if the encoder can not and has
+    /// to be recreated for one.
+    fn request_key_frame(&mut self) -> bool {
+        false
+    }
 }
 
 pub struct Encoder {
@@ -886,6 +898,9 @@ pub fn allow_d3d_render() -> bool {
 pub const BR_BEST: f32 = 1.5;
 pub const BR_BALANCED: f32 = 0.67;
 pub const BR_SPEED: f32 = 0.5;
+/// Flag of `custom_image_quality` asking for lossless screen content coding. It is
+/// above the 12 bits of the custom quality, older peers only see the quality.
+pub const IMAGE_QUALITY_LOSSLESS: i32 = 1 << 20;
 
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum Quality {
@@ -893,6 +908,8 @@ pub enum Quality {
     Balanced,
     Low,
     Custom(f32),
+    /// AV1 lossless with the screen content tools, `Best` for other codecs.
+    Lossless,
 }
 
 impl Default for Quality {
@@ -911,7 +928,7 @@ impl Quality {
 
     pub fn ratio(&self) -> f32 {
         match self {
-            Quality::Best => BR_BEST,
+            Quality::Best | Quality::Lossless => BR_BEST,
             Quality::Balanced => BR_BALANCED,
             Quality::Low => BR_SPEED,
             Quality::Custom(v) => *v,
@@ -1100,6 +1117,7 @@ pub fn test_av1() {
                     height,
                     quality,
                     keyframe_interval,
+                    lossless: false,
                 }),
                 i444,
             ) else {
@@ -52,6 +52,7 @@ pub mod aom;
 #[cfg(not(any(target_os = "ios")))]
 pub mod camera;
 pub mod record;
+pub mod region;
 mod vpx;
 
 #[repr(usize)]
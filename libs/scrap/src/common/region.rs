@@ -0,0 +1,277 @@
+// Change detection on captured frames and per block hints for the encoders.
+//
+// Frames are split into blocks of 16x16 pixels, the macroblocks of the VP8, VP9
+// and AV1 active maps. Every block is hashed per frame:
+//     no block changed => the frame can be skipped
+//     unchanged since the last frame an encoder encoded, and for STATIC_FRAMES
+//         frames => Static, the encoder copies it from the reference
+//     text-like near the cursor => Focus, encoded at a higher quality
+//     else => Normal
+
+/// Block size in pixels.
+pub const BLOCK: usize = 16;
+// Frames a block must be unchanged before it is static, so that the encoder has
+// refined it.
+const STATIC_FRAMES: u64 = 10;
+// Blocks around the cursor checked for text.
+const FOCUS_RADIUS: usize = 12;
+// Luma step of a glyph edge.
+const TEXT_CONTRAST: i32 = 64;
+// Edges of the 16 * 15 horizontal neighbours in a text-like block.
+const TEXT_EDGES: usize = 24;
+const SEED: u64 = 0xcbf2_9ce4_8422_2325;
+
+pub fn blocks(pixels: usize) -> usize {
+    pixels.div_ceil(BLOCK)
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Region {
+    #[default]
+    Normal,
+    Focus,
+    Static,
+}
+
+/// One `Region` per block, row by row.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct RegionHints {
+    pub cols: usize,
+    pub rows: usize,
+    pub map: Vec<Region>,
+}
+
+impl RegionHints {
+    pub fn fits(&self, width: usize, height: usize) -> bool {
+        self.cols == blocks(width) && self.rows == blocks(height)
+    }
+
+    pub fn get(&self, col: usize, row: usize) -> Region {
+        self.map
+            .get(row * self.cols + col)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    pub fn is_normal(&self) -> bool {
+        self.map.iter().all(|r| *r == Region::Normal)
+    }
+}
+
+/// Tracks the blocks of 4 bytes per pixel frames, BGRA or RGBA.
+#[derive(Debug, Default)]
+pub struct DirtyTracker {
+    width: usize,
+    height: usize,
+    cols: usize,
+    frame_no: u64,
+    hashes: Vec<u64>,
+    // Frame number of the last change of every block.
+    changed_at: Vec<u64>,
+    // Whether a block is text-like, computed on demand until it changes.
+    text: Vec<Option<bool>>,
+    focus: Vec<bool>,
+}
+
+impl DirtyTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Number of the last frame passed to `update`.
+    pub fn frame_no(&self) -> u64 {
+        self.frame_no
+    }
+
+    /// Hash the blocks of a frame, returns the number of changed blocks, all of
+    /// them for the first frame or a new size. `cursor` is in frame pixels.
+    pub fn update(
+        &mut self,
+        data: &[u8],
+        width: usize,
+        height: usize,
+        stride: usize,
+        cursor: Option<(usize, usize)>,
+    ) -> usize {
+        self.frame_no += 1;
+        let (cols, rows) = (blocks(width), blocks(height));
+        let n = cols * rows;
+        if height == 0 || stride < width * 4 || data.len() < stride * (height - 1) + width * 4 {
+            self.hashes.clear();
+            return n;
+        }
+        let resized = (width, height) != (self.width, self.height) || self.hashes.len() != n;
+        if resized {
+            self.width = width;
+            self.height = height;
+            self.cols = cols;
+            self.changed_at = vec![self.frame_no; n];
+            self.text = vec![None; n];
+        }
+        let mut hashes = vec![SEED; n];
+        for y in 0..height {
+            let row = &data[y * stride..y * stride + width * 4];
+            let row_hashes = &mut hashes[(y / BLOCK) * cols..][..cols];
+            for (h, pixels) in row_hashes.iter_mut().zip(row.chunks(BLOCK * 4)) {
+                *h = hash(*h, pixels);
+            }
+        }
+        let mut changed = 0;
+        for (i, h) in hashes.iter().enumerate() {
+            if resized || self.hashes[i] != *h {
+                self.changed_at[i] = self.frame_no;
+                self.text[i] = None;
+                changed += 1;
+            }
+        }
+        self.hashes = hashes;
+        self.update_focus(data, stride, cursor);
+        changed
+    }
+
+    /// Hints for an encoder whose last encoded frame is `last_encoded`, only
+    /// blocks unchanged since then are static.
+    pub fn hints(&self, last_encoded: Option<u64>) -> RegionHints {
+        let map = self
+            .changed_at
+            .iter()
+            .zip(self.focus.iter())
+            .map(|(changed_at, focus)| {
+                let unchanged = last_encoded.is_some_and(|l| *changed_at <= l);
+                if unchanged && self.frame_no - changed_at >= STATIC_FRAMES {
+                    Region::Static
+                } else if *focus {
+                    Region::Focus
+                } else {
+                    Region::Normal
+                }
+            })
+            .collect();
+        RegionHints {
+            cols: self.cols,
+            rows: blocks(self.height),
+            map,
+        }
+    }
+
+    fn update_focus(&mut self, data: &[u8], stride: usize, cursor: Option<(usize, usize)>) {
+        self.focus = vec![false; self.hashes.len()];
+        let Some((x, y)) = cursor else {
+            return;
+        };
+        if x >= self.width || y >= self.height {
+            return;
+        }
+        let rows = blocks(self.height);
+        let (col, row) = (x / BLOCK, y / BLOCK);
+        for r in row.saturating_sub(FOCUS_RADIUS)..(row + FOCUS_RADIUS + 1).min(rows) {
+            for c in col.saturating_sub(FOCUS_RADIUS)..(col + FOCUS_RADIUS + 1).min(self.cols) {
+                let i = r * self.cols + c;
+                let (width, height) = (self.width, self.height);
+                let text = *self.text[i]
+                    .get_or_insert_with(|| text_like(data, stride, width, height, c, r));
+                self.focus[i] = text;
+            }
+        }
+    }
+}
+
+fn mix(h: u64, w: u64) -> u64 {
+    (h.rotate_left(5) ^ w).wrapping_mul(0x517c_c1b7_2722_0a95)
+}
+
+fn hash(mut h: u64, bytes: &[u8]) -> u64 {
+    let mut words = bytes.chunks_exact(8);
+    for w in &mut words {
+        h = mix(h, u64::from_le_bytes(w.try_into().unwrap_or_default()));
+    }
+    for b in words.remainder() {
+        h = mix(h, *b as u64);
+    }
+    h
+}
+
+// Text has many sharp luma steps between neighbours, photos and gradients few.
+fn text_like(
+    data: &[u8],
+    stride: usize,
+    width: usize,
+    height: usize,
+    col: usize,
+    row: usize,
+) -> bool {
+    let luma = |p: &[u8]| (p[0] as i32 + 2 * p[1] as i32 + p[2] as i32) / 4;
+    let x0 = col * BLOCK;
+    let x1 = (x0 + BLOCK).min(width);
+    let mut edges = 0;
+    for y in row * BLOCK..(row * BLOCK + BLOCK).min(height) {
+        let pixels = &data[y * stride + x0 * 4..y * stride + x1 * 4];
+        edges += pixels
+            .chunks_exact(4)
+            .zip(pixels.chunks_exact(4).skip(1))
+            .filter(|(a, b)| (luma(a) - luma(b)).abs() >= TEXT_CONTRAST)
+            .count();
+    }
+    edges >= TEXT_EDGES
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const W: usize = 100;
+    const H: usize = 40;
+
+    fn frame() -> Vec<u8> {
+        vec![0x80; W * 4 * H]
+    }
+
+    #[test]
+    fn test_dirty_blocks() {
+        let mut tracker = DirtyTracker::new();
+        let mut data = frame();
+        assert_eq!(
+            tracker.update(&data, W, H, W * 4, None),
+            blocks(W) * blocks(H)
+        );
+        assert_eq!(tracker.update(&data, W, H, W * 4, None), 0);
+        data[(20 * W + 50) * 4] = 0;
+        assert_eq!(tracker.update(&data, W, H, W * 4, None), 1);
+        let hints = tracker.hints(Some(tracker.frame_no() - 1));
+        assert!(hints.fits(W, H));
+        assert!(hints.is_normal());
+        // Static once unchanged long enough, the changed block only later.
+        let mut last = tracker.frame_no();
+        for _ in 0..STATIC_FRAMES - 1 {
+            tracker.update(&data, W, H, W * 4, None);
+            last = tracker.frame_no();
+        }
+        let hints = tracker.hints(Some(last));
+        assert_eq!(hints.get(0, 0), Region::Static);
+        assert_eq!(hints.get(50 / BLOCK, 20 / BLOCK), Region::Normal);
+        // Not static for an encoder that has not encoded the change yet.
+        assert_eq!(
+            tracker.hints(Some(2)).get(50 / BLOCK, 20 / BLOCK),
+            Region::Normal
+        );
+    }
+
+    #[test]
+    fn test_focus() {
+        let mut tracker = DirtyTracker::new();
+        let mut data = frame();
+        // Black and white stripes look like text.
+        for y in 0..BLOCK {
+            for x in 0..BLOCK {
+                let v = if x % 2 == 0 { 0 } else { 0xff };
+                data[(y * W + x) * 4..][..4].copy_from_slice(&[v, v, v, 0xff]);
+            }
+        }
+        tracker.update(&data, W, H, W * 4, Some((90, 30)));
+        let hints = tracker.hints(None);
+        assert_eq!(hints.get(0, 0), Region::Focus);
+        assert_eq!(hints.get(1, 0), Region::Normal);
+        tracker.update(&data, W, H, W * 4, None);
+        assert!(tracker.hints(None).is_normal());
+    }
+}
@@ -9,6 +9,7 @@ use hbb_common::message_proto::{Chroma, EncodedVideoFrame, EncodedVideoFrames, V
 use hbb_common::ResultType;
 
 use crate::codec::{base_bitrate, codec_thread_num, EncoderApi};
+use crate::region::{self, Region, RegionHints};
 use crate::{EncodeInput, EncodeYuvFormat, GoogleImage, Pixfmt, STRIDE_ALIGN};
 
 use super::vpx::{vp8e_enc_control_id::*, vpx_codec_err_t::*, *};
@@ -39,6 +40,11 @@ pub struct VpxEncoder {
     id: VpxVideoCodecId,
     i444: bool,
     yuvfmt: EncodeYuvFormat,
+    // Periodic key frames can not skip static blocks.
+    keyframes: bool,
+    region_hints: RegionHints,
+    // Forced on the next frame, which can not skip static blocks either.
+    key_frame: bool,
 }
 
 pub struct VpxDecoder {
@@ -165,6 +171,9 @@ impl EncoderApi for VpxEncoder {
                     id: config.codec,
                     i444,
                     yuvfmt: Self::get_yuvfmt(config.width, config.height, i444),
+                    keyframes: config.keyframe_interval.is_some(),
+                    region_hints: Default::default(),
+                    key_frame: false,
                 })
             }
             _ => Err(anyhow!("encoder type mismatch")),
@@ -228,9 +237,118 @@ impl EncoderApi for VpxEncoder {
     }
 
     fn disable(&self) {}
+
+    fn set_region_hints(&mut self, hints: &RegionHints) -> ResultType<()> {
+        if !hints.fits(self.width, self.height) {
+            return Ok(());
+        }
+        let mut hints = hints.clone();
+        if self.keyframes || self.key_frame {
+            for r in hints.map.iter_mut().filter(|r| **r == Region::Static) {
+                *r = Region::Normal;
+            }
+        }
+        if hints == self.region_hints {
+            return Ok(());
+        }
+        match self.id {
+            VpxVideoCodecId::VP8 => self.set_vp8_region_hints(&hints)?,
+            VpxVideoCodecId::VP9 => self.set_vp9_region_hints(&hints)?,
+        }
+        self.region_hints = hints;
+        Ok(())
+    }
+
+    fn request_key_frame(&mut self) -> bool {
+        self.key_frame = true;
+        true
+    }
 }
 
+// Segments of the roi map.
+const SEGMENT_FOCUS: usize = 1;
+const SEGMENT_STATIC: usize = 2;
+// Quantizer delta of focus blocks, in the range -63..=63.
+const FOCUS_DELTA_Q: c_int = -20;
+
 impl VpxEncoder {
+    // VP8 maps are per macroblock, static blocks are inactive.
+    fn set_vp8_region_hints(&mut self, hints: &RegionHints) -> ResultType<()> {
+        let mut roi_map: Vec<u8> = hints
+            .map
+            .iter()
+            .map(|r| {
+                if *r == Region::Focus {
+                    SEGMENT_FOCUS as _
+                } else {
+                    0
+                }
+            })
+            .collect();
+        // The size is checked even when a null map disables the hints.
+        let mut roi: vpx_roi_map_t = unsafe { std::mem::zeroed() };
+        roi.rows = hints.rows as _;
+        roi.cols = hints.cols as _;
+        roi.delta_q[SEGMENT_FOCUS] = FOCUS_DELTA_Q;
+        if roi_map.iter().any(|s| *s != 0) {
+            roi.roi_map = roi_map.as_mut_ptr();
+        }
+        call_vpx!(vpx_codec_control_(
+            &mut self.ctx,
+            VP8E_SET_ROI_MAP as _,
+            &mut roi as *mut vpx_roi_map_t
+        ));
+        let mut active_map: Vec<u8> = hints
+            .map
+            .iter()
+            .map(|r| (*r != Region::Static) as u8)
+            .collect();
+        let mut active: vpx_active_map_t = unsafe { std::mem::zeroed() };
+        active.rows = hints.rows as _;
+        active.cols = hints.cols as _;
+        if active_map.iter().any(|a| *a == 0) {
+            active.active_map = active_map.as_mut_ptr();
+        }
+        call_vpx!(vpx_codec_control_(
+            &mut self.ctx,
+            VP8E_SET_ACTIVEMAP as _,
+            &mut active as *mut vpx_active_map_t
+        ));
+        Ok(())
+    }
+
+    // The VP9 roi map is per 8x8 block and replaces the active map, static blocks
+    // are skipped with the last frame as reference.
+    fn set_vp9_region_hints(&mut self, hints: &RegionHints) -> ResultType<()> {
+        let (cols, rows) = ((self.width + 7) / 8, (self.height + 7) / 8);
+        let mut roi_map = vec![0u8; cols * rows];
+        for (i, segment) in roi_map.iter_mut().enumerate() {
+            let (col, row) = (i % cols * 8 / region::BLOCK, i / cols * 8 / region::BLOCK);
+            *segment = match hints.get(col, row) {
+                Region::Normal => 0,
+                Region::Focus => SEGMENT_FOCUS as _,
+                Region::Static => SEGMENT_STATIC as _,
+            };
+        }
+        let mut roi: vpx_roi_map_t = unsafe { std::mem::zeroed() };
+        roi.rows = rows as _;
+        roi.cols = cols as _;
+        roi.delta_q[SEGMENT_FOCUS] = FOCUS_DELTA_Q;
+        roi.skip[SEGMENT_STATIC] = 1;
+        roi.ref_frame = [-1; 8];
+        roi.ref_frame[SEGMENT_STATIC] = 1; // LAST_FRAME
+        if !hints.is_normal() {
+            roi.enabled = 1;
+            roi.roi_map = roi_map.as_mut_ptr();
+        }
+        call_vpx!(vpx_codec_control_(
+            &mut self.ctx,
+            VP8E_SET_ROI_MAP as _,
+            &mut roi as *mut vpx_roi_map_t
+        ));
+        Ok(())
+    }
+
     pub fn encode(&mut self, pts: i64, data: &[u8], stride_align: usize) -> Result<EncodeFrames> {
         let bpp = if self.i444 { 24 } else { 12 };
         if data.len() < self.width * self.height * bpp / 8 {
@@ -252,12 +370,17 @@ impl VpxEncoder {
             data.as_ptr() as _,
         ));
 
+        let flags = if std::mem::take(&mut self.key_frame) {
+            VPX_EFLAG_FORCE_KF
+        } else {
+            0
+        };
         call_vpx!(vpx_codec_encode(
             &mut self.ctx,
             &image,
             pts as _,
             1, // Duration
-            0, // Flags
+            flags as _,
             VPX_DL_REALTIME as _,
         ));
 
@@ -2,9 +2,13 @@ pub use self::capturer::*;
 pub use self::display::*;
 pub use self::iter::*;
 pub use self::server::*;
+#[cfg(feature = "x11-window")]
+pub use self::window::*;
 
 mod capturer;
 mod display;
 mod ffi;
 mod iter;
 mod server;
+#[cfg(feature = "x11-window")]
+mod window;
@@ -0,0 +1,692 @@
+use hbb_common::libc;
+use std::{ffi::CString, io, ptr, rc::Rc, slice};
+
+use self::ffi_ext::*;
+use super::ffi::*;
+use super::Server;
+use crate::Pixfmt;
+
+// XCB requests only needed for window capture, kept out of `ffi` so the
+// Composite library is linked only with the `x11-window` feature.
+#[allow(non_camel_case_types, dead_code)]
+mod ffi_ext {
+    use super::super::ffi::*;
+    use std::ffi::c_void;
+
+    pub const XCB_MAP_STATE_VIEWABLE: u8 = 2;
+    pub const XCB_COMPOSITE_REDIRECT_AUTOMATIC: u8 = 0;
+    pub const XCB_ATOM_NONE: xcb_atom_t = 0;
+    pub const XCB_ATOM_CARDINAL: xcb_atom_t = 6;
+    pub const XCB_ATOM_WINDOW: xcb_atom_t = 33;
+
+    pub type xcb_pixmap_t = u32;
+
+    #[link(name = "xcb")]
+    extern "C" {
+        pub fn xcb_get_window_attributes(
+            c: *mut xcb_connection_t,
+            window: xcb_window_t,
+        ) -> xcb_get_window_attributes_cookie_t;
+
+        pub fn xcb_get_window_attributes_reply(
+            c: *mut xcb_connection_t,
+            cookie: xcb_get_window_attributes_cookie_t,
+            e: *mut *mut xcb_generic_error_t,
+        ) -> *mut xcb_get_window_attributes_reply_t;
+
+        pub fn xcb_translate_coordinates(
+            c: *mut xcb_connection_t,
+            src_window: xcb_window_t,
+            dst_window: xcb_window_t,
+            src_x: i16,
+            src_y: i16,
+        ) -> xcb_translate_coordinates_cookie_t;
+
+        pub fn xcb_translate_coordinates_reply(
+            c: *mut xcb_connection_t,
+            cookie: xcb_translate_coordinates_cookie_t,
+            e: *mut *mut xcb_generic_error_t,
+        ) -> *mut xcb_translate_coordinates_reply_t;
+
+        pub fn xcb_query_tree(c: *mut xcb_connection_t, window: xcb_window_t)
+            -> xcb_query_tree_cookie_t;
+
+        pub fn xcb_query_tree_reply(
+            c: *mut xcb_connection_t,
+            cookie: xcb_query_tree_cookie_t,
+            e: *mut *mut xcb_generic_error_t,
+        ) -> *mut xcb_query_tree_reply_t;
+
+        pub fn xcb_query_tree_children(r: *const xcb_query_tree_reply_t) -> *mut xcb_window_t;
+
+        pub fn xcb_query_tree_children_length(r: *const xcb_query_tree_reply_t) -> i32;
+
+        pub fn xcb_intern_atom(
+            c: *mut xcb_connection_t,
+            only_if_exists: u8,
+            name_len: u16,
+            name: *const i8,
+        ) -> xcb_intern_atom_cookie_t;
+
+        pub fn xcb_intern_atom_reply(
+            c: *mut xcb_connection_t,
+            cookie: xcb_intern_atom_cookie_t,
+            e: *mut *mut xcb_generic_error_t,
+        ) -> *mut xcb_intern_atom_reply_t;
+
+        pub fn xcb_get_property(
+            c: *mut xcb_connection_t,
+            delete: u8,
+            window: xcb_window_t,
+            property: xcb_atom_t,
+            type_: xcb_atom_t,
+            long_offset: u32,
+            long_length: u32,
+        ) -> xcb_get_property_cookie_t;
+
+        pub fn xcb_get_property_reply(
+            c: *mut xcb_connection_t,
+            cookie: xcb_get_property_cookie_t,
+            e: *mut *mut xcb_generic_error_t,
+        ) -> *mut xcb_get_property_reply_t;
+
+        pub fn xcb_get_property_value(r: *const xcb_get_property_reply_t) -> *mut c_void;
+
+        pub fn xcb_get_property_value_length(r: *const xcb_get_property_reply_t) -> i32;
+
+        pub fn xcb_free_pixmap(c: *mut xcb_connection_t, pixmap: xcb_pixmap_t) -> xcb_void_cookie_t;
+
+        pub fn xcb_flush(c: *mut xcb_connection_t) -> i32;
+    }
+
+    // Composite lives in its own library, only linked when window capture is enabled.
+    #[link(name = "xcb-composite")]
+    extern "C" {
+        pub fn xcb_composite_query_version(
+            c: *mut xcb_connection_t,
+            client_major_version: u32,
+            client_minor_version: u32,
+        ) -> xcb_composite_query_version_cookie_t;
+
+        pub fn xcb_composite_query_version_reply(
+            c: *mut xcb_connection_t,
+            cookie: xcb_composite_query_version_cookie_t,
+            e: *mut *mut xcb_generic_error_t,
+        ) -> *mut xcb_composite_query_version_reply_t;
+
+        pub fn xcb_composite_redirect_window(
+            c: *mut xcb_connection_t,
+            window: xcb_window_t,
+            update: u8,
+        ) -> xcb_void_cookie_t;
+
+        pub fn xcb_composite_unredirect_window(
+            c: *mut xcb_connection_t,
+            window: xcb_window_t,
+            update: u8,
+        ) -> xcb_void_cookie_t;
+
+        pub fn xcb_composite_name_window_pixmap(
+            c: *mut xcb_connection_t,
+            window: xcb_window_t,
+            pixmap: xcb_pixmap_t,
+        ) -> xcb_void_cookie_t;
+    }
+
+    #[repr(C)]
+    #[derive(Clone, Copy)]
+    pub struct xcb_composite_query_version_cookie_t {
+        pub sequence: u32,
+    }
+
+    #[repr(C)]
+    pub struct xcb_composite_query_version_reply_t {
+        pub response_type: u8,
+        pub pad0: u8,
+        pub sequence: u16,
+        pub length: u32,
+        pub major_version: u32,
+        pub minor_version: u32,
+        pub pad1: [u8; 16],
+    }
+
+    #[repr(C)]
+    #[derive(Clone, Copy)]
+    pub struct xcb_get_window_attributes_cookie_t {
+        pub sequence: u32,
+    }
+
+    #[repr(C)]
+    pub struct xcb_get_window_attributes_reply_t {
+        pub response_type: u8,
+        pub backing_store: u8,
+        pub sequence: u16,
+        pub length: u32,
+        pub visual: xcb_visualid_t,
+        pub _class: u16,
+        pub bit_gravity: u8,
+        pub win_gravity: u8,
+        pub backing_planes: u32,
+        pub backing_pixel: u32,
+        pub save_under: u8,
+        pub map_is_installed: u8,
+        pub map_state: u8,
+        pub override_redirect: u8,
+        pub colormap: xcb_colormap_t,
+        pub all_event_masks: u32,
+        pub your_event_mask: u32,
+        pub do_not_propagate_mask: u16,
+        pub pad0: [u8; 2],
+    }
+
+    #[repr(C)]
+    #[derive(Clone, Copy)]
+    pub struct xcb_translate_coordinates_cookie_t {
+        pub sequence: u32,
+    }
+
+    #[repr(C)]
+    pub struct xcb_translate_coordinates_reply_t {
+        pub response_type: u8,
+        pub same_screen: u8,
+        pub sequence: u16,
+        pub length: u32,
+        pub child: xcb_window_t,
+        pub dst_x: i16,
+        pub dst_y: i16,
+    }
+
+    #[repr(C)]
+    #[derive(Clone, Copy)]
+    pub struct xcb_query_tree_cookie_t {
+        pub sequence: u32,
+    }
+
+    #[repr(C)]
+    pub struct xcb_query_tree_reply_t {
+        pub response_type: u8,
+        pub pad0: u8,
+        pub sequence: u16,
+        pub length: u32,
+        pub root: xcb_window_t,
+        pub parent: xcb_window_t,
+        pub children_len: u16,
+        pub pad1: [u8; 14],
+    }
+
+    #[repr(C)]
+    #[derive(Clone, Copy)]
+    pub struct xcb_intern_atom_cookie_t {
+        pub sequence: u32,
+    }
+
+    #[repr(C)]
+    pub struct xcb_intern_atom_reply_t {
+        pub response_type: u8,
+        pub pad0: u8,
+        pub sequence: u16,
+        pub length: u32,
+        pub atom: xcb_atom_t,
+    }
+
+    #[repr(C)]
+    #[derive(Clone, Copy)]
+    pub struct xcb_get_property_cookie_t {
+        pub sequence: u32,
+    }
+
+    #[repr(C)]
+    pub struct xcb_get_property_reply_t {
+        pub response_type: u8,
+        pub format: u8,
+        pub sequence: u16,
+        pub length: u32,
+        pub type_: xcb_atom_t,
+        pub bytes_after: u32,
+        pub value_len: u32,
+        pub pad0: [u8; 12],
+    }
+}
+
+/// Geometry of a window's content, in root window coordinates.
+#[derive(Copy, Clone, Debug, Default, Hash, Eq, PartialEq)]
+pub struct WindowGeometry {
+    pub x: i32,
+    pub y: i32,
+    pub w: u16,
+    pub h: u16,
+}
+
+struct ShmSegment {
+    shmid: i32,
+    xcbid: u32,
+    buffer: *const u8,
+    size: usize,
+}
+
+impl ShmSegment {
+    fn new(server: &Server, size: usize) -> io::Result<ShmSegment> {
+        let shmid = unsafe { libc::shmget(libc::IPC_PRIVATE, size, libc::IPC_CREAT | 0o600) };
+        if shmid == -1 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let buffer = unsafe { libc::shmat(shmid, ptr::null(), libc::SHM_RDONLY) } as *mut u8;
+        if buffer as isize == -1 {
+            let err = io::Error::last_os_error();
+            unsafe {
+                libc::shmctl(shmid, libc::IPC_RMID, ptr::null_mut());
+            }
+            return Err(err);
+        }
+
+        let xcbid = unsafe { xcb_generate_id(server.raw()) };
+        unsafe {
+            xcb_shm_attach(server.raw(), xcbid, shmid as u32, 0);
+        }
+
+        Ok(ShmSegment {
+            shmid,
+            xcbid,
+            buffer,
+            size,
+        })
+    }
+
+    fn release(&mut self, server: &Server) {
+        unsafe {
+            xcb_shm_detach(server.raw(), self.xcbid);
+            libc::shmdt(self.buffer as *mut _);
+            libc::shmctl(self.shmid, libc::IPC_RMID, ptr::null_mut());
+        }
+    }
+}
+
+/// Captures the content of a single top-level window.
+///
+/// When the Composite extension is available the window is redirected
+/// off-screen, so its content is correct even when it is covered by other
+/// windows. Otherwise the window is read directly, like `xwd` does.
+pub struct WindowCapturer {
+    server: Rc<Server>,
+    window: xcb_window_t,
+    composite: bool,
+    pixmap: Option<xcb_pixmap_t>,
+    shm: Option<ShmSegment>,
+    geometry: WindowGeometry,
+    border: u16,
+    saved_raw_data: Vec<u8>,
+}
+
+impl WindowCapturer {
+    pub fn new(server: Rc<Server>, window: xcb_window_t) -> io::Result<WindowCapturer> {
+        let composite = unsafe { composite_available(server.raw()) };
+        if composite {
+            unsafe {
+                xcb_composite_redirect_window(server.raw(), window, XCB_COMPOSITE_REDIRECT_AUTOMATIC);
+            }
+        }
+
+        let mut capturer = WindowCapturer {
+            server,
+            window,
+            composite,
+            pixmap: None,
+            shm: None,
+            geometry: WindowGeometry::default(),
+            border: 0,
+            saved_raw_data: Vec::new(),
+        };
+        capturer.update_geometry()?;
+        Ok(capturer)
+    }
+
+    pub fn window(&self) -> xcb_window_t {
+        self.window
+    }
+
+    /// Geometry seen by the last `frame` call.
+    pub fn geometry(&self) -> WindowGeometry {
+        self.geometry
+    }
+
+    pub fn pixfmt(&self) -> Pixfmt {
+        Pixfmt::BGRA
+    }
+
+    /// Whether the window is mapped and visible, i.e. not withdrawn or minimized.
+    pub fn is_viewable(&self) -> bool {
+        unsafe { window_map_state(self.server.raw(), self.window) == Some(XCB_MAP_STATE_VIEWABLE) }
+    }
+
+    /// Grab the current window content as BGRA with `geometry().w * 4` stride.
+    ///
+    /// Returns `WouldBlock` when the content did not change or the window is
+    /// not viewable, and `NotFound` once the window has been destroyed.
+    pub fn frame<'b>(&'b mut self) -> io::Result<&'b [u8]> {
+        if !self.is_viewable() {
+            // Unmapping discards the composite pixmap.
+            self.free_pixmap();
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+
+        if self.update_geometry()? || self.shm.is_none() {
+            self.free_pixmap();
+            if let Some(mut shm) = self.shm.take() {
+                shm.release(&self.server);
+            }
+            let size = self.geometry.w as usize * self.geometry.h as usize * 4;
+            self.shm = Some(ShmSegment::new(&self.server, size)?);
+            self.saved_raw_data.clear();
+        }
+
+        let (drawable, offset) = if self.composite {
+            (self.name_pixmap(), self.border as i16)
+        } else {
+            (self.window, 0)
+        };
+
+        let shm = match self.shm.as_ref() {
+            Some(shm) => shm,
+            None => return Err(io::ErrorKind::WouldBlock.into()),
+        };
+        unsafe {
+            let mut e: *mut xcb_generic_error_t = ptr::null_mut();
+            let cookie = xcb_shm_get_image_unchecked(
+                self.server.raw(),
+                drawable,
+                offset,
+                offset,
+                self.geometry.w,
+                self.geometry.h,
+                !0,
+                XCB_IMAGE_FORMAT_Z_PIXMAP,
+                shm.xcbid,
+                0,
+            );
+            let reply = xcb_shm_get_image_reply(self.server.raw(), cookie, &mut e as _);
+            if !e.is_null() {
+                libc::free(e as *mut _);
+            }
+            if reply.is_null() {
+                // The window may have been resized or unmapped in between, retry next time.
+                self.free_pixmap();
+                return Err(io::ErrorKind::WouldBlock.into());
+            }
+            libc::free(reply as *mut _);
+        }
+
+        let result = unsafe { slice::from_raw_parts(shm.buffer, shm.size) };
+        crate::would_block_if_equal(&mut self.saved_raw_data, result)?;
+        Ok(result)
+    }
+
+    /// Content grabbed by the last successful `frame` call.
+    pub fn last_frame(&self) -> &[u8] {
+        match self.shm.as_ref() {
+            Some(shm) => unsafe { slice::from_raw_parts(shm.buffer, shm.size) },
+            None => &[],
+        }
+    }
+
+    /// Re-read the window geometry, returns true if the size changed.
+    fn update_geometry(&mut self) -> io::Result<bool> {
+        let (geometry, border) = unsafe { window_geometry(self.server.raw(), self.window) }
+            .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))?;
+        let resized = geometry.w != self.geometry.w || geometry.h != self.geometry.h;
+        if resized {
+            log::debug!(
+                "window {:#x} resized: {}x{} -> {}x{}",
+                self.window,
+                self.geometry.w,
+                self.geometry.h,
+                geometry.w,
+                geometry.h
+            );
+        }
+        self.geometry = geometry;
+        self.border = border;
+        Ok(resized)
+    }
+
+    fn name_pixmap(&mut self) -> xcb_drawable_t {
+        if let Some(pixmap) = self.pixmap {
+            return pixmap;
+        }
+        let raw = self.server.raw();
+        let pixmap = unsafe { xcb_generate_id(raw) };
+        unsafe {
+            xcb_composite_name_window_pixmap(raw, self.window, pixmap);
+        }
+        self.pixmap = Some(pixmap);
+        pixmap
+    }
+
+    fn free_pixmap(&mut self) {
+        if let Some(pixmap) = self.pixmap.take() {
+            unsafe {
+                xcb_free_pixmap(self.server.raw(), pixmap);
+            }
+        }
+    }
+}
+
+impl Drop for WindowCapturer {
+    fn drop(&mut self) {
+        self.free_pixmap();
+        if let Some(mut shm) = self.shm.take() {
+            shm.release(&self.server);
+        }
+        if self.composite {
+            unsafe {
+                xcb_composite_unredirect_window(
+                    self.server.raw(),
+                    self.window,
+                    XCB_COMPOSITE_REDIRECT_AUTOMATIC,
+                );
+            }
+        }
+        unsafe {
+            xcb_flush(self.server.raw());
+        }
+    }
+}
+
+/// Top-level client windows of the display.
+///
+/// Uses the window manager's `_NET_CLIENT_LIST` when there is one, and falls
+/// back to the children of the root window otherwise (e.g. on a bare Xvfb).
+pub fn client_windows(server: &Server) -> Vec<xcb_window_t> {
+    let raw = server.raw();
+    unsafe {
+        let root = match root_window(server) {
+            Some(root) => root,
+            None => return Vec::new(),
+        };
+        let client_list = intern_atom(raw, "_NET_CLIENT_LIST");
+        let candidates = if client_list != XCB_ATOM_NONE {
+            get_property_u32(raw, root, client_list, XCB_ATOM_WINDOW)
+        } else {
+            Vec::new()
+        };
+        if candidates.is_empty() {
+            query_children(raw, root)
+        } else {
+            candidates
+        }
+    }
+}
+
+/// Top-level client windows whose `_NET_WM_PID` is one of `pids`.
+pub fn windows_of_pids(server: &Server, pids: &[u32]) -> Vec<xcb_window_t> {
+    let raw = server.raw();
+    unsafe {
+        let pid_atom = intern_atom(raw, "_NET_WM_PID");
+        if pid_atom == XCB_ATOM_NONE {
+            return Vec::new();
+        }
+        client_windows(server)
+            .into_iter()
+            .filter(|w| {
+                get_property_u32(raw, *w, pid_atom, XCB_ATOM_CARDINAL)
+                    .first()
+                    .map(|pid| pids.contains(pid))
+                    .unwrap_or(false)
+            })
+            .collect()
+    }
+}
+
+/// The window manager's `_NET_ACTIVE_WINDOW`, if any.
+pub fn active_window(server: &Server) -> Option<xcb_window_t> {
+    let raw = server.raw();
+    unsafe {
+        let root = root_window(server)?;
+        let atom = intern_atom(raw, "_NET_ACTIVE_WINDOW");
+        if atom == XCB_ATOM_NONE {
+            return None;
+        }
+        get_property_u32(raw, root, atom, XCB_ATOM_WINDOW)
+            .first()
+            .copied()
+            .filter(|w| *w != 0)
+    }
+}
+
+/// Whether `window` exists and is mapped and visible.
+pub fn is_window_viewable(server: &Server, window: xcb_window_t) -> bool {
+    unsafe { window_map_state(server.raw(), window) == Some(XCB_MAP_STATE_VIEWABLE) }
+}
+
+/// Geometry of `window` in root coordinates, `None` if it no longer exists.
+pub fn get_window_geometry(server: &Server, window: xcb_window_t) -> Option<WindowGeometry> {
+    unsafe { window_geometry(server.raw(), window).map(|(geometry, _)| geometry) }
+}
+
+unsafe fn root_window(server: &Server) -> Option<xcb_window_t> {
+    let iter = xcb_setup_roots_iterator(server.setup());
+    if iter.rem == 0 || iter.data.is_null() {
+        return None;
+    }
+    Some((*iter.data).root)
+}
+
+unsafe fn composite_available(conn: *mut xcb_connection_t) -> bool {
+    let mut e: *mut xcb_generic_error_t = ptr::null_mut();
+    let reply = xcb_composite_query_version_reply(conn, xcb_composite_query_version(conn, 0, 2), &mut e as _);
+    if !e.is_null() {
+        libc::free(e as *mut _);
+    }
+    if reply.is_null() {
+        return false;
+    }
+    let ok = (*reply).major_version > 0 || (*reply).minor_version >= 2;
+    libc::free(reply as *mut _);
+    ok
+}
+
+unsafe fn window_map_state(conn: *mut xcb_connection_t, window: xcb_window_t) -> Option<u8> {
+    let mut e: *mut xcb_generic_error_t = ptr::null_mut();
+    let reply = xcb_get_window_attributes_reply(conn, xcb_get_window_attributes(conn, window), &mut e as _);
+    if !e.is_null() {
+        libc::free(e as *mut _);
+    }
+    if reply.is_null() {
+        return None;
+    }
+    let state = (*reply).map_state;
+    libc::free(reply as *mut _);
+    Some(state)
+}
+
+unsafe fn window_geometry(
+    conn: *mut xcb_connection_t,
+    window: xcb_window_t,
+) -> Option<(WindowGeometry, u16)> {
+    let mut e: *mut xcb_generic_error_t = ptr::null_mut();
+    let geo = xcb_get_geometry_reply(conn, xcb_get_geometry_unchecked(conn, window), &mut e as _);
+    if !e.is_null() {
+        libc::free(e as *mut _);
+    }
+    if geo.is_null() {
+        return None;
+    }
+    let (root, w, h, border) = ((*geo).root, (*geo).width, (*geo).height, (*geo).border_width);
+    libc::free(geo as _);
+
+    // Geometry is relative to the parent (often a WM frame), translate to the root.
+    let mut e: *mut xcb_generic_error_t = ptr::null_mut();
+    let pos = xcb_translate_coordinates_reply(
+        conn,
+        xcb_translate_coordinates(conn, window, root, 0, 0),
+        &mut e as _,
+    );
+    if !e.is_null() {
+        libc::free(e as *mut _);
+    }
+    if pos.is_null() {
+        return None;
+    }
+    let (x, y) = ((*pos).dst_x as i32, (*pos).dst_y as i32);
+    libc::free(pos as _);
+
+    Some((WindowGeometry { x, y, w, h }, border))
+}
+
+unsafe fn intern_atom(conn: *mut xcb_connection_t, name: &str) -> xcb_atom_t {
+    let name = match CString::new(name) {
+        Ok(name) => name,
+        Err(_) => return XCB_ATOM_NONE,
+    };
+    let cookie = xcb_intern_atom(conn, 1, name.as_bytes().len() as _, name.as_ptr() as _);
+    let reply = xcb_intern_atom_reply(conn, cookie, ptr::null_mut());
+    if reply.is_null() {
+        return XCB_ATOM_NONE;
+    }
+    let atom = (*reply).atom;
+    libc::free(reply as *mut _);
+    atom
+}
+
+unsafe fn get_property_u32(
+    conn: *mut xcb_connection_t,
+    window: xcb_window_t,
+    property: xcb_atom_t,
+    type_: xcb_atom_t,
+) -> Vec<u32> {
+    let cookie = xcb_get_property(conn, 0, window, property, type_, 0, u32::MAX / 4);
+    let mut e: *mut xcb_generic_error_t = ptr::null_mut();
+    let reply = xcb_get_property_reply(conn, cookie, &mut e as _);
+    if !e.is_null() {
+        libc::free(e as *mut _);
+    }
+    if reply.is_null() {
+        return Vec::new();
+    }
+    let mut values = Vec::new();
+    if (*reply).format == 32 {
+        let len = xcb_get_property_value_length(reply) as usize / 4;
+        let data = xcb_get_property_value(reply) as *const u32;
+        if !data.is_null() {
+            values.extend_from_slice(slice::from_raw_parts(data, len));
+        }
+    }
+    libc::free(reply as *mut _);
+    values
+}
+
+unsafe fn query_children(conn: *mut xcb_connection_t, window: xcb_window_t) -> Vec<xcb_window_t> {
+    let reply = xcb_query_tree_reply(conn, xcb_query_tree(conn, window), ptr::null_mut());
+    if reply.is_null() {
+        return Vec::new();
+    }
+    let len = xcb_query_tree_children_length(reply) as usize;
+    let data = xcb_query_tree_children(reply);
+    let children = if data.is_null() {
+        Vec::new()
+    } else {
+        slice::from_raw_parts(data, len).to_vec()
+    };
+    libc::free(reply as *mut _);
+    children
+}
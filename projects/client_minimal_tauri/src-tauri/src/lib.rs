@@ -1,95 +1,94 @@
-use serde::{Deserialize, Serialize};
+use futures_util::{SinkExt, StreamExt};
+use protocol_minimal::{ClientMessage, FrameHeader, ServerMessage, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION};
+use serde::Serialize;
 use std::sync::{Arc, Mutex};
-use tauri::{Emitter, Manager, State};
+use tauri::{Emitter, State};
 use tokio::sync::mpsc;
 use tokio_tungstenite::{connect_async, tungstenite::Message};
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-struct FrameData {
+/// An encoded video frame for the frontend, which decodes it with WebCodecs.
+#[derive(Debug, Clone, Serialize)]
+struct VideoFrameEvent {
+    /// Codec name as used in `Hello`, e.g. "vp9"
+    codec: &'static str,
+    key: bool,
     width: u32,
     height: u32,
+    pts: i64,
     data: Vec<u8>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-struct ServerMessage {
-    #[serde(rename = "type")]
-    msg_type: String,
-    frame: Option<FrameData>,
-    error: Option<String>,
-}
-
-#[derive(Debug, Clone, Serialize, Deserialize)]
-struct ClientMessage {
-    #[serde(rename = "type")]
-    msg_type: String,
-    mode: Option<String>,
-    app_name: Option<String>,
-    input_type: Option<String>,
-    x: Option<i32>,
-    y: Option<i32>,
-    button: Option<String>,
-    key: Option<String>,
-}
-
 type ConnectionState = Arc<Mutex<Option<mpsc::UnboundedSender<Message>>>>;
 
+/// Connect and introduce ourselves. `codecs` are the codecs the frontend can
+/// decode, in order of preference; the server answers with `Welcome` naming
+/// the one it picked.
 #[tauri::command]
 async fn connect_to_server(
     app: tauri::AppHandle,
     connection: State<'_, ConnectionState>,
     url: String,
+    codecs: Vec<String>,
 ) -> Result<String, String> {
     let (ws_stream, _) = connect_async(&url).await.map_err(|e| e.to_string())?;
-    let (write, mut read) = ws_stream.split();
+    let (mut write, mut read) = ws_stream.split();
     let (tx, mut rx) = mpsc::unbounded_channel();
-    
-    // Store sender for sending messages
+
+    let hello = ClientMessage::Hello {
+        version: PROTOCOL_VERSION,
+        codecs,
+    };
+    tx.send(Message::Text(serde_json::to_string(&hello).map_err(|e| e.to_string())?))
+        .map_err(|e| e.to_string())?;
+
+    // Store sender for sending messages, replacing an earlier connection
     *connection.lock().unwrap() = Some(tx);
-    
-    // Spawn task to handle outgoing messages
-    let write = Arc::new(Mutex::new(write));
-    let write_clone = write.clone();
+
+    // Spawn task to handle outgoing messages, it closes the socket once every sender is gone
     tokio::spawn(async move {
         while let Some(msg) = rx.recv().await {
-            if let Ok(mut writer) = write_clone.lock() {
-                let _ = futures_util::SinkExt::send(&mut *writer, msg).await;
+            if write.send(msg).await.is_err() {
+                break;
             }
         }
+        let _ = write.close().await;
     });
-    
+
     // Spawn task to handle incoming messages
     tokio::spawn(async move {
-        use futures_util::StreamExt;
         while let Some(msg) = read.next().await {
             match msg {
-                Ok(Message::Text(text)) => {
-                    if let Ok(server_msg) = serde_json::from_str::<ServerMessage>(&text) {
-                        if let Err(e) = app.emit("frame-update", &server_msg) {
-                            log::error!("Failed to emit frame-update: {}", e);
+                Ok(Message::Text(text)) => match serde_json::from_str::<ServerMessage>(&text) {
+                    Ok(server_msg) => {
+                        if let ServerMessage::Welcome { version, codec } = &server_msg {
+                            if *version < MIN_PROTOCOL_VERSION {
+                                log::warn!("Server speaks protocol version {}, expected at least {}", version, MIN_PROTOCOL_VERSION);
+                            }
+                            log::info!("Server protocol version {}, video codec {}", version, codec.name());
+                        }
+                        if let Err(e) = app.emit("server-message", &server_msg) {
+                            log::error!("Failed to emit server-message: {}", e);
                         }
                     }
-                }
-                Ok(Message::Binary(data)) => {
-                    // Handle binary frame data
-                    if data.len() >= 8 {
-                        let width = u32::from_le_bytes([data[0], data[1], data[2], data[3]]);
-                        let height = u32::from_le_bytes([data[4], data[5], data[6], data[7]]);
-                        let frame_data = FrameData {
-                            width,
-                            height,
-                            data: data[8..].to_vec(),
+                    Err(e) => log::debug!("Ignoring unknown server message: {}", e),
+                },
+                Ok(Message::Binary(data)) => match FrameHeader::decode(&data) {
+                    Ok((header, payload)) => {
+                        let frame = VideoFrameEvent {
+                            codec: header.codec.name(),
+                            key: header.key,
+                            width: header.width,
+                            height: header.height,
+                            pts: header.pts,
+                            data: payload.to_vec(),
                         };
-                        let server_msg = ServerMessage {
-                            msg_type: "frame".to_string(),
-                            frame: Some(frame_data),
-                            error: None,
-                        };
-                        if let Err(e) = app.emit("frame-update", &server_msg) {
-                            log::error!("Failed to emit frame-update: {}", e);
+                        if let Err(e) = app.emit("video-frame", &frame) {
+                            log::error!("Failed to emit video-frame: {}", e);
                         }
                     }
-                }
+                    Err(e) => log::warn!("Dropping video frame: {}", e),
+                },
+                Ok(Message::Close(_)) => break,
                 Err(e) => {
                     log::error!("WebSocket error: {}", e);
                     break;
@@ -97,8 +96,9 @@ async fn connect_to_server(
                 _ => {}
             }
         }
+        let _ = app.emit("disconnected", ());
     });
-    
+
     Ok("Connected successfully".to_string())
 }
 
@@ -108,6 +108,8 @@ async fn disconnect_from_server(connection: State<'_, ConnectionState>) -> Resul
     Ok("Disconnected".to_string())
 }
 
+/// Send any protocol message, the frontend passes it in its JSON form,
+/// e.g. `{ "type": "MouseMove", "x": 10, "y": 20 }`.
 #[tauri::command]
 async fn send_message(
     connection: State<'_, ConnectionState>,
@@ -125,42 +127,31 @@ async fn send_message(
 }
 
 #[tauri::command]
-async fn switch_mode(
+async fn login(
     connection: State<'_, ConnectionState>,
-    mode: String,
-    app_name: Option<String>,
+    secret: String,
+    totp: Option<String>,
 ) -> Result<String, String> {
-    let message = ClientMessage {
-        msg_type: "switch_mode".to_string(),
-        mode: Some(mode),
-        app_name,
-        input_type: None,
-        x: None,
-        y: None,
-        button: None,
-        key: None,
-    };
-    send_message(connection, message).await
+    send_message(connection, ClientMessage::Login { secret, totp }).await
 }
 
+/// Pick desktop or application streaming on a hybrid server.
 #[tauri::command]
-async fn send_input(
+async fn switch_mode(
     connection: State<'_, ConnectionState>,
-    input_type: String,
-    x: Option<i32>,
-    y: Option<i32>,
-    button: Option<String>,
-    key: Option<String>,
+    mode: String,
+    screen_id: Option<u32>,
+    command: Option<String>,
+    args: Option<Vec<String>>,
 ) -> Result<String, String> {
-    let message = ClientMessage {
-        msg_type: "input".to_string(),
-        mode: None,
-        app_name: None,
-        input_type: Some(input_type),
-        x,
-        y,
-        button,
-        key,
+    let message = ClientMessage::SetMode {
+        mode,
+        screen_id,
+        command,
+        args,
+        workdir: None,
+        isolate_files: None,
+        isolate_display: None,
     };
     send_message(connection, message).await
 }
@@ -168,7 +159,7 @@ async fn send_input(
 #[cfg_attr(mobile, tauri::mobile_entry_point)]
 pub fn run() {
     env_logger::init();
-    
+
     tauri::Builder::default()
         .plugin(tauri_plugin_opener::init())
         .manage(ConnectionState::default())
@@ -176,8 +167,8 @@ pub fn run() {
             connect_to_server,
             disconnect_from_server,
             send_message,
-            switch_mode,
-            send_input
+            login,
+            switch_mode
         ])
         .run(tauri::generate_context!())
         .expect("error while running tauri application");
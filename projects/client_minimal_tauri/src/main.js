@@ -1,18 +1,197 @@
 const { invoke } = window.__TAURI__.core;
+const { listen } = window.__TAURI__.event;
 
-let greetInputEl;
-let greetMsgEl;
+// WebCodecs codec strings for the codec names used by the protocol.
+const CODEC_STRINGS = {
+  vp9: "vp09.00.10.08",
+  av1: "av01.0.04M.08",
+  vp8: "vp8",
+};
 
-async function greet() {
-  // Learn more about Tauri commands at https://tauri.app/develop/calling-rust/
-  greetMsgEl.textContent = await invoke("greet", { name: greetInputEl.value });
+const status = document.querySelector("#status");
+const canvas = document.querySelector("#canvas");
+const ctx = canvas.getContext("2d");
+
+let decoder = null;
+let decoderCodec = null;
+let decoderWidth = 0;
+let decoderHeight = 0;
+let waitingForKeyframe = true;
+
+// Codecs this webview can decode, in order of preference.
+async function supportedCodecs() {
+  if (!("VideoDecoder" in window)) {
+    return [];
+  }
+  const supported = [];
+  for (const [name, codec] of Object.entries(CODEC_STRINGS)) {
+    try {
+      const { supported: ok } = await VideoDecoder.isConfigSupported({ codec });
+      if (ok) {
+        supported.push(name);
+      }
+    } catch (e) {
+      // Unknown codec string, not supported.
+    }
+  }
+  return supported;
+}
+
+function closeDecoder() {
+  if (decoder && decoder.state !== "closed") {
+    decoder.close();
+  }
+  decoder = null;
+  waitingForKeyframe = true;
+}
+
+// (Re)create the decoder when the codec or the frame size changes.
+function ensureDecoder(frame) {
+  if (decoder && decoder.state !== "closed" && decoderCodec === frame.codec &&
+      decoderWidth === frame.width && decoderHeight === frame.height) {
+    return true;
+  }
+  closeDecoder();
+  const codec = CODEC_STRINGS[frame.codec];
+  if (!codec) {
+    status.textContent = "Unsupported codec " + frame.codec;
+    return false;
+  }
+  decoder = new VideoDecoder({
+    output: (videoFrame) => {
+      if (canvas.width !== videoFrame.displayWidth || canvas.height !== videoFrame.displayHeight) {
+        canvas.width = videoFrame.displayWidth;
+        canvas.height = videoFrame.displayHeight;
+      }
+      ctx.drawImage(videoFrame, 0, 0);
+      videoFrame.close();
+    },
+    error: (e) => {
+      console.error("Decoder error", e);
+      closeDecoder();
+    },
+  });
+  decoder.configure({ codec, codedWidth: frame.width, codedHeight: frame.height });
+  decoderCodec = frame.codec;
+  decoderWidth = frame.width;
+  decoderHeight = frame.height;
+  return true;
+}
+
+function onVideoFrame(frame) {
+  if (!ensureDecoder(frame)) {
+    return;
+  }
+  // A decoder can only start from a keyframe.
+  if (waitingForKeyframe && !frame.key) {
+    return;
+  }
+  waitingForKeyframe = false;
+  decoder.decode(new EncodedVideoChunk({
+    type: frame.key ? "key" : "delta",
+    timestamp: frame.pts * 1000,
+    data: new Uint8Array(frame.data),
+  }));
+}
+
+function onServerMessage(message) {
+  switch (message.type) {
+    case "AuthRequired":
+      document.querySelector("#login").hidden = false;
+      document.querySelector("#totp").hidden = !message.totp;
+      status.textContent = "Login required";
+      break;
+    case "LoginResult":
+      document.querySelector("#login").hidden = message.success;
+      status.textContent = message.message;
+      break;
+    case "Welcome":
+      status.textContent = `Connected, protocol ${message.version}, ${message.codec}`;
+      break;
+    case "ModeSet":
+      status.textContent = message.message;
+      break;
+    case "DisplayChanged":
+      status.textContent = `Display ${message.display.index}: ${message.display.name}`;
+      break;
+    case "Error":
+      status.textContent = "Error: " + message.message;
+      break;
+  }
+}
+
+function send(message) {
+  invoke("send_message", { message }).catch(() => {});
+}
+
+// Map a mouse event to frame coordinates, the canvas may be scaled by CSS.
+function framePoint(e) {
+  return {
+    x: Math.round(e.offsetX * canvas.width / canvas.clientWidth),
+    y: Math.round(e.offsetY * canvas.height / canvas.clientHeight),
+  };
 }
 
-window.addEventListener("DOMContentLoaded", () => {
-  greetInputEl = document.querySelector("#greet-input");
-  greetMsgEl = document.querySelector("#greet-msg");
-  document.querySelector("#greet-form").addEventListener("submit", (e) => {
+window.addEventListener("DOMContentLoaded", async () => {
+  await listen("video-frame", (event) => onVideoFrame(event.payload));
+  await listen("server-message", (event) => onServerMessage(event.payload));
+  await listen("disconnected", () => {
+    closeDecoder();
+    status.textContent = "Disconnected";
+  });
+
+  document.querySelector("#controls").addEventListener("submit", async (e) => {
+    e.preventDefault();
+    closeDecoder();
+    status.textContent = "Connecting...";
+    try {
+      const codecs = await supportedCodecs();
+      await invoke("connect_to_server", { url: document.querySelector("#url").value, codecs });
+    } catch (e) {
+      status.textContent = "Error: " + e;
+    }
+  });
+
+  document.querySelector("#disconnect").addEventListener("click", () => {
+    invoke("disconnect_from_server");
+  });
+
+  document.querySelector("#login-button").addEventListener("click", () => {
+    const totp = document.querySelector("#totp");
+    invoke("login", {
+      secret: document.querySelector("#secret").value,
+      totp: totp.hidden || !totp.value ? null : totp.value,
+    }).catch((e) => (status.textContent = "Error: " + e));
+  });
+
+  document.querySelector("#start").addEventListener("click", () => {
+    const mode = document.querySelector("#mode").value;
+    const [command, ...args] = document.querySelector("#command").value.trim().split(/\s+/);
+    invoke("switch_mode", {
+      mode,
+      screenId: mode === "desktop" ? 0 : null,
+      command: mode === "app" ? command : null,
+      args: mode === "app" ? args : null,
+    }).catch((e) => (status.textContent = "Error: " + e));
+  });
+
+  canvas.addEventListener("mousemove", (e) => send({ type: "MouseMove", ...framePoint(e) }));
+  canvas.addEventListener("mousedown", (e) => {
+    canvas.focus();
+    send({ type: "MouseClick", button: e.button, pressed: true });
+  });
+  canvas.addEventListener("mouseup", (e) => send({ type: "MouseClick", button: e.button, pressed: false }));
+  canvas.addEventListener("contextmenu", (e) => e.preventDefault());
+  canvas.addEventListener("wheel", (e) => {
+    e.preventDefault();
+    send({ type: "MouseWheel", dx: Math.sign(e.deltaX), dy: Math.sign(e.deltaY) });
+  });
+  canvas.addEventListener("keydown", (e) => {
+    e.preventDefault();
+    send({ type: "KeyPress", key: e.key, pressed: true });
+  });
+  canvas.addEventListener("keyup", (e) => {
     e.preventDefault();
-    greet();
+    send({ type: "KeyPress", key: e.key, pressed: false });
   });
 });
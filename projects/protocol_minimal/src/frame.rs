@@ -0,0 +1,133 @@
+use std::fmt;
+
+use crate::VideoCodec;
+
+/// Size in bytes of the header prepended to every binary video message.
+pub const FRAME_HEADER_LEN: usize = 20;
+/// Bumped whenever the header layout changes.
+pub const FRAME_HEADER_VERSION: u8 = 1;
+
+const FLAG_KEYFRAME: u8 = 0x01;
+
+/// Header of a binary video message.
+///
+/// Wire layout (little-endian):
+///
+/// | offset | size | field                      |
+/// |--------|------|----------------------------|
+/// | 0      | 1    | header version             |
+/// | 1      | 1    | codec (`VideoCodec` value) |
+/// | 2      | 1    | flags, bit 0 = keyframe    |
+/// | 3      | 1    | reserved                   |
+/// | 4      | 4    | width                      |
+/// | 8      | 4    | height                     |
+/// | 12     | 8    | pts in milliseconds        |
+/// | 20     | ..   | codec bitstream            |
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameHeader {
+    pub codec: VideoCodec,
+    pub key: bool,
+    pub width: u32,
+    pub height: u32,
+    pub pts: i64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FrameError {
+    /// The message is shorter than `FRAME_HEADER_LEN`.
+    Truncated(usize),
+    UnsupportedVersion(u8),
+    UnknownCodec(u8),
+}
+
+impl fmt::Display for FrameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FrameError::Truncated(len) => write!(f, "video frame of {} bytes is shorter than its header", len),
+            FrameError::UnsupportedVersion(version) => write!(f, "unsupported video frame header version {}", version),
+            FrameError::UnknownCodec(codec) => write!(f, "unknown video codec {}", codec),
+        }
+    }
+}
+
+impl std::error::Error for FrameError {}
+
+impl FrameHeader {
+    /// Build a complete binary message from this header and the codec bitstream.
+    pub fn encode(&self, payload: &[u8]) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(FRAME_HEADER_LEN + payload.len());
+        buf.push(FRAME_HEADER_VERSION);
+        buf.push(self.codec as u8);
+        buf.push(if self.key { FLAG_KEYFRAME } else { 0 });
+        buf.push(0);
+        buf.extend_from_slice(&self.width.to_le_bytes());
+        buf.extend_from_slice(&self.height.to_le_bytes());
+        buf.extend_from_slice(&self.pts.to_le_bytes());
+        buf.extend_from_slice(payload);
+        buf
+    }
+
+    /// Split a binary message into its header and codec bitstream.
+    pub fn decode(data: &[u8]) -> Result<(FrameHeader, &[u8]), FrameError> {
+        if data.len() < FRAME_HEADER_LEN {
+            return Err(FrameError::Truncated(data.len()));
+        }
+        if data[0] != FRAME_HEADER_VERSION {
+            return Err(FrameError::UnsupportedVersion(data[0]));
+        }
+        let codec = VideoCodec::from_u8(data[1]).ok_or(FrameError::UnknownCodec(data[1]))?;
+        let u32_at = |offset: usize| u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap());
+        let header = FrameHeader {
+            codec,
+            key: data[2] & FLAG_KEYFRAME != 0,
+            width: u32_at(4),
+            height: u32_at(8),
+            pts: i64::from_le_bytes(data[12..20].try_into().unwrap()),
+        };
+        Ok((header, &data[FRAME_HEADER_LEN..]))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn header_round_trip() {
+        let header = FrameHeader {
+            codec: VideoCodec::Av1,
+            key: true,
+            width: 1920,
+            height: 1080,
+            pts: 123_456,
+        };
+        let bytes = header.encode(&[1, 2, 3]);
+        assert_eq!(bytes.len(), FRAME_HEADER_LEN + 3);
+
+        let (decoded, payload) = FrameHeader::decode(&bytes).unwrap();
+        assert_eq!(decoded, header);
+        assert_eq!(payload, &[1, 2, 3]);
+    }
+
+    #[test]
+    fn rejects_malformed_headers() {
+        let header = FrameHeader {
+            codec: VideoCodec::Vp8,
+            key: false,
+            width: 1,
+            height: 1,
+            pts: 0,
+        };
+        let bytes = header.encode(&[]);
+
+        assert_eq!(FrameHeader::decode(&bytes[..8]), Err(FrameError::Truncated(8)));
+
+        let mut bad = bytes.clone();
+        bad[0] = FRAME_HEADER_VERSION + 1;
+        assert_eq!(FrameHeader::decode(&bad), Err(FrameError::UnsupportedVersion(FRAME_HEADER_VERSION + 1)));
+
+        let mut bad = bytes;
+        bad[1] = 0;
+        assert_eq!(FrameHeader::decode(&bad), Err(FrameError::UnknownCodec(0)));
+    }
+}
@@ -0,0 +1,23 @@
+//! Wire protocol between `server_minimal` and its clients.
+//!
+//! Control messages are JSON text frames, tagged by a `type` field. Video is
+//! sent as binary frames: a fixed [`FrameHeader`] followed by the codec
+//! bitstream.
+//!
+//! A client opens a session with [`ClientMessage::Hello`], stating the protocol
+//! version it speaks and the codecs it can decode. The server answers with
+//! [`ServerMessage::Welcome`] naming the codec it will send. Servers with
+//! authentication enabled expect [`ClientMessage::Login`] before anything else.
+
+mod frame;
+mod message;
+
+pub use frame::{FrameError, FrameHeader, FRAME_HEADER_LEN, FRAME_HEADER_VERSION};
+pub use message::{ClientMessage, DisplayInfo, ServerMessage, VideoCodec};
+
+/// Version of the message schema. Bumped on incompatible changes; additions
+/// with `#[serde(default)]` fields keep the version.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// Oldest client protocol version a server still accepts.
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
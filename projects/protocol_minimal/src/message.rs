@@ -0,0 +1,160 @@
+use serde::{Deserialize, Serialize};
+
+/// Video codecs a server can encode with. The discriminant is the codec byte
+/// of the binary [`FrameHeader`](crate::FrameHeader).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[cfg_attr(feature = "clap", derive(clap::ValueEnum))]
+#[serde(rename_all = "lowercase")]
+#[repr(u8)]
+pub enum VideoCodec {
+    Vp8 = 1,
+    Vp9 = 2,
+    Av1 = 3,
+}
+
+impl VideoCodec {
+    pub const ALL: [VideoCodec; 3] = [VideoCodec::Vp8, VideoCodec::Vp9, VideoCodec::Av1];
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "vp8" => Some(VideoCodec::Vp8),
+            "vp9" => Some(VideoCodec::Vp9),
+            "av1" => Some(VideoCodec::Av1),
+            _ => None,
+        }
+    }
+
+    pub fn from_u8(value: u8) -> Option<Self> {
+        Self::ALL.into_iter().find(|codec| *codec as u8 == value)
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            VideoCodec::Vp8 => "vp8",
+            VideoCodec::Vp9 => "vp9",
+            VideoCodec::Av1 => "av1",
+        }
+    }
+
+    /// Pick the codec for a client: `preferred` if the client can decode it,
+    /// otherwise the first codec it lists that the server supports.
+    ///
+    /// An empty `offered` list means the client did not say, `preferred` is
+    /// used then.
+    pub fn negotiate(preferred: VideoCodec, offered: &[String]) -> Option<VideoCodec> {
+        if offered.is_empty() {
+            return Some(preferred);
+        }
+        let offered: Vec<VideoCodec> = offered.iter().filter_map(|name| Self::from_name(name)).collect();
+        if offered.contains(&preferred) {
+            Some(preferred)
+        } else {
+            offered.first().copied()
+        }
+    }
+}
+
+/// A monitor as reported to clients by `ListDisplays`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DisplayInfo {
+    /// Index used by `screen_id` and `SwitchDisplay`, 0 is the primary display
+    pub index: u32,
+    pub name: String,
+    pub x: i32,
+    pub y: i32,
+    pub width: u32,
+    pub height: u32,
+    pub primary: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum ClientMessage {
+    /// Protocol version and the codecs the client can decode, by name in order
+    /// of preference. Unknown codec names are ignored so newer clients can
+    /// list codecs older servers do not know about.
+    Hello {
+        version: u32,
+        #[serde(default)]
+        codecs: Vec<String>,
+    },
+    /// First message on servers with authentication enabled. `secret` is the
+    /// shared secret or a per-user token.
+    Login {
+        secret: String,
+        totp: Option<String>,
+    },
+    SetMode {
+        mode: String,
+        screen_id: Option<u32>,
+        command: Option<String>,
+        args: Option<Vec<String>>,
+        workdir: Option<String>,
+        isolate_files: Option<bool>,
+        isolate_display: Option<bool>,
+    },
+    MouseMove { x: i32, y: i32 },
+    /// `button` follows `MouseEvent.button`: 0 left, 1 middle, 2 right, 3 back, 4 forward.
+    MouseClick { button: u8, pressed: bool },
+    /// Positive `dy` scrolls down and positive `dx` scrolls right, like DOM wheel events.
+    MouseWheel { dx: i32, dy: i32 },
+    /// `key` follows `KeyboardEvent.key`.
+    KeyPress { key: String, pressed: bool },
+    /// Ask for the displays that can be captured.
+    ListDisplays,
+    /// Capture another display in desktop mode, `display` is a `DisplayInfo::index`.
+    SwitchDisplay { display: u32 },
+    Ping,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum ServerMessage {
+    /// Reply to `Hello`. `codec` is what the following video frames use.
+    Welcome { version: u32, codec: VideoCodec },
+    AuthRequired { totp: bool },
+    LoginResult { success: bool, message: String },
+    ModeSet { success: bool, message: String },
+    Displays { displays: Vec<DisplayInfo>, current: Option<u32> },
+    /// Reply to `SwitchDisplay`, also sent when a desktop mode session starts.
+    DisplayChanged { display: DisplayInfo },
+    Pong,
+    Error { message: String },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn messages_are_tagged_by_type() {
+        let json = serde_json::to_string(&ClientMessage::MouseClick { button: 2, pressed: true }).unwrap();
+        assert_eq!(json, r#"{"type":"MouseClick","button":2,"pressed":true}"#);
+
+        let json = r#"{"type":"Welcome","version":1,"codec":"vp9"}"#;
+        match serde_json::from_str::<ServerMessage>(json).unwrap() {
+            ServerMessage::Welcome { version, codec } => {
+                assert_eq!(version, 1);
+                assert_eq!(codec, VideoCodec::Vp9);
+            }
+            other => panic!("unexpected message {:?}", other),
+        }
+    }
+
+    #[test]
+    fn hello_codecs_are_optional() {
+        match serde_json::from_str::<ClientMessage>(r#"{"type":"Hello","version":1}"#).unwrap() {
+            ClientMessage::Hello { codecs, .. } => assert!(codecs.is_empty()),
+            other => panic!("unexpected message {:?}", other),
+        }
+    }
+
+    #[test]
+    fn negotiate_codec() {
+        let offered = |names: &[&str]| names.iter().map(|n| n.to_string()).collect::<Vec<_>>();
+        assert_eq!(VideoCodec::negotiate(VideoCodec::Vp9, &[]), Some(VideoCodec::Vp9));
+        assert_eq!(VideoCodec::negotiate(VideoCodec::Vp9, &offered(&["av1", "vp9"])), Some(VideoCodec::Vp9));
+        assert_eq!(VideoCodec::negotiate(VideoCodec::Vp9, &offered(&["h265", "VP8"])), Some(VideoCodec::Vp8));
+        assert_eq!(VideoCodec::negotiate(VideoCodec::Vp9, &offered(&["h264"])), None);
+    }
+}
@@ -1,18 +1,44 @@
-use std::process::{Command, Stdio, Child};
 use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::rc::Rc;
+use std::time::{Duration, Instant};
 use anyhow::Result;
-use uuid::Uuid;
+use scrap::x11::{self, WindowCapturer, WindowGeometry};
 
+use crate::headless_display::{self, HeadlessDisplay, HeadlessDisplayConfig};
+use crate::input::InputArea;
 use crate::isolation::IsolationEnvironment;
+use crate::sandbox::{AppProcess, SandboxConfig, SandboxPaths};
+
+/// How often the window list of the application is refreshed.
+const WINDOW_SCAN_INTERVAL: Duration = Duration::from_secs(1);
+/// How often the disk usage of an isolated session is checked against its limit.
+const DISK_USAGE_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// A captured application window, BGRA with `width * 4` stride.
+pub struct WindowFrame<'a> {
+    pub data: &'a [u8],
+    pub width: usize,
+    pub height: usize,
+}
 
 pub struct AppStreamer {
-    pub process: Option<Child>,
-    pub window_id: Option<u64>,
+    pub process: Option<AppProcess>,
+    /// All top-level windows owned by the application's process tree.
+    pub windows: Vec<u32>,
+    x11_server: Option<Rc<x11::Server>>,
+    capturer: Option<WindowCapturer>,
+    last_window_scan: Option<Instant>,
+    /// Private X server for this session, declared after the X connections
+    /// above so those are closed before the server stops.
+    headless_display: Option<HeadlessDisplay>,
+    headless_config: Option<HeadlessDisplayConfig>,
+    sandbox: Option<SandboxConfig>,
     pub isolation_env: Option<IsolationEnvironment>,
+    last_disk_usage_check: Instant,
     pub command: String,
     pub args: Vec<String>,
     pub workdir: Option<String>,
-    pub isolate_files: bool,
 }
 
 impl AppStreamer {
@@ -20,24 +46,25 @@ impl AppStreamer {
         command: String,
         args: Vec<String>,
         workdir: Option<String>,
-        isolate_files: bool,
-        session_id: Uuid,
-    ) -> Result<Self> {
-        let mut streamer = Self {
+        isolation_env: Option<IsolationEnvironment>,
+        headless_config: Option<HeadlessDisplayConfig>,
+        sandbox: Option<SandboxConfig>,
+    ) -> Self {
+        Self {
             process: None,
-            window_id: None,
-            isolation_env: None,
+            windows: Vec::new(),
+            x11_server: None,
+            capturer: None,
+            last_window_scan: None,
+            headless_display: None,
+            headless_config,
+            sandbox,
+            isolation_env,
+            last_disk_usage_check: Instant::now(),
             command,
             args,
             workdir,
-            isolate_files,
-        };
-
-        if isolate_files {
-            streamer.isolation_env = Some(IsolationEnvironment::new(session_id)?);
         }
-
-        Ok(streamer)
     }
 
     pub fn start_application(&mut self) -> Result<()> {
@@ -50,8 +77,6 @@ impl AppStreamer {
 
         // Set up isolation environment if needed
         if let Some(ref isolation_env) = self.isolation_env {
-            // TODO: copy any configured shared files into isolation dirs
-            // isolation_env.copy_shared_files(&shared_paths)?;
             cmd.env("HOME", isolation_env.home_dir.as_os_str());
             cmd.env("XDG_DATA_HOME", isolation_env.data_dir.as_os_str());
             cmd.env("XDG_CONFIG_HOME", isolation_env.config_dir.as_os_str());
@@ -59,151 +84,286 @@ impl AppStreamer {
             cmd.env("TMPDIR", isolation_env.temp_dir.as_os_str());
         }
 
-        cmd.stdout(Stdio::piped())
-           .stderr(Stdio::piped());
+        if let Some(config) = &self.headless_config {
+            let auth_dir = match &self.isolation_env {
+                Some(isolation_env) => isolation_env.base_dir.clone(),
+                None => std::env::temp_dir(),
+            };
+            let display = HeadlessDisplay::start(config, &auth_dir)?;
+            cmd.env("DISPLAY", display.display());
+            cmd.env("XAUTHORITY", display.xauthority());
+            self.headless_display = Some(display);
+        } else if let Some(xauthority) = headless_display::host_xauthority() {
+            // Not the server's own file, which holds the cookies of every session.
+            cmd.env("XAUTHORITY", xauthority);
+        }
+
+        // Nobody reads the output, a full pipe would block the application.
+        cmd.stdout(Stdio::null())
+           .stderr(Stdio::null());
 
         log::info!("Starting application: {} with args: {:?}", self.command, self.args);
-        
-        let child = cmd.spawn()
-            .map_err(|e| anyhow::anyhow!("Failed to start application '{}': {}", self.command, e))?;
 
-        self.process = Some(child);
-        
-        // Give the process a moment to start and create its window
-        std::thread::sleep(std::time::Duration::from_millis(500));
-        
-        // Try to find the window ID
-        if let Err(e) = self.find_window_id() {
-            log::warn!("Could not find window ID for application: {}", e);
+        let process = match &self.sandbox {
+            Some(sandbox) => {
+                let paths = self.sandbox_paths(&mut cmd)?;
+                AppProcess::spawn_sandboxed(&cmd, sandbox, &paths)
+            }
+            None => AppProcess::spawn(cmd),
         }
+        .map_err(|e| anyhow::anyhow!("Failed to start application '{}': {}", self.command, e))?;
 
+        self.process = Some(process);
+        
         Ok(())
     }
 
-    pub fn capture_window_frame(&mut self) -> Result<Option<Vec<u8>>> {
-        if let Some(window_id) = self.window_id {
-            self.capture_window_by_id(window_id)
-        } else {
-            // Try to find window again
-            if self.find_window_id().is_ok() && self.window_id.is_some() {
-                return self.capture_window_frame();
+    /// Hide other sessions' directories and the host homes from a sandboxed
+    /// application, and give it an X cookie it can still read.
+    fn sandbox_paths(&self, cmd: &mut Command) -> Result<SandboxPaths> {
+        let isolation_env = self
+            .isolation_env
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("Sandboxed applications need an isolation environment"))?;
+
+        let mut hide = isolation_env.private_roots();
+        hide.push(PathBuf::from("/home"));
+        hide.push(PathBuf::from("/root"));
+        hide.extend(std::env::var_os("HOME").map(PathBuf::from));
+        hide.extend(headless_display::server_xauthority().map(|p| p.to_path_buf()));
+
+        if self.headless_display.is_none() {
+            if let Ok(display) = std::env::var("DISPLAY") {
+                let xauthority = isolation_env.base_dir.join("xauthority");
+                headless_display::extract_cookie(&display, &xauthority)?;
+                cmd.env("XAUTHORITY", &xauthority);
             }
-            Ok(None)
         }
+        if self.workdir.is_none() {
+            cmd.current_dir(&isolation_env.home_dir);
+        }
+
+        Ok(SandboxPaths {
+            hide,
+            expose: vec![isolation_env.base_dir.clone(), isolation_env.home_dir.clone()],
+        })
+    }
+
+    /// Capture the application's current main window.
+    ///
+    /// Returns `None` when nothing changed or no window is viewable, e.g. while
+    /// the application starts up or all of its windows are minimized.
+    pub fn capture_window_frame(&mut self) -> Result<Option<WindowFrame<'_>>> {
+        let scan_due = self
+            .last_window_scan
+            .map(|t| t.elapsed() >= WINDOW_SCAN_INTERVAL)
+            .unwrap_or(true);
+        if scan_due || self.capturer.is_none() {
+            self.update_windows()?;
+        }
+
+        let capturer = match self.capturer.as_mut() {
+            Some(capturer) => capturer,
+            None => return Ok(None),
+        };
+
+        match capturer.frame() {
+            Ok(_) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => return Ok(None),
+            Err(e) => {
+                log::info!("Lost window {:#x}: {}", capturer.window(), e);
+                self.capturer = None;
+                self.last_window_scan = None;
+                return Ok(None);
+            }
+        }
+
+        // Borrow again immutably so the frame can outlive the match above.
+        let capturer = match self.capturer.as_ref() {
+            Some(capturer) => capturer,
+            None => return Ok(None),
+        };
+        let WindowGeometry { w, h, .. } = capturer.geometry();
+        Ok(Some(WindowFrame {
+            data: capturer.last_frame(),
+            width: w as usize,
+            height: h as usize,
+        }))
     }
 
     pub fn is_running(&mut self) -> bool {
         if let Some(ref mut process) = self.process {
-            match process.try_wait() {
-                Ok(Some(_)) => {
-                    log::info!("Application process has exited");
-                    false
-                }
-                Ok(None) => true, // Still running
-                Err(_) => false,  // Error checking status
-            }
+            process.is_running()
         } else {
             false
         }
     }
 
+    /// Why the application is no longer available, `None` while it runs.
+    /// Stops the application when its session went over the disk usage limit.
+    pub fn stop_reason(&mut self) -> Option<String> {
+        if self.last_disk_usage_check.elapsed() >= DISK_USAGE_CHECK_INTERVAL {
+            self.last_disk_usage_check = Instant::now();
+            let exceeded = self.isolation_env.as_ref().and_then(|env| env.disk_usage_exceeded());
+            if let Some(usage) = exceeded {
+                log::warn!("Application '{}' exceeded its disk usage limit ({} bytes used)", self.command, usage);
+                let _ = self.stop_application();
+                return Some("Disk usage limit exceeded".to_string());
+            }
+        }
+
+        if !self.is_running() {
+            return Some("Application has stopped".to_string());
+        }
+        None
+    }
+
     pub fn stop_application(&mut self) -> Result<()> {
         if let Some(mut process) = self.process.take() {
-            log::info!("Stopping application process");
-            
-            // Try graceful shutdown first
-            process.kill().ok();
-            
-            // Wait a bit for graceful shutdown
-            std::thread::sleep(std::time::Duration::from_millis(1000));
-            
-            // Force kill if still running
-            let _ = process.wait();
+            log::info!("Stopping application process group {}", process.id());
+            process.terminate();
         }
 
         Ok(())
     }
 
-    #[cfg(target_os = "linux")]
-    fn find_window_id(&mut self) -> Result<()> {
-        if let Some(ref process) = self.process {
-            let pid = process.id();
-            
-            // Use xdotool to find window by PID
-            let output = Command::new("xdotool")
-                .args(&["search", "--pid", &pid.to_string()])
-                .output();
-
-            match output {
-                Ok(output) if output.status.success() => {
-                    let window_ids = String::from_utf8_lossy(&output.stdout);
-                    if let Some(first_window) = window_ids.lines().next() {
-                        if let Ok(window_id) = first_window.parse::<u64>() {
-                            self.window_id = Some(window_id);
-                            log::info!("Found window ID: {} for PID: {}", window_id, pid);
-                            return Ok(());
-                        }
-                    }
+    /// The session's private X display, `None` when running on the server's own `$DISPLAY`.
+    pub fn x11_display(&self) -> Option<&str> {
+        self.headless_display.as_ref().map(|d| d.display())
+    }
+
+    /// Current on-screen geometry of the captured window, used to map client input.
+    pub fn window_area(&self) -> Option<InputArea> {
+        let geometry = self.capturer.as_ref()?.geometry();
+        Some(InputArea {
+            x: geometry.x,
+            y: geometry.y,
+            width: geometry.w as u32,
+            height: geometry.h as u32,
+        })
+    }
+
+    /// Refresh the list of application windows and pick the one to stream:
+    /// the focused window if it belongs to the application, otherwise the
+    /// current one while it stays viewable, otherwise the largest viewable one.
+    fn update_windows(&mut self) -> Result<()> {
+        self.last_window_scan = Some(Instant::now());
+        let pid = match self.process.as_ref() {
+            Some(process) => process.id(),
+            None => return Ok(()),
+        };
+
+        if self.x11_server.is_none() {
+            let server = match self.x11_display() {
+                Some(display) => {
+                    let display = std::ffi::CString::new(display)?;
+                    x11::Server::connect(display.as_ptr() as _).map(Rc::new)
                 }
-                Ok(_) => log::warn!("xdotool found no windows for PID: {}", pid),
-                Err(e) => log::warn!("Failed to run xdotool: {}", e),
+                None => x11::Server::default(),
             }
+            .map_err(|e| anyhow::anyhow!("Failed to connect to X server: {:?}", e))?;
+            self.x11_server = Some(server);
         }
-        
-        Err(anyhow::anyhow!("Could not find window ID"))
-    }
+        let server = match self.x11_server.as_ref() {
+            Some(server) => server,
+            None => return Ok(()),
+        };
+
+        // Inside its pid namespace a sandboxed application sets _NET_WM_PID to
+        // pids every sandbox shares, its display is private so all of it is taken.
+        let windows = if self.sandbox.is_some() {
+            x11::client_windows(server)
+        } else {
+            x11::windows_of_pids(server, &process_tree(pid))
+        };
+        if windows != self.windows {
+            log::debug!("Application windows for PID {}: {:x?}", pid, windows);
+            self.windows = windows;
+        }
+
+        let viewable: Vec<u32> = self
+            .windows
+            .iter()
+            .copied()
+            .filter(|w| x11::is_window_viewable(server, *w))
+            .collect();
+        let current = self.capturer.as_ref().map(|c| c.window());
+
+        let target = x11::active_window(server)
+            .filter(|w| viewable.contains(w))
+            .or_else(|| current.filter(|w| viewable.contains(w)))
+            .or_else(|| {
+                viewable.iter().copied().max_by_key(|w| {
+                    x11::get_window_geometry(server, *w)
+                        .map(|g| g.w as u32 * g.h as u32)
+                        .unwrap_or(0)
+                })
+            });
 
-    #[cfg(target_os = "linux")]
-    fn capture_window_by_id(&self, window_id: u64) -> Result<Option<Vec<u8>>> {
-        // Use xwd to capture the window
-        let output = Command::new("xwd")
-            .args(&["-id", &window_id.to_string(), "-out", "/dev/stdout"])
-            .output();
-
-        match output {
-            Ok(output) if output.status.success() => {
-                // Parse XWD format and convert to RGB
-                if let Ok(rgb_data) = self.parse_xwd_to_rgb(&output.stdout) {
-                    Ok(Some(rgb_data))
-                } else {
-                    log::warn!("Failed to parse XWD data for window {}", window_id);
-                    Ok(None)
+        if target != current {
+            self.capturer = match target {
+                Some(window) => {
+                    log::info!("Capturing window {:#x} of PID {}", window, pid);
+                    Some(
+                        WindowCapturer::new(Rc::clone(server), window)
+                            .map_err(|e| anyhow::anyhow!("Failed to capture window {:#x}: {}", window, e))?,
+                    )
                 }
-            }
-            Ok(_) => {
-                log::warn!("xwd failed to capture window {}", window_id);
-                Ok(None)
-            }
-            Err(e) => {
-                log::error!("Failed to run xwd: {}", e);
-                Ok(None)
-            }
+                None => None,
+            };
         }
+
+        Ok(())
     }
+}
 
-    // Simple XWD to RGB conversion (basic implementation)
-    fn parse_xwd_to_rgb(&self, xwd_data: &[u8]) -> Result<Vec<u8>> {
-        // XWD header is typically 100 bytes, but we'll do a basic check
-        if xwd_data.len() < 100 {
-            return Err(anyhow::anyhow!("XWD data too short"));
+/// `root` and all of its descendant processes, so windows opened by helper
+/// processes (launcher scripts, multi-process browsers) are found too.
+fn process_tree(root: u32) -> Vec<u32> {
+    let mut parents = Vec::new();
+    if let Ok(entries) = std::fs::read_dir("/proc") {
+        for entry in entries.flatten() {
+            let pid = match entry.file_name().to_str().and_then(|s| s.parse::<u32>().ok()) {
+                Some(pid) => pid,
+                None => continue,
+            };
+            let stat = match std::fs::read_to_string(entry.path().join("stat")) {
+                Ok(stat) => stat,
+                Err(_) => continue,
+            };
+            // The command name may contain spaces, the ppid is the second field after it.
+            let ppid = stat
+                .rsplit_once(')')
+                .and_then(|(_, rest)| rest.split_whitespace().nth(1))
+                .and_then(|ppid| ppid.parse::<u32>().ok());
+            if let Some(ppid) = ppid {
+                parents.push((pid, ppid));
+            }
         }
+    }
 
-        // Skip XWD header (simplified - real implementation would parse header properly)
-        // For now, just return the data after the header as a placeholder
-        // In production, you'd want proper XWD parsing or use a different capture method
-        let header_size = 100; // Simplified assumption
-        if xwd_data.len() > header_size {
-            // Return the image data portion (this is a simplified approach)
-            Ok(xwd_data[header_size..].to_vec())
-        } else {
-            Err(anyhow::anyhow!("Invalid XWD format"))
+    let mut tree = vec![root];
+    let mut i = 0;
+    while i < tree.len() {
+        let parent = tree[i];
+        for (pid, ppid) in &parents {
+            if *ppid == parent && !tree.contains(pid) {
+                tree.push(*pid);
+            }
         }
+        i += 1;
     }
+    tree
 }
 
 impl Drop for AppStreamer {
     fn drop(&mut self) {
         let _ = self.stop_application();
+
+        // Close our X connections before the display they point to goes away.
+        self.capturer = None;
+        self.x11_server = None;
+        self.headless_display = None;
         
         if let Some(ref isolation_env) = self.isolation_env {
             if let Err(e) = isolation_env.cleanup() {
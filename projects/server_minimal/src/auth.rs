@@ -0,0 +1,208 @@
+use std::collections::HashMap;
+use std::fs;
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use anyhow::Result;
+use totp_rs::{Algorithm, Secret, TOTP};
+
+// Same TOTP parameters as `src/auth_2fa.rs` in the main client, so existing
+// authenticator app entries keep working.
+const TOTP_ISSUER: &str = "RustDesk Minimal";
+const TOTP_DIGITS: usize = 6;
+const TOTP_SKEW: u8 = 1;
+const TOTP_STEP: u64 = 30;
+
+/// Delay before answering the first failed login of an address, doubled for
+/// every further failure.
+const FAILURE_DELAY: Duration = Duration::from_secs(1);
+const MAX_FAILURE_DELAY: Duration = Duration::from_secs(30);
+/// Failures of an address after which its connections are refused.
+const MAX_FAILURES: u32 = 10;
+/// How long failures of an address are remembered.
+const FAILURE_WINDOW: Duration = Duration::from_secs(600);
+
+/// Credentials accepted by the login handshake.
+///
+/// A client authenticates with either the shared secret or one of the per-user
+/// tokens. When a TOTP secret is configured, a valid code is required as well.
+#[derive(Default)]
+pub struct AuthConfig {
+    shared_secret: Option<String>,
+    /// token -> user name
+    tokens: HashMap<String, String>,
+    totp: Option<TOTP>,
+    /// Time step of the last accepted TOTP code, a code is only good once.
+    last_totp_step: Mutex<u64>,
+}
+
+impl AuthConfig {
+    pub fn load(shared_secret: Option<String>, tokens_file: Option<&Path>, totp_secret: Option<String>) -> Result<Self> {
+        let shared_secret = shared_secret.filter(|s| !s.is_empty());
+
+        let tokens = match tokens_file {
+            Some(path) => Self::load_tokens(path)?,
+            None => HashMap::new(),
+        };
+
+        let totp = match totp_secret.filter(|s| !s.is_empty()) {
+            Some(secret) => {
+                let secret = Secret::Encoded(secret.replace(' ', "").to_uppercase())
+                    .to_bytes()
+                    .map_err(|e| anyhow::anyhow!("Invalid base32 TOTP secret: {:?}", e))?;
+                Some(
+                    TOTP::new(
+                        Algorithm::SHA1,
+                        TOTP_DIGITS,
+                        TOTP_SKEW,
+                        TOTP_STEP,
+                        secret,
+                        Some(TOTP_ISSUER.to_string()),
+                        "server".to_string(),
+                    )
+                    .map_err(|e| anyhow::anyhow!("Invalid TOTP secret: {}", e))?,
+                )
+            }
+            None => None,
+        };
+
+        Ok(Self {
+            shared_secret,
+            tokens,
+            totp,
+            last_totp_step: Mutex::new(0),
+        })
+    }
+
+    /// Token file format: one `user:token` pair per line, `#` starts a comment.
+    fn load_tokens(path: &Path) -> Result<HashMap<String, String>> {
+        let content = fs::read_to_string(path)
+            .map_err(|e| anyhow::anyhow!("Failed to read tokens file {:?}: {}", path, e))?;
+
+        let mut tokens = HashMap::new();
+        for (line_no, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (user, token) = line
+                .split_once(':')
+                .ok_or_else(|| anyhow::anyhow!("{:?}:{}: expected 'user:token'", path, line_no + 1))?;
+            let (user, token) = (user.trim(), token.trim());
+            if user.is_empty() || token.is_empty() {
+                return Err(anyhow::anyhow!("{:?}:{}: empty user or token", path, line_no + 1));
+            }
+            tokens.insert(token.to_string(), user.to_string());
+        }
+
+        log::info!("Loaded {} user tokens from {:?}", tokens.len(), path);
+        Ok(tokens)
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.shared_secret.is_some() || !self.tokens.is_empty()
+    }
+
+    pub fn requires_totp(&self) -> bool {
+        self.totp.is_some()
+    }
+
+    /// Check a login attempt. On success returns the user name bound to the
+    /// token, or `None` for shared-secret logins, which carry no identity.
+    pub fn verify(&self, secret: &str, totp_code: Option<&str>) -> Result<Option<String>, &'static str> {
+        let mut user = None;
+        let mut matched = false;
+
+        // Compare against every credential so timing does not reveal which one matched.
+        if let Some(shared_secret) = &self.shared_secret {
+            matched |= constant_time_eq(shared_secret.as_bytes(), secret.as_bytes());
+        }
+        for (token, token_user) in &self.tokens {
+            if constant_time_eq(token.as_bytes(), secret.as_bytes()) {
+                matched = true;
+                user = Some(token_user.clone());
+            }
+        }
+
+        if !matched {
+            return Err("Invalid credentials");
+        }
+
+        if let Some(totp) = &self.totp {
+            let code = totp_code.ok_or("TOTP code required")?;
+            self.check_totp(totp, code)?;
+        }
+
+        Ok(user)
+    }
+
+    /// Same window as `TOTP::check_current`, but a step is accepted only once
+    /// so that an observed code cannot be replayed.
+    fn check_totp(&self, totp: &TOTP, code: &str) -> Result<(), &'static str> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_err(|_| "Invalid system time")?
+            .as_secs()
+            / TOTP_STEP;
+        let steps = now.saturating_sub(TOTP_SKEW as u64)..=now + TOTP_SKEW as u64;
+        let step = steps
+            .filter(|step| constant_time_eq(totp.generate(step * TOTP_STEP).as_bytes(), code.as_bytes()))
+            .next_back()
+            .ok_or("Invalid TOTP code")?;
+        let mut last = self.last_totp_step.lock().unwrap();
+        if step <= *last {
+            return Err("TOTP code already used");
+        }
+        *last = step;
+        Ok(())
+    }
+}
+
+/// Failed logins per client address, shared by all connections so that a
+/// client cannot avoid the delay by opening new ones.
+#[derive(Default)]
+pub struct LoginFailures {
+    failures: Mutex<HashMap<IpAddr, (u32, Instant)>>,
+}
+
+impl LoginFailures {
+    fn lock(&self) -> MutexGuard<'_, HashMap<IpAddr, (u32, Instant)>> {
+        let mut failures = self.failures.lock().unwrap();
+        failures.retain(|_, (_, last)| last.elapsed() < FAILURE_WINDOW);
+        failures
+    }
+
+    /// Whether the address failed too often to get another attempt.
+    pub fn is_blocked(&self, ip: IpAddr) -> bool {
+        self.lock().get(&ip).map(|(count, _)| *count).unwrap_or(0) >= MAX_FAILURES
+    }
+
+    /// Record a failure, returns how long to wait before answering.
+    pub fn add(&self, ip: IpAddr) -> Duration {
+        let count = {
+            let mut failures = self.lock();
+            let failure = failures.entry(ip).or_insert((0, Instant::now()));
+            failure.0 += 1;
+            failure.1 = Instant::now();
+            failure.0
+        };
+        FAILURE_DELAY
+            .saturating_mul(1 << (count - 1).min(5))
+            .min(MAX_FAILURE_DELAY)
+    }
+
+    pub fn clear(&self, ip: IpAddr) {
+        self.failures.lock().unwrap().remove(&ip);
+    }
+}
+
+/// Compares every byte of the longer input, only the length of the longer one
+/// can be told from the timing.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    let len = a.len().max(b.len());
+    let diff = (0..len).fold(a.len() ^ b.len(), |acc, i| {
+        acc | (a.get(i).copied().unwrap_or(0) ^ b.get(i).copied().unwrap_or(0)) as usize
+    });
+    diff == 0
+}
@@ -1,45 +1,118 @@
-use scrap::{Capturer, Display};
+use scrap::{Capturer, Display, TraitCapturer};
+use protocol_minimal::DisplayInfo;
 use std::io::ErrorKind::WouldBlock;
 use std::time::{Duration, Instant};
 use anyhow::Result;
 
+use crate::input::InputArea;
+use crate::video_encoder::{FrameEncoder, VideoCodec, VideoConfig, VideoPacket};
+
+fn display_info(index: usize, display: &Display) -> DisplayInfo {
+    let (x, y) = display.origin();
+    DisplayInfo {
+        index: index as u32,
+        name: display.name(),
+        x,
+        y,
+        width: display.width() as u32,
+        height: display.height() as u32,
+        primary: display.is_primary(),
+    }
+}
+
+/// Displays in `screen_id` order: the primary display is always 0, the others
+/// follow in the order the system lists them.
+fn all_displays() -> Result<Vec<Display>> {
+    let mut displays = Display::all().map_err(|e| anyhow::anyhow!("Failed to enumerate displays: {}", e))?;
+    displays.sort_by_key(|display| !display.is_primary());
+    Ok(displays)
+}
+
+/// All displays that can be captured, in `screen_id` order.
+pub fn list_displays() -> Result<Vec<DisplayInfo>> {
+    let displays = all_displays()?;
+    Ok(displays
+        .iter()
+        .enumerate()
+        .map(|(index, display)| display_info(index, display))
+        .collect())
+}
+
 pub struct DesktopStreamer {
     capturer: Capturer,
+    display: DisplayInfo,
+    encoder: FrameEncoder,
     frame_rate: u32,
+    started: Instant,
     last_frame: Instant,
 }
 
 impl DesktopStreamer {
-    pub fn new(screen_id: u32, frame_rate: u32) -> Result<Self> {
-        let display = Display::primary().map_err(|e| anyhow::anyhow!("Failed to get primary display: {}", e))?;
-        let capturer = Capturer::new(display).map_err(|e| anyhow::anyhow!("Failed to create capturer: {}", e))?;
-        
+    pub fn new(screen_id: u32, video_config: VideoConfig) -> Result<Self> {
+        let (capturer, display) = Self::open_display(screen_id)?;
+
         Ok(Self {
             capturer,
-            frame_rate,
+            display,
+            encoder: FrameEncoder::new(video_config),
+            frame_rate: video_config.frame_rate.max(1),
+            started: Instant::now(),
             last_frame: Instant::now(),
         })
     }
 
-    pub fn capture_frame(&mut self) -> Result<Option<Vec<u8>>> {
+    fn open_display(screen_id: u32) -> Result<(Capturer, DisplayInfo)> {
+        let mut displays = all_displays()?;
+        let count = displays.len();
+        if screen_id as usize >= count {
+            return Err(anyhow::anyhow!("Display {} not found, {} display(s) available", screen_id, count));
+        }
+
+        let display = displays.remove(screen_id as usize);
+        let info = display_info(screen_id as usize, &display);
+        let capturer = Capturer::new(display).map_err(|e| anyhow::anyhow!("Failed to create capturer: {}", e))?;
+        log::info!("Capturing display {} '{}' ({}x{} at {},{})", info.index, info.name, info.width, info.height, info.x, info.y);
+        Ok((capturer, info))
+    }
+
+    /// Capture another display from now on. The encoder is kept and restarts
+    /// with a keyframe, or is recreated if the size differs.
+    pub fn switch_display(&mut self, screen_id: u32) -> Result<&DisplayInfo> {
+        if screen_id != self.display.index {
+            let (capturer, display) = Self::open_display(screen_id)?;
+            self.capturer = capturer;
+            self.display = display;
+            self.encoder.request_keyframe();
+        }
+        Ok(&self.display)
+    }
+
+    pub fn display(&self) -> &DisplayInfo {
+        &self.display
+    }
+
+    pub fn capture_frame(&mut self) -> Result<Vec<VideoPacket>> {
         let frame_duration = Duration::from_millis(1000 / self.frame_rate as u64);
-        
+
         if self.last_frame.elapsed() < frame_duration {
-            return Ok(None);
+            return Ok(Vec::new());
         }
 
-        match self.capturer.frame() {
+        let (width, height) = (self.capturer.width(), self.capturer.height());
+        match self.capturer.frame(Duration::from_millis(0)) {
             Ok(frame) => {
                 self.last_frame = Instant::now();
-                
-                // Convert BGRA to RGB and compress (simple implementation)
-                let rgb_data = self.bgra_to_rgb(&frame);
-                Ok(Some(rgb_data))
+                if !frame.valid() {
+                    return Ok(Vec::new());
+                }
+
+                let ms = self.started.elapsed().as_millis() as i64;
+                self.encoder.encode(&frame, width, height, ms)
             }
             Err(error) => {
                 if error.kind() == WouldBlock {
                     // Frame not ready yet
-                    Ok(None)
+                    Ok(Vec::new())
                 } else {
                     Err(anyhow::anyhow!("Capture error: {}", error))
                 }
@@ -47,19 +120,25 @@ impl DesktopStreamer {
         }
     }
 
+    pub fn set_codec(&mut self, codec: VideoCodec) {
+        self.encoder.set_codec(codec);
+    }
+
+    pub fn request_keyframe(&mut self) {
+        self.encoder.request_keyframe();
+    }
+
     pub fn get_dimensions(&self) -> (u32, u32) {
         (self.capturer.width() as u32, self.capturer.height() as u32)
     }
 
-    fn bgra_to_rgb(&self, bgra_data: &[u8]) -> Vec<u8> {
-        let mut rgb_data = Vec::with_capacity((bgra_data.len() / 4) * 3);
-        
-        for chunk in bgra_data.chunks_exact(4) {
-            rgb_data.push(chunk[2]); // R
-            rgb_data.push(chunk[1]); // G
-            rgb_data.push(chunk[0]); // B
+    pub fn input_area(&self) -> InputArea {
+        let (width, height) = self.get_dimensions();
+        InputArea {
+            x: self.display.x,
+            y: self.display.y,
+            width,
+            height,
         }
-        
-        rgb_data
     }
 }
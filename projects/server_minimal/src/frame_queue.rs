@@ -0,0 +1,82 @@
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use tokio::sync::Notify;
+
+use crate::video_encoder::VideoPacket;
+
+/// Bounded queue of encoded video packets between a session's capture thread
+/// and its WebSocket writer.
+///
+/// The producer never waits: when the client falls behind the oldest packets
+/// are dropped. Delta frames are useless without the frames before them, so
+/// an overflow discards the whole backlog, asks the encoder for a keyframe and
+/// skips delta frames until one arrives.
+pub struct FrameQueue {
+    state: Mutex<QueueState>,
+    notify: Notify,
+    capacity: usize,
+}
+
+#[derive(Default)]
+struct QueueState {
+    packets: VecDeque<VideoPacket>,
+    keyframe_requested: bool,
+    waiting_for_keyframe: bool,
+    dropped: u64,
+}
+
+impl FrameQueue {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            state: Mutex::new(QueueState::default()),
+            notify: Notify::new(),
+            capacity: capacity.max(1),
+        }
+    }
+
+    pub fn push(&self, packet: VideoPacket) {
+        let mut state = self.state.lock().unwrap();
+
+        if packet.key {
+            // Everything still queued is superseded by the keyframe.
+            state.dropped += state.packets.len() as u64;
+            state.packets.clear();
+            state.waiting_for_keyframe = false;
+        } else if state.waiting_for_keyframe {
+            state.dropped += 1;
+            return;
+        } else if state.packets.len() >= self.capacity {
+            state.dropped += state.packets.len() as u64 + 1;
+            state.packets.clear();
+            state.keyframe_requested = true;
+            state.waiting_for_keyframe = true;
+            log::debug!("Video queue overflow, {} packets dropped so far", state.dropped);
+            return;
+        }
+
+        state.packets.push_back(packet);
+        drop(state);
+        self.notify.notify_one();
+    }
+
+    /// Wait for the next packet.
+    pub async fn pop(&self) -> VideoPacket {
+        loop {
+            if let Some(packet) = self.state.lock().unwrap().packets.pop_front() {
+                return packet;
+            }
+            // `notify_one` keeps a permit when nobody is waiting, so a push
+            // between the check above and this await is not lost.
+            self.notify.notified().await;
+        }
+    }
+
+    /// Returns true once after an overflow, the encoder should then start a new keyframe.
+    pub fn take_keyframe_request(&self) -> bool {
+        std::mem::take(&mut self.state.lock().unwrap().keyframe_requested)
+    }
+
+    pub fn dropped(&self) -> u64 {
+        self.state.lock().unwrap().dropped
+    }
+}
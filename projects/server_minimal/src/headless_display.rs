@@ -0,0 +1,285 @@
+use std::fs;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+use anyhow::Result;
+use uuid::Uuid;
+
+// Display numbers for headless servers start well above the ones used by
+// local seats and by the main RustDesk desktop manager (0..51).
+const DISPLAY_RANGE: std::ops::Range<u32> = 100..200;
+const START_TIMEOUT: Duration = Duration::from_secs(10);
+
+static SERVER_XAUTHORITY: OnceLock<PathBuf> = OnceLock::new();
+/// `XAUTHORITY` this process was started with, for children on the host display.
+static HOST_XAUTHORITY: OnceLock<Option<PathBuf>> = OnceLock::new();
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum DisplayBackend {
+    /// Xvfb virtual framebuffer
+    Xvfb,
+    /// Xorg with the dummy video driver, needs a matching xorg.conf
+    XorgDummy,
+}
+
+#[derive(Debug, Clone)]
+pub struct HeadlessDisplayConfig {
+    pub backend: DisplayBackend,
+    pub width: u32,
+    pub height: u32,
+    /// xorg.conf for `DisplayBackend::XorgDummy`
+    pub xorg_config: Option<PathBuf>,
+    /// Window manager started on the display, e.g. `openbox`
+    pub window_manager: Option<String>,
+}
+
+impl Default for HeadlessDisplayConfig {
+    fn default() -> Self {
+        Self {
+            backend: DisplayBackend::Xvfb,
+            width: 1280,
+            height: 800,
+            xorg_config: None,
+            window_manager: None,
+        }
+    }
+}
+
+/// Point `XAUTHORITY` of this process at a private file that holds the
+/// inherited credentials plus the cookies of every headless display, so the
+/// capture (xcb) and input (xdo) connections can reach them.
+///
+/// Must run at startup, before the tokio runtime or any other thread starts:
+/// changing the environment races with threads reading it. Children never
+/// inherit this file, they get `XAUTHORITY` set explicitly.
+pub fn init_xauthority() -> Result<()> {
+    if SERVER_XAUTHORITY.get().is_some() {
+        return Ok(());
+    }
+
+    let path = std::env::temp_dir().join(format!("rustdesk-minimal-{}.xauth", std::process::id()));
+    fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&path)
+        .map_err(|e| anyhow::anyhow!("Failed to create {:?}: {}", path, e))?;
+
+    let inherited = host_xauthority();
+    if let Some(inherited) = inherited.filter(|p| p.exists()) {
+        if let Err(e) = xauth(&path, &["merge", &inherited.to_string_lossy()]) {
+            log::warn!("Failed to merge {:?} into server xauthority: {}", inherited, e);
+        }
+    }
+
+    std::env::set_var("XAUTHORITY", &path);
+    let _ = SERVER_XAUTHORITY.set(path);
+    Ok(())
+}
+
+/// Credentials for the display this process was started on, what applications
+/// on that display get as `XAUTHORITY`.
+pub fn host_xauthority() -> Option<&'static Path> {
+    HOST_XAUTHORITY
+        .get_or_init(|| {
+            std::env::var_os("XAUTHORITY")
+                .map(PathBuf::from)
+                .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".Xauthority")))
+        })
+        .as_deref()
+}
+
+/// The xauthority file created by `init_xauthority`, it holds the cookies of all sessions.
+pub fn server_xauthority() -> Option<&'static Path> {
+    SERVER_XAUTHORITY.get().map(PathBuf::as_path)
+}
+
+/// Copy the cookie for `display` from the inherited credentials into `dest`,
+/// for applications that can not read the server's own xauthority file.
+pub fn extract_cookie(display: &str, dest: &Path) -> Result<()> {
+    let source = host_xauthority()
+        .ok_or_else(|| anyhow::anyhow!("No xauthority file to take the cookie for {} from", display))?;
+    xauth(source, &["extract", &dest.to_string_lossy(), display])
+}
+
+/// A private X server started for one session and stopped when dropped.
+pub struct HeadlessDisplay {
+    display_num: u32,
+    display: String,
+    /// Cookie file handed to the application, it only knows this display.
+    xauthority: PathBuf,
+    server: Child,
+    window_manager: Option<Child>,
+}
+
+impl HeadlessDisplay {
+    /// Start a display server, `auth_dir` receives the application's xauthority file.
+    pub fn start(config: &HeadlessDisplayConfig, auth_dir: &Path) -> Result<Self> {
+        let server_xauthority = SERVER_XAUTHORITY
+            .get()
+            .ok_or_else(|| anyhow::anyhow!("init_xauthority must be called before starting headless displays"))?;
+        fs::create_dir_all(auth_dir)?;
+
+        let cookie = Uuid::new_v4().simple().to_string();
+        let mut last_error = anyhow::anyhow!("No available display found in range {:?}", DISPLAY_RANGE);
+
+        for display_num in DISPLAY_RANGE {
+            if is_x_server_running(display_num) {
+                continue;
+            }
+
+            let display = format!(":{}", display_num);
+            let xauthority = auth_dir.join(format!("rustdesk-xauth-{}", display_num));
+            xauth(&xauthority, &["add", &display, ".", &cookie])?;
+            xauth(server_xauthority, &["add", &display, ".", &cookie])?;
+
+            // Another server may grab the same number between the check and the
+            // start, in that case move on to the next one.
+            match Self::start_server(config, &display, &xauthority) {
+                Ok(mut server) => match wait_x_server_running(&mut server, display_num) {
+                    Ok(()) => {
+                        log::info!("Started {:?} display {} ({}x{})", config.backend, display, config.width, config.height);
+                        let mut headless = Self {
+                            display_num,
+                            display,
+                            xauthority,
+                            server,
+                            window_manager: None,
+                        };
+                        if let Some(wm) = &config.window_manager {
+                            headless.window_manager = Some(headless.start_window_manager(wm)?);
+                        }
+                        return Ok(headless);
+                    }
+                    Err(e) => {
+                        let _ = server.kill();
+                        let _ = server.wait();
+                        last_error = e;
+                    }
+                },
+                Err(e) => last_error = e,
+            }
+            let _ = xauth(server_xauthority, &["remove", &display]);
+            let _ = fs::remove_file(&xauthority);
+        }
+
+        Err(last_error)
+    }
+
+    pub fn display(&self) -> &str {
+        &self.display
+    }
+
+    pub fn xauthority(&self) -> &Path {
+        &self.xauthority
+    }
+
+    fn start_server(config: &HeadlessDisplayConfig, display: &str, xauthority: &Path) -> Result<Child> {
+        let mut cmd = match config.backend {
+            DisplayBackend::Xvfb => {
+                let mut cmd = Command::new("Xvfb");
+                cmd.arg(display)
+                    .args(["-screen", "0", &format!("{}x{}x24", config.width, config.height)])
+                    .args(["+extension", "Composite", "+extension", "RANDR"]);
+                cmd
+            }
+            DisplayBackend::XorgDummy => {
+                let conf = config
+                    .xorg_config
+                    .as_ref()
+                    .ok_or_else(|| anyhow::anyhow!("xorg-dummy backend needs an xorg.conf"))?;
+                let mut cmd = Command::new("Xorg");
+                cmd.arg(display)
+                    .arg("-config")
+                    .arg(conf)
+                    .args(["+extension", "GLX", "+extension", "RANDR", "+extension", "RENDER"]);
+                cmd
+            }
+        };
+
+        cmd.arg("-auth")
+            .arg(xauthority)
+            .args(["-nolisten", "tcp", "-noreset"])
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null());
+
+        cmd.spawn()
+            .map_err(|e| anyhow::anyhow!("Failed to start X server on display {}: {}", display, e))
+    }
+
+    fn start_window_manager(&self, command: &str) -> Result<Child> {
+        let mut parts = command.split_whitespace();
+        let program = parts.next().ok_or_else(|| anyhow::anyhow!("Empty window manager command"))?;
+        Command::new(program)
+            .args(parts)
+            .env("DISPLAY", &self.display)
+            .env("XAUTHORITY", &self.xauthority)
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .map_err(|e| anyhow::anyhow!("Failed to start window manager '{}': {}", command, e))
+    }
+}
+
+impl Drop for HeadlessDisplay {
+    fn drop(&mut self) {
+        if let Some(mut wm) = self.window_manager.take() {
+            let _ = wm.kill();
+            let _ = wm.wait();
+        }
+
+        log::info!("Stopping headless display {}", self.display);
+        let _ = self.server.kill();
+        let _ = self.server.wait();
+
+        if let Some(server_xauthority) = SERVER_XAUTHORITY.get() {
+            let _ = xauth(server_xauthority, &["remove", &self.display]);
+        }
+        let _ = fs::remove_file(&self.xauthority);
+        // Xvfb normally removes these itself, but not when it is killed hard.
+        let _ = fs::remove_file(format!("/tmp/.X{}-lock", self.display_num));
+        let _ = fs::remove_file(format!("/tmp/.X11-unix/X{}", self.display_num));
+    }
+}
+
+fn is_x_server_running(display_num: u32) -> bool {
+    Path::new(&format!("/tmp/.X11-unix/X{}", display_num)).exists()
+        || Path::new(&format!("/tmp/.X{}-lock", display_num)).exists()
+}
+
+fn wait_x_server_running(server: &mut Child, display_num: u32) -> Result<()> {
+    let begin = Instant::now();
+    loop {
+        if let Some(status) = server.try_wait()? {
+            return Err(anyhow::anyhow!("X server on display :{} exited with {}", display_num, status));
+        }
+        if Path::new(&format!("/tmp/.X11-unix/X{}", display_num)).exists() {
+            return Ok(());
+        }
+        if begin.elapsed() > START_TIMEOUT {
+            return Err(anyhow::anyhow!("X server on display :{} did not start within {:?}", display_num, START_TIMEOUT));
+        }
+        std::thread::sleep(Duration::from_millis(100));
+    }
+}
+
+fn xauth(file: &Path, args: &[&str]) -> Result<()> {
+    let output = Command::new("xauth")
+        .arg("-q")
+        .arg("-f")
+        .arg(file)
+        .args(args)
+        .output()
+        .map_err(|e| anyhow::anyhow!("Failed to run xauth: {}", e))?;
+    // xauth succeeds with this message when the file is new, like in the main desktop manager.
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    if !output.status.success() || (!stderr.is_empty() && !stderr.contains("does not exist")) {
+        return Err(anyhow::anyhow!("xauth {:?} failed: {}", args, stderr.trim()));
+    }
+    Ok(())
+}
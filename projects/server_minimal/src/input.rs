@@ -0,0 +1,184 @@
+use enigo::{Enigo, Key, KeyboardControllable, MouseButton, MouseControllable};
+use std::collections::HashSet;
+use anyhow::Result;
+
+/// Screen area that client coordinates are relative to, in global screen pixels.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct InputArea {
+    pub x: i32,
+    pub y: i32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl InputArea {
+    /// Map a point in frame coordinates to global screen coordinates, clamped to the area.
+    pub fn map(&self, x: i32, y: i32) -> (i32, i32) {
+        let max_x = (self.width as i32 - 1).max(0);
+        let max_y = (self.height as i32 - 1).max(0);
+        (self.x + x.clamp(0, max_x), self.y + y.clamp(0, max_y))
+    }
+}
+
+/// Injects client input through enigo and remembers what is held down,
+/// so everything can be released when the client goes away.
+pub struct InputInjector {
+    enigo: Enigo,
+    area: InputArea,
+    pressed_keys: HashSet<Key>,
+    pressed_buttons: Vec<MouseButton>,
+}
+
+impl InputInjector {
+    /// `x11_display` selects a specific X display (e.g. a headless one) instead of `$DISPLAY`.
+    pub fn new(area: InputArea, x11_display: Option<&str>) -> Self {
+        let enigo = match x11_display {
+            #[cfg(target_os = "linux")]
+            Some(display) => Enigo::new_for_x11_display(display),
+            _ => Enigo::new(),
+        };
+        Self {
+            enigo,
+            area,
+            pressed_keys: HashSet::new(),
+            pressed_buttons: Vec::new(),
+        }
+    }
+
+    pub fn set_area(&mut self, area: InputArea) {
+        if self.area != area {
+            log::debug!("Input area changed to {:?}", area);
+            self.area = area;
+        }
+    }
+
+    pub fn mouse_move(&mut self, x: i32, y: i32) {
+        let (x, y) = self.area.map(x, y);
+        self.enigo.mouse_move_to(x, y);
+    }
+
+    pub fn mouse_button(&mut self, button: u8, pressed: bool) -> Result<()> {
+        let button = map_button(button)
+            .ok_or_else(|| anyhow::anyhow!("Unsupported mouse button: {}", button))?;
+
+        if pressed {
+            self.enigo
+                .mouse_down(button)
+                .map_err(|e| anyhow::anyhow!("Failed to press mouse button {:?}: {}", button, e))?;
+            if !self.pressed_buttons.contains(&button) {
+                self.pressed_buttons.push(button);
+            }
+        } else {
+            self.enigo.mouse_up(button);
+            self.pressed_buttons.retain(|b| *b != button);
+        }
+        Ok(())
+    }
+
+    /// Positive `dy` scrolls down and positive `dx` scrolls right, like DOM wheel events.
+    pub fn mouse_wheel(&mut self, dx: i32, dy: i32) {
+        if dx != 0 {
+            self.enigo.mouse_scroll_x(dx);
+        }
+        if dy != 0 {
+            self.enigo.mouse_scroll_y(dy);
+        }
+    }
+
+    pub fn key(&mut self, name: &str, pressed: bool) -> Result<()> {
+        let key = map_key(name).ok_or_else(|| anyhow::anyhow!("Unsupported key: {}", name))?;
+
+        if pressed {
+            self.enigo
+                .key_down(key)
+                .map_err(|e| anyhow::anyhow!("Failed to press key {:?}: {}", key, e))?;
+            self.pressed_keys.insert(key);
+        } else {
+            self.enigo.key_up(key);
+            self.pressed_keys.remove(&key);
+        }
+        Ok(())
+    }
+
+    /// Release every key and button the client left pressed.
+    pub fn release_all(&mut self) {
+        for key in self.pressed_keys.drain() {
+            self.enigo.key_up(key);
+        }
+        for button in self.pressed_buttons.drain(..) {
+            self.enigo.mouse_up(button);
+        }
+    }
+}
+
+impl Drop for InputInjector {
+    fn drop(&mut self) {
+        self.release_all();
+    }
+}
+
+/// Button numbers follow `MouseEvent.button` in the browser.
+fn map_button(button: u8) -> Option<MouseButton> {
+    match button {
+        0 => Some(MouseButton::Left),
+        1 => Some(MouseButton::Middle),
+        2 => Some(MouseButton::Right),
+        3 => Some(MouseButton::Back),
+        4 => Some(MouseButton::Forward),
+        _ => None,
+    }
+}
+
+/// Key names follow `KeyboardEvent.key` in the browser; printable keys are sent as the character.
+fn map_key(name: &str) -> Option<Key> {
+    let mut chars = name.chars();
+    if let (Some(c), None) = (chars.next(), chars.next()) {
+        return Some(if c == ' ' { Key::Space } else { Key::Layout(c) });
+    }
+
+    let key = match name {
+        "Alt" | "AltLeft" => Key::Alt,
+        "AltRight" | "AltGraph" => Key::RightAlt,
+        "Control" | "ControlLeft" => Key::Control,
+        "ControlRight" => Key::RightControl,
+        "Shift" | "ShiftLeft" => Key::Shift,
+        "ShiftRight" => Key::RightShift,
+        "Meta" | "MetaLeft" | "OS" | "Super" => Key::Meta,
+        "MetaRight" => Key::RWin,
+        "CapsLock" => Key::CapsLock,
+        "NumLock" => Key::NumLock,
+        "ScrollLock" => Key::Scroll,
+        "Backspace" => Key::Backspace,
+        "Delete" => Key::Delete,
+        "Insert" => Key::Insert,
+        "Enter" => Key::Return,
+        "Tab" => Key::Tab,
+        "Escape" => Key::Escape,
+        "Space" => Key::Space,
+        "ArrowUp" => Key::UpArrow,
+        "ArrowDown" => Key::DownArrow,
+        "ArrowLeft" => Key::LeftArrow,
+        "ArrowRight" => Key::RightArrow,
+        "Home" => Key::Home,
+        "End" => Key::End,
+        "PageUp" => Key::PageUp,
+        "PageDown" => Key::PageDown,
+        "PrintScreen" => Key::Snapshot,
+        "Pause" => Key::Pause,
+        "ContextMenu" => Key::Apps,
+        "F1" => Key::F1,
+        "F2" => Key::F2,
+        "F3" => Key::F3,
+        "F4" => Key::F4,
+        "F5" => Key::F5,
+        "F6" => Key::F6,
+        "F7" => Key::F7,
+        "F8" => Key::F8,
+        "F9" => Key::F9,
+        "F10" => Key::F10,
+        "F11" => Key::F11,
+        "F12" => Key::F12,
+        _ => return None,
+    };
+    Some(key)
+}
@@ -1,8 +1,56 @@
-use std::path::PathBuf;
+use std::ffi::OsStr;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::Mutex;
 use std::fs;
 use anyhow::Result;
 use uuid::Uuid;
 
+/// Marker file in each session directory holding the pid of the server that created it.
+const OWNER_FILE: &str = ".owner";
+const SHARED_DIR: &str = "shared";
+
+/// Persistent homes currently used by a session of this server.
+static HOMES_IN_USE: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum ShareMode {
+    /// Copy shared paths into each session and mark the copies read-only. The
+    /// application runs as the owner of the copies and can make them writable
+    /// again, changes only ever reach its own copy
+    Copy,
+    /// Read-only bind mounts, needs root
+    Bind,
+}
+
+/// Server-wide settings for file isolation.
+#[derive(Debug, Clone)]
+pub struct IsolationConfig {
+    /// Directory holding one sub-directory per isolated session
+    pub root: PathBuf,
+    /// Files and directories made available read-only in every session's home
+    pub shared_paths: Vec<PathBuf>,
+    pub share_mode: ShareMode,
+    /// Bytes a session may use, including a persistent home. Only checked
+    /// periodically, writes are not refused when it is reached
+    pub disk_usage_limit: Option<u64>,
+    /// Keep homes of authenticated users here across sessions
+    pub persistent_homes: Option<PathBuf>,
+}
+
+impl Default for IsolationConfig {
+    fn default() -> Self {
+        Self {
+            root: PathBuf::from("/tmp/rustdesk-isolation"),
+            shared_paths: Vec::new(),
+            share_mode: ShareMode::Copy,
+            disk_usage_limit: None,
+            persistent_homes: None,
+        }
+    }
+}
+
 pub struct IsolationEnvironment {
     pub session_id: Uuid,
     pub base_dir: PathBuf,
@@ -11,28 +59,33 @@ pub struct IsolationEnvironment {
     pub config_dir: PathBuf,
     pub cache_dir: PathBuf,
     pub temp_dir: PathBuf,
+    disk_usage_limit: Option<u64>,
+    /// User whose persistent home is used as `home_dir`
+    persistent_user: Option<String>,
+    /// Links to shared paths placed in a persistent home
+    shared_links: Vec<PathBuf>,
 }
 
 impl IsolationEnvironment {
-    pub fn new(session_id: Uuid) -> Result<Self> {
-        let base_dir = PathBuf::from("/tmp/rustdesk-isolation").join(session_id.to_string());
-        
-        let home_dir = base_dir.join("home");
+    pub fn new(config: &IsolationConfig, session_id: Uuid, user: Option<&str>) -> Result<Self> {
+        let base_dir = config.root.join(session_id.to_string());
+        fs::create_dir_all(&base_dir)?;
+        fs::write(base_dir.join(OWNER_FILE), std::process::id().to_string())?;
+
+        let persistent_user = match (&config.persistent_homes, user) {
+            (Some(_), Some(user)) => claim_persistent_home(user),
+            _ => None,
+        };
+        let home_dir = match (&config.persistent_homes, &persistent_user) {
+            (Some(homes), Some(user)) => homes.join(user),
+            _ => base_dir.join("home"),
+        };
         let data_dir = base_dir.join("data");
         let config_dir = base_dir.join("config");
         let cache_dir = base_dir.join("cache");
         let temp_dir = base_dir.join("tmp");
 
-        // Create all directories
-        fs::create_dir_all(&home_dir)?;
-        fs::create_dir_all(&data_dir)?;
-        fs::create_dir_all(&config_dir)?;
-        fs::create_dir_all(&cache_dir)?;
-        fs::create_dir_all(&temp_dir)?;
-
-        log::info!("Created isolation environment for session {} at {:?}", session_id, base_dir);
-
-        Ok(Self {
+        let mut env = Self {
             session_id,
             base_dir,
             home_dir,
@@ -40,13 +93,51 @@ impl IsolationEnvironment {
             config_dir,
             cache_dir,
             temp_dir,
-        })
+            disk_usage_limit: config.disk_usage_limit,
+            persistent_user,
+            shared_links: Vec::new(),
+        };
+
+        if let Err(e) = env.populate(config) {
+            let _ = env.cleanup();
+            return Err(e);
+        }
+
+        log::info!(
+            "Created isolation environment for session {} at {:?}, home {:?}",
+            session_id, env.base_dir, env.home_dir
+        );
+        Ok(env)
+    }
+
+    fn populate(&mut self, config: &IsolationConfig) -> Result<()> {
+        // Create all directories
+        fs::create_dir_all(&self.home_dir)?;
+        fs::create_dir_all(&self.data_dir)?;
+        fs::create_dir_all(&self.config_dir)?;
+        fs::create_dir_all(&self.cache_dir)?;
+        fs::create_dir_all(&self.temp_dir)?;
+
+        match config.share_mode {
+            ShareMode::Copy => self.copy_shared_files(&config.shared_paths),
+            ShareMode::Bind => self.bind_shared_files(&config.shared_paths),
+        }
     }
 
     pub fn cleanup(&self) -> Result<()> {
         log::info!("Cleaning up isolation environment for session {}", self.session_id);
-        
+
+        for link in &self.shared_links {
+            let _ = fs::remove_file(link);
+        }
+        if let Some(user) = &self.persistent_user {
+            release_persistent_home(user);
+        }
+
         if self.base_dir.exists() {
+            // Removing a directory with a bind mount inside would delete the
+            // shared files themselves.
+            unmount_all(&self.base_dir)?;
             fs::remove_dir_all(&self.base_dir)
                 .map_err(|e| anyhow::anyhow!("Failed to remove isolation directory {:?}: {}", self.base_dir, e))?;
         }
@@ -54,41 +145,302 @@ impl IsolationEnvironment {
         Ok(())
     }
 
-    pub fn copy_shared_files(&self, source_paths: &[PathBuf]) -> Result<()> {
+    /// Directories holding other sessions' files, hidden from sandboxed applications.
+    pub fn private_roots(&self) -> Vec<PathBuf> {
+        let mut roots: Vec<PathBuf> = self.base_dir.parent().map(Path::to_path_buf).into_iter().collect();
+        if self.persistent_user.is_some() {
+            roots.extend(self.home_dir.parent().map(Path::to_path_buf));
+        }
+        roots
+    }
+
+    /// Bytes on disk written by the session, shared paths not included.
+    pub fn disk_usage(&self) -> u64 {
+        let shared_dir = self.base_dir.join(SHARED_DIR);
+        let mut usage = dir_usage(&self.base_dir, &shared_dir);
+        if self.persistent_user.is_some() {
+            usage += dir_usage(&self.home_dir, &shared_dir);
+        }
+        usage
+    }
+
+    /// Returns the usage when it is above the configured limit.
+    pub fn disk_usage_exceeded(&self) -> Option<u64> {
+        let limit = self.disk_usage_limit?;
+        let usage = self.disk_usage();
+        (usage > limit).then_some(usage)
+    }
+
+    /// Copy shared paths into the session, read-only, and link them from the home.
+    pub fn copy_shared_files(&mut self, source_paths: &[PathBuf]) -> Result<()> {
         for source_path in source_paths {
             if source_path.exists() {
-                let file_name = source_path.file_name()
-                    .ok_or_else(|| anyhow::anyhow!("Invalid file path: {:?}", source_path))?;
-                
-                let dest_path = self.home_dir.join(file_name);
-                
+                let dest_path = self.shared_dest(source_path)?;
+
                 if source_path.is_dir() {
                     self.copy_dir_recursive(source_path, &dest_path)?;
                 } else {
                     fs::copy(source_path, &dest_path)?;
+                    set_read_only(&dest_path)?;
                 }
-                
+
                 log::debug!("Copied {:?} to {:?}", source_path, dest_path);
+                self.link_into_home(&dest_path)?;
+            } else {
+                log::warn!("Shared path {:?} does not exist", source_path);
+            }
+        }
+        Ok(())
+    }
+
+    fn bind_shared_files(&mut self, source_paths: &[PathBuf]) -> Result<()> {
+        for source_path in source_paths {
+            if !source_path.exists() {
+                log::warn!("Shared path {:?} does not exist", source_path);
+                continue;
+            }
+
+            let dest_path = self.shared_dest(source_path)?;
+            if source_path.is_dir() {
+                fs::create_dir_all(&dest_path)?;
+            } else {
+                fs::write(&dest_path, b"")?;
+            }
+
+            run_mount(&[OsStr::new("--bind"), source_path.as_os_str(), dest_path.as_os_str()])?;
+            // The bind flag has to be set first, read-only needs a remount.
+            run_mount(&[OsStr::new("-o"), OsStr::new("remount,bind,ro"), dest_path.as_os_str()])?;
+
+            log::debug!("Bind mounted {:?} read-only at {:?}", source_path, dest_path);
+            self.link_into_home(&dest_path)?;
+        }
+        Ok(())
+    }
+
+    fn shared_dest(&self, source_path: &Path) -> Result<PathBuf> {
+        let file_name = source_path.file_name()
+            .ok_or_else(|| anyhow::anyhow!("Invalid file path: {:?}", source_path))?;
+        let shared_dir = self.base_dir.join(SHARED_DIR);
+        fs::create_dir_all(&shared_dir)?;
+        Ok(shared_dir.join(file_name))
+    }
+
+    /// Shared paths live outside the home so a persistent home never keeps a
+    /// copy, the home only gets a link to them.
+    fn link_into_home(&mut self, dest_path: &Path) -> Result<()> {
+        let link = match dest_path.file_name() {
+            Some(file_name) => self.home_dir.join(file_name),
+            None => return Ok(()),
+        };
+
+        match fs::symlink_metadata(&link) {
+            // Left over from an earlier session of a persistent home.
+            Ok(metadata) if metadata.file_type().is_symlink() => fs::remove_file(&link)?,
+            Ok(_) => {
+                log::warn!("Not linking shared path, {:?} already exists in the home", link);
+                return Ok(());
             }
+            Err(_) => {}
+        }
+
+        std::os::unix::fs::symlink(dest_path, &link)?;
+        if self.persistent_user.is_some() {
+            self.shared_links.push(link);
         }
         Ok(())
     }
 
     fn copy_dir_recursive(&self, src: &PathBuf, dst: &PathBuf) -> Result<()> {
         fs::create_dir_all(dst)?;
-        
+
         for entry in fs::read_dir(src)? {
             let entry = entry?;
             let src_path = entry.path();
             let dst_path = dst.join(entry.file_name());
-            
-            if src_path.is_dir() {
+
+            // Not following symlinks, a loop would never end and a link may lead out of the shared path.
+            let file_type = entry.file_type()?;
+            if file_type.is_symlink() {
+                log::debug!("Not copying symlink {:?}", src_path);
+            } else if file_type.is_dir() {
                 self.copy_dir_recursive(&src_path, &dst_path)?;
             } else {
                 fs::copy(&src_path, &dst_path)?;
+                set_read_only(&dst_path)?;
             }
         }
-        
+
         Ok(())
     }
 }
+
+/// Remove session directories left behind by servers that are no longer
+/// running, e.g. after a crash. Only directories named after a session id
+/// whose owner file names a dead process are removed, anything else in `root`
+/// is left alone.
+pub fn cleanup_stale(root: &Path) -> Result<()> {
+    let entries = match fs::read_dir(root) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(anyhow::anyhow!("Failed to read isolation root {:?}: {}", root, e)),
+    };
+
+    for entry in entries.flatten() {
+        let dir = entry.path();
+        // Not following symlinks, a link to elsewhere is never removed.
+        if !entry.file_type().map(|t| t.is_dir()).unwrap_or(false) {
+            continue;
+        }
+        let is_session = entry.file_name().to_str().map(|name| Uuid::parse_str(name).is_ok()).unwrap_or(false);
+        if !is_session {
+            log::debug!("Skipping {:?} in isolation root, not a session directory", dir);
+            continue;
+        }
+
+        let owner = fs::read_to_string(dir.join(OWNER_FILE))
+            .ok()
+            .and_then(|pid| pid.trim().parse::<u32>().ok());
+        let pid = match owner {
+            Some(pid) => pid,
+            None => {
+                log::warn!("Skipping {:?} in isolation root, it has no valid owner file", dir);
+                continue;
+            }
+        };
+        if pid == std::process::id() || Path::new(&format!("/proc/{}", pid)).exists() {
+            continue;
+        }
+
+        log::info!("Removing stale isolation directory {:?}", dir);
+        let result = unmount_all(&dir).and_then(|_| {
+            fs::remove_dir_all(&dir).map_err(|e| anyhow::anyhow!("Failed to remove {:?}: {}", dir, e))
+        });
+        if let Err(e) = result {
+            log::warn!("{}", e);
+        }
+    }
+    Ok(())
+}
+
+/// Only letters, digits, `.`, `_` and `-` are used as directory names.
+fn claim_persistent_home(user: &str) -> Option<String> {
+    let valid = !user.is_empty()
+        && user != "."
+        && user != ".."
+        && user.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-'));
+    if !valid {
+        log::warn!("User name {:?} can not be used as a home directory, using a temporary home", user);
+        return None;
+    }
+
+    let mut homes = HOMES_IN_USE.lock().unwrap();
+    if homes.iter().any(|u| u == user) {
+        log::warn!("Home of {} is used by another session, using a temporary home", user);
+        return None;
+    }
+    homes.push(user.to_string());
+    Some(user.to_string())
+}
+
+fn release_persistent_home(user: &str) {
+    HOMES_IN_USE.lock().unwrap().retain(|u| u != user);
+}
+
+fn set_read_only(path: &Path) -> Result<()> {
+    let mut permissions = fs::metadata(path)?.permissions();
+    permissions.set_mode(permissions.mode() & !0o222);
+    fs::set_permissions(path, permissions)?;
+    Ok(())
+}
+
+/// Allocated size of everything below `dir`, without following links or entering `skip`.
+fn dir_usage(dir: &Path, skip: &Path) -> u64 {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return 0,
+    };
+
+    let mut usage = 0;
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if path == skip {
+            continue;
+        }
+        if let Ok(metadata) = fs::symlink_metadata(&path) {
+            usage += metadata.blocks() * 512;
+            if metadata.is_dir() {
+                usage += dir_usage(&path, skip);
+            }
+        }
+    }
+    usage
+}
+
+fn run_mount(args: &[&OsStr]) -> Result<()> {
+    let output = Command::new("mount")
+        .args(args)
+        .output()
+        .map_err(|e| anyhow::anyhow!("Failed to run mount: {}", e))?;
+    if !output.status.success() {
+        return Err(anyhow::anyhow!(
+            "mount {:?} failed: {}",
+            args,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(())
+}
+
+/// Unmount everything mounted below `dir`, deepest first. Fails if anything is left.
+fn unmount_all(dir: &Path) -> Result<()> {
+    let mut mounts = mounts_under(dir);
+    mounts.sort_by_key(|m| std::cmp::Reverse(m.components().count()));
+    for mount in &mounts {
+        let status = Command::new("umount").arg(mount).status();
+        if !matches!(status, Ok(s) if s.success()) {
+            // Detach lazily if something still has files open there.
+            let _ = Command::new("umount").arg("-l").arg(mount).status();
+        }
+    }
+
+    let left = mounts_under(dir);
+    if !left.is_empty() {
+        return Err(anyhow::anyhow!("Refusing to remove {:?}, still mounted: {:?}", dir, left));
+    }
+    Ok(())
+}
+
+fn mounts_under(dir: &Path) -> Vec<PathBuf> {
+    let mountinfo = match fs::read_to_string("/proc/self/mountinfo") {
+        Ok(mountinfo) => mountinfo,
+        Err(_) => return Vec::new(),
+    };
+
+    mountinfo
+        .lines()
+        .filter_map(|line| line.split(' ').nth(4))
+        .map(|mount_point| PathBuf::from(unescape_mount_point(mount_point)))
+        .filter(|mount_point| mount_point.starts_with(dir))
+        .collect()
+}
+
+/// Mount points in mountinfo escape space, tab, newline and backslash as octal.
+fn unescape_mount_point(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            let code: String = chars.by_ref().take(3).collect();
+            match u8::from_str_radix(&code, 8) {
+                Ok(b) => out.push(b as char),
+                Err(_) => {
+                    out.push(c);
+                    out.push_str(&code);
+                }
+            }
+        } else {
+            out.push(c);
+        }
+    }
+    out
+}
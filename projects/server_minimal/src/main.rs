@@ -1,12 +1,27 @@
 use clap::{Parser, Subcommand};
 use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
 use anyhow::Result;
 
+mod auth;
 mod server;
+mod transport;
 mod desktop_stream;
 mod app_stream;
+mod frame_queue;
+mod headless_display;
 mod session;
 mod isolation;
+mod sandbox;
+mod input;
+mod video_encoder;
+
+use headless_display::{DisplayBackend, HeadlessDisplayConfig};
+use isolation::{IsolationConfig, ShareMode};
+use sandbox::SandboxConfig;
+use session::{SessionSettings, StreamMode};
+use video_encoder::{VideoCodec, VideoConfig};
 
 #[derive(Parser)]
 #[command(name = "rustdesk-server-minimal")]
@@ -20,6 +35,89 @@ struct Cli {
     #[arg(short, long, default_value = "10")]
     max_connections: usize,
 
+    /// Video codec used to encode frames
+    #[arg(long, value_enum, default_value = "vp9")]
+    codec: VideoCodec,
+
+    /// Encoder bitrate ratio (0.5 = speed, 0.67 = balanced, 1.5 = best)
+    #[arg(long, default_value = "0.67")]
+    quality: f32,
+
+    /// Target frames per second
+    #[arg(long, default_value = "30")]
+    fps: u32,
+
+    /// Shared secret clients must send to log in
+    #[arg(long, env = "RUSTDESK_MINIMAL_PASSWORD", hide_env_values = true)]
+    password: Option<String>,
+
+    /// File with per-user tokens, one `user:token` per line
+    #[arg(long)]
+    tokens_file: Option<PathBuf>,
+
+    /// Base32 TOTP secret; when set, logins also need a valid one-time code
+    #[arg(long, env = "RUSTDESK_MINIMAL_TOTP_SECRET", hide_env_values = true)]
+    totp_secret: Option<String>,
+
+    /// PEM certificate chain, enables wss:// together with --tls-key
+    #[arg(long, requires = "tls_key")]
+    tls_cert: Option<PathBuf>,
+
+    /// PEM private key for --tls-cert
+    #[arg(long, requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+
+    /// Allow clients to connect without logging in
+    #[arg(long)]
+    no_auth: bool,
+
+    /// Directory for per-session files of isolated applications
+    #[arg(long, default_value = "/tmp/rustdesk-isolation")]
+    isolation_root: PathBuf,
+
+    /// File or directory made available read-only in isolated homes (repeatable)
+    #[arg(long = "shared-path")]
+    shared_paths: Vec<PathBuf>,
+
+    /// How shared paths are made available
+    #[arg(long, value_enum, default_value = "copy")]
+    share_mode: ShareMode,
+
+    /// Stop the application of an isolated session when its files use more than
+    /// this many MiB. Usage is checked every few seconds, so this is not a hard quota
+    #[arg(long)]
+    disk_usage_limit: Option<u64>,
+
+    /// Keep the home of each authenticated user in this directory across sessions
+    #[arg(long)]
+    persistent_homes: Option<PathBuf>,
+
+    /// Run applications in user/mount/pid/network namespaces with seccomp and rlimits,
+    /// app mode also needs --isolate-display
+    #[arg(long)]
+    sandbox: bool,
+
+    /// Let sandboxed applications use the host network
+    #[arg(long, requires = "sandbox")]
+    sandbox_allow_network: bool,
+
+    /// Address space limit for sandboxed applications in MiB
+    #[arg(long, requires = "sandbox")]
+    sandbox_memory: Option<u64>,
+
+    /// Process limit (cgroup pids.max) for each sandboxed application
+    #[arg(long, requires = "sandbox_cgroup")]
+    sandbox_max_processes: Option<u64>,
+
+    /// Delegated cgroup v2 directory with the pids controller enabled for its
+    /// children, each sandboxed application gets a cgroup in it
+    #[arg(long, requires = "sandbox")]
+    sandbox_cgroup: Option<PathBuf>,
+
+    /// Open file limit for sandboxed applications
+    #[arg(long, requires = "sandbox")]
+    sandbox_max_open_files: Option<u64>,
+
     #[command(subcommand)]
     command: Option<Commands>,
 }
@@ -28,7 +126,7 @@ struct Cli {
 enum Commands {
     /// Start server in desktop mode (full screen capture)
     Desktop {
-        /// Screen to capture (0 for primary)
+        /// Display to capture, as numbered by the ListDisplays message (0 is the primary display)
         #[arg(short, long, default_value = "0")]
         screen: u32,
     },
@@ -49,33 +147,144 @@ enum Commands {
         /// Enable file isolation for each client
         #[arg(long)]
         isolate_files: bool,
+
+        /// Run each client's application on its own headless X display
+        #[arg(long)]
+        isolate_display: bool,
+
+        /// Display server used with --isolate-display
+        #[arg(long, value_enum, default_value = "xvfb")]
+        display_backend: DisplayBackend,
+
+        /// Headless display size, e.g. 1920x1080
+        #[arg(long, default_value = "1280x800", value_parser = parse_display_size)]
+        display_size: (u32, u32),
+
+        /// xorg.conf for the xorg-dummy backend
+        #[arg(long)]
+        xorg_config: Option<PathBuf>,
+
+        /// Window manager to start on each headless display, e.g. openbox
+        #[arg(long)]
+        window_manager: Option<String>,
     },
 }
 
-#[tokio::main]
-async fn main() -> Result<()> {
+fn parse_display_size(s: &str) -> Result<(u32, u32), String> {
+    let (w, h) = s.split_once('x').ok_or("expected WIDTHxHEIGHT")?;
+    let w = w.parse().map_err(|_| format!("invalid width '{}'", w))?;
+    let h = h.parse().map_err(|_| format!("invalid height '{}'", h))?;
+    Ok((w, h))
+}
+
+fn main() -> Result<()> {
     env_logger::init();
     let cli = Cli::parse();
+    // Isolated displays need the server's own xauthority, the environment is
+    // only changed here while the process is still single threaded.
+    let isolated_displays = match &cli.command {
+        Some(Commands::App { isolate_display, .. }) => *isolate_display,
+        Some(Commands::Desktop { .. }) => false,
+        None => true,
+    };
+    if cli.sandbox && matches!(cli.command, Some(Commands::App { isolate_display: false, .. })) {
+        anyhow::bail!("--sandbox needs --isolate-display, sandboxed windows are only told apart by their display");
+    }
+    if isolated_displays {
+        headless_display::init_xauthority()?;
+    }
+    tokio::runtime::Runtime::new()?.block_on(run(cli))
+}
+
+async fn run(cli: Cli) -> Result<()> {
+    let video_config = VideoConfig {
+        codec: cli.codec,
+        quality: cli.quality,
+        frame_rate: cli.fps,
+    };
+
+    let auth = auth::AuthConfig::load(cli.password.clone(), cli.tokens_file.as_deref(), cli.totp_secret.clone())?;
+    if !auth.is_enabled() {
+        if !cli.no_auth {
+            anyhow::bail!("No credentials configured, use --password or --tokens-file (or --no-auth to allow anyone)");
+        }
+        log::warn!("Authentication disabled, any client reaching {} gets full access", cli.bind);
+    }
+    let tls = match (&cli.tls_cert, &cli.tls_key) {
+        (Some(cert), Some(key)) => Some(transport::load_tls_acceptor(cert, key)?),
+        _ => {
+            log::warn!("TLS disabled, traffic including credentials is sent in clear text");
+            None
+        }
+    };
+    let security = Arc::new(server::ServerSecurity {
+        auth,
+        tls,
+        failures: Default::default(),
+    });
+
+    let isolation = IsolationConfig {
+        root: cli.isolation_root.clone(),
+        shared_paths: cli.shared_paths.clone(),
+        share_mode: cli.share_mode,
+        disk_usage_limit: cli.disk_usage_limit.map(|mib| mib * 1024 * 1024),
+        persistent_homes: cli.persistent_homes.clone(),
+    };
+    isolation::cleanup_stale(&isolation.root)?;
+    let sandbox = cli.sandbox.then(|| SandboxConfig {
+        allow_network: cli.sandbox_allow_network,
+        memory_limit: cli.sandbox_memory.map(|mib| mib * 1024 * 1024),
+        max_processes: cli.sandbox_max_processes,
+        cgroup: cli.sandbox_cgroup.clone(),
+        max_open_files: cli.sandbox_max_open_files,
+        max_file_size: isolation.disk_usage_limit,
+    });
+    let settings = Arc::new(SessionSettings {
+        video: video_config,
+        isolation,
+        sandbox,
+    });
 
     match &cli.command {
         Some(Commands::Desktop { screen }) => {
             log::info!("Starting desktop streaming server on {} for screen {}", cli.bind, screen);
-            server::start_desktop_server(cli.bind, cli.max_connections, *screen).await?;
+            server::start_desktop_server(cli.bind, cli.max_connections, *screen, settings, security).await?;
         }
-        Some(Commands::App { command, args, workdir, isolate_files }) => {
+        Some(Commands::App {
+            command,
+            args,
+            workdir,
+            isolate_files,
+            isolate_display,
+            display_backend,
+            display_size,
+            xorg_config,
+            window_manager,
+        }) => {
             log::info!("Starting app streaming server on {} for command: {}", cli.bind, command);
-            server::start_app_server(
-                cli.bind,
-                cli.max_connections,
-                command.clone(),
-                args.clone(),
-                workdir.clone(),
-                *isolate_files,
-            ).await?;
+            let headless_display = if *isolate_display {
+                Some(HeadlessDisplayConfig {
+                    backend: *display_backend,
+                    width: display_size.0,
+                    height: display_size.1,
+                    xorg_config: xorg_config.clone(),
+                    window_manager: window_manager.clone(),
+                })
+            } else {
+                None
+            };
+            let mode = StreamMode::Application {
+                command: command.clone(),
+                args: args.clone(),
+                workdir: workdir.clone(),
+                isolate_files: *isolate_files,
+                headless_display,
+            };
+            server::start_app_server(cli.bind, cli.max_connections, mode, settings, security).await?;
         }
         None => {
             log::info!("Starting hybrid server on {} (supports both desktop and app modes)", cli.bind);
-            server::start_hybrid_server(cli.bind, cli.max_connections).await?;
+            server::start_hybrid_server(cli.bind, cli.max_connections, settings, security).await?;
         }
     }
 
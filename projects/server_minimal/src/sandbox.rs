@@ -0,0 +1,732 @@
+use nix::mount::{mount, MsFlags};
+use nix::sched::{clone, CloneFlags};
+use nix::sys::resource::{setrlimit, Resource};
+use nix::sys::signal::{killpg, Signal};
+use nix::sys::wait::{waitpid, WaitPidFlag, WaitStatus};
+use nix::unistd::Pid;
+use std::ffi::{CStr, CString, OsStr};
+use std::fs::File;
+use std::io::Read;
+use std::os::raw::c_char;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::{Duration, Instant};
+use anyhow::Result;
+
+/// Time an application gets to exit after SIGTERM before its group is killed.
+const TERMINATE_GRACE: Duration = Duration::from_secs(1);
+const CLONE_STACK_SIZE: usize = 1024 * 1024;
+
+/// Numbers the cgroups of sandboxed applications started by this server.
+static NEXT_CGROUP: AtomicU32 = AtomicU32::new(0);
+
+/// Limits and namespaces applied to sandboxed applications.
+#[derive(Debug, Clone, Default)]
+pub struct SandboxConfig {
+    /// Keep the host network instead of an empty network namespace
+    pub allow_network: bool,
+    /// Address space limit in bytes (RLIMIT_AS)
+    pub memory_limit: Option<u64>,
+    /// pids.max of the application's cgroup, needs `cgroup`
+    pub max_processes: Option<u64>,
+    /// Delegated cgroup v2 directory with the pids controller enabled for its
+    /// children; every application gets its own cgroup below it
+    pub cgroup: Option<PathBuf>,
+    /// RLIMIT_NOFILE
+    pub max_open_files: Option<u64>,
+    /// RLIMIT_FSIZE, largest file the application can write
+    pub max_file_size: Option<u64>,
+}
+
+/// What a sandboxed application may see of the host file system.
+#[derive(Debug, Clone, Default)]
+pub struct SandboxPaths {
+    /// Directories replaced by an empty read-only tmpfs, files by /dev/null
+    pub hide: Vec<PathBuf>,
+    /// Directories inside hidden ones that stay visible, e.g. the session's own dirs
+    pub expose: Vec<PathBuf>,
+}
+
+/// A running application.
+///
+/// The application leads its own process group. In sandbox mode it is also
+/// the init process of its own pid namespace, so everything it started dies
+/// with it, even processes that left the group.
+pub struct AppProcess {
+    pid: Pid,
+    /// Set for applications started through `std::process::Command`
+    child: Option<Child>,
+    exited: bool,
+    /// Removed once the application is gone
+    cgroup: Option<PathBuf>,
+}
+
+impl AppProcess {
+    pub fn spawn(mut cmd: Command) -> Result<Self> {
+        let child = cmd
+            .process_group(0)
+            .spawn()
+            .map_err(|e| anyhow::anyhow!("Failed to start application {:?}: {}", cmd.get_program(), e))?;
+        Ok(Self {
+            pid: Pid::from_raw(child.id() as i32),
+            child: Some(child),
+            exited: false,
+            cgroup: None,
+        })
+    }
+
+    /// Start `cmd` in new user, mount, pid and (optionally) network namespaces
+    /// with rlimits and a seccomp filter. Only program, arguments, environment
+    /// and working directory of `cmd` are used, stdio goes to /dev/null.
+    pub fn spawn_sandboxed(cmd: &Command, config: &SandboxConfig, paths: &SandboxPaths) -> Result<Self> {
+        let cgroup = match config.max_processes {
+            Some(max_processes) => Some(create_cgroup(config, max_processes)?),
+            None => None,
+        };
+        let plan = match ChildPlan::new(cmd, config, paths, cgroup.as_deref()) {
+            Ok(plan) => plan,
+            Err(e) => {
+                if let Some(cgroup) = &cgroup {
+                    remove_cgroup(cgroup);
+                }
+                return Err(e);
+            }
+        };
+
+        let mut flags = CloneFlags::CLONE_NEWUSER | CloneFlags::CLONE_NEWNS | CloneFlags::CLONE_NEWPID;
+        if !config.allow_network {
+            flags |= CloneFlags::CLONE_NEWNET;
+        }
+
+        // The child reports setup failures through this pipe. The write end is
+        // close-on-exec, so EOF without data means exec succeeded.
+        let (read_end, write_end) = nix::unistd::pipe2(nix::fcntl::OFlag::O_CLOEXEC)?;
+        let mut stack = vec![0u8; CLONE_STACK_SIZE];
+        let error_fd = write_end.as_raw_fd();
+
+        // Safety: the child only runs `ChildPlan::run`, which uses data
+        // prepared above and makes no allocations before exec.
+        let pid = unsafe {
+            clone(
+                Box::new(|| plan.run(error_fd)),
+                &mut stack,
+                flags,
+                Some(libc::SIGCHLD),
+            )
+        }
+        .map_err(|e| {
+            if let Some(cgroup) = &cgroup {
+                remove_cgroup(cgroup);
+            }
+            anyhow::anyhow!("Failed to create sandbox (are unprivileged user namespaces enabled?): {}", e)
+        })?;
+        drop(write_end);
+
+        let mut report = Vec::new();
+        let _ = File::from(read_end).read_to_end(&mut report);
+        if report.len() >= 5 {
+            let errno = i32::from_le_bytes([report[1], report[2], report[3], report[4]]);
+            let _ = waitpid(pid, None);
+            if let Some(cgroup) = &cgroup {
+                remove_cgroup(cgroup);
+            }
+            return Err(anyhow::anyhow!(
+                "Sandbox setup failed at {}: {}",
+                SetupStage::name(report[0]),
+                std::io::Error::from_raw_os_error(errno)
+            ));
+        }
+
+        log::info!("Started sandboxed application {:?} as PID {}", cmd.get_program(), pid);
+        Ok(Self {
+            pid,
+            child: None,
+            exited: false,
+            cgroup,
+        })
+    }
+
+    pub fn id(&self) -> u32 {
+        self.pid.as_raw() as u32
+    }
+
+    /// Whether the application (the group leader) is still running.
+    pub fn is_running(&mut self) -> bool {
+        if self.exited {
+            return false;
+        }
+        let running = match &mut self.child {
+            Some(child) => matches!(child.try_wait(), Ok(None)),
+            None => matches!(waitpid(self.pid, Some(WaitPidFlag::WNOHANG)), Ok(WaitStatus::StillAlive)),
+        };
+        self.exited = !running;
+        running
+    }
+
+    /// Ask the whole process group to exit, kill it if it does not, and reap the leader.
+    pub fn terminate(&mut self) {
+        let _ = killpg(self.pid, Signal::SIGTERM);
+
+        let deadline = Instant::now() + TERMINATE_GRACE;
+        while self.is_running() && Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(50));
+        }
+
+        // Children may still be around after the leader exited.
+        let _ = killpg(self.pid, Signal::SIGKILL);
+        if !self.exited {
+            match &mut self.child {
+                Some(child) => {
+                    let _ = child.wait();
+                }
+                None => {
+                    let _ = waitpid(self.pid, None);
+                }
+            }
+            self.exited = true;
+        }
+        if let Some(cgroup) = self.cgroup.take() {
+            remove_cgroup(&cgroup);
+        }
+    }
+}
+
+/// Create the cgroup of one application and set its process limit. RLIMIT_NPROC
+/// is not used, it counts every process of the user, not of the application.
+fn create_cgroup(config: &SandboxConfig, max_processes: u64) -> Result<PathBuf> {
+    let parent = config
+        .cgroup
+        .as_ref()
+        .ok_or_else(|| anyhow::anyhow!("A process limit needs a delegated cgroup"))?;
+    let cgroup = parent.join(format!("app-{}-{}", std::process::id(), NEXT_CGROUP.fetch_add(1, Ordering::Relaxed)));
+    std::fs::create_dir(&cgroup).map_err(|e| anyhow::anyhow!("Failed to create cgroup {:?}: {}", cgroup, e))?;
+    if let Err(e) = std::fs::write(cgroup.join("pids.max"), max_processes.to_string()) {
+        remove_cgroup(&cgroup);
+        return Err(anyhow::anyhow!(
+            "Failed to set pids.max in {:?} (is the pids controller enabled in {:?}?): {}",
+            cgroup, parent, e
+        ));
+    }
+    Ok(cgroup)
+}
+
+fn remove_cgroup(cgroup: &Path) {
+    // The last processes of the group may take a moment to be released after being reaped.
+    for _ in 0..10 {
+        match std::fs::remove_dir(cgroup) {
+            Ok(()) => return,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return,
+            Err(_) => std::thread::sleep(Duration::from_millis(20)),
+        }
+    }
+    log::warn!("Failed to remove cgroup {:?}", cgroup);
+}
+
+#[derive(Clone, Copy)]
+#[repr(u8)]
+enum SetupStage {
+    UserMap = 1,
+    Mounts,
+    Proc,
+    Network,
+    Session,
+    Chdir,
+    Limits,
+    Stdio,
+    Seccomp,
+    Exec,
+    Cgroup,
+}
+
+impl SetupStage {
+    fn name(stage: u8) -> &'static str {
+        match stage {
+            1 => "user id mapping",
+            2 => "mounts",
+            3 => "/proc",
+            4 => "network",
+            5 => "session",
+            6 => "working directory",
+            7 => "resource limits",
+            8 => "stdio",
+            9 => "seccomp",
+            10 => "exec",
+            11 => "cgroup",
+            _ => "unknown stage",
+        }
+    }
+}
+
+/// Everything the cloned child needs, prepared in the parent because the
+/// child must not allocate: another server thread may hold the allocator
+/// lock at the time of the clone.
+struct ChildPlan {
+    /// Resolved with the application's `PATH`, exec does not search
+    program: CString,
+    _args: Vec<CString>,
+    _env: Vec<CString>,
+    /// NUL terminated pointers into `_args` and `_env` for execve
+    argv: Vec<*const c_char>,
+    envp: Vec<*const c_char>,
+    /// `cgroup.procs` of the application's cgroup
+    cgroup_procs: Option<CString>,
+    cwd: Option<CString>,
+    uid_map: CString,
+    gid_map: CString,
+    /// Directories to cover with an empty tmpfs
+    hide_dirs: Vec<CString>,
+    /// Files to cover with /dev/null
+    hide_files: Vec<CString>,
+    /// (`/proc/self/fd/N` of an `O_PATH` fd opened in the parent, target,
+    /// directories to create inside a tmpfs first)
+    expose: Vec<(CString, CString, Vec<CString>)>,
+    /// Kept open until the child has bind mounted them
+    _expose_fds: Vec<OwnedFd>,
+    config: SandboxConfig,
+    filter: Vec<libc::sock_filter>,
+}
+
+impl ChildPlan {
+    fn new(cmd: &Command, config: &SandboxConfig, paths: &SandboxPaths, cgroup: Option<&Path>) -> Result<Self> {
+        let mut args = vec![cstring(cmd.get_program())?];
+        for arg in cmd.get_args() {
+            args.push(cstring(arg)?);
+        }
+
+        let mut env: Vec<(std::ffi::OsString, std::ffi::OsString)> = std::env::vars_os().collect();
+        for (key, value) in cmd.get_envs() {
+            env.retain(|(k, _)| k != key);
+            if let Some(value) = value {
+                env.push((key.to_owned(), value.to_owned()));
+            }
+        }
+        let program = resolve_program(cmd.get_program(), &env)?;
+        let env = env
+            .into_iter()
+            .map(|(k, v)| {
+                let mut pair = k.as_bytes().to_vec();
+                pair.push(b'=');
+                pair.extend_from_slice(v.as_bytes());
+                CString::new(pair).map_err(|e| anyhow::anyhow!("Invalid environment variable: {}", e))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        // The heap buffers of the strings do not move with the vectors.
+        let argv = args.iter().map(|arg| arg.as_ptr()).chain(Some(std::ptr::null())).collect();
+        let envp = env.iter().map(|var| var.as_ptr()).chain(Some(std::ptr::null())).collect();
+        let cgroup_procs = cgroup.map(|cgroup| cstring(cgroup.join("cgroup.procs").as_os_str())).transpose()?;
+
+        let cwd = cmd.get_current_dir().map(|dir| cstring(dir.as_os_str())).transpose()?;
+
+        // Keep our own ids inside the namespace, the application gets no
+        // capabilities after exec unless the server runs as root.
+        let uid = nix::unistd::getuid();
+        let gid = nix::unistd::getgid();
+        let uid_map = CString::new(format!("{} {} 1", uid, uid))?;
+        let gid_map = CString::new(format!("{} {} 1", gid, gid))?;
+
+        // Nested hidden paths disappear with their parent anyway.
+        let hidden: Vec<&PathBuf> = paths
+            .hide
+            .iter()
+            .filter(|p| p.exists())
+            .filter(|p| !paths.hide.iter().any(|other| other != *p && p.starts_with(other)))
+            .collect();
+        let mut hide_dirs = Vec::new();
+        let mut hide_files = Vec::new();
+        for path in &hidden {
+            if path.is_dir() {
+                hide_dirs.push(cstring(path.as_os_str())?);
+            } else {
+                hide_files.push(cstring(path.as_os_str())?);
+            }
+        }
+
+        let mut expose = Vec::new();
+        let mut expose_fds = Vec::new();
+        for path in &paths.expose {
+            let parent = match hidden.iter().find(|h| path.starts_with(h) && h.is_dir()) {
+                Some(parent) => parent,
+                None => continue,
+            };
+            let fd = open_path(path)?;
+            let source = CString::new(format!("/proc/self/fd/{}", fd.as_raw_fd()))?;
+            let mut dirs = Vec::new();
+            let mut dir = parent.to_path_buf();
+            for component in path.strip_prefix(parent)?.components() {
+                dir.push(component);
+                dirs.push(cstring(dir.as_os_str())?);
+            }
+            expose.push((source, cstring(path.as_os_str())?, dirs));
+            expose_fds.push(fd);
+        }
+
+        Ok(Self {
+            program,
+            _args: args,
+            _env: env,
+            argv,
+            envp,
+            cgroup_procs,
+            cwd,
+            uid_map,
+            gid_map,
+            hide_dirs,
+            hide_files,
+            expose,
+            _expose_fds: expose_fds,
+            config: config.clone(),
+            filter: seccomp_filter(),
+        })
+    }
+
+    /// Runs in the cloned child. Only returns on failure.
+    fn run(&self, error_fd: RawFd) -> isize {
+        let (stage, error) = match self.setup() {
+            Ok(()) => {
+                unsafe { libc::execve(self.program.as_ptr(), self.argv.as_ptr(), self.envp.as_ptr()) };
+                (SetupStage::Exec, nix::errno::Errno::last())
+            }
+            Err(failure) => failure,
+        };
+
+        let mut report = [0u8; 5];
+        report[0] = stage as u8;
+        report[1..].copy_from_slice(&(error as i32).to_le_bytes());
+        unsafe {
+            libc::write(error_fd, report.as_ptr() as *const libc::c_void, report.len());
+            libc::_exit(127);
+        }
+    }
+
+    fn setup(&self) -> std::result::Result<(), (SetupStage, nix::Error)> {
+        // Joined first, nothing started by the application escapes the limit.
+        if let Some(cgroup_procs) = &self.cgroup_procs {
+            write_proc_file(cgroup_procs, b"0").map_err(at(SetupStage::Cgroup))?;
+        }
+        write_proc_file(cstr(b"/proc/self/setgroups\0"), b"deny").map_err(at(SetupStage::UserMap))?;
+        write_proc_file(cstr(b"/proc/self/uid_map\0"), self.uid_map.as_bytes()).map_err(at(SetupStage::UserMap))?;
+        write_proc_file(cstr(b"/proc/self/gid_map\0"), self.gid_map.as_bytes()).map_err(at(SetupStage::UserMap))?;
+
+        self.setup_mounts().map_err(at(SetupStage::Mounts))?;
+
+        // We are pid 1 of the new namespace, give it a matching /proc.
+        mount(
+            Some(cstr(b"proc\0")),
+            cstr(b"/proc\0"),
+            Some(cstr(b"proc\0")),
+            MsFlags::MS_NOSUID | MsFlags::MS_NODEV | MsFlags::MS_NOEXEC,
+            None::<&CStr>,
+        )
+        .map_err(at(SetupStage::Proc))?;
+
+        if !self.config.allow_network {
+            loopback_up().map_err(at(SetupStage::Network))?;
+        }
+
+        nix::unistd::setsid().map_err(at(SetupStage::Session))?;
+
+        if let Some(cwd) = &self.cwd {
+            nix::unistd::chdir(cwd.as_c_str()).map_err(at(SetupStage::Chdir))?;
+        }
+
+        self.set_limits().map_err(at(SetupStage::Limits))?;
+        redirect_stdio().map_err(at(SetupStage::Stdio))?;
+        install_seccomp(&self.filter).map_err(at(SetupStage::Seccomp))?;
+        Ok(())
+    }
+
+    fn setup_mounts(&self) -> nix::Result<()> {
+        // Nothing done here may propagate back to the host.
+        mount(
+            None::<&CStr>,
+            cstr(b"/\0"),
+            None::<&CStr>,
+            MsFlags::MS_REC | MsFlags::MS_PRIVATE,
+            None::<&CStr>,
+        )?;
+
+        for dir in &self.hide_dirs {
+            mount(
+                Some(cstr(b"tmpfs\0")),
+                dir.as_c_str(),
+                Some(cstr(b"tmpfs\0")),
+                MsFlags::MS_NOSUID | MsFlags::MS_NODEV,
+                Some(cstr(b"size=64k,mode=755\0")),
+            )?;
+        }
+        for file in &self.hide_files {
+            mount(Some(cstr(b"/dev/null\0")), file.as_c_str(), None::<&CStr>, MsFlags::MS_BIND, None::<&CStr>)?;
+        }
+
+        for (source, target, dirs) in &self.expose {
+            for dir in dirs {
+                match nix::unistd::mkdir(dir.as_c_str(), nix::sys::stat::Mode::from_bits_truncate(0o755)) {
+                    Ok(()) | Err(nix::errno::Errno::EEXIST) => {}
+                    Err(e) => return Err(e),
+                }
+            }
+            mount(
+                Some(source.as_c_str()),
+                target.as_c_str(),
+                None::<&CStr>,
+                MsFlags::MS_BIND | MsFlags::MS_REC,
+                None::<&CStr>,
+            )?;
+        }
+
+        // The placeholders can be made read-only now that the mount points exist.
+        for dir in &self.hide_dirs {
+            mount(
+                None::<&CStr>,
+                dir.as_c_str(),
+                None::<&CStr>,
+                MsFlags::MS_REMOUNT | MsFlags::MS_RDONLY | MsFlags::MS_NOSUID | MsFlags::MS_NODEV,
+                None::<&CStr>,
+            )?;
+        }
+        Ok(())
+    }
+
+    fn set_limits(&self) -> nix::Result<()> {
+        let limits = [
+            (Resource::RLIMIT_AS, self.config.memory_limit),
+            (Resource::RLIMIT_NOFILE, self.config.max_open_files),
+            (Resource::RLIMIT_FSIZE, self.config.max_file_size),
+            (Resource::RLIMIT_CORE, Some(0)),
+        ];
+        for (resource, limit) in limits {
+            if let Some(limit) = limit {
+                setrlimit(resource, limit as _, limit as _)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn at(stage: SetupStage) -> impl Fn(nix::Error) -> (SetupStage, nix::Error) {
+    move |e| (stage, e)
+}
+
+fn cstr(bytes: &'static [u8]) -> &'static CStr {
+    CStr::from_bytes_with_nul(bytes).unwrap()
+}
+
+fn cstring(s: &OsStr) -> Result<CString> {
+    CString::new(s.as_bytes()).map_err(|e| anyhow::anyhow!("Invalid argument {:?}: {}", s, e))
+}
+
+/// What execvp would run: `program` itself when it has a slash, otherwise the
+/// first executable file of that name in the application's `PATH`.
+fn resolve_program(program: &OsStr, env: &[(std::ffi::OsString, std::ffi::OsString)]) -> Result<CString> {
+    if program.as_bytes().contains(&b'/') {
+        return cstring(program);
+    }
+    let path = env
+        .iter()
+        .find(|(key, _)| key == "PATH")
+        .map(|(_, value)| value.clone())
+        .unwrap_or_else(|| "/usr/local/bin:/usr/bin:/bin".into());
+    std::env::split_paths(&path)
+        .map(|dir| dir.join(program))
+        .find(|candidate| {
+            std::fs::metadata(candidate)
+                .map(|m| m.is_file() && m.permissions().mode() & 0o111 != 0)
+                .unwrap_or(false)
+        })
+        .ok_or_else(|| anyhow::anyhow!("{:?} not found in PATH", program))
+        .and_then(|candidate| cstring(candidate.as_os_str()))
+}
+
+fn open_path(path: &Path) -> Result<OwnedFd> {
+    let c_path = cstring(path.as_os_str())?;
+    let fd = unsafe { libc::open(c_path.as_ptr(), libc::O_PATH | libc::O_DIRECTORY | libc::O_CLOEXEC) };
+    if fd < 0 {
+        return Err(anyhow::anyhow!("Failed to open {:?}: {}", path, std::io::Error::last_os_error()));
+    }
+    // The child needs the fd across clone, clone does not close it.
+    Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+}
+
+fn write_proc_file(path: &CStr, data: &[u8]) -> nix::Result<()> {
+    unsafe {
+        let fd = libc::open(path.as_ptr(), libc::O_WRONLY | libc::O_CLOEXEC);
+        if fd < 0 {
+            return Err(nix::errno::Errno::last());
+        }
+        let written = libc::write(fd, data.as_ptr() as *const libc::c_void, data.len());
+        libc::close(fd);
+        if written < 0 {
+            return Err(nix::errno::Errno::last());
+        }
+    }
+    Ok(())
+}
+
+/// A new network namespace only has a loopback device, and it starts down.
+fn loopback_up() -> nix::Result<()> {
+    unsafe {
+        let sock = libc::socket(libc::AF_INET, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, 0);
+        if sock < 0 {
+            return Err(nix::errno::Errno::last());
+        }
+        let mut req: libc::ifreq = std::mem::zeroed();
+        for (dst, src) in req.ifr_name.iter_mut().zip(b"lo\0") {
+            *dst = *src as libc::c_char;
+        }
+        let mut result = libc::ioctl(sock, libc::SIOCGIFFLAGS, &mut req);
+        if result == 0 {
+            req.ifr_ifru.ifru_flags |= libc::IFF_UP as libc::c_short;
+            result = libc::ioctl(sock, libc::SIOCSIFFLAGS, &req);
+        }
+        let errno = nix::errno::Errno::last();
+        libc::close(sock);
+        if result < 0 {
+            return Err(errno);
+        }
+    }
+    Ok(())
+}
+
+fn redirect_stdio() -> nix::Result<()> {
+    unsafe {
+        let null = libc::open(cstr(b"/dev/null\0").as_ptr(), libc::O_RDWR);
+        if null < 0 {
+            return Err(nix::errno::Errno::last());
+        }
+        for fd in 0..3 {
+            if libc::dup2(null, fd) < 0 {
+                return Err(nix::errno::Errno::last());
+            }
+        }
+        if null > 2 {
+            libc::close(null);
+        }
+    }
+    Ok(())
+}
+
+fn install_seccomp(filter: &[libc::sock_filter]) -> nix::Result<()> {
+    let prog = libc::sock_fprog {
+        len: filter.len() as u16,
+        filter: filter.as_ptr() as *mut libc::sock_filter,
+    };
+    unsafe {
+        if libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) != 0 {
+            return Err(nix::errno::Errno::last());
+        }
+        if libc::prctl(libc::PR_SET_SECCOMP, libc::SECCOMP_MODE_FILTER, &prog as *const libc::sock_fprog) != 0 {
+            return Err(nix::errno::Errno::last());
+        }
+    }
+    Ok(())
+}
+
+// Classic BPF and seccomp constants from linux/filter.h and linux/seccomp.h.
+/// BPF_LD | BPF_W | BPF_ABS
+const BPF_LD_W_ABS: u16 = 0x20;
+/// BPF_JMP | BPF_JEQ | BPF_K
+const BPF_JMP_JEQ_K: u16 = 0x15;
+/// BPF_JMP | BPF_JGE | BPF_K
+const BPF_JMP_JGE_K: u16 = 0x35;
+/// BPF_JMP | BPF_JSET | BPF_K
+const BPF_JMP_JSET_K: u16 = 0x45;
+/// BPF_RET | BPF_K
+const BPF_RET_K: u16 = 0x06;
+const SECCOMP_RET_KILL_PROCESS: u32 = 0x8000_0000;
+const SECCOMP_RET_ERRNO: u32 = 0x0005_0000;
+const SECCOMP_RET_ALLOW: u32 = 0x7fff_0000;
+/// Offsets in `struct seccomp_data`
+const SECCOMP_DATA_NR: u32 = 0;
+const SECCOMP_DATA_ARCH: u32 = 4;
+const SECCOMP_DATA_ARG0_LOW: u32 = 16;
+
+#[cfg(target_arch = "x86_64")]
+const AUDIT_ARCH: u32 = 0xc000_003e;
+#[cfg(target_arch = "aarch64")]
+const AUDIT_ARCH: u32 = 0xc000_00b7;
+
+/// Syscalls that let a process escape or inspect the sandbox, or touch the
+/// kernel. They fail with EPERM instead of killing the application.
+const DENIED_SYSCALLS: &[libc::c_long] = &[
+    libc::SYS_ptrace,
+    libc::SYS_process_vm_readv,
+    libc::SYS_process_vm_writev,
+    libc::SYS_mount,
+    libc::SYS_umount2,
+    libc::SYS_pivot_root,
+    libc::SYS_unshare,
+    libc::SYS_setns,
+    libc::SYS_kexec_load,
+    libc::SYS_init_module,
+    libc::SYS_finit_module,
+    libc::SYS_delete_module,
+    libc::SYS_reboot,
+    libc::SYS_swapon,
+    libc::SYS_swapoff,
+    libc::SYS_acct,
+    libc::SYS_bpf,
+    libc::SYS_perf_event_open,
+    libc::SYS_keyctl,
+    libc::SYS_add_key,
+    libc::SYS_request_key,
+    libc::SYS_userfaultfd,
+    libc::SYS_open_by_handle_at,
+];
+
+/// clone() flags that create namespaces.
+const CLONE_NAMESPACE_FLAGS: u32 = (libc::CLONE_NEWNS
+    | libc::CLONE_NEWCGROUP
+    | libc::CLONE_NEWUTS
+    | libc::CLONE_NEWIPC
+    | libc::CLONE_NEWUSER
+    | libc::CLONE_NEWPID
+    | libc::CLONE_NEWNET) as u32;
+
+fn stmt(code: u16, k: u32) -> libc::sock_filter {
+    libc::sock_filter { code, jt: 0, jf: 0, k }
+}
+
+fn jump(code: u16, k: u32, jt: u8, jf: u8) -> libc::sock_filter {
+    libc::sock_filter { code, jt, jf, k }
+}
+
+/// Deny-list filter: unknown architectures are killed, denied syscalls get
+/// EPERM, clone3 gets ENOSYS so libc falls back to clone, whose flags can be
+/// checked, and everything else is allowed.
+fn seccomp_filter() -> Vec<libc::sock_filter> {
+    let eperm = SECCOMP_RET_ERRNO | libc::EPERM as u32;
+    let mut filter = vec![
+        stmt(BPF_LD_W_ABS, SECCOMP_DATA_ARCH),
+        jump(BPF_JMP_JEQ_K, AUDIT_ARCH, 1, 0),
+        stmt(BPF_RET_K, SECCOMP_RET_KILL_PROCESS),
+        stmt(BPF_LD_W_ABS, SECCOMP_DATA_NR),
+    ];
+
+    #[cfg(target_arch = "x86_64")]
+    {
+        // x32 syscalls share the arch value, refuse all of them.
+        filter.push(jump(BPF_JMP_JGE_K, 0x4000_0000, 0, 1));
+        filter.push(stmt(BPF_RET_K, eperm));
+    }
+
+    filter.push(jump(BPF_JMP_JEQ_K, libc::SYS_clone3 as u32, 0, 1));
+    filter.push(stmt(BPF_RET_K, SECCOMP_RET_ERRNO | libc::ENOSYS as u32));
+
+    filter.push(jump(BPF_JMP_JEQ_K, libc::SYS_clone as u32, 0, 4));
+    filter.push(stmt(BPF_LD_W_ABS, SECCOMP_DATA_ARG0_LOW));
+    filter.push(jump(BPF_JMP_JSET_K, CLONE_NAMESPACE_FLAGS, 0, 1));
+    filter.push(stmt(BPF_RET_K, eperm));
+    filter.push(stmt(BPF_RET_K, SECCOMP_RET_ALLOW));
+
+    for nr in DENIED_SYSCALLS {
+        filter.push(jump(BPF_JMP_JEQ_K, *nr as u32, 0, 1));
+        filter.push(stmt(BPF_RET_K, eperm));
+    }
+    filter.push(stmt(BPF_RET_K, SECCOMP_RET_ALLOW));
+    filter
+}
@@ -1,16 +1,36 @@
 use tokio::net::{TcpListener, TcpStream};
+use tokio_rustls::TlsAcceptor;
+use tokio_tungstenite::tungstenite::Message;
 use tokio_tungstenite::{accept_async, WebSocketStream};
+use futures_util::{SinkExt, StreamExt};
 use std::net::SocketAddr;
 use std::sync::Arc;
+use std::time::Duration;
 use anyhow::Result;
 use uuid::Uuid;
 
-use crate::session::{Session, SessionManager, StreamMode};
+use crate::auth::{AuthConfig, LoginFailures};
+use crate::session::{ClientMessage, ServerMessage, Session, SessionManager, SessionSettings, StreamMode};
+use crate::transport::{self, ServerStream};
+
+/// How long a client has for the TLS handshake and for the WebSocket upgrade.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long a client has to complete the login handshake.
+const LOGIN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Authentication and transport settings shared by every connection.
+pub struct ServerSecurity {
+    pub auth: AuthConfig,
+    pub tls: Option<TlsAcceptor>,
+    pub failures: LoginFailures,
+}
 
 pub async fn start_desktop_server(
     bind_addr: SocketAddr,
     max_connections: usize,
     screen_id: u32,
+    settings: Arc<SessionSettings>,
+    security: Arc<ServerSecurity>,
 ) -> Result<()> {
     let session_manager = Arc::new(SessionManager::new(max_connections));
     let listener = TcpListener::bind(bind_addr).await?;
@@ -19,9 +39,11 @@ pub async fn start_desktop_server(
 
     while let Ok((stream, addr)) = listener.accept().await {
         let session_manager = Arc::clone(&session_manager);
+        let settings = Arc::clone(&settings);
+        let security = Arc::clone(&security);
         
         tokio::spawn(async move {
-            if let Err(e) = handle_desktop_connection(stream, addr, session_manager, screen_id).await {
+            if let Err(e) = handle_desktop_connection(stream, addr, session_manager, screen_id, settings, security).await {
                 log::error!("Desktop connection error from {}: {}", addr, e);
             }
         });
@@ -30,13 +52,13 @@ pub async fn start_desktop_server(
     Ok(())
 }
 
+/// `mode` is the `StreamMode::Application` every client gets.
 pub async fn start_app_server(
     bind_addr: SocketAddr,
     max_connections: usize,
-    command: String,
-    args: Vec<String>,
-    workdir: Option<String>,
-    isolate_files: bool,
+    mode: StreamMode,
+    settings: Arc<SessionSettings>,
+    security: Arc<ServerSecurity>,
 ) -> Result<()> {
     let session_manager = Arc::new(SessionManager::new(max_connections));
     let listener = TcpListener::bind(bind_addr).await?;
@@ -45,20 +67,12 @@ pub async fn start_app_server(
 
     while let Ok((stream, addr)) = listener.accept().await {
         let session_manager = Arc::clone(&session_manager);
-        let command = command.clone();
-        let args = args.clone();
-        let workdir = workdir.clone();
+        let settings = Arc::clone(&settings);
+        let security = Arc::clone(&security);
+        let mode = mode.clone();
         
         tokio::spawn(async move {
-            if let Err(e) = handle_app_connection(
-                stream, 
-                addr, 
-                session_manager, 
-                command, 
-                args, 
-                workdir, 
-                isolate_files
-            ).await {
+            if let Err(e) = handle_app_connection(stream, addr, session_manager, mode, settings, security).await {
                 log::error!("App connection error from {}: {}", addr, e);
             }
         });
@@ -70,6 +84,8 @@ pub async fn start_app_server(
 pub async fn start_hybrid_server(
     bind_addr: SocketAddr,
     max_connections: usize,
+    settings: Arc<SessionSettings>,
+    security: Arc<ServerSecurity>,
 ) -> Result<()> {
     let session_manager = Arc::new(SessionManager::new(max_connections));
     let listener = TcpListener::bind(bind_addr).await?;
@@ -78,9 +94,11 @@ pub async fn start_hybrid_server(
 
     while let Ok((stream, addr)) = listener.accept().await {
         let session_manager = Arc::clone(&session_manager);
+        let settings = Arc::clone(&settings);
+        let security = Arc::clone(&security);
         
         tokio::spawn(async move {
-            if let Err(e) = handle_hybrid_connection(stream, addr, session_manager).await {
+            if let Err(e) = handle_hybrid_connection(stream, addr, session_manager, settings, security).await {
                 log::error!("Hybrid connection error from {}: {}", addr, e);
             }
         });
@@ -94,8 +112,10 @@ async fn handle_desktop_connection(
     addr: SocketAddr,
     session_manager: Arc<SessionManager>,
     screen_id: u32,
+    settings: Arc<SessionSettings>,
+    security: Arc<ServerSecurity>,
 ) -> Result<()> {
-    let ws_stream = accept_async(stream).await?;
+    let (ws_stream, user, hello) = accept_connection(stream, addr, &security).await?;
     let session_id = Uuid::new_v4();
     
     log::info!("New desktop session {} from {}", session_id, addr);
@@ -104,11 +124,11 @@ async fn handle_desktop_connection(
         session_id,
         addr,
         StreamMode::Desktop { screen_id },
-        ws_stream,
+        user,
+        settings,
     );
     
-    session_manager.add_session(session).await?;
-    session_manager.run_session(session_id).await?;
+    session_manager.run_session(session, ws_stream, hello).await?;
     
     Ok(())
 }
@@ -117,30 +137,26 @@ async fn handle_app_connection(
     stream: TcpStream,
     addr: SocketAddr,
     session_manager: Arc<SessionManager>,
-    command: String,
-    args: Vec<String>,
-    workdir: Option<String>,
-    isolate_files: bool,
+    mode: StreamMode,
+    settings: Arc<SessionSettings>,
+    security: Arc<ServerSecurity>,
 ) -> Result<()> {
-    let ws_stream = accept_async(stream).await?;
+    let (ws_stream, user, hello) = accept_connection(stream, addr, &security).await?;
     let session_id = Uuid::new_v4();
     
-    log::info!("New app session {} from {} for command: {}", session_id, addr, command);
+    if let StreamMode::Application { command, .. } = &mode {
+        log::info!("New app session {} from {} for command: {}", session_id, addr, command);
+    }
     
     let session = Session::new(
         session_id,
         addr,
-        StreamMode::Application {
-            command,
-            args,
-            workdir,
-            isolate_files,
-        },
-        ws_stream,
+        mode,
+        user,
+        settings,
     );
     
-    session_manager.add_session(session).await?;
-    session_manager.run_session(session_id).await?;
+    session_manager.run_session(session, ws_stream, hello).await?;
     
     Ok(())
 }
@@ -149,8 +165,10 @@ async fn handle_hybrid_connection(
     stream: TcpStream,
     addr: SocketAddr,
     session_manager: Arc<SessionManager>,
+    settings: Arc<SessionSettings>,
+    security: Arc<ServerSecurity>,
 ) -> Result<()> {
-    let ws_stream = accept_async(stream).await?;
+    let (ws_stream, user, hello) = accept_connection(stream, addr, &security).await?;
     let session_id = Uuid::new_v4();
     
     log::info!("New hybrid session {} from {}", session_id, addr);
@@ -160,11 +178,91 @@ async fn handle_hybrid_connection(
         session_id,
         addr,
         StreamMode::Hybrid,
-        ws_stream,
+        user,
+        settings,
     );
     
-    session_manager.add_session(session).await?;
-    session_manager.run_session(session_id).await?;
+    session_manager.run_session(session, ws_stream, hello).await?;
     
     Ok(())
 }
+
+/// TLS, WebSocket upgrade and login. Nothing is captured or spawned for a
+/// connection until this succeeds.
+///
+/// Clients may send `Hello` before `Login` since they cannot know whether the
+/// server wants a login; it is returned for the session to answer.
+async fn accept_connection(
+    stream: TcpStream,
+    addr: SocketAddr,
+    security: &ServerSecurity,
+) -> Result<(WebSocketStream<ServerStream>, Option<String>, Option<ClientMessage>)> {
+    if security.auth.is_enabled() && security.failures.is_blocked(addr.ip()) {
+        return Err(anyhow::anyhow!("Too many failed logins"));
+    }
+    let stream = tokio::time::timeout(HANDSHAKE_TIMEOUT, transport::accept_transport(stream, security.tls.as_ref()))
+        .await
+        .map_err(|_| anyhow::anyhow!("TLS handshake timed out"))??;
+    let mut ws_stream = tokio::time::timeout(HANDSHAKE_TIMEOUT, accept_async(stream))
+        .await
+        .map_err(|_| anyhow::anyhow!("WebSocket upgrade timed out"))??;
+
+    if !security.auth.is_enabled() {
+        return Ok((ws_stream, None, None));
+    }
+
+    send_message(&mut ws_stream, &ServerMessage::AuthRequired {
+        totp: security.auth.requires_totp(),
+    }).await?;
+
+    let deadline = tokio::time::Instant::now() + LOGIN_TIMEOUT;
+    let mut hello = None;
+    let login = loop {
+        let message = match tokio::time::timeout_at(deadline, ws_stream.next()).await {
+            Ok(Some(Ok(Message::Text(text)))) => serde_json::from_str::<ClientMessage>(&text).ok(),
+            Ok(_) => None,
+            Err(_) => {
+                let _ = ws_stream.close(None).await;
+                return Err(anyhow::anyhow!("Login timed out"));
+            }
+        };
+        match message {
+            Some(message @ ClientMessage::Hello { .. }) if hello.is_none() => hello = Some(message),
+            message => break message,
+        }
+    };
+
+    // Other connections from the address may have failed since this one was accepted.
+    let result = match login {
+        _ if security.failures.is_blocked(addr.ip()) => Err("Too many failed logins"),
+        Some(ClientMessage::Login { secret, totp }) => security.auth.verify(&secret, totp.as_deref()),
+        _ => Err("Expected login message"),
+    };
+
+    match result {
+        Ok(user) => {
+            security.failures.clear(addr.ip());
+            log::info!("Client {} authenticated{}", addr, user.as_ref().map(|u| format!(" as {}", u)).unwrap_or_default());
+            send_message(&mut ws_stream, &ServerMessage::LoginResult {
+                success: true,
+                message: "Authenticated".to_string(),
+            }).await?;
+            Ok((ws_stream, user, hello))
+        }
+        Err(reason) => {
+            log::warn!("Rejected login from {}: {}", addr, reason);
+            tokio::time::sleep(security.failures.add(addr.ip())).await;
+            let _ = send_message(&mut ws_stream, &ServerMessage::LoginResult {
+                success: false,
+                message: reason.to_string(),
+            }).await;
+            let _ = ws_stream.close(None).await;
+            Err(anyhow::anyhow!("Authentication failed"))
+        }
+    }
+}
+
+async fn send_message(ws_stream: &mut WebSocketStream<ServerStream>, message: &ServerMessage) -> Result<()> {
+    ws_stream.send(Message::Text(serde_json::to_string(message)?)).await?;
+    Ok(())
+}
@@ -1,17 +1,33 @@
 use tokio_tungstenite::WebSocketStream;
-use tokio::net::TcpStream;
-use tokio::sync::{RwLock, mpsc};
+use tokio::sync::mpsc;
 use std::collections::HashMap;
 use std::net::SocketAddr;
-use std::sync::Arc;
+use std::sync::{mpsc as std_mpsc, Arc, Mutex};
+use std::time::{Duration, Instant};
 use anyhow::Result;
 use uuid::Uuid;
-use serde::{Deserialize, Serialize};
 use tokio_tungstenite::tungstenite::Message;
 use futures_util::{SinkExt, StreamExt};
+use protocol_minimal::{MIN_PROTOCOL_VERSION, PROTOCOL_VERSION};
 
-use crate::desktop_stream::DesktopStreamer;
+use crate::desktop_stream::{self, DesktopStreamer};
 use crate::app_stream::AppStreamer;
+use crate::frame_queue::FrameQueue;
+use crate::input::{InputArea, InputInjector};
+use crate::headless_display::HeadlessDisplayConfig;
+use crate::isolation::{IsolationConfig, IsolationEnvironment};
+use crate::sandbox::SandboxConfig;
+use crate::transport::ServerStream;
+use crate::video_encoder::{FrameEncoder, VideoCodec, VideoConfig, VideoPacket};
+
+/// Control messages waiting for the writer. The capture thread blocks when it is full.
+const CONTROL_QUEUE_SIZE: usize = 32;
+/// Client messages waiting for the capture thread. The socket is not read while it is full.
+const COMMAND_QUEUE_SIZE: usize = 64;
+/// How often a coalesced mouse move is offered again while the command queue is full.
+const MOUSE_MOVE_RETRY: Duration = Duration::from_millis(5);
+/// Encoded video packets waiting for the writer, about 100 ms at 30 fps.
+const VIDEO_QUEUE_SIZE: usize = 3;
 
 #[derive(Debug, Clone)]
 pub enum StreamMode {
@@ -21,47 +37,46 @@ pub enum StreamMode {
         args: Vec<String>,
         workdir: Option<String>,
         isolate_files: bool,
+        /// Run the application on its own headless X display
+        headless_display: Option<HeadlessDisplayConfig>,
     },
     Hybrid,
 }
 
-#[derive(Serialize, Deserialize)]
-#[serde(tag = "type")]
-pub enum ClientMessage {
-    SetMode {
-        mode: String,
-        screen_id: Option<u32>,
-        command: Option<String>,
-        args: Option<Vec<String>>,
-        workdir: Option<String>,
-        isolate_files: Option<bool>,
-    },
-    MouseMove { x: i32, y: i32 },
-    MouseClick { button: u8, pressed: bool },
-    KeyPress { key: String, pressed: bool },
-    Ping,
+pub use protocol_minimal::{ClientMessage, ServerMessage};
+
+/// Messages queued for the client: JSON control messages or binary video packets.
+pub enum OutboundMessage {
+    Control(ServerMessage),
+    Video(VideoPacket),
 }
 
-#[derive(Serialize, Deserialize)]
-#[serde(tag = "type")]
-pub enum ServerMessage {
-    Frame {
-        width: u32,
-        height: u32,
-        data: Vec<u8>,
-    },
-    ModeSet { success: bool, message: String },
-    Pong,
-    Error { message: String },
+impl OutboundMessage {
+    pub fn into_ws_message(self) -> Result<Message> {
+        match self {
+            OutboundMessage::Control(msg) => Ok(Message::Text(serde_json::to_string(&msg)?)),
+            OutboundMessage::Video(packet) => Ok(Message::Binary(packet.to_bytes())),
+        }
+    }
+}
+
+/// Server-wide settings every session starts from.
+#[derive(Debug, Clone)]
+pub struct SessionSettings {
+    pub video: VideoConfig,
+    pub isolation: IsolationConfig,
+    /// Run every application session in a sandbox, implies file isolation
+    pub sandbox: Option<SandboxConfig>,
 }
 
+/// An authenticated connection and what it asked to stream.
 pub struct Session {
     pub id: Uuid,
     pub addr: SocketAddr,
     pub mode: StreamMode,
-    pub ws_stream: WebSocketStream<TcpStream>,
-    pub desktop_streamer: Option<DesktopStreamer>,
-    pub app_streamer: Option<AppStreamer>,
+    /// Authenticated user name, `None` for anonymous or shared-secret logins.
+    pub user: Option<String>,
+    pub settings: Arc<SessionSettings>,
 }
 
 impl Session {
@@ -69,33 +84,65 @@ impl Session {
         id: Uuid,
         addr: SocketAddr,
         mode: StreamMode,
-        ws_stream: WebSocketStream<TcpStream>,
+        user: Option<String>,
+        settings: Arc<SessionSettings>,
     ) -> Self {
         Self {
             id,
             addr,
             mode,
-            ws_stream,
+            user,
+            settings,
+        }
+    }
+}
+
+/// Runtime state of a session. Streamers, encoders and the input injector
+/// hold X11/capture handles that are not `Send`, so this lives on the
+/// session's capture thread and is never shared.
+struct SessionWorker {
+    id: Uuid,
+    mode: StreamMode,
+    user: Option<String>,
+    settings: Arc<SessionSettings>,
+    desktop_streamer: Option<DesktopStreamer>,
+    app_streamer: Option<AppStreamer>,
+    input: Option<InputInjector>,
+    video_config: VideoConfig,
+    app_encoder: FrameEncoder,
+    started: Instant,
+}
+
+impl SessionWorker {
+    fn new(session: Session) -> Self {
+        Self {
+            id: session.id,
+            mode: session.mode,
+            user: session.user,
             desktop_streamer: None,
             app_streamer: None,
+            input: None,
+            video_config: session.settings.video,
+            app_encoder: FrameEncoder::new(session.settings.video),
+            settings: session.settings,
+            started: Instant::now(),
         }
     }
 
-    pub async fn initialize_streamers(&mut self) -> Result<()> {
+    fn initialize_streamers(&mut self) -> Result<()> {
         match &self.mode {
             StreamMode::Desktop { screen_id } => {
-                self.desktop_streamer = Some(DesktopStreamer::new(*screen_id, 30)?);
+                self.desktop_streamer = Some(DesktopStreamer::new(*screen_id, self.video_config)?);
                 log::info!("Initialized desktop streamer for session {}", self.id);
             }
-            StreamMode::Application { command, args, workdir, isolate_files } => {
-                let mut app_streamer = AppStreamer::new(
+            StreamMode::Application { command, args, workdir, isolate_files, headless_display } => {
+                let app_streamer = self.start_app_streamer(
                     command.clone(),
                     args.clone(),
                     workdir.clone(),
                     *isolate_files,
-                    self.id,
+                    headless_display.clone(),
                 )?;
-                app_streamer.start_application()?;
                 self.app_streamer = Some(app_streamer);
                 log::info!("Initialized app streamer for session {}", self.id);
             }
@@ -107,9 +154,37 @@ impl Session {
         Ok(())
     }
 
-    pub async fn handle_message(&mut self, message: ClientMessage) -> Result<Option<ServerMessage>> {
+    fn start_app_streamer(
+        &self,
+        command: String,
+        args: Vec<String>,
+        workdir: Option<String>,
+        isolate_files: bool,
+        headless_display: Option<HeadlessDisplayConfig>,
+    ) -> Result<AppStreamer> {
+        if self.settings.sandbox.is_some() && headless_display.is_none() {
+            anyhow::bail!("Sandboxed applications need their own display");
+        }
+        let isolation_env = if isolate_files || self.settings.sandbox.is_some() {
+            Some(IsolationEnvironment::new(&self.settings.isolation, self.id, self.user.as_deref())?)
+        } else {
+            None
+        };
+        let mut app_streamer = AppStreamer::new(
+            command,
+            args,
+            workdir,
+            isolation_env,
+            headless_display,
+            self.settings.sandbox.clone(),
+        );
+        app_streamer.start_application()?;
+        Ok(app_streamer)
+    }
+
+    fn handle_message(&mut self, message: ClientMessage) -> Result<Option<ServerMessage>> {
         match message {
-            ClientMessage::SetMode { mode, screen_id, command, args, workdir, isolate_files } => {
+            ClientMessage::SetMode { mode, screen_id, command, args, workdir, isolate_files, isolate_display } => {
                 if !matches!(self.mode, StreamMode::Hybrid) {
                     return Ok(Some(ServerMessage::Error {
                         message: "Mode can only be set in hybrid mode".to_string(),
@@ -119,8 +194,8 @@ impl Session {
                 match mode.as_str() {
                     "desktop" => {
                         let screen_id = screen_id.unwrap_or(0);
+                        self.desktop_streamer = Some(DesktopStreamer::new(screen_id, self.video_config)?);
                         self.mode = StreamMode::Desktop { screen_id };
-                        self.desktop_streamer = Some(DesktopStreamer::new(screen_id, 30)?);
                         Ok(Some(ServerMessage::ModeSet {
                             success: true,
                             message: "Desktop mode set".to_string(),
@@ -128,22 +203,28 @@ impl Session {
                     }
                     "app" => {
                         if let Some(command) = command {
+                            let args = args.unwrap_or_default();
+                            let isolate_files = isolate_files.unwrap_or(false);
+                            // Sandboxed applications always get their own display.
+                            let headless_display = (isolate_display.unwrap_or(false)
+                                || self.settings.sandbox.is_some())
+                            .then(HeadlessDisplayConfig::default);
+                            let app_streamer = self.start_app_streamer(
+                                command.clone(),
+                                args.clone(),
+                                workdir.clone(),
+                                isolate_files,
+                                headless_display.clone(),
+                            )?;
+                            self.app_streamer = Some(app_streamer);
+                            // Only now, a session whose application failed to start stays hybrid.
                             self.mode = StreamMode::Application {
-                                command: command.clone(),
-                                args: args.unwrap_or_default(),
-                                workdir,
-                                isolate_files: isolate_files.unwrap_or(false),
-                            };
-                            
-                            let mut app_streamer = AppStreamer::new(
                                 command,
-                                args.unwrap_or_default(),
+                                args,
                                 workdir,
-                                isolate_files.unwrap_or(false),
-                                self.id,
-                            )?;
-                            app_streamer.start_application()?;
-                            self.app_streamer = Some(app_streamer);
+                                isolate_files,
+                                headless_display,
+                            };
                             
                             Ok(Some(ServerMessage::ModeSet {
                                 success: true,
@@ -160,143 +241,391 @@ impl Session {
                     })),
                 }
             }
+            ClientMessage::Hello { version, codecs } => Ok(Some(self.negotiate(version, &codecs))),
+            ClientMessage::Login { .. } => Ok(Some(ServerMessage::Error {
+                message: "Already authenticated".to_string(),
+            })),
             ClientMessage::Ping => Ok(Some(ServerMessage::Pong)),
-            ClientMessage::MouseMove { x: _, y: _ } => {
-                // TODO: Implement mouse input handling
+            ClientMessage::ListDisplays => Ok(Some(ServerMessage::Displays {
+                displays: desktop_stream::list_displays()?,
+                current: self.desktop_streamer.as_ref().map(|d| d.display().index),
+            })),
+            ClientMessage::SwitchDisplay { display } => {
+                let desktop_streamer = match &mut self.desktop_streamer {
+                    Some(desktop_streamer) => desktop_streamer,
+                    None => {
+                        return Ok(Some(ServerMessage::Error {
+                            message: "Displays can only be switched in desktop mode".to_string(),
+                        }))
+                    }
+                };
+                let display = desktop_streamer.switch_display(display)?.clone();
+                self.mode = StreamMode::Desktop { screen_id: display.index };
+                self.refresh_input_area();
+                Ok(Some(ServerMessage::DisplayChanged { display }))
+            }
+            ClientMessage::MouseMove { x, y } => {
+                self.refresh_input_area();
+                if let Some(input) = self.input() {
+                    input.mouse_move(x, y);
+                }
+                Ok(None)
+            }
+            ClientMessage::MouseClick { button, pressed } => {
+                self.refresh_input_area();
+                if let Some(input) = self.input() {
+                    if let Err(e) = input.mouse_button(button, pressed) {
+                        log::warn!("Session {}: {}", self.id, e);
+                    }
+                }
                 Ok(None)
             }
-            ClientMessage::MouseClick { button: _, pressed: _ } => {
-                // TODO: Implement mouse click handling
+            ClientMessage::MouseWheel { dx, dy } => {
+                if let Some(input) = self.input() {
+                    input.mouse_wheel(dx, dy);
+                }
                 Ok(None)
             }
-            ClientMessage::KeyPress { key: _, pressed: _ } => {
-                // TODO: Implement keyboard input handling
+            ClientMessage::KeyPress { key, pressed } => {
+                if let Some(input) = self.input() {
+                    if let Err(e) = input.key(&key, pressed) {
+                        log::warn!("Session {}: {}", self.id, e);
+                    }
+                }
                 Ok(None)
             }
         }
     }
 
-    pub async fn capture_frame(&mut self) -> Result<Option<ServerMessage>> {
-        match &mut self.desktop_streamer {
-            Some(desktop_streamer) => {
-                if let Some(frame_data) = desktop_streamer.capture_frame()? {
-                    let (width, height) = desktop_streamer.get_dimensions();
-                    return Ok(Some(ServerMessage::Frame {
-                        width,
-                        height,
-                        data: frame_data,
-                    }));
+    /// Check the client's protocol version and switch to a codec it can decode.
+    fn negotiate(&mut self, version: u32, codecs: &[String]) -> ServerMessage {
+        if version < MIN_PROTOCOL_VERSION {
+            return ServerMessage::Error {
+                message: format!(
+                    "Protocol version {} is not supported, this server speaks {} to {}",
+                    version, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION
+                ),
+            };
+        }
+        let codec = match VideoCodec::negotiate(self.settings.video.codec, codecs) {
+            Some(codec) => codec,
+            None => {
+                return ServerMessage::Error {
+                    message: format!("No common video codec, this server supports {}", VideoCodec::ALL.map(|c| c.name()).join(", ")),
                 }
             }
-            None => {}
+        };
+        if codec != self.video_config.codec {
+            log::info!("Session {} uses {} video", self.id, codec.name());
+        }
+        self.set_codec(codec);
+        ServerMessage::Welcome {
+            version: PROTOCOL_VERSION,
+            codec,
+        }
+    }
+
+    fn set_codec(&mut self, codec: VideoCodec) {
+        self.video_config.codec = codec;
+        self.app_encoder.set_codec(codec);
+        if let Some(desktop_streamer) = &mut self.desktop_streamer {
+            desktop_streamer.set_codec(codec);
+        }
+    }
+
+    fn input_area(&self) -> Option<InputArea> {
+        if let Some(desktop_streamer) = &self.desktop_streamer {
+            return Some(desktop_streamer.input_area());
+        }
+        self.app_streamer.as_ref().and_then(|app_streamer| app_streamer.window_area())
+    }
+
+    fn refresh_input_area(&mut self) {
+        if let Some(area) = self.input_area() {
+            if let Some(input) = &mut self.input {
+                input.set_area(area);
+            }
+        }
+    }
+
+    /// Input injector for the active streamer, `None` until a mode is set.
+    fn input(&mut self) -> Option<&mut InputInjector> {
+        if self.input.is_none() {
+            let area = self.input_area()?;
+            let x11_display = self.app_streamer.as_ref().and_then(|app_streamer| app_streamer.x11_display());
+            self.input = Some(InputInjector::new(area, x11_display));
+        }
+        self.input.as_mut()
+    }
+
+    /// Release keys and buttons the client still holds, e.g. after a disconnect.
+    fn release_input(&mut self) {
+        if let Some(input) = &mut self.input {
+            input.release_all();
+        }
+    }
+
+    fn request_keyframe(&mut self) {
+        if let Some(desktop_streamer) = &mut self.desktop_streamer {
+            desktop_streamer.request_keyframe();
+        }
+        self.app_encoder.request_keyframe();
+    }
+
+    fn application_stopped(&mut self) -> Option<String> {
+        self.app_streamer.as_mut().and_then(|app_streamer| app_streamer.stop_reason())
+    }
+
+    fn capture_frame(&mut self) -> Result<Vec<VideoPacket>> {
+        if let Some(desktop_streamer) = &mut self.desktop_streamer {
+            return desktop_streamer.capture_frame();
+        }
+
+        if let Some(app_streamer) = &mut self.app_streamer {
+            if let Some(frame) = app_streamer.capture_window_frame()? {
+                let ms = self.started.elapsed().as_millis() as i64;
+                return self.app_encoder.encode_raw(
+                    frame.data,
+                    scrap::Pixfmt::BGRA,
+                    frame.width,
+                    frame.height,
+                    ms,
+                );
+            }
+        }
+
+        Ok(Vec::new())
+    }
+
+    /// Body of the session's capture thread.
+    ///
+    /// Client messages are handled as soon as they arrive; frames are captured
+    /// in between at the configured frame rate. Returns when the connection
+    /// drops the command sender or the application exits.
+    fn run(
+        session: Session,
+        commands: std_mpsc::Receiver<ClientMessage>,
+        control: mpsc::Sender<ServerMessage>,
+        video: Arc<FrameQueue>,
+    ) {
+        let mut worker = Self::new(session);
+        worker.run_loop(commands, control, &video);
+        worker.release_input();
+        log::debug!("Session {} capture thread finished, {} video packets dropped", worker.id, video.dropped());
+    }
+
+    fn run_loop(
+        &mut self,
+        commands: std_mpsc::Receiver<ClientMessage>,
+        control: mpsc::Sender<ServerMessage>,
+        video: &FrameQueue,
+    ) {
+        if let Err(e) = self.initialize_streamers() {
+            log::error!("Failed to initialize session {}: {}", self.id, e);
+            let _ = control.blocking_send(ServerMessage::Error {
+                message: format!("Failed to start session: {}", e),
+            });
+            return;
+        }
+        if let Some(desktop_streamer) = &self.desktop_streamer {
+            let display = desktop_streamer.display().clone();
+            if control.blocking_send(ServerMessage::DisplayChanged { display }).is_err() {
+                return;
+            }
         }
 
-        match &mut self.app_streamer {
-            Some(app_streamer) => {
-                if app_streamer.is_running() {
-                    if let Some(frame_data) = app_streamer.capture_window_frame()? {
-                        // For now, use fixed dimensions (in real implementation, get from window)
-                        return Ok(Some(ServerMessage::Frame {
-                            width: 800,
-                            height: 600,
-                            data: frame_data,
-                        }));
+        let frame_interval = Duration::from_millis(1000 / self.video_config.frame_rate.max(1) as u64);
+        let mut next_frame = Instant::now();
+
+        loop {
+            match commands.recv_timeout(next_frame.saturating_duration_since(Instant::now())) {
+                Ok(message) => {
+                    let was_hybrid = matches!(self.mode, StreamMode::Hybrid);
+                    let response = self.handle_message(message).unwrap_or_else(|e| {
+                        Some(ServerMessage::Error { message: e.to_string() })
+                    });
+                    if let Some(response) = response {
+                        if control.blocking_send(response).is_err() {
+                            break;
+                        }
+                    }
+                    // A hybrid session that just switched to desktop mode, like on start.
+                    let to_desktop = was_hybrid && matches!(self.mode, StreamMode::Desktop { .. });
+                    if let Some(desktop_streamer) = self.desktop_streamer.as_ref().filter(|_| to_desktop) {
+                        let display = desktop_streamer.display().clone();
+                        if control.blocking_send(ServerMessage::DisplayChanged { display }).is_err() {
+                            break;
+                        }
+                    }
+                    // Keep capturing even while input keeps arriving.
+                    if Instant::now() < next_frame {
+                        continue;
                     }
-                } else {
-                    log::info!("Application process has stopped for session {}", self.id);
-                    return Ok(Some(ServerMessage::Error {
-                        message: "Application has stopped".to_string(),
-                    }));
                 }
+                Err(std_mpsc::RecvTimeoutError::Timeout) => {}
+                Err(std_mpsc::RecvTimeoutError::Disconnected) => break,
+            }
+            next_frame = Instant::now() + frame_interval;
+
+            if let Some(reason) = self.application_stopped() {
+                log::info!("Ending session {}: {}", self.id, reason);
+                let _ = control.blocking_send(ServerMessage::Error { message: reason });
+                break;
             }
-            None => {}
-        }
 
-        Ok(None)
+            if video.take_keyframe_request() {
+                self.request_keyframe();
+            }
+            match self.capture_frame() {
+                Ok(packets) => packets.into_iter().for_each(|packet| video.push(packet)),
+                Err(e) => log::warn!("Frame capture failed for session {}: {}", self.id, e),
+            }
+        }
     }
 }
 
+/// Bookkeeping the manager keeps for each connected session. The session
+/// state itself is owned by the session's own tasks.
+#[derive(Debug, Clone)]
+pub struct SessionInfo {
+    pub addr: SocketAddr,
+    pub user: Option<String>,
+    pub connected_at: Instant,
+}
+
 pub struct SessionManager {
-    sessions: Arc<RwLock<HashMap<Uuid, Session>>>,
+    sessions: Mutex<HashMap<Uuid, SessionInfo>>,
     max_connections: usize,
 }
 
 impl SessionManager {
     pub fn new(max_connections: usize) -> Self {
         Self {
-            sessions: Arc::new(RwLock::new(HashMap::new())),
+            sessions: Mutex::new(HashMap::new()),
             max_connections,
         }
     }
 
-    pub async fn add_session(&self, mut session: Session) -> Result<()> {
-        let mut sessions = self.sessions.write().await;
-        
+    fn add_session(&self, session: &Session) -> Result<()> {
+        let mut sessions = self.sessions.lock().unwrap();
+
         if sessions.len() >= self.max_connections {
             return Err(anyhow::anyhow!("Maximum connections reached"));
         }
 
-        session.initialize_streamers().await?;
-        sessions.insert(session.id, session);
-        
-        log::info!("Added session, total sessions: {}", sessions.len());
+        sessions.insert(session.id, SessionInfo {
+            addr: session.addr,
+            user: session.user.clone(),
+            connected_at: Instant::now(),
+        });
+
+        log::info!("Added session {} from {}, total sessions: {}", session.id, session.addr, sessions.len());
         Ok(())
     }
 
-    pub async fn remove_session(&self, session_id: Uuid) {
-        let mut sessions = self.sessions.write().await;
-        sessions.remove(&session_id);
-        log::info!("Removed session {}, total sessions: {}", session_id, sessions.len());
+    fn remove_session(&self, session_id: Uuid) {
+        let mut sessions = self.sessions.lock().unwrap();
+        if let Some(info) = sessions.remove(&session_id) {
+            log::info!(
+                "Removed session {} from {}{} after {:?}, total sessions: {}",
+                session_id,
+                info.addr,
+                info.user.map(|u| format!(" ({})", u)).unwrap_or_default(),
+                info.connected_at.elapsed(),
+                sessions.len()
+            );
+        }
     }
 
-    pub async fn run_session(&self, session_id: Uuid) -> Result<()> {
-        let (tx, mut rx) = mpsc::channel(100);
-        
-        // Spawn frame capture task
-        let sessions_clone = Arc::clone(&self.sessions);
-        let tx_clone = tx.clone();
-        tokio::spawn(async move {
-            let mut interval = tokio::time::interval(tokio::time::Duration::from_millis(33)); // ~30 FPS
-            
+    /// Run a session until the client disconnects.
+    ///
+    /// The session is moved to its own capture thread. This task reads client
+    /// messages and forwards them to that thread, while a writer task owns the
+    /// WebSocket sink and drains the control channel and the video queue. No
+    /// lock is held while waiting on the network, so a slow or idle client
+    /// only ever delays itself.
+    ///
+    /// `hello` is a `ClientMessage::Hello` the client sent before logging in,
+    /// it is handled before anything read from `ws_stream`.
+    pub async fn run_session(
+        &self,
+        session: Session,
+        ws_stream: WebSocketStream<ServerStream>,
+        hello: Option<ClientMessage>,
+    ) -> Result<()> {
+        let session_id = session.id;
+        self.add_session(&session)?;
+
+        let (commands_tx, commands_rx) = std_mpsc::sync_channel(COMMAND_QUEUE_SIZE);
+        if let Some(hello) = hello {
+            let _ = commands_tx.send(hello);
+        }
+        let (control_tx, mut control_rx) = mpsc::channel::<ServerMessage>(CONTROL_QUEUE_SIZE);
+        let video = Arc::new(FrameQueue::new(VIDEO_QUEUE_SIZE));
+
+        let worker = {
+            let video = Arc::clone(&video);
+            std::thread::Builder::new()
+                .name(format!("session-{}", session_id))
+                .spawn(move || SessionWorker::run(session, commands_rx, control_tx, video))
+        };
+        let worker = match worker {
+            Ok(worker) => worker,
+            Err(e) => {
+                self.remove_session(session_id);
+                return Err(anyhow::anyhow!("Failed to spawn capture thread: {}", e));
+            }
+        };
+
+        let (mut sink, mut stream) = ws_stream.split();
+
+        let writer = tokio::spawn(async move {
             loop {
-                interval.tick().await;
-                
-                let sessions = sessions_clone.read().await;
-                if let Some(session) = sessions.get(&session_id) {
-                    // We can't mutably borrow in a read guard, so we'll need a different approach
-                    // For now, we'll handle frame capture in the main loop
-                    drop(sessions);
-                    
-                    let mut sessions = sessions_clone.write().await;
-                    if let Some(session) = sessions.get_mut(&session_id) {
-                        if let Ok(Some(frame_msg)) = session.capture_frame().await {
-                            let _ = tx_clone.send(frame_msg).await;
-                        }
+                let message = tokio::select! {
+                    biased;
+                    control = control_rx.recv() => match control {
+                        Some(control) => OutboundMessage::Control(control),
+                        // The capture thread has finished.
+                        None => break,
+                    },
+                    packet = video.pop() => OutboundMessage::Video(packet),
+                };
+                let message = match message.into_ws_message() {
+                    Ok(message) => message,
+                    Err(e) => {
+                        log::error!("Failed to serialize message for session {}: {}", session_id, e);
+                        continue;
                     }
-                } else {
+                };
+                if sink.send(message).await.is_err() {
                     break;
                 }
             }
+            let _ = sink.close().await;
         });
 
-        // Main message handling loop
+        // Only the latest position matters, a move that found the queue full
+        // waits here and is replaced by the next one.
+        let mut pending_move = None;
         loop {
-            let mut sessions = self.sessions.write().await;
-            let session = match sessions.get_mut(&session_id) {
-                Some(s) => s,
-                None => break,
-            };
-
             tokio::select! {
-                // Handle incoming WebSocket messages
-                ws_msg = session.ws_stream.next() => {
+                ws_msg = stream.next() => {
                     match ws_msg {
                         Some(Ok(Message::Text(text))) => {
-                            if let Ok(client_msg) = serde_json::from_str::<ClientMessage>(&text) {
-                                if let Ok(Some(response)) = session.handle_message(client_msg).await {
-                                    let response_text = serde_json::to_string(&response).unwrap();
-                                    let _ = session.ws_stream.send(Message::Text(response_text)).await;
+                            match serde_json::from_str::<ClientMessage>(&text) {
+                                Ok(client_msg @ ClientMessage::MouseMove { .. }) => pending_move = Some(client_msg),
+                                Ok(client_msg) => {
+                                    // Keep the order of input events.
+                                    if let Some(mouse_move) = pending_move.take() {
+                                        if send_command(&commands_tx, mouse_move).await.is_err() {
+                                            break;
+                                        }
+                                    }
+                                    if send_command(&commands_tx, client_msg).await.is_err() {
+                                        break;
+                                    }
                                 }
+                                Err(e) => log::debug!("Ignoring invalid message from session {}: {}", session_id, e),
                             }
                         }
                         Some(Ok(Message::Close(_))) | None => {
@@ -310,20 +639,46 @@ impl SessionManager {
                         _ => {}
                     }
                 }
-                
-                // Handle outgoing frame messages
-                frame_msg = rx.recv() => {
-                    if let Some(frame) = frame_msg {
-                        let frame_text = serde_json::to_string(&frame).unwrap();
-                        if session.ws_stream.send(Message::Text(frame_text)).await.is_err() {
-                            break;
-                        }
-                    }
+
+                _ = tokio::time::sleep(MOUSE_MOVE_RETRY), if pending_move.is_some() => {}
+
+                // The writer stops when the capture thread ends or the client stops reading.
+                _ = &mut writer => break,
+            }
+
+            if let Some(mouse_move) = pending_move.take() {
+                match commands_tx.try_send(mouse_move) {
+                    Ok(()) => {}
+                    Err(std_mpsc::TrySendError::Full(mouse_move)) => pending_move = Some(mouse_move),
+                    Err(std_mpsc::TrySendError::Disconnected(_)) => break,
                 }
             }
         }
 
-        self.remove_session(session_id).await;
+        // Dropping the sender stops the capture thread, which releases held input
+        // and tears down the streamers before the slot is freed.
+        drop(commands_tx);
+        writer.abort();
+        if !matches!(tokio::task::spawn_blocking(move || worker.join()).await, Ok(Ok(()))) {
+            log::error!("Capture thread for session {} panicked", session_id);
+        }
+
+        self.remove_session(session_id);
         Ok(())
     }
 }
+
+/// Queue a client message for the capture thread. When the queue is full this
+/// waits off the runtime, so the socket is not read until the thread catches up.
+async fn send_command(commands: &std_mpsc::SyncSender<ClientMessage>, message: ClientMessage) -> Result<()> {
+    match commands.try_send(message) {
+        Ok(()) => Ok(()),
+        Err(std_mpsc::TrySendError::Full(message)) => {
+            let commands = commands.clone();
+            tokio::task::spawn_blocking(move || commands.send(message))
+                .await?
+                .map_err(|_| anyhow::anyhow!("Capture thread has finished"))
+        }
+        Err(std_mpsc::TrySendError::Disconnected(_)) => Err(anyhow::anyhow!("Capture thread has finished")),
+    }
+}
@@ -0,0 +1,92 @@
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use tokio_rustls::rustls::ServerConfig;
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use anyhow::Result;
+
+/// Client connection, either plain TCP (`ws://`) or TLS (`wss://`).
+pub enum ServerStream {
+    Plain(TcpStream),
+    Tls(Box<TlsStream<TcpStream>>),
+}
+
+impl AsyncRead for ServerStream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            ServerStream::Plain(s) => Pin::new(s).poll_read(cx, buf),
+            ServerStream::Tls(s) => Pin::new(s.as_mut()).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for ServerStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+        match self.get_mut() {
+            ServerStream::Plain(s) => Pin::new(s).poll_write(cx, buf),
+            ServerStream::Tls(s) => Pin::new(s.as_mut()).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            ServerStream::Plain(s) => Pin::new(s).poll_flush(cx),
+            ServerStream::Tls(s) => Pin::new(s.as_mut()).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            ServerStream::Plain(s) => Pin::new(s).poll_shutdown(cx),
+            ServerStream::Tls(s) => Pin::new(s.as_mut()).poll_shutdown(cx),
+        }
+    }
+}
+
+/// Build a TLS acceptor from PEM encoded certificate chain and private key files.
+pub fn load_tls_acceptor(cert_path: &Path, key_path: &Path) -> Result<TlsAcceptor> {
+    let mut cert_reader = BufReader::new(
+        File::open(cert_path).map_err(|e| anyhow::anyhow!("Failed to open TLS certificate {:?}: {}", cert_path, e))?,
+    );
+    let certs: Vec<CertificateDer<'static>> = rustls_pemfile::certs(&mut cert_reader)
+        .collect::<std::io::Result<_>>()
+        .map_err(|e| anyhow::anyhow!("Failed to parse TLS certificate {:?}: {}", cert_path, e))?;
+    if certs.is_empty() {
+        return Err(anyhow::anyhow!("No certificates found in {:?}", cert_path));
+    }
+
+    let mut key_reader = BufReader::new(
+        File::open(key_path).map_err(|e| anyhow::anyhow!("Failed to open TLS key {:?}: {}", key_path, e))?,
+    );
+    let key: PrivateKeyDer<'static> = rustls_pemfile::private_key(&mut key_reader)
+        .map_err(|e| anyhow::anyhow!("Failed to parse TLS key {:?}: {}", key_path, e))?
+        .ok_or_else(|| anyhow::anyhow!("No private key found in {:?}", key_path))?;
+
+    let config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|e| anyhow::anyhow!("Invalid TLS certificate/key pair: {}", e))?;
+
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+/// Wrap an accepted TCP connection in TLS when an acceptor is configured.
+pub async fn accept_transport(stream: TcpStream, tls: Option<&TlsAcceptor>) -> Result<ServerStream> {
+    match tls {
+        Some(acceptor) => {
+            let stream = acceptor
+                .accept(stream)
+                .await
+                .map_err(|e| anyhow::anyhow!("TLS handshake failed: {}", e))?;
+            Ok(ServerStream::Tls(Box::new(stream)))
+        }
+        None => Ok(ServerStream::Plain(stream)),
+    }
+}
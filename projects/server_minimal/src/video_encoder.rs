@@ -0,0 +1,186 @@
+use scrap::codec::{Encoder, EncoderCfg};
+use scrap::aom::AomEncoderConfig;
+use scrap::{Frame, PixelBuffer, Pixfmt, VpxEncoderConfig, VpxVideoCodecId};
+use hbb_common::message_proto::{video_frame, EncodedVideoFrame};
+use protocol_minimal::FrameHeader;
+use anyhow::Result;
+
+pub use protocol_minimal::VideoCodec;
+
+fn encoder_cfg(codec: VideoCodec, width: usize, height: usize, quality: f32) -> EncoderCfg {
+    match codec {
+        VideoCodec::Vp8 | VideoCodec::Vp9 => EncoderCfg::VPX(VpxEncoderConfig {
+            width: width as _,
+            height: height as _,
+            quality,
+            codec: if codec == VideoCodec::Vp8 {
+                VpxVideoCodecId::VP8
+            } else {
+                VpxVideoCodecId::VP9
+            },
+            keyframe_interval: None,
+        }),
+        VideoCodec::Av1 => EncoderCfg::AOM(AomEncoderConfig {
+            width: width as _,
+            height: height as _,
+            quality,
+            keyframe_interval: None,
+            lossless: false,
+        }),
+    }
+}
+
+/// Encoding parameters shared by every session of a server.
+#[derive(Debug, Clone, Copy)]
+pub struct VideoConfig {
+    pub codec: VideoCodec,
+    /// Bitrate ratio passed to the encoder, see `scrap::codec::Quality::ratio`.
+    pub quality: f32,
+    pub frame_rate: u32,
+}
+
+impl Default for VideoConfig {
+    fn default() -> Self {
+        Self {
+            codec: VideoCodec::Vp9,
+            quality: scrap::codec::BR_BALANCED,
+            frame_rate: 30,
+        }
+    }
+}
+
+/// One encoded packet, sent to the client as a binary WebSocket message
+/// laid out as described by `protocol_minimal::FrameHeader`.
+pub struct VideoPacket {
+    pub codec: VideoCodec,
+    pub key: bool,
+    pub pts: i64,
+    pub width: u32,
+    pub height: u32,
+    pub data: Vec<u8>,
+}
+
+impl VideoPacket {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        FrameHeader {
+            codec: self.codec,
+            key: self.key,
+            width: self.width,
+            height: self.height,
+            pts: self.pts,
+        }
+        .encode(&self.data)
+    }
+}
+
+/// Wraps a `scrap::codec::Encoder` and recreates it whenever the input size changes.
+pub struct FrameEncoder {
+    config: VideoConfig,
+    encoder: Option<Encoder>,
+    width: usize,
+    height: usize,
+    yuv: Vec<u8>,
+    mid_data: Vec<u8>,
+}
+
+impl FrameEncoder {
+    pub fn new(config: VideoConfig) -> Self {
+        Self {
+            config,
+            encoder: None,
+            width: 0,
+            height: 0,
+            yuv: Vec::new(),
+            mid_data: Vec::new(),
+        }
+    }
+
+    pub fn codec(&self) -> VideoCodec {
+        self.config.codec
+    }
+
+    /// Switch to another codec, e.g. after negotiating with the client. The
+    /// next frame is a keyframe of the new codec.
+    pub fn set_codec(&mut self, codec: VideoCodec) {
+        if self.config.codec != codec {
+            self.config.codec = codec;
+            self.encoder = None;
+        }
+    }
+
+    /// Make the next encoded frame a keyframe. Like the main video service,
+    /// this simply recreates the encoder on the next frame.
+    pub fn request_keyframe(&mut self) {
+        self.encoder = None;
+    }
+
+    /// Encode a captured frame. Returns an empty list when the encoder buffered the input.
+    pub fn encode(&mut self, frame: &Frame, width: usize, height: usize, ms: i64) -> Result<Vec<VideoPacket>> {
+        self.ensure_encoder(width, height)?;
+        let encoder = match self.encoder.as_mut() {
+            Some(encoder) => encoder,
+            None => return Ok(Vec::new()),
+        };
+
+        let input = frame
+            .to(encoder.yuvfmt(), &mut self.yuv, &mut self.mid_data)
+            .map_err(|e| anyhow::anyhow!("Failed to convert frame to YUV: {}", e))?;
+
+        let video_frame = match encoder.encode_to_message(input, ms) {
+            Ok(video_frame) => video_frame,
+            Err(e) => {
+                // The VPX/AOM encoders report buffered input as an error.
+                log::trace!("Encoder produced no packet: {}", e);
+                return Ok(Vec::new());
+            }
+        };
+
+        let frames = match video_frame.union {
+            Some(video_frame::Union::Vp8s(frames))
+            | Some(video_frame::Union::Vp9s(frames))
+            | Some(video_frame::Union::Av1s(frames)) => frames.frames,
+            _ => return Err(anyhow::anyhow!("Unexpected video frame type from encoder")),
+        };
+
+        Ok(frames
+            .into_iter()
+            .map(|frame| self.packet(frame))
+            .collect())
+    }
+
+    /// Encode a raw pixel buffer that did not come from a `scrap::Capturer`.
+    pub fn encode_raw(&mut self, data: &[u8], pixfmt: Pixfmt, width: usize, height: usize, ms: i64) -> Result<Vec<VideoPacket>> {
+        if width == 0 || height == 0 || data.len() < width * height * pixfmt.bytes_per_pixel() {
+            return Err(anyhow::anyhow!("Invalid raw frame {}x{} with {} bytes", width, height, data.len()));
+        }
+        let frame = Frame::PixelBuffer(PixelBuffer::new(data, pixfmt, width, height));
+        self.encode(&frame, width, height, ms)
+    }
+
+    fn ensure_encoder(&mut self, width: usize, height: usize) -> Result<()> {
+        if self.encoder.is_some() && self.width == width && self.height == height {
+            return Ok(());
+        }
+
+        let cfg = encoder_cfg(self.config.codec, width, height, self.config.quality);
+        let encoder = Encoder::new(cfg, false)
+            .map_err(|e| anyhow::anyhow!("Failed to create {} encoder: {}", self.config.codec.name(), e))?;
+
+        log::info!("Created {} encoder for {}x{}", self.config.codec.name(), width, height);
+        self.encoder = Some(encoder);
+        self.width = width;
+        self.height = height;
+        Ok(())
+    }
+
+    fn packet(&self, frame: EncodedVideoFrame) -> VideoPacket {
+        VideoPacket {
+            codec: self.config.codec,
+            key: frame.key,
+            pts: frame.pts,
+            width: self.width as u32,
+            height: self.height as u32,
+            data: frame.data.to_vec(),
+        }
+    }
+}
@@ -0,0 +1,367 @@
+// Opus multistream encoder and decoder, on the libopus linked by magnum-opus,
+// which only wraps mono and stereo and has no DTX control.
+//
+// One or two channels are coded as a single stream, the packets are plain Opus
+// packets and peers decoding them with magnum_opus are not affected.
+// More channels, 5.1 for example, are split into coupled and mono streams. The
+// channels keep the order of the devices (WAVE: FL FR FC LFE BL BR SL SR), the
+// mapping below is used on both sides instead of being transmitted.
+
+// Nothing else of the crate is used here, keep its libopus linked.
+extern crate magnum_opus;
+
+use hbb_common::{bail, ResultType};
+use std::{
+    ffi::CStr,
+    os::raw::{c_char, c_int},
+};
+
+pub const MAX_CHANNELS: u16 = 8;
+
+const OPUS_OK: c_int = 0;
+const OPUS_AUTO: c_int = -1000;
+const OPUS_APPLICATION_VOIP: c_int = 2048;
+const OPUS_APPLICATION_AUDIO: c_int = 2049;
+const OPUS_APPLICATION_RESTRICTED_LOWDELAY: c_int = 2051;
+const OPUS_SIGNAL_VOICE: c_int = 3001;
+const OPUS_SIGNAL_MUSIC: c_int = 3002;
+const OPUS_SET_BITRATE_REQUEST: c_int = 4002;
+const OPUS_SET_INBAND_FEC_REQUEST: c_int = 4012;
+const OPUS_SET_PACKET_LOSS_PERC_REQUEST: c_int = 4014;
+const OPUS_SET_DTX_REQUEST: c_int = 4016;
+const OPUS_SET_SIGNAL_REQUEST: c_int = 4024;
+// Expected loss, in-band FEC is only added when it is not zero.
+const FEC_PACKET_LOSS_PERC: c_int = 10;
+
+#[repr(C)]
+struct OpusMSEncoder {
+    _private: [u8; 0],
+}
+
+#[repr(C)]
+struct OpusMSDecoder {
+    _private: [u8; 0],
+}
+
+extern "C" {
+    fn opus_multistream_encoder_create(
+        fs: i32,
+        channels: c_int,
+        streams: c_int,
+        coupled_streams: c_int,
+        mapping: *const u8,
+        application: c_int,
+        error: *mut c_int,
+    ) -> *mut OpusMSEncoder;
+    fn opus_multistream_encode_float(
+        st: *mut OpusMSEncoder,
+        pcm: *const f32,
+        frame_size: c_int,
+        data: *mut u8,
+        max_data_bytes: i32,
+    ) -> c_int;
+    fn opus_multistream_encoder_ctl(st: *mut OpusMSEncoder, request: c_int, ...) -> c_int;
+    fn opus_multistream_encoder_destroy(st: *mut OpusMSEncoder);
+    fn opus_multistream_decoder_create(
+        fs: i32,
+        channels: c_int,
+        streams: c_int,
+        coupled_streams: c_int,
+        mapping: *const u8,
+        error: *mut c_int,
+    ) -> *mut OpusMSDecoder;
+    fn opus_multistream_decode_float(
+        st: *mut OpusMSDecoder,
+        data: *const u8,
+        len: i32,
+        pcm: *mut f32,
+        frame_size: c_int,
+        decode_fec: c_int,
+    ) -> c_int;
+    fn opus_multistream_decoder_destroy(st: *mut OpusMSDecoder);
+    fn opus_strerror(error: c_int) -> *const c_char;
+}
+
+fn check(code: c_int, what: &str) -> ResultType<c_int> {
+    if code < OPUS_OK {
+        let err = unsafe { CStr::from_ptr(opus_strerror(code)) };
+        bail!("opus {} failed: {}", what, err.to_string_lossy());
+    }
+    Ok(code)
+}
+
+// (streams, coupled streams, stream channel of every device channel), the
+// left and right pairs are coupled.
+fn layout(channels: u16) -> ResultType<(c_int, c_int, &'static [u8])> {
+    Ok(match channels {
+        1 => (1, 0, &[0]),
+        2 => (1, 1, &[0, 1]),
+        3 => (2, 1, &[0, 1, 2]),
+        4 => (2, 2, &[0, 1, 2, 3]),
+        5 => (3, 2, &[0, 1, 4, 2, 3]),
+        6 => (4, 2, &[0, 1, 4, 5, 2, 3]),
+        7 => (5, 2, &[0, 1, 4, 5, 6, 2, 3]),
+        8 => (5, 3, &[0, 1, 6, 7, 2, 3, 4, 5]),
+        _ => bail!("unsupported audio channels: {}", channels),
+    })
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum AudioProfile {
+    /// CELT only, the lowest latency.
+    #[default]
+    LowDelay,
+    Voice,
+    Music,
+}
+
+impl AudioProfile {
+    pub fn from_option(v: &str) -> Self {
+        match v {
+            "voice" => Self::Voice,
+            "music" => Self::Music,
+            _ => Self::LowDelay,
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct EncoderConfig {
+    pub sample_rate: u32,
+    pub channels: u16,
+    pub profile: AudioProfile,
+    /// Bits per second of all channels, 0 for the encoder's choice.
+    pub bitrate: u32,
+    pub fec: bool,
+    pub dtx: bool,
+}
+
+pub struct Encoder {
+    st: *mut OpusMSEncoder,
+    channels: u16,
+}
+
+// The state is only reached through `&mut self`.
+unsafe impl Send for Encoder {}
+
+impl Encoder {
+    pub fn new(cfg: &EncoderConfig) -> ResultType<Self> {
+        let (streams, coupled, mapping) = layout(cfg.channels)?;
+        let application = match cfg.profile {
+            AudioProfile::LowDelay => OPUS_APPLICATION_RESTRICTED_LOWDELAY,
+            AudioProfile::Voice => OPUS_APPLICATION_VOIP,
+            AudioProfile::Music => OPUS_APPLICATION_AUDIO,
+        };
+        let mut error = OPUS_OK;
+        let st = unsafe {
+            opus_multistream_encoder_create(
+                cfg.sample_rate as _,
+                cfg.channels as _,
+                streams,
+                coupled,
+                mapping.as_ptr(),
+                application,
+                &mut error,
+            )
+        };
+        check(error, "encoder create")?;
+        if st.is_null() {
+            bail!("opus encoder create failed");
+        }
+        let encoder = Self {
+            st,
+            channels: cfg.channels,
+        };
+        let bitrate = if cfg.bitrate > 0 {
+            cfg.bitrate as c_int
+        } else {
+            OPUS_AUTO
+        };
+        encoder.ctl(OPUS_SET_BITRATE_REQUEST, bitrate, "set bitrate")?;
+        match cfg.profile {
+            AudioProfile::Voice => {
+                encoder.ctl(OPUS_SET_SIGNAL_REQUEST, OPUS_SIGNAL_VOICE, "set signal")?
+            }
+            AudioProfile::Music => {
+                encoder.ctl(OPUS_SET_SIGNAL_REQUEST, OPUS_SIGNAL_MUSIC, "set signal")?
+            }
+            AudioProfile::LowDelay => {}
+        }
+        if cfg.fec {
+            encoder.ctl(OPUS_SET_INBAND_FEC_REQUEST, 1, "set fec")?;
+            encoder.ctl(
+                OPUS_SET_PACKET_LOSS_PERC_REQUEST,
+                FEC_PACKET_LOSS_PERC,
+                "set packet loss",
+            )?;
+        }
+        encoder.ctl(OPUS_SET_DTX_REQUEST, cfg.dtx as _, "set dtx")?;
+        Ok(encoder)
+    }
+
+    fn ctl(&self, request: c_int, value: c_int, what: &str) -> ResultType<()> {
+        check(
+            unsafe { opus_multistream_encoder_ctl(self.st, request, value) },
+            what,
+        )?;
+        Ok(())
+    }
+
+    /// Encode interleaved samples, one frame of 2.5, 5, 10, 20, 40 or 60 ms.
+    pub fn encode_vec_float(&mut self, input: &[f32], max_size: usize) -> ResultType<Vec<u8>> {
+        let mut output = vec![0u8; max_size];
+        let n = check(
+            unsafe {
+                opus_multistream_encode_float(
+                    self.st,
+                    input.as_ptr(),
+                    (input.len() / self.channels as usize) as _,
+                    output.as_mut_ptr(),
+                    max_size as _,
+                )
+            },
+            "encode",
+        )?;
+        output.truncate(n as _);
+        Ok(output)
+    }
+}
+
+impl Drop for Encoder {
+    fn drop(&mut self) {
+        unsafe { opus_multistream_encoder_destroy(self.st) };
+    }
+}
+
+pub struct Decoder {
+    st: *mut OpusMSDecoder,
+    channels: u16,
+}
+
+unsafe impl Send for Decoder {}
+
+impl Decoder {
+    pub fn new(sample_rate: u32, channels: u16) -> ResultType<Self> {
+        let (streams, coupled, mapping) = layout(channels)?;
+        let mut error = OPUS_OK;
+        let st = unsafe {
+            opus_multistream_decoder_create(
+                sample_rate as _,
+                channels as _,
+                streams,
+                coupled,
+                mapping.as_ptr(),
+                &mut error,
+            )
+        };
+        check(error, "decoder create")?;
+        if st.is_null() {
+            bail!("opus decoder create failed");
+        }
+        Ok(Self { st, channels })
+    }
+
+    /// Decode a packet into interleaved samples, returns the samples per channel.
+    pub fn decode_float(
+        &mut self,
+        input: &[u8],
+        output: &mut [f32],
+        fec: bool,
+    ) -> ResultType<usize> {
+        let n = check(
+            unsafe {
+                opus_multistream_decode_float(
+                    self.st,
+                    input.as_ptr(),
+                    input.len() as _,
+                    output.as_mut_ptr(),
+                    (output.len() / self.channels as usize) as _,
+                    fec as _,
+                )
+            },
+            "decode",
+        )?;
+        Ok(n as _)
+    }
+}
+
+impl Drop for Decoder {
+    fn drop(&mut self) {
+        unsafe { opus_multistream_decoder_destroy(self.st) };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_layout() {
+        for channels in 1..=MAX_CHANNELS {
+            let (streams, coupled, mapping) = layout(channels).unwrap();
+            assert!(coupled <= streams);
+            assert_eq!((streams + coupled) as u16, channels);
+            // Every channel has its own stream channel.
+            let mut sorted = mapping.to_vec();
+            sorted.sort();
+            assert_eq!(sorted, (0..channels as u8).collect::<Vec<_>>());
+        }
+        // FL FR and BL BR are the coupled streams of 5.1.
+        assert_eq!(layout(6).unwrap(), (4, 2, &[0, 1, 4, 5, 2, 3][..]));
+        assert!(layout(0).is_err());
+        assert!(layout(MAX_CHANNELS + 1).is_err());
+    }
+
+    // A tone on one channel only, it must come back on that channel.
+    fn round_trip(channels: u16) {
+        let sample_rate = 48000;
+        let frame_size = 480;
+        let tone = (channels as usize - 1).min(2);
+        let mut encoder = Encoder::new(&EncoderConfig {
+            sample_rate,
+            channels,
+            ..Default::default()
+        })
+        .unwrap();
+        let mut decoder = Decoder::new(sample_rate, channels).unwrap();
+        let mut output = vec![0.; frame_size * channels as usize];
+        for i in 0..20 {
+            let input = (0..output.len())
+                .map(|j| {
+                    if j % channels as usize != tone {
+                        return 0.;
+                    }
+                    let t = (i * frame_size + j / channels as usize) as f32 / sample_rate as f32;
+                    (t * 440. * 2. * std::f32::consts::PI).sin() * 0.5
+                })
+                .collect::<Vec<f32>>();
+            let packet = encoder.encode_vec_float(&input, 4000).unwrap();
+            assert!(!packet.is_empty());
+            let n = decoder.decode_float(&packet, &mut output, false).unwrap();
+            assert_eq!(n, frame_size);
+        }
+        let energy = (0..channels as usize)
+            .map(|c| {
+                output
+                    .iter()
+                    .skip(c)
+                    .step_by(channels as usize)
+                    .map(|s| s * s)
+                    .sum::<f32>()
+            })
+            .collect::<Vec<_>>();
+        assert!(energy[tone] > 1.0, "{:?}", energy);
+        for (c, e) in energy.iter().enumerate() {
+            if c != tone {
+                assert!(*e < energy[tone] / 10., "{:?}", energy);
+            }
+        }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        round_trip(1);
+        round_trip(2);
+        round_trip(6);
+    }
+}
@@ -1,36 +1,69 @@
 use hbb_common::{
     anyhow::anyhow,
     bail,
-    config::Config,
-    get_time,
+    config::{Config, Status},
+    get_time, log,
     password_security::{decrypt_vec_or_original, encrypt_vec_or_original},
+    rand::Rng,
     ResultType,
 };
 use serde_derive::{Deserialize, Serialize};
 use std::sync::Mutex;
 use totp_rs::{Algorithm, Secret, TOTP};
 
+pub mod notifier;
+
 lazy_static::lazy_static! {
     static ref CURRENT_2FA: Mutex<Option<(TOTPInfo, TOTP)>> = Mutex::new(None);
+    // Held while a code is checked against the last accepted step.
+    static ref CHECK_LOCK: Mutex<()> = Mutex::new(());
 }
 
 const ISSUER: &str = "RustDesk";
 const TAG_LOGIN: &str = "Connection";
 
+/// Algorithm of newly generated secrets: SHA1 (default), SHA256 or SHA512.
+pub const OPTION_2FA_ALGORITHM: &str = "2fa-algorithm";
+/// Digits of newly generated secrets, 6 (default) or 8.
+pub const OPTION_2FA_DIGITS: &str = "2fa-digits";
+/// Time steps of 30 seconds accepted before and after now, 1 by default.
+pub const OPTION_2FA_SKEW: &str = "2fa-skew";
+/// Encrypted JSON array of the unused recovery codes.
+const OPTION_2FA_RECOVERY: &str = "2fa-recovery";
+// `fingerprint:step` of the time step of the last accepted code, codes of it or
+// before are replays. The fingerprint of the secret resets it on a new one.
+const STATUS_2FA_LAST_STEP: &str = "2fa_last_step";
+const RECOVERY_CODES: usize = 10;
+// About 66 bits, and longer than any TOTP code so that both fit the digit only input.
+const RECOVERY_CODE_DIGITS: usize = 20;
+const MAX_SKEW: u8 = 10;
+
 #[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct TOTPInfo {
     pub name: String,
     pub secret: Vec<u8>,
     pub digits: usize,
     pub created_at: i64,
+    /// Empty for SHA1, secrets created before this field existed.
+    #[serde(default)]
+    pub algorithm: String,
 }
 
 impl TOTPInfo {
     fn new_totp(&self) -> ResultType<TOTP> {
+        let algorithm = match self.algorithm.to_uppercase().as_str() {
+            "SHA256" => Algorithm::SHA256,
+            "SHA512" => Algorithm::SHA512,
+            _ => Algorithm::SHA1,
+        };
+        let skew = Config::get_option(OPTION_2FA_SKEW)
+            .parse::<u8>()
+            .unwrap_or(1)
+            .min(MAX_SKEW);
         let totp = TOTP::new(
-            Algorithm::SHA1,
+            algorithm,
             self.digits,
-            1,
+            skew,
             30,
             self.secret.clone(),
             Some(format!("{} {}", ISSUER, TAG_LOGIN)),
@@ -39,14 +72,14 @@ impl TOTPInfo {
         Ok(totp)
     }
 
-    fn gen_totp_info(name: String, digits: usize) -> ResultType<TOTPInfo> {
+    fn gen_totp_info(name: String, digits: usize, algorithm: String) -> ResultType<TOTPInfo> {
         let secret = Secret::generate_secret();
         let totp = TOTPInfo {
             secret: secret.to_bytes()?,
             name,
             digits,
             created_at: get_time(),
-            ..Default::default()
+            algorithm,
         };
         Ok(totp)
     }
@@ -78,7 +111,18 @@ pub fn generate2fa() -> String {
     let id = crate::ipc::get_id();
     #[cfg(any(target_os = "android", target_os = "ios"))]
     let id = Config::get_id();
-    if let Ok(info) = TOTPInfo::gen_totp_info(id, 6) {
+    let digits = match Config::get_option(OPTION_2FA_DIGITS).as_str() {
+        "8" => 8,
+        _ => 6,
+    };
+    let algorithm = match Config::get_option(OPTION_2FA_ALGORITHM)
+        .to_uppercase()
+        .as_str()
+    {
+        x @ ("SHA256" | "SHA512") => x.to_owned(),
+        _ => "".to_owned(),
+    };
+    if let Ok(info) = TOTPInfo::gen_totp_info(id, digits, algorithm) {
         if let Ok(totp) = info.new_totp() {
             let code = totp.get_url();
             *CURRENT_2FA.lock().unwrap() = Some((info, totp));
@@ -93,10 +137,9 @@ pub fn verify2fa(code: String) -> bool {
         if let Ok(res) = totp.check_current(&code) {
             if res {
                 if let Ok(v) = info.into_string() {
-                    #[cfg(not(any(target_os = "android", target_os = "ios")))]
-                    crate::ipc::set_option("2fa", &v);
-                    #[cfg(any(target_os = "android", target_os = "ios"))]
-                    Config::set_option("2fa".to_owned(), v);
+                    set_option("2fa", v);
+                    // Recovery codes of the previous secret.
+                    set_option(OPTION_2FA_RECOVERY, "".to_owned());
                     return res;
                 }
             }
@@ -111,6 +154,139 @@ pub fn get_2fa(raw: Option<String>) -> Option<TOTP> {
         .unwrap_or_default()
 }
 
+fn set_option(key: &str, value: String) {
+    #[cfg(not(any(target_os = "android", target_os = "ios")))]
+    crate::ipc::set_option(key, &value);
+    #[cfg(any(target_os = "android", target_os = "ios"))]
+    Config::set_option(key.to_owned(), value);
+}
+
+/// Check the 2FA code of a login, called in the server process.
+///
+/// A TOTP code is accepted within the configured skew, but only once: codes of
+/// the time step of the last accepted one or before are rejected. Otherwise
+/// `code` may be an unused recovery code, which is then used up.
+pub fn check_code(totp: &TOTP, code: &str) -> bool {
+    let code = code.trim();
+    if code.len() == totp.digits {
+        let _lock = CHECK_LOCK.lock().unwrap();
+        let fingerprint = fingerprint(totp);
+        let mut last_step = match Status::get(STATUS_2FA_LAST_STEP).split_once(':') {
+            Some((f, step)) if f == fingerprint => step.parse().unwrap_or_default(),
+            _ => 0,
+        };
+        if !check_totp(totp, code, get_time() as u64 / 1000, &mut last_step) {
+            return false;
+        }
+        Status::set(
+            STATUS_2FA_LAST_STEP,
+            format!("{}:{}", fingerprint, last_step),
+        );
+        true
+    } else if code.len() == RECOVERY_CODE_DIGITS {
+        use_recovery_code(code, get_recovery_codes, |codes| {
+            Config::set_option(
+                OPTION_2FA_RECOVERY.to_owned(),
+                encode_recovery_codes(codes)?,
+            );
+            Ok(())
+        })
+    } else {
+        false
+    }
+}
+
+fn check_totp(totp: &TOTP, code: &str, now: u64, last_step: &mut u64) -> bool {
+    let step = now / totp.step;
+    let skew = totp.skew as u64;
+    for s in step.saturating_sub(skew)..=step + skew {
+        if s > *last_step && eq_constant_time(&totp.generate(s * totp.step), code) {
+            *last_step = s;
+            return true;
+        }
+    }
+    false
+}
+
+fn fingerprint(totp: &TOTP) -> String {
+    use sha2::{Digest, Sha256};
+    hex::encode(&Sha256::digest(&totp.secret)[..8])
+}
+
+fn eq_constant_time(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0u8, |acc, (x, y)| acc | (x ^ y))
+            == 0
+}
+
+fn get_recovery_codes() -> Vec<String> {
+    let data = Config::get_option(OPTION_2FA_RECOVERY);
+    if data.is_empty() {
+        return vec![];
+    }
+    let Ok(data) = serde_json::from_str::<Vec<u8>>(&data) else {
+        return vec![];
+    };
+    let (data, success, _) = decrypt_vec_or_original(&data, "00");
+    if !success {
+        return vec![];
+    }
+    serde_json::from_slice(&data).unwrap_or_default()
+}
+
+fn encode_recovery_codes(codes: &[String]) -> ResultType<String> {
+    if codes.is_empty() {
+        return Ok("".to_owned());
+    }
+    let data = encrypt_vec_or_original(serde_json::to_string(codes)?.as_bytes(), "00", 1024);
+    Ok(serde_json::to_string(&data)?)
+}
+
+// The codes are loaded, checked and stored under the lock, a code is only
+// accepted once however many logins try it at the same time.
+fn use_recovery_code(
+    code: &str,
+    load: impl FnOnce() -> Vec<String>,
+    store: impl FnOnce(&[String]) -> ResultType<()>,
+) -> bool {
+    let _lock = CHECK_LOCK.lock().unwrap();
+    let mut codes = load();
+    let Some(i) = codes.iter().position(|c| eq_constant_time(c, code)) else {
+        return false;
+    };
+    codes.remove(i);
+    match store(&codes) {
+        Ok(()) => {
+            log::info!("2FA recovery code used, {} left", codes.len());
+            true
+        }
+        Err(err) => {
+            log::error!("Failed to store 2FA recovery codes: {}", err);
+            false
+        }
+    }
+}
+
+/// Replace the recovery codes of the enabled 2FA with new ones, shown once.
+/// `raw` is the `2fa` option as in [`get_2fa`].
+pub fn generate_recovery_codes(raw: Option<String>) -> ResultType<Vec<String>> {
+    if get_2fa(raw).is_none() {
+        bail!("2FA is not enabled");
+    }
+    let mut rng = hbb_common::rand::thread_rng();
+    let codes: Vec<String> = (0..RECOVERY_CODES)
+        .map(|_| {
+            (0..RECOVERY_CODE_DIGITS)
+                .map(|_| char::from(b'0' + rng.gen_range(0..10)))
+                .collect()
+        })
+        .collect();
+    set_option(OPTION_2FA_RECOVERY, encode_recovery_codes(&codes)?);
+    Ok(codes)
+}
+
 #[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct TelegramBot {
     #[serde(skip)]
@@ -202,3 +378,84 @@ pub fn get_chatid_telegram(bot_token: &str) -> ResultType<Option<String>> {
 
     Ok(chat_id)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_totp() {
+        let totp = TOTP::new(
+            Algorithm::SHA256,
+            8,
+            1,
+            30,
+            b"12345678901234567890123456789012".to_vec(),
+            Some(ISSUER.to_owned()),
+            "test".to_owned(),
+        )
+        .unwrap();
+        let now = 1_700_000_000;
+        let mut last_step = 0;
+        let code = totp.generate(now);
+        assert_eq!(code.len(), 8);
+        assert!(check_totp(&totp, &code, now, &mut last_step));
+        assert!(!check_totp(&totp, &code, now, &mut last_step));
+        // the previous step is within the skew but older than the used one
+        assert!(!check_totp(
+            &totp,
+            &totp.generate(now - 30),
+            now,
+            &mut last_step
+        ));
+        assert!(check_totp(
+            &totp,
+            &totp.generate(now + 30),
+            now,
+            &mut last_step
+        ));
+        assert!(!check_totp(
+            &totp,
+            &totp.generate(now + 90),
+            now,
+            &mut last_step
+        ));
+        assert!(!check_totp(&totp, "0000000", now, &mut last_step));
+    }
+
+    #[test]
+    fn test_use_recovery_code() {
+        let code = "1".repeat(RECOVERY_CODE_DIGITS);
+        let other = "2".repeat(RECOVERY_CODE_DIGITS);
+        let stored = std::sync::Arc::new(Mutex::new(vec![code.clone(), other.clone()]));
+        let try_code = |code: &str| {
+            let (load, store) = (stored.clone(), stored.clone());
+            use_recovery_code(
+                code,
+                move || {
+                    let codes = load.lock().unwrap().clone();
+                    // Let concurrent logins load the same codes if they could.
+                    std::thread::sleep(std::time::Duration::from_millis(10));
+                    codes
+                },
+                move |codes| {
+                    *store.lock().unwrap() = codes.to_vec();
+                    Ok(())
+                },
+            )
+        };
+        let used = std::thread::scope(|s| {
+            let threads: Vec<_> = (0..8).map(|_| s.spawn(|| try_code(&code))).collect();
+            threads
+                .into_iter()
+                .map(|t| t.join().unwrap())
+                .filter(|used| *used)
+                .count()
+        });
+        assert_eq!(used, 1);
+        assert!(!try_code(&code));
+        assert_eq!(*stored.lock().unwrap(), vec![other.clone()]);
+        assert!(try_code(&other));
+        assert!(stored.lock().unwrap().is_empty());
+    }
+}
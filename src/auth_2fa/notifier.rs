@@ -0,0 +1,596 @@
+use super::TelegramBot;
+use async_trait::async_trait;
+use hbb_common::{
+    bail,
+    config::Config,
+    futures::future::join_all,
+    log,
+    password_security::{decrypt_vec_or_original, encrypt_vec_or_original},
+    ResultType,
+};
+use std::{collections::HashMap, time::Duration};
+
+/// JSON of the code and the connection is posted to this url.
+pub const OPTION_WEBHOOK_URL: &str = "2fa-webhook-url";
+/// Extra header of the webhook request, `Name: value`, e.g. for authorization. Stored encrypted.
+pub const OPTION_WEBHOOK_HEADER: &str = "2fa-webhook-header";
+/// `host[:port]` of the SMTP server, the port defaults by `2fa-smtp-tls`. IPv6
+/// addresses take brackets with a port, `[2001:db8::1]:587`.
+pub const OPTION_SMTP_SERVER: &str = "2fa-smtp-server";
+/// `starttls` (default, port 587), `tls` (port 465) or `none` (port 25).
+pub const OPTION_SMTP_TLS: &str = "2fa-smtp-tls";
+pub const OPTION_SMTP_USER: &str = "2fa-smtp-user";
+/// Stored encrypted.
+pub const OPTION_SMTP_PASSWORD: &str = "2fa-smtp-password";
+pub const OPTION_SMTP_FROM: &str = "2fa-smtp-from";
+/// Recipients, separated by commas.
+pub const OPTION_SMTP_TO: &str = "2fa-smtp-to";
+/// ntfy topic url, e.g. `https://ntfy.sh/mytopic`, or Gotify message url, e.g. `https://gotify.example.com/message`.
+pub const OPTION_PUSH_URL: &str = "2fa-push-url";
+/// `ntfy` (default) or `gotify`.
+pub const OPTION_PUSH_TYPE: &str = "2fa-push-type";
+/// Access token of ntfy or application token of Gotify. Stored encrypted.
+pub const OPTION_PUSH_TOKEN: &str = "2fa-push-token";
+
+// Encrypted by `encrypt_options` when they are set.
+const SECRET_OPTIONS: [&str; 3] = [
+    OPTION_WEBHOOK_HEADER,
+    OPTION_SMTP_PASSWORD,
+    OPTION_PUSH_TOKEN,
+];
+
+const TITLE: &str = "RustDesk 2FA code";
+const TIMEOUT: Duration = Duration::from_secs(12);
+
+/// The code of a login waiting for 2FA and where the login comes from.
+#[derive(Debug, Clone, Default)]
+pub struct Notification {
+    pub code: String,
+    pub id: String,
+    pub ip: String,
+}
+
+impl Notification {
+    pub fn text(&self) -> String {
+        format!(
+            "2FA code: {}\n\nA new connection has been established to your device with ID {}. The source IP address is {}.",
+            self.code, self.id, self.ip,
+        )
+    }
+}
+
+/// Out-of-band delivery of 2FA codes.
+#[async_trait]
+pub trait SecondFactorNotifier: Send + Sync {
+    fn name(&self) -> &'static str;
+    async fn notify(&self, notification: &Notification) -> ResultType<()>;
+}
+
+#[async_trait]
+impl SecondFactorNotifier for TelegramBot {
+    fn name(&self) -> &'static str {
+        "telegram"
+    }
+
+    async fn notify(&self, notification: &Notification) -> ResultType<()> {
+        super::send_2fa_code_to_telegram(&notification.text(), self.clone()).await
+    }
+}
+
+/// Posts `{"id", "ip", "code", "text"}` as JSON.
+pub struct Webhook {
+    pub url: String,
+    pub header: String,
+}
+
+#[async_trait]
+impl SecondFactorNotifier for Webhook {
+    fn name(&self) -> &'static str {
+        "webhook"
+    }
+
+    async fn notify(&self, notification: &Notification) -> ResultType<()> {
+        let body = serde_json::json!({
+            "id": notification.id,
+            "ip": notification.ip,
+            "code": notification.code,
+            "text": notification.text(),
+        });
+        let mut req = crate::hbbs_http::create_http_client_async()
+            .post(&self.url)
+            .json(&body)
+            .timeout(TIMEOUT);
+        if let Some((name, value)) = self.header.split_once(':') {
+            req = req.header(name.trim(), value.trim());
+        }
+        req.send().await?.error_for_status()?;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SmtpTls {
+    StartTls,
+    Tls,
+    None,
+}
+
+pub struct Smtp {
+    pub host: String,
+    pub port: u16,
+    pub tls: SmtpTls,
+    pub user: String,
+    pub password: String,
+    pub from: String,
+    pub to: Vec<String>,
+}
+
+impl Smtp {
+    fn from_options() -> Option<Self> {
+        let server = Config::get_option(OPTION_SMTP_SERVER);
+        let to: Vec<String> = Config::get_option(OPTION_SMTP_TO)
+            .split(',')
+            .map(|x| x.trim().to_owned())
+            .filter(|x| !x.is_empty())
+            .collect();
+        if server.is_empty() || to.is_empty() {
+            return None;
+        }
+        let tls = match Config::get_option(OPTION_SMTP_TLS).to_lowercase().as_str() {
+            "tls" => SmtpTls::Tls,
+            "none" => SmtpTls::None,
+            _ => SmtpTls::StartTls,
+        };
+        let default_port = match tls {
+            SmtpTls::StartTls => 587,
+            SmtpTls::Tls => 465,
+            SmtpTls::None => 25,
+        };
+        let (host, port) = split_host_port(&server, default_port);
+        let user = Config::get_option(OPTION_SMTP_USER);
+        let mut from = Config::get_option(OPTION_SMTP_FROM);
+        if from.is_empty() {
+            from = user.clone();
+        }
+        Some(Self {
+            host,
+            port,
+            tls,
+            user,
+            password: get_secret_option(OPTION_SMTP_PASSWORD),
+            from,
+            to,
+        })
+    }
+}
+
+// `[v6]:port`, `host:port`, or a host or a bare IPv6 address without a port.
+fn split_host_port(server: &str, default_port: u16) -> (String, u16) {
+    let parse = |port: &str| port.parse().unwrap_or(default_port);
+    if let Some((host, rest)) = server.strip_prefix('[').and_then(|x| x.split_once(']')) {
+        return (
+            host.to_owned(),
+            rest.strip_prefix(':').map_or(default_port, parse),
+        );
+    }
+    match server.rsplit_once(':') {
+        Some((host, port)) if !host.contains(':') => (host.to_owned(), parse(port)),
+        _ => (server.to_owned(), default_port),
+    }
+}
+
+#[async_trait]
+impl SecondFactorNotifier for Smtp {
+    fn name(&self) -> &'static str {
+        "smtp"
+    }
+
+    async fn notify(&self, notification: &Notification) -> ResultType<()> {
+        use lettre::{
+            message::Mailbox, transport::smtp::authentication::Credentials, AsyncSmtpTransport,
+            AsyncTransport, Message, Tokio1Executor,
+        };
+        let mut email = Message::builder()
+            .from(self.from.parse::<Mailbox>()?)
+            .subject(TITLE);
+        for to in self.to.iter() {
+            email = email.to(to.parse::<Mailbox>()?);
+        }
+        let email = email.body(notification.text())?;
+        let mut transport = match self.tls {
+            SmtpTls::StartTls => AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&self.host)?,
+            SmtpTls::Tls => AsyncSmtpTransport::<Tokio1Executor>::relay(&self.host)?,
+            SmtpTls::None => AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(&self.host),
+        }
+        .port(self.port)
+        .timeout(Some(TIMEOUT));
+        if !self.user.is_empty() {
+            transport =
+                transport.credentials(Credentials::new(self.user.clone(), self.password.clone()));
+        }
+        transport.build().send(email).await?;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PushType {
+    Ntfy,
+    Gotify,
+}
+
+/// ntfy or Gotify style push, both are plain HTTP posts with a token.
+pub struct Push {
+    pub typ: PushType,
+    pub url: String,
+    pub token: String,
+}
+
+#[async_trait]
+impl SecondFactorNotifier for Push {
+    fn name(&self) -> &'static str {
+        match self.typ {
+            PushType::Ntfy => "ntfy",
+            PushType::Gotify => "gotify",
+        }
+    }
+
+    async fn notify(&self, notification: &Notification) -> ResultType<()> {
+        let client = crate::hbbs_http::create_http_client_async();
+        let req = match self.typ {
+            PushType::Ntfy => {
+                let mut req = client
+                    .post(&self.url)
+                    .header("Title", TITLE)
+                    .header("Priority", "high")
+                    .body(notification.text());
+                if !self.token.is_empty() {
+                    req = req.bearer_auth(&self.token);
+                }
+                req
+            }
+            PushType::Gotify => client
+                .post(&self.url)
+                .header("X-Gotify-Key", &self.token)
+                .json(&serde_json::json!({
+                    "title": TITLE,
+                    "message": notification.text(),
+                    "priority": 8,
+                })),
+        };
+        req.timeout(TIMEOUT).send().await?.error_for_status()?;
+        Ok(())
+    }
+}
+
+fn encrypt_secret(value: &str) -> String {
+    let data = encrypt_vec_or_original(value.as_bytes(), "00", 1024);
+    serde_json::to_string(&data).unwrap_or_default()
+}
+
+fn decrypt_secret(value: &str) -> Option<String> {
+    let data = serde_json::from_str::<Vec<u8>>(value).ok()?;
+    let (data, success, _) = decrypt_vec_or_original(&data, "00");
+    if !success {
+        return None;
+    }
+    String::from_utf8(data).ok()
+}
+
+/// Encrypt the values of the secret options in `options` that are in plain
+/// text, called before the options are stored.
+pub fn encrypt_options(options: &mut HashMap<String, String>) {
+    for key in SECRET_OPTIONS {
+        if let Some(v) = options.get_mut(key) {
+            if !v.is_empty() && decrypt_secret(v).is_none() {
+                *v = encrypt_secret(v);
+            }
+        }
+    }
+}
+
+// Plain text values are still taken, where options are stored without `encrypt_options`.
+fn get_secret_option(key: &str) -> String {
+    let v = Config::get_option(key);
+    decrypt_secret(&v).unwrap_or(v)
+}
+
+/// The notifiers configured in the options.
+pub fn notifiers() -> Vec<Box<dyn SecondFactorNotifier>> {
+    let mut v: Vec<Box<dyn SecondFactorNotifier>> = Vec::new();
+    match TelegramBot::get() {
+        Ok(Some(bot)) => v.push(Box::new(bot)),
+        Ok(None) => {}
+        Err(err) => log::error!("Failed to get telegram bot: {}", err),
+    }
+    let url = Config::get_option(OPTION_WEBHOOK_URL);
+    if !url.is_empty() {
+        v.push(Box::new(Webhook {
+            url,
+            header: get_secret_option(OPTION_WEBHOOK_HEADER),
+        }));
+    }
+    if let Some(smtp) = Smtp::from_options() {
+        v.push(Box::new(smtp));
+    }
+    let url = Config::get_option(OPTION_PUSH_URL);
+    if !url.is_empty() {
+        let typ = match Config::get_option(OPTION_PUSH_TYPE).to_lowercase().as_str() {
+            "gotify" => PushType::Gotify,
+            _ => PushType::Ntfy,
+        };
+        v.push(Box::new(Push {
+            typ,
+            url,
+            token: get_secret_option(OPTION_PUSH_TOKEN),
+        }));
+    }
+    v
+}
+
+/// Send `notification` with all `notifiers` at once, returning the error of each failed one.
+pub async fn notify(
+    notifiers: &[Box<dyn SecondFactorNotifier>],
+    notification: &Notification,
+) -> Vec<(&'static str, String)> {
+    let results = join_all(notifiers.iter().map(|n| n.notify(notification))).await;
+    notifiers
+        .iter()
+        .zip(results)
+        .filter_map(|(n, res)| {
+            let err = res.err()?;
+            log::error!("Failed to send 2fa code with {}: {}", n.name(), err);
+            Some((n.name(), err.to_string()))
+        })
+        .collect()
+}
+
+/// Send a sample code with the configured notifiers, for checking the settings.
+#[tokio::main(flavor = "current_thread")]
+pub async fn test() -> ResultType<()> {
+    let notifiers = notifiers();
+    if notifiers.is_empty() {
+        bail!("No 2FA notifier configured");
+    }
+    let notification = Notification {
+        code: "123456".to_owned(),
+        id: Config::get_id(),
+        ip: "127.0.0.1".to_owned(),
+    };
+    let errors = notify(&notifiers, &notification).await;
+    if !errors.is_empty() {
+        let errors: Vec<String> = errors
+            .iter()
+            .map(|(name, err)| format!("{}: {}", name, err))
+            .collect();
+        bail!("{}", errors.join("\n"));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hbb_common::tokio::{
+        self,
+        io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+        net::TcpListener,
+    };
+
+    fn notification() -> Notification {
+        Notification {
+            code: "654321".to_owned(),
+            id: "123456789".to_owned(),
+            ip: "10.0.0.1".to_owned(),
+        }
+    }
+
+    // Accepts one HTTP request and returns its head and body.
+    async fn http_stand_in() -> (String, tokio::task::JoinHandle<(String, String)>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let handle = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut stream = BufReader::new(stream);
+            let mut head = String::new();
+            loop {
+                let mut line = String::new();
+                stream.read_line(&mut line).await.unwrap();
+                if line.trim().is_empty() {
+                    break;
+                }
+                head += &line.to_lowercase();
+            }
+            let len = head
+                .lines()
+                .find_map(|l| l.strip_prefix("content-length:"))
+                .and_then(|x| x.trim().parse().ok())
+                .unwrap_or(0);
+            let mut body = vec![0; len];
+            stream.read_exact(&mut body).await.unwrap();
+            stream
+                .write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\n\r\n")
+                .await
+                .unwrap();
+            (head, String::from_utf8(body).unwrap())
+        });
+        (url, handle)
+    }
+
+    #[test]
+    fn test_split_host_port() {
+        let split = |s: &str| split_host_port(s, 587);
+        assert_eq!(
+            split("smtp.example.com"),
+            ("smtp.example.com".to_owned(), 587)
+        );
+        assert_eq!(
+            split("smtp.example.com:25"),
+            ("smtp.example.com".to_owned(), 25)
+        );
+        assert_eq!(split("10.0.0.1:465"), ("10.0.0.1".to_owned(), 465));
+        assert_eq!(split("2001:db8::1"), ("2001:db8::1".to_owned(), 587));
+        assert_eq!(split("[2001:db8::1]"), ("2001:db8::1".to_owned(), 587));
+        assert_eq!(split("[2001:db8::1]:25"), ("2001:db8::1".to_owned(), 25));
+    }
+
+    #[test]
+    fn test_encrypt_options() {
+        let mut options: HashMap<String, String> = [
+            (OPTION_SMTP_PASSWORD, "secret"),
+            (OPTION_PUSH_TOKEN, ""),
+            (OPTION_SMTP_USER, "user"),
+        ]
+        .iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect();
+        encrypt_options(&mut options);
+        let encrypted = options[OPTION_SMTP_PASSWORD].clone();
+        assert!(!encrypted.contains("secret"));
+        assert_eq!(decrypt_secret(&encrypted).unwrap(), "secret");
+        assert_eq!(options[OPTION_PUSH_TOKEN], "");
+        assert_eq!(options[OPTION_SMTP_USER], "user");
+        // Already encrypted values are kept.
+        encrypt_options(&mut options);
+        assert_eq!(options[OPTION_SMTP_PASSWORD], encrypted);
+    }
+
+    struct Slow(Result<(), &'static str>);
+
+    #[async_trait]
+    impl SecondFactorNotifier for Slow {
+        fn name(&self) -> &'static str {
+            self.0.err().unwrap_or("slow")
+        }
+
+        async fn notify(&self, _: &Notification) -> ResultType<()> {
+            tokio::time::sleep(Duration::from_millis(300)).await;
+            if let Err(err) = self.0 {
+                bail!("{} failed", err);
+            }
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_notify() {
+        let notifiers: Vec<Box<dyn SecondFactorNotifier>> = vec![
+            Box::new(Slow(Err("a"))),
+            Box::new(Slow(Ok(()))),
+            Box::new(Slow(Err("b"))),
+        ];
+        let start = std::time::Instant::now();
+        let errors = notify(&notifiers, &notification()).await;
+        assert!(start.elapsed() < Duration::from_millis(800));
+        assert_eq!(
+            errors,
+            [("a", "a failed".to_owned()), ("b", "b failed".to_owned())]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_webhook() {
+        let (url, handle) = http_stand_in().await;
+        let webhook = Webhook {
+            url,
+            header: "Authorization: Bearer abc".to_owned(),
+        };
+        webhook.notify(&notification()).await.unwrap();
+        let (head, body) = handle.await.unwrap();
+        assert!(head.contains("authorization: bearer abc"));
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(body["code"], "654321");
+        assert_eq!(body["ip"], "10.0.0.1");
+    }
+
+    #[tokio::test]
+    async fn test_push() {
+        let (url, handle) = http_stand_in().await;
+        let push = Push {
+            typ: PushType::Ntfy,
+            url: format!("{}/topic", url),
+            token: "tk".to_owned(),
+        };
+        push.notify(&notification()).await.unwrap();
+        let (head, body) = handle.await.unwrap();
+        assert!(head.starts_with("post /topic "));
+        assert!(head.contains("authorization: bearer tk"));
+        assert!(body.contains("2FA code: 654321"));
+
+        let (url, handle) = http_stand_in().await;
+        let push = Push {
+            typ: PushType::Gotify,
+            url: format!("{}/message", url),
+            token: "tk".to_owned(),
+        };
+        push.notify(&notification()).await.unwrap();
+        let (head, body) = handle.await.unwrap();
+        assert!(head.contains("x-gotify-key: tk"));
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(body["priority"], 8);
+    }
+
+    #[tokio::test]
+    async fn test_smtp() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let handle = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut stream = BufReader::new(stream);
+            let mut commands = Vec::new();
+            let mut data = String::new();
+            let mut in_data = false;
+            stream.write_all(b"220 stand-in\r\n").await.unwrap();
+            loop {
+                let mut line = String::new();
+                if stream.read_line(&mut line).await.unwrap() == 0 {
+                    break;
+                }
+                if in_data {
+                    if line == ".\r\n" {
+                        in_data = false;
+                        stream.write_all(b"250 queued\r\n").await.unwrap();
+                    } else {
+                        data += &line;
+                    }
+                    continue;
+                }
+                let command = line.trim().to_owned();
+                let reply: &[u8] = if command.starts_with("EHLO") {
+                    b"250 stand-in\r\n"
+                } else if command == "DATA" {
+                    in_data = true;
+                    b"354 go ahead\r\n"
+                } else if command == "QUIT" {
+                    b"221 bye\r\n"
+                } else {
+                    b"250 ok\r\n"
+                };
+                stream.write_all(reply).await.unwrap();
+                commands.push(command);
+                if commands.last().map(|x| x.as_str()) == Some("QUIT") {
+                    break;
+                }
+            }
+            (commands, data)
+        });
+        let smtp = Smtp {
+            host: "127.0.0.1".to_owned(),
+            port,
+            tls: SmtpTls::None,
+            user: "".to_owned(),
+            password: "".to_owned(),
+            from: "rustdesk@example.com".to_owned(),
+            to: vec!["admin@example.com".to_owned()],
+        };
+        smtp.notify(&notification()).await.unwrap();
+        let (commands, data) = handle.await.unwrap();
+        assert!(commands
+            .iter()
+            .any(|c| c.starts_with("MAIL FROM:<rustdesk@example.com>")));
+        assert!(commands
+            .iter()
+            .any(|c| c.starts_with("RCPT TO:<admin@example.com>")));
+        assert!(data.contains("2FA code: 654321"));
+    }
+}
@@ -1,17 +1,37 @@
 use crate::client::*;
 use async_trait::async_trait;
 use hbb_common::{
+    allow_err, bail,
     config::PeerConfig,
     config::READ_TIMEOUT,
+    fs,
     futures::{SinkExt, StreamExt},
     log,
     message_proto::*,
     protobuf::Message as _,
     rendezvous_proto::ConnType,
-    tokio::{self, sync::mpsc},
-    Stream,
+    timeout,
+    tokio::{
+        self,
+        sync::mpsc,
+        time::{self, Duration, Instant},
+    },
+    ResultType, Stream,
 };
-use std::sync::{Arc, RwLock};
+use serde_json::{json, Value};
+use std::{
+    path::PathBuf,
+    sync::{Arc, RwLock},
+};
+
+/// Password of the peer, used instead of prompting.
+pub const ENV_PASSWORD: &str = "RUSTDESK_PASSWORD";
+/// File whose first line is the password of the peer.
+pub const ENV_PASSWORD_FILE: &str = "RUSTDESK_PASSWORD_FILE";
+/// 2FA code sent if the peer requires one.
+pub const ENV_2FA_CODE: &str = "RUSTDESK_2FA_CODE";
+
+const JOB_ID: i32 = 1;
 
 #[derive(Clone)]
 pub struct Session {
@@ -19,27 +39,45 @@ pub struct Session {
     lc: Arc<RwLock<LoginConfigHandler>>,
     sender: mpsc::UnboundedSender<Data>,
     password: String,
+    // Prompt on the terminal for what is missing, off if the password is given.
+    interactive: bool,
 }
 
 impl Session {
     pub fn new(id: &str, sender: mpsc::UnboundedSender<Data>) -> Self {
-        let mut password = "".to_owned();
-        if PeerConfig::load(id).password.is_empty() {
-            password = rpassword::prompt_password("Enter password: ").unwrap();
-        }
+        Self::with_password(id, ConnType::PORT_FORWARD, None, sender)
+    }
+
+    /// With `password` given, login errors fail instead of prompting.
+    pub fn with_password(
+        id: &str,
+        conn_type: ConnType,
+        password: Option<String>,
+        sender: mpsc::UnboundedSender<Data>,
+    ) -> Self {
+        let interactive = password.is_none();
+        let password = match password {
+            Some(password) => password,
+            None if PeerConfig::load(id).password.is_empty() => {
+                rpassword::prompt_password("Enter password: ").unwrap_or_default()
+            }
+            None => "".to_owned(),
+        };
         let session = Self {
             id: id.to_owned(),
             sender,
             password,
+            interactive,
             lc: Default::default(),
         };
         session.lc.write().unwrap().initialize(
             id.to_owned(),
-            ConnType::PORT_FORWARD,
+            conn_type,
             None,
             false,
             None,
             None,
+            None,
         );
         session
     }
@@ -47,7 +85,7 @@ impl Session {
 
 #[async_trait]
 impl Interface for Session {
-    fn get_login_config_handler(&self) -> Arc<RwLock<LoginConfigHandler>> {
+    fn get_lch(&self) -> Arc<RwLock<LoginConfigHandler>> {
         return self.lc.clone();
     }
 
@@ -55,14 +93,20 @@ impl Interface for Session {
         match msgtype {
             "input-password" => {
                 self.sender
-                    .send(Data::Login((self.password.clone(), true)))
+                    .send(Data::Login((
+                        "".to_owned(),
+                        "".to_owned(),
+                        self.password.clone(),
+                        self.interactive,
+                    )))
                     .ok();
             }
             "re-input-password" => {
                 log::error!("{}: {}", title, text);
                 match rpassword::prompt_password("Enter password: ") {
                     Ok(password) => {
-                        let login_data = Data::Login((password, true));
+                        let login_data =
+                            Data::Login(("".to_owned(), "".to_owned(), password, true));
                         self.sender.send(login_data).ok();
                     }
                     Err(e) => {
@@ -70,6 +114,24 @@ impl Interface for Session {
                     }
                 }
             }
+            "input-2fa" => {
+                let code = match std::env::var(ENV_2FA_CODE) {
+                    Ok(code) => code,
+                    Err(_) => match rpassword::prompt_password("Enter 2FA code: ") {
+                        Ok(code) => code,
+                        Err(e) => {
+                            log::error!("input 2fa code failed, {:?}", e);
+                            return;
+                        }
+                    },
+                };
+                let mut msg_out = Message::new();
+                msg_out.set_auth_2fa(Auth2FA {
+                    code: code.trim().to_owned(),
+                    ..Default::default()
+                });
+                self.sender.send(Data::Message(msg_out)).ok();
+            }
             msg if msg.contains("error") => {
                 log::error!("{}: {}: {}", msgtype, title, text);
             }
@@ -80,6 +142,14 @@ impl Interface for Session {
     }
 
     fn handle_login_error(&self, err: &str) -> bool {
+        // Retrying with the same password or code is pointless without a terminal.
+        if !self.interactive
+            && (err == LOGIN_MSG_PASSWORD_WRONG
+                || err == LOGIN_MSG_2FA_WRONG
+                || (err == REQUIRE_2FA && std::env::var(ENV_2FA_CODE).is_err()))
+        {
+            return false;
+        }
         handle_login_error(self.lc.clone(), err, self)
     }
 
@@ -87,6 +157,22 @@ impl Interface for Session {
         self.lc.write().unwrap().handle_peer_info(&pi);
     }
 
+    fn set_multiple_windows_session(&self, _sessions: Vec<WindowsSession>) {}
+
+    fn update_port_forward_stats(&self, stats: &[PortForwardStats]) {
+        for s in stats {
+            log::info!(
+                "{}: {} connections, {} active, {} bytes sent, {} bytes received, {}s",
+                s.rule,
+                s.connections,
+                s.active,
+                s.bytes_sent,
+                s.bytes_received,
+                s.duration
+            );
+        }
+    }
+
     async fn handle_hash(&self, pass: &str, hash: Hash, peer: &mut Stream) {
         log::info!(
             "password={}",
@@ -131,7 +217,7 @@ pub async fn connect_test(id: &str, key: String, token: String) {
         Err(err) => {
             log::error!("Failed to connect {}: {}", &id, err);
         }
-        Ok((mut stream, direct)) => {
+        Ok(((mut stream, direct, _pk), _)) => {
             log::info!("direct: {}", direct);
             // rpassword::prompt_password("Input anything to exit").ok();
             loop {
@@ -160,34 +246,670 @@ pub async fn connect_test(id: &str, key: String, token: String) {
     }
 }
 
+/// `rate_limit` in KB/s and `idle_timeout` in seconds override the local options.
 #[tokio::main(flavor = "current_thread")]
-pub async fn start_one_port_forward(
+pub async fn start_port_forward(
     id: String,
-    port: i32,
-    remote_host: String,
-    remote_port: i32,
+    rules: Vec<String>,
+    rate_limit: Option<u64>,
+    idle_timeout: Option<u64>,
+    password: Option<String>,
     key: String,
     token: String,
 ) {
+    let rules = match rules
+        .iter()
+        .map(|rule| rule.parse())
+        .collect::<Result<Vec<_>, _>>()
+    {
+        Ok(rules) => rules,
+        Err(err) => {
+            log::error!("{}", err);
+            return;
+        }
+    };
     crate::common::test_rendezvous_server();
     crate::common::test_nat_type();
-    let (sender, mut receiver) = mpsc::unbounded_channel::<Data>();
-    let handler = Session::new(&id, sender);
-    if let Err(err) = crate::port_forward::listen(
+    let mut limits = crate::port_forward::TunnelLimits::from_options();
+    if let Some(rate_limit) = rate_limit {
+        limits.rate_limit = rate_limit;
+    }
+    if let Some(idle_timeout) = idle_timeout {
+        limits.idle_timeout =
+            (idle_timeout > 0).then(|| std::time::Duration::from_secs(idle_timeout));
+    }
+    let (sender, receiver) = mpsc::unbounded_channel::<Data>();
+    let handler = Session::with_password(&id, ConnType::PORT_FORWARD, password, sender);
+    if let Err(err) = crate::port_forward::run_tunnel(
         handler.id.clone(),
         handler.password.clone(),
-        port,
+        rules,
+        limits,
         handler.clone(),
         receiver,
         &key,
         &token,
         handler.lc.clone(),
-        remote_host,
-        remote_port,
     )
     .await
     {
-        log::error!("Failed to listen on {}: {}", port, err);
+        log::error!("Port forwarding to {} failed: {}", id, err);
+    }
+    log::info!("port forward to {} exit", id);
+}
+
+/// A subcommand of the headless client, run by [`run`].
+pub enum Command {
+    Info,
+    Screenshot {
+        display: i32,
+        output: String,
+    },
+    /// Wait up to `wait` seconds for the peer to send its clipboard, which it
+    /// does on connecting and on every change.
+    ClipboardGet {
+        wait: u64,
+    },
+    ClipboardSet {
+        text: String,
+    },
+    FilePush {
+        local: String,
+        remote: String,
+        include_hidden: bool,
+        overwrite: bool,
+    },
+    FilePull {
+        remote: String,
+        local: String,
+        include_hidden: bool,
+        overwrite: bool,
+    },
+}
+
+impl Command {
+    fn conn_type(&self) -> ConnType {
+        match self {
+            Command::FilePush { .. } | Command::FilePull { .. } => ConnType::FILE_TRANSFER,
+            _ => ConnType::DEFAULT_CONN,
+        }
+    }
+}
+
+/// Value of the numeric option `--name`, an error for anything but a number
+/// so a typo does not silently drop a limit.
+pub fn parse_number_arg(value: Option<&str>, name: &str) -> ResultType<Option<u64>> {
+    match value {
+        Some(value) => match value.trim().parse() {
+            Ok(n) => Ok(Some(n)),
+            Err(_) => bail!("Invalid --{} {:?}, expected a number", name, value),
+        },
+        None => Ok(None),
+    }
+}
+
+/// Password for non-interactive use: `password_file`, [`ENV_PASSWORD`] or
+/// [`ENV_PASSWORD_FILE`], `None` to use the saved one or prompt.
+pub fn read_password(password_file: Option<&str>) -> ResultType<Option<String>> {
+    let path = match password_file {
+        Some(path) => path.to_owned(),
+        None => {
+            if let Ok(password) = std::env::var(ENV_PASSWORD) {
+                return Ok(Some(password));
+            }
+            match std::env::var(ENV_PASSWORD_FILE) {
+                Ok(path) => path,
+                Err(_) => return Ok(None),
+            }
+        }
+    };
+    let content = std::fs::read_to_string(&path)
+        .map_err(|e| hbb_common::anyhow::anyhow!("Failed to read {}: {}", path, e))?;
+    Ok(Some(content.lines().next().unwrap_or_default().to_owned()))
+}
+
+/// Run `command` against `id` and print the result as one line of JSON,
+/// `{"ok": true, ...}` or `{"ok": false, "error": "..."}`. Returns `false` on failure.
+#[tokio::main(flavor = "current_thread")]
+pub async fn run(
+    id: String,
+    command: Command,
+    password: Option<String>,
+    key: String,
+    token: String,
+) -> bool {
+    let res = async {
+        let mut conn = Connection::new(&id, command.conn_type(), password, &key, &token).await?;
+        conn.run(command).await
+    }
+    .await;
+    let ok = res.is_ok();
+    println!("{}", output(res));
+    ok
+}
+
+fn output(res: ResultType<Value>) -> Value {
+    let (ok, mut output) = match res {
+        Ok(output) => (true, output),
+        Err(err) => (false, json!({ "error": err.to_string() })),
+    };
+    output["ok"] = json!(ok);
+    output
+}
+
+struct Connection {
+    session: Session,
+    stream: Stream,
+    receiver: mpsc::UnboundedReceiver<Data>,
+    peer_info: PeerInfo,
+    direct: bool,
+    _keep_it: Option<mpsc::UnboundedSender<()>>,
+}
+
+impl Connection {
+    async fn new(
+        id: &str,
+        conn_type: ConnType,
+        password: Option<String>,
+        key: &str,
+        token: &str,
+    ) -> ResultType<Self> {
+        let (sender, mut receiver) = mpsc::unbounded_channel::<Data>();
+        let session = Session::with_password(id, conn_type, password, sender);
+        let ((mut stream, direct, _pk), (feedback, rendezvous_server)) =
+            Client::start(id, key, token, conn_type, session.clone()).await?;
+        let _keep_it = hc_connection(feedback, rendezvous_server, token).await;
+        loop {
+            tokio::select! {
+                res = timeout(READ_TIMEOUT, stream.next()) => match res {
+                    Err(_) => {
+                        bail!("Timeout");
+                    }
+                    Ok(Some(Ok(bytes))) => {
+                        let msg_in = Message::parse_from_bytes(&bytes)?;
+                        match msg_in.union {
+                            Some(message::Union::Hash(hash)) => {
+                                session.handle_hash(&session.password, hash, &mut stream).await;
+                            }
+                            Some(message::Union::LoginResponse(lr)) => match lr.union {
+                                Some(login_response::Union::Error(err)) => {
+                                    if !session.handle_login_error(&err) {
+                                        bail!("{}", err);
+                                    }
+                                }
+                                Some(login_response::Union::PeerInfo(pi)) => {
+                                    session.handle_peer_info(pi.clone());
+                                    return Ok(Self {
+                                        session,
+                                        stream,
+                                        receiver,
+                                        peer_info: pi,
+                                        direct,
+                                        _keep_it,
+                                    });
+                                }
+                                _ => {}
+                            },
+                            Some(message::Union::TestDelay(t)) => {
+                                session.handle_test_delay(t, &mut stream).await;
+                            }
+                            _ => {}
+                        }
+                    }
+                    Ok(Some(Err(err))) => {
+                        bail!("Connection closed: {}", err);
+                    }
+                    _ => {
+                        bail!("Reset by the peer");
+                    }
+                },
+                d = receiver.recv() => match d {
+                    Some(Data::Login((os_username, os_password, password, remember))) => {
+                        session.handle_login_from_ui(os_username, os_password, password, remember, &mut stream).await;
+                    }
+                    Some(Data::Message(msg)) => {
+                        allow_err!(stream.send(&msg).await);
+                    }
+                    _ => {}
+                },
+            }
+        }
+    }
+
+    async fn run(&mut self, command: Command) -> ResultType<Value> {
+        match command {
+            Command::Info => Ok(self.info()),
+            Command::Screenshot { display, output } => self.screenshot(display, output).await,
+            Command::ClipboardGet { wait } => self.clipboard_get(wait).await,
+            Command::ClipboardSet { text } => self.clipboard_set(text).await,
+            Command::FilePush {
+                local,
+                remote,
+                include_hidden,
+                overwrite,
+            } => self.push(local, remote, include_hidden, overwrite).await,
+            Command::FilePull {
+                remote,
+                local,
+                include_hidden,
+                overwrite,
+            } => self.pull(remote, local, include_hidden, overwrite).await,
+        }
+    }
+
+    /// Next message from the peer, answering the delay tests on the way.
+    async fn next(&mut self) -> ResultType<Message> {
+        loop {
+            match timeout(READ_TIMEOUT, self.stream.next()).await {
+                Err(_) => bail!("Timeout"),
+                Ok(Some(Ok(bytes))) => {
+                    let msg_in = Message::parse_from_bytes(&bytes)?;
+                    if let Some(message::Union::TestDelay(t)) = msg_in.union {
+                        handle_test_delay(t, &mut self.stream).await;
+                        continue;
+                    }
+                    return Ok(msg_in);
+                }
+                Ok(Some(Err(err))) => bail!("Connection closed: {}", err),
+                _ => bail!("Reset by the peer"),
+            }
+        }
+    }
+
+    fn info(&self) -> Value {
+        peer_info_json(&self.session.id, self.direct, &self.peer_info)
+    }
+
+    async fn screenshot(&mut self, display: i32, output: String) -> ResultType<Value> {
+        let sid = hbb_common::get_time().to_string();
+        let mut msg_out = Message::new();
+        msg_out.set_screenshot_request(ScreenshotRequest {
+            display,
+            sid: sid.clone(),
+            ..Default::default()
+        });
+        self.stream.send(&msg_out).await?;
+        loop {
+            if let Some(message::Union::ScreenshotResponse(res)) = self.next().await?.union {
+                if res.sid != sid {
+                    continue;
+                }
+                if !res.msg.is_empty() {
+                    bail!("{}", res.msg);
+                }
+                std::fs::write(&output, &res.data)?;
+                return Ok(json!({
+                    "display": display,
+                    "path": output,
+                    "size": res.data.len(),
+                }));
+            }
+        }
+    }
+
+    async fn clipboard_get(&mut self, wait: u64) -> ResultType<Value> {
+        let deadline = Instant::now() + Duration::from_secs(wait);
+        loop {
+            let msg_in = match time::timeout_at(deadline, self.next()).await {
+                Ok(msg_in) => msg_in?,
+                Err(_) => bail!("No clipboard received in {}s", wait),
+            };
+            let clipboards = match msg_in.union {
+                Some(message::Union::Clipboard(cb)) => vec![cb],
+                Some(message::Union::MultiClipboards(mcb)) => mcb.clipboards,
+                _ => continue,
+            };
+            let text = clipboards
+                .into_iter()
+                .find(|cb| cb.format.enum_value() == Ok(ClipboardFormat::Text));
+            if let Some(cb) = text {
+                let content = if cb.compress {
+                    hbb_common::compress::decompress(&cb.content)
+                } else {
+                    cb.content.into()
+                };
+                return Ok(json!({ "text": String::from_utf8_lossy(&content) }));
+            }
+        }
+    }
+
+    async fn clipboard_set(&mut self, text: String) -> ResultType<Value> {
+        let size = text.len();
+        let mut msg_out = Message::new();
+        msg_out.set_clipboard(Clipboard {
+            content: text.into_bytes().into(),
+            format: ClipboardFormat::Text.into(),
+            ..Default::default()
+        });
+        self.stream.send(&msg_out).await?;
+        Ok(json!({ "size": size }))
+    }
+
+    async fn push(
+        &mut self,
+        local: String,
+        remote: String,
+        include_hidden: bool,
+        overwrite: bool,
+    ) -> ResultType<Value> {
+        let od = fs::can_enable_overwrite_detection(self.session.lc.read().unwrap().version);
+        let job = fs::TransferJob::new_read(
+            JOB_ID,
+            fs::JobType::Generic,
+            remote.clone(),
+            fs::DataSource::FilePath(PathBuf::from(&local)),
+            0,
+            include_hidden,
+            false,
+            od,
+        )?;
+        #[cfg(not(windows))]
+        let files = job.files().clone();
+        #[cfg(windows)]
+        let mut files = job.files().clone();
+        #[cfg(windows)]
+        if self.peer_info.platform != "Windows" {
+            // peer is not windows, need transform \ to /
+            fs::transform_windows_path(&mut files);
+        }
+        let (num, total_size) = (files.len(), job.total_size());
+        self.stream
+            .send(&fs::new_receive(
+                JOB_ID,
+                remote.clone(),
+                0,
+                files,
+                total_size,
+            ))
+            .await?;
+        let mut read_jobs = vec![job];
+        let mut timer = time::interval(MILLI1);
+        let mut last_recv = Instant::now();
+        loop {
+            tokio::select! {
+                res = self.next() => {
+                    last_recv = Instant::now();
+                    let Some(message::Union::FileResponse(fr)) = res?.union else {
+                        continue;
+                    };
+                    match fr.union {
+                        Some(file_response::Union::Digest(digest)) if digest.is_upload => {
+                            // The peer only asks for the files it already has.
+                            if let Some(job) = fs::get_job(digest.id, &mut read_jobs) {
+                                let req = confirm_request(&digest, overwrite);
+                                job.confirm(&req);
+                                self.stream.send(&fs::new_send_confirm(req)).await?;
+                            }
+                        }
+                        Some(file_response::Union::Done(d)) if d.id == JOB_ID => break,
+                        Some(file_response::Union::Error(e)) if e.id == JOB_ID => {
+                            bail!("{}", e.error);
+                        }
+                        _ => {}
+                    }
+                }
+                _ = timer.tick() => {
+                    if last_recv.elapsed() >= SEC30 {
+                        bail!("Timeout");
+                    }
+                    if !read_jobs.is_empty() {
+                        fs::handle_read_jobs(&mut read_jobs, &mut self.stream).await?;
+                    }
+                }
+            }
+        }
+        Ok(json!({
+            "local": local,
+            "remote": remote,
+            "files": num,
+            "size": total_size,
+        }))
+    }
+
+    async fn pull(
+        &mut self,
+        remote: String,
+        local: String,
+        include_hidden: bool,
+        overwrite: bool,
+    ) -> ResultType<Value> {
+        let od = fs::can_enable_overwrite_detection(self.session.lc.read().unwrap().version);
+        let mut write_jobs = vec![fs::TransferJob::new_write(
+            JOB_ID,
+            fs::JobType::Generic,
+            remote.clone(),
+            fs::DataSource::FilePath(PathBuf::from(&local)),
+            0,
+            include_hidden,
+            true,
+            Vec::new(),
+            od,
+        )];
+        self.stream
+            .send(&fs::new_send(
+                JOB_ID,
+                fs::JobType::Generic,
+                remote.clone(),
+                0,
+                include_hidden,
+            ))
+            .await?;
+        loop {
+            let Some(message::Union::FileResponse(fr)) = self.next().await?.union else {
+                continue;
+            };
+            match fr.union {
+                Some(file_response::Union::Dir(fd)) => {
+                    #[cfg(windows)]
+                    let entries = fd.entries.to_vec();
+                    #[cfg(not(windows))]
+                    let mut entries = fd.entries.to_vec();
+                    #[cfg(not(windows))]
+                    if self.peer_info.platform == "Windows" {
+                        fs::transform_windows_path(&mut entries);
+                    }
+                    if let Some(job) = fs::get_job(fd.id, &mut write_jobs) {
+                        job.set_files(entries);
+                    }
+                }
+                Some(file_response::Union::Digest(digest)) if !digest.is_upload => {
+                    if let Some(job) = fs::get_job(digest.id, &mut write_jobs) {
+                        let Some(file) = job.files().get(digest.file_num as usize) else {
+                            continue;
+                        };
+                        let fs::DataSource::FilePath(p) = &job.data_source else {
+                            continue;
+                        };
+                        let write_path = fs::get_string(&fs::TransferJob::join(p, &file.name));
+                        let write = match fs::is_write_need_confirmation(&write_path, &digest)? {
+                            fs::DigestCheckResult::IsSame => false,
+                            fs::DigestCheckResult::NeedConfirm(_) => overwrite,
+                            fs::DigestCheckResult::NoSuchFile => true,
+                        };
+                        let req = confirm_request(&digest, write);
+                        job.confirm(&req);
+                        self.stream.send(&fs::new_send_confirm(req)).await?;
+                    }
+                }
+                Some(file_response::Union::Block(block)) => {
+                    if let Some(job) = fs::get_job(block.id, &mut write_jobs) {
+                        job.write(block).await?;
+                    }
+                }
+                Some(file_response::Union::Done(d)) => {
+                    if let Some(job) = fs::remove_job(d.id, &mut write_jobs) {
+                        job.modify_time();
+                        if let Some(err) = job.job_error() {
+                            bail!("{}", err);
+                        }
+                        return Ok(json!({
+                            "remote": remote,
+                            "local": local,
+                            "files": job.files().len(),
+                            "size": job.files().iter().map(|f| f.size).sum::<u64>(),
+                        }));
+                    }
+                }
+                Some(file_response::Union::Error(e)) if e.id == JOB_ID => {
+                    bail!("{}", e.error);
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+fn peer_info_json(id: &str, direct: bool, pi: &PeerInfo) -> Value {
+    json!({
+        "id": id,
+        "direct": direct,
+        "username": pi.username,
+        "hostname": pi.hostname,
+        "platform": pi.platform,
+        "version": pi.version,
+        "current_display": pi.current_display,
+        "displays": pi.displays.iter().map(|d| json!({
+            "x": d.x,
+            "y": d.y,
+            "width": d.width,
+            "height": d.height,
+            "name": d.name,
+            "online": d.online,
+        })).collect::<Vec<_>>(),
+    })
+}
+
+fn confirm_request(digest: &FileTransferDigest, write: bool) -> FileTransferSendConfirmRequest {
+    FileTransferSendConfirmRequest {
+        id: digest.id,
+        file_num: digest.file_num,
+        union: Some(if write {
+            file_transfer_send_confirm_request::Union::OffsetBlk(0)
+        } else {
+            file_transfer_send_confirm_request::Union::Skip(true)
+        }),
+        ..Default::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_number_arg() {
+        assert_eq!(parse_number_arg(None, "rate-limit").unwrap(), None);
+        assert_eq!(
+            parse_number_arg(Some("512"), "rate-limit").unwrap(),
+            Some(512)
+        );
+        assert_eq!(
+            parse_number_arg(Some(" 0 "), "idle-timeout").unwrap(),
+            Some(0)
+        );
+        let err = parse_number_arg(Some("10s"), "idle-timeout").unwrap_err();
+        assert!(err.to_string().contains("--idle-timeout"));
+        assert!(parse_number_arg(Some("-1"), "rate-limit").is_err());
+    }
+
+    #[test]
+    fn test_read_password() {
+        // The variables are global to the process, every case is in this test.
+        let dir = std::env::temp_dir().join(format!("rustdesk_cli_test_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let arg_file = dir.join("arg");
+        let env_file = dir.join("env");
+        std::fs::write(&arg_file, "from-arg\nsecond line\n").unwrap();
+        std::fs::write(&env_file, "from-env-file").unwrap();
+        let arg = arg_file.to_str();
+        std::env::remove_var(ENV_PASSWORD);
+        std::env::remove_var(ENV_PASSWORD_FILE);
+        assert_eq!(read_password(None).unwrap(), None);
+        std::env::set_var(ENV_PASSWORD_FILE, &env_file);
+        assert_eq!(read_password(None).unwrap().unwrap(), "from-env-file");
+        std::env::set_var(ENV_PASSWORD, "from-env");
+        assert_eq!(read_password(None).unwrap().unwrap(), "from-env");
+        assert_eq!(read_password(arg).unwrap().unwrap(), "from-arg");
+        std::env::remove_var(ENV_PASSWORD);
+        assert_eq!(read_password(arg).unwrap().unwrap(), "from-arg");
+        std::env::set_var(ENV_PASSWORD_FILE, dir.join("missing"));
+        assert!(read_password(None).is_err());
+        assert!(read_password(dir.join("missing").to_str()).is_err());
+        std::env::remove_var(ENV_PASSWORD_FILE);
+        std::fs::write(&arg_file, "").unwrap();
+        assert_eq!(read_password(arg).unwrap().unwrap(), "");
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_command() {
+        let path = || "a".to_owned();
+        let file_push = Command::FilePush {
+            local: path(),
+            remote: path(),
+            include_hidden: false,
+            overwrite: false,
+        };
+        let file_pull = Command::FilePull {
+            remote: path(),
+            local: path(),
+            include_hidden: true,
+            overwrite: true,
+        };
+        assert_eq!(file_push.conn_type(), ConnType::FILE_TRANSFER);
+        assert_eq!(file_pull.conn_type(), ConnType::FILE_TRANSFER);
+        for command in [
+            Command::Info,
+            Command::Screenshot {
+                display: 0,
+                output: path(),
+            },
+            Command::ClipboardGet { wait: 5 },
+            Command::ClipboardSet { text: path() },
+        ] {
+            assert_eq!(command.conn_type(), ConnType::DEFAULT_CONN);
+        }
+    }
+
+    #[test]
+    fn test_output() {
+        let ok = output(Ok(json!({ "size": 3 })));
+        assert_eq!(ok, json!({ "size": 3, "ok": true }));
+        let err = output(Err(hbb_common::anyhow::anyhow!("Reset by the peer")));
+        assert_eq!(err, json!({ "error": "Reset by the peer", "ok": false }));
+        // One line per command.
+        assert!(!err.to_string().contains('\n'));
+
+        let pi = PeerInfo {
+            username: "user".to_owned(),
+            hostname: "host".to_owned(),
+            platform: "Linux".to_owned(),
+            version: "1.3.0".to_owned(),
+            current_display: 1,
+            displays: vec![DisplayInfo {
+                width: 1920,
+                height: 1080,
+                name: "HDMI-1".to_owned(),
+                online: true,
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let info = peer_info_json("123456789", true, &pi);
+        assert_eq!(info["id"], "123456789");
+        assert_eq!(info["direct"], true);
+        assert_eq!(info["hostname"], "host");
+        assert_eq!(info["current_display"], 1);
+        assert_eq!(
+            info["displays"],
+            json!([{
+                "x": 0,
+                "y": 0,
+                "width": 1920,
+                "height": 1080,
+                "name": "HDMI-1",
+                "online": true,
+            }])
+        );
     }
-    log::info!("port forward (:{}) exit", port);
 }
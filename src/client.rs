@@ -10,7 +10,6 @@ use cpal::{
     Device, Host, StreamConfig,
 };
 use crossbeam_queue::ArrayQueue;
-use magnum_opus::{Channels::*, Decoder as AudioDecoder};
 #[cfg(not(target_os = "linux"))]
 use ringbuf::{ring_buffer::RbBase, Rb};
 use serde::{Deserialize, Serialize};
@@ -28,6 +27,7 @@ use std::{
 use uuid::Uuid;
 
 use crate::{
+    audio_codec::Decoder as AudioDecoder,
     check_port,
     common::input::{MOUSE_BUTTON_LEFT, MOUSE_BUTTON_RIGHT, MOUSE_TYPE_DOWN, MOUSE_TYPE_UP},
     create_symmetric_key_msg, decode_id_pk, get_rs_pk, is_keyboard_mode_supported, secure_tcp,
@@ -1073,6 +1073,12 @@ impl AudioHandler {
         if !spec.is_valid() {
             bail!("Invalid audio format");
         }
+        // The default map of PulseAudio is not the WAVE order of multichannel audio.
+        let map = (spec.channels > 2).then(|| {
+            let mut map = pulse::channelmap::Map::default();
+            map.init_extend(spec.channels, pulse::channelmap::MapDef::WAVEEx);
+            map
+        });
 
         self.simple = Some(Simple::new(
             None,                   // Use the default server
@@ -1081,7 +1087,7 @@ impl AudioHandler {
             None,                   // Use the default device
             "playback",             // Description of our stream
             &spec,                  // Our sample format
-            None,                   // Use default channel map
+            map.as_ref(),           // Channel map
             None,                   // Use default buffering attributes
         )?);
         self.sample_rate = (format0.sample_rate, format0.sample_rate);
@@ -1139,9 +1145,10 @@ impl AudioHandler {
         Ok(())
     }
 
-    /// Handle audio format and create an audio decoder.
+    /// Handle audio format and create an audio decoder, more than two channels
+    /// are multistream.
     pub fn handle_format(&mut self, f: AudioFormat) {
-        match AudioDecoder::new(f.sample_rate, if f.channels > 1 { Stereo } else { Mono }) {
+        match AudioDecoder::new(f.sample_rate, f.channels as _) {
             Ok(d) => {
                 let buffer = vec![0.; f.sample_rate as usize * f.channels as usize];
                 self.audio_decoder = Some((d, buffer));
@@ -1936,6 +1943,8 @@ impl LoginConfigHandler {
                 msg.custom_fps = custom_fps;
                 *self.custom_fps.lock().unwrap() = Some(custom_fps as _);
             }
+        } else if q == "lossless" {
+            msg.custom_image_quality = Self::lossless_image_quality();
         }
         let view_only = self.get_toggle_option("view-only");
         if view_only {
@@ -1967,12 +1976,23 @@ impl LoginConfigHandler {
     }
 
     pub fn get_supported_decoding(&self) -> SupportedDecoding {
-        Decoder::supported_decodings(
+        let mut decoding = Decoder::supported_decodings(
             Some(&self.id),
             use_texture_render(),
             self.adapter_luid,
             &self.mark_unsupported,
-        )
+        );
+        // Lossless is encoded with AV1 only, in 4:4:4 to keep colored text sharp.
+        if self.image_quality == "lossless" && decoding.ability_av1 > 0 {
+            decoding.prefer = supported_decoding::PreferCodec::AV1.into();
+            decoding.prefer_chroma = Chroma::I444.into();
+        }
+        decoding
+    }
+
+    // Peers without lossless support see a custom quality of 100.
+    fn lossless_image_quality() -> i32 {
+        scrap::codec::IMAGE_QUALITY_LOSSLESS | 100 << 8
     }
 
     /// Parse the image quality option.
@@ -2068,17 +2088,22 @@ impl LoginConfigHandler {
     /// * `bitrate` - The given bitrate.
     /// * `quantizer` - The given quantizer.
     pub fn save_custom_image_quality(&mut self, image_quality: i32) -> Message {
-        let mut misc = Misc::new();
-        misc.set_option(OptionMessage {
-            custom_image_quality: image_quality << 8,
-            ..Default::default()
-        });
-        let mut msg_out = Message::new();
-        msg_out.set_misc(misc);
+        let was_lossless = self.image_quality == "lossless";
         let mut config = self.load_config();
         config.image_quality = "custom".to_owned();
         config.custom_image_quality = vec![image_quality as _];
         self.save_config(config);
+        let mut option = OptionMessage {
+            custom_image_quality: image_quality << 8,
+            ..Default::default()
+        };
+        if was_lossless {
+            option.supported_decoding = MessageField::some(self.get_supported_decoding());
+        }
+        let mut misc = Misc::new();
+        misc.set_option(option);
+        let mut msg_out = Message::new();
+        msg_out.set_misc(misc);
         msg_out
     }
 
@@ -2088,21 +2113,35 @@ impl LoginConfigHandler {
     ///
     /// * `value` - The image quality.
     pub fn save_image_quality(&mut self, value: String) -> Option<Message> {
-        let mut res = None;
+        let mut option = None;
         if let Some(q) = self.get_image_quality_enum(&value, false) {
-            let mut misc = Misc::new();
-            misc.set_option(OptionMessage {
+            option = Some(OptionMessage {
                 image_quality: q.into(),
                 ..Default::default()
             });
-            let mut msg_out = Message::new();
-            msg_out.set_misc(misc);
-            res = Some(msg_out);
+        } else if value == "lossless" {
+            option = Some(OptionMessage {
+                custom_image_quality: Self::lossless_image_quality(),
+                ..Default::default()
+            });
         }
+        let lossless_changed = (value == "lossless") != (self.image_quality == "lossless");
         let mut config = self.load_config();
         config.image_quality = value;
         self.save_config(config);
-        res
+        // The codec and chroma preferences follow lossless.
+        if lossless_changed {
+            option
+                .get_or_insert_with(Default::default)
+                .supported_decoding = MessageField::some(self.get_supported_decoding());
+        }
+        option.map(|option| {
+            let mut misc = Misc::new();
+            misc.set_option(option);
+            let mut msg_out = Message::new();
+            msg_out.set_misc(misc);
+            msg_out
+        })
     }
 
     pub fn save_trackpad_speed(&mut self, speed: i32) {
@@ -2393,12 +2432,7 @@ impl LoginConfigHandler {
     }
 
     pub fn update_supported_decodings(&self) -> Message {
-        let decoding = scrap::codec::Decoder::supported_decodings(
-            Some(&self.id),
-            use_texture_render(),
-            self.adapter_luid,
-            &self.mark_unsupported,
-        );
+        let decoding = self.get_supported_decoding();
         let mut misc = Misc::new();
         misc.set_option(OptionMessage {
             supported_decoding: hbb_common::protobuf::MessageField::some(decoding),
@@ -3286,6 +3320,9 @@ pub trait Interface: Send + Clone + 'static + Sized {
         self.get_lch().write().unwrap().received = received;
     }
 
+    /// Traffic of the forwards of a port forward session, updated periodically.
+    fn update_port_forward_stats(&self, _stats: &[PortForwardStats]) {}
+
     fn on_establish_connection_error(&self, err: String) {
         let title = "Connection Error";
         let text = err.to_string();
@@ -3707,3 +3744,16 @@ pub mod peer_online {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lossless_image_quality() {
+        // The lossless flag, over a custom quality of 100 for older hosts.
+        let q = LoginConfigHandler::lossless_image_quality();
+        assert_eq!(q, scrap::codec::IMAGE_QUALITY_LOSSLESS | 100 << 8);
+        assert_eq!(q >> 8 & 0xFFF, 100);
+    }
+}
@@ -3,6 +3,7 @@ use hbb_common::{
     message_proto::{Message, VoiceCallRequest, VoiceCallResponse},
 };
 use scrap::CodecFormat;
+use serde_derive::Serialize;
 use std::collections::HashMap;
 
 #[derive(Debug, Default)]
@@ -15,6 +16,21 @@ pub struct QualityStatus {
     pub chroma: Option<String>,
 }
 
+/// Traffic of one port forward rule since the session started.
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct PortForwardStats {
+    pub rule: String,
+    pub local_port: u16,
+    /// Connections, or UDP flows, so far
+    pub connections: u64,
+    pub active: u64,
+    /// Bytes sent to the peer
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    /// Total seconds of the connections that have ended
+    pub duration: u64,
+}
+
 #[inline]
 pub fn new_voice_call_request(is_connect: bool) -> Message {
     let mut req = VoiceCallRequest::new();
@@ -159,6 +159,12 @@ pub fn is_support_screenshot_num(ver: i64) -> bool {
     ver >= hbb_common::get_version_number("1.4.0")
 }
 
+// Peers before it only decode mono and stereo audio.
+#[inline]
+pub fn is_support_multichannel_audio(ver: &str) -> bool {
+    hbb_common::get_version_number(ver) >= hbb_common::get_version_number("1.4.1")
+}
+
 // is server process, with "--server" args
 #[inline]
 pub fn is_server() -> bool {
@@ -453,6 +453,76 @@ pub fn core_main() -> Option<Vec<String>> {
                 println!("Installation and administrative privileges required!");
             }
             return None;
+        } else if args[0] == "--login-failures" {
+            if crate::platform::is_installed() && is_root() {
+                match crate::ipc::get_login_failures() {
+                    Ok(v) => println!("{}", v),
+                    Err(err) => println!("{}", err),
+                }
+            } else {
+                println!("Installation and administrative privileges required!");
+            }
+            return None;
+        } else if args[0] == "--unban-login" {
+            // `--unban-login ip:1.2.3.4` or `id:123456789`, or all without a key.
+            if crate::platform::is_installed() && is_root() {
+                let key = args.get(1).cloned().unwrap_or_default();
+                match crate::ipc::unban_login(key) {
+                    Ok(n) => println!("{} entries removed", n),
+                    Err(err) => println!("{}", err),
+                }
+            } else {
+                println!("Installation and administrative privileges required!");
+            }
+            return None;
+        } else if args[0] == "--2fa-recovery-codes" {
+            // Replaces the previous codes, each one can be used once instead of a 2FA code.
+            if crate::platform::is_installed() && is_root() {
+                match crate::auth_2fa::generate_recovery_codes(None) {
+                    Ok(codes) => println!("{}", codes.join("\n")),
+                    Err(err) => println!("{}", err),
+                }
+            } else {
+                println!("Installation and administrative privileges required!");
+            }
+            return None;
+        } else if args[0] == "--test-2fa-notifiers" {
+            // Sends a sample code with every configured 2FA notifier.
+            if crate::platform::is_installed() && is_root() {
+                match crate::auth_2fa::notifier::test() {
+                    Ok(()) => println!("Sent"),
+                    Err(err) => println!("{}", err),
+                }
+            } else {
+                println!("Installation and administrative privileges required!");
+            }
+            return None;
+        } else if args[0] == "--check-whitelist" {
+            // `--check-whitelist <ip> [HH:MM] [rules]`, the current `whitelist` option if no rules.
+            let Some(ip) = args.get(1).and_then(|x| x.parse().ok()) else {
+                println!("Usage: --check-whitelist <ip> [HH:MM] [rules]");
+                return None;
+            };
+            let mut rest = &args[2..];
+            let minute = match rest
+                .first()
+                .and_then(|x| crate::server::whitelist::parse_window(&format!("{0}-{0}", x)))
+            {
+                Some((minute, _)) => {
+                    rest = &rest[1..];
+                    minute
+                }
+                None => crate::server::whitelist::local_minute(),
+            };
+            let option = if rest.is_empty() {
+                crate::ipc::get_options()
+                    .remove("whitelist")
+                    .unwrap_or_default()
+            } else {
+                rest.join(",")
+            };
+            println!("{}", crate::server::whitelist::describe(&option, ip, minute));
+            return None;
         } else if args[0] == "--assign" {
             if config::Config::no_register_device() {
                 println!("Cannot assign an unregistrable device!");
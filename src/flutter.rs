@@ -716,6 +716,17 @@ impl InvokeUiSession for FlutterHandler {
         );
     }
 
+    fn update_port_forward_stats(&self, stats: &[PortForwardStats]) {
+        self.push_event(
+            "update_port_forward_stats",
+            &[(
+                "stats",
+                &serde_json::ser::to_string(stats).unwrap_or("".to_owned()),
+            )],
+            &[],
+        );
+    }
+
     fn set_connection_type(&self, is_secured: bool, direct: bool) {
         self.push_event(
             "connection_ready",
@@ -2362,6 +2362,10 @@ pub fn main_verify2fa(code: String) -> bool {
     verify2fa(code)
 }
 
+pub fn main_generate2fa_recovery_codes() -> String {
+    generate2fa_recovery_codes()
+}
+
 pub fn main_has_valid_2fa_sync() -> SyncReturn<bool> {
     SyncReturn(has_valid_2fa())
 }
@@ -284,6 +284,8 @@ pub enum Data {
     #[cfg(target_os = "windows")]
     PortForwardSessionCount(Option<usize>),
     SocksWs(Option<Box<(Option<config::Socks5Server>, String)>>),
+    LoginFailures(Option<String>),
+    UnbanLogin((String, Option<usize>)),
 }
 
 #[tokio::main(flavor = "current_thread")]
@@ -353,7 +355,7 @@ pub async fn new_listener(postfix: &str) -> ResultType<Incoming> {
 pub struct CheckIfRestart {
     stop_service: String,
     rendezvous_servers: Vec<String>,
-    audio_input: String,
+    audio_options: Vec<String>,
     voice_call_input: String,
     ws: String,
     api_server: String,
@@ -364,7 +366,7 @@ impl CheckIfRestart {
         CheckIfRestart {
             stop_service: Config::get_option("stop-service"),
             rendezvous_servers: Config::get_rendezvous_servers(),
-            audio_input: Config::get_option("audio-input"),
+            audio_options: crate::audio_service::option_values(),
             voice_call_input: Config::get_option("voice-call-input"),
             ws: Config::get_option(OPTION_ALLOW_WEBSOCKET),
             api_server: Config::get_option("api-server"),
@@ -380,7 +382,7 @@ impl Drop for CheckIfRestart {
         {
             RendezvousMediator::restart();
         }
-        if self.audio_input != Config::get_option("audio-input") {
+        if self.audio_options != crate::audio_service::option_values() {
             crate::audio_service::restart();
         }
         if self.voice_call_input != Config::get_option("voice-call-input") {
@@ -569,12 +571,13 @@ async fn handle(data: Data, stream: &mut Connection) {
                 let v = Config::get_options();
                 allow_err!(stream.send(&Data::Options(Some(v))).await);
             }
-            Some(value) => {
+            Some(mut value) => {
                 let _chk = CheckIfRestart::new();
                 let _nat = CheckTestNatType::new();
                 if let Some(v) = value.get("privacy-mode-impl-key") {
                     crate::privacy_mode::switch(v);
                 }
+                crate::auth_2fa::notifier::encrypt_options(&mut value);
                 Config::set_options(value);
                 allow_err!(stream.send(&Data::Options(None)).await);
             }
@@ -741,6 +744,14 @@ async fn handle(data: Data, stream: &mut Connection) {
                 // Port forward session count is only a get value.
             }
         },
+        Data::LoginFailures(None) => {
+            let v = crate::server::login_failure::list();
+            allow_err!(stream.send(&Data::LoginFailures(Some(v))).await);
+        }
+        Data::UnbanLogin((key, None)) => {
+            let n = crate::server::login_failure::unban(&key);
+            allow_err!(stream.send(&Data::UnbanLogin((key, Some(n)))).await);
+        }
         _ => {}
     }
 }
@@ -1285,6 +1296,27 @@ pub async fn get_port_forward_session_count(ms_timeout: u64) -> ResultType<usize
     bail!("Failed to get port forward session count");
 }
 
+#[tokio::main(flavor = "current_thread")]
+pub async fn get_login_failures() -> ResultType<String> {
+    let mut c = connect(1_000, "").await?;
+    c.send(&Data::LoginFailures(None)).await?;
+    if let Some(Data::LoginFailures(Some(v))) = c.next_timeout(1_000).await? {
+        return Ok(v);
+    }
+    bail!("Failed to get login failures");
+}
+
+/// Empty `key` unbans all.
+#[tokio::main(flavor = "current_thread")]
+pub async fn unban_login(key: String) -> ResultType<usize> {
+    let mut c = connect(1_000, "").await?;
+    c.send(&Data::UnbanLogin((key, None))).await?;
+    if let Some(Data::UnbanLogin((_, Some(n)))) = c.next_timeout(1_000).await? {
+        return Ok(n);
+    }
+    bail!("Failed to unban");
+}
+
 #[cfg(feature = "hwcodec")]
 #[cfg(not(any(target_os = "android", target_os = "ios")))]
 #[tokio::main(flavor = "current_thread")]
@@ -699,5 +699,8 @@ pub static ref T: std::collections::HashMap<&'static str, &'static str> =
         ("Trackpad speed", "سرعة لوحة التتبع"),
         ("Default trackpad speed", "سرعة لوحة التتبع الافتراضية"),
         ("Numeric one-time password", "كلمة مرور رقمية لمرة واحدة"),
+        ("Lossless text", ""),
+        ("Recovery codes", ""),
+        ("2fa-recovery-codes-tip", ""),
     ].iter().cloned().collect();
 }
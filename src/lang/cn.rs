@@ -699,5 +699,8 @@ pub static ref T: std::collections::HashMap<&'static str, &'static str> =
         ("Trackpad speed", "触控板速度"),
         ("Default trackpad speed", "默认触控板速度"),
         ("Numeric one-time password", "一次性密码为数字"),
+        ("Lossless text", ""),
+        ("Recovery codes", ""),
+        ("2fa-recovery-codes-tip", ""),
     ].iter().cloned().collect();
 }
@@ -699,5 +699,8 @@ pub static ref T: std::collections::HashMap<&'static str, &'static str> =
         ("Trackpad speed", "Geschwindigkeit des Trackpads"),
         ("Default trackpad speed", "Standardgeschwindigkeit des Trackpads"),
         ("Numeric one-time password", "Numerisches Einmalpasswort"),
+        ("Lossless text", ""),
+        ("Recovery codes", ""),
+        ("2fa-recovery-codes-tip", ""),
     ].iter().cloned().collect();
 }
@@ -259,5 +259,6 @@ pub static ref T: std::collections::HashMap<&'static str, &'static str> =
         ("download-new-version-failed-tip", "Download failed. You can try again or click the \"Download\" button to download from the release page and upgrade manually."),
         ("update-failed-check-msi-tip", "Installation method check failed. Please click the \"Download\" button to download from the release page and upgrade manually."),
         ("websocket_tip", "When using WebSocket, only relay connections are supported."),
+        ("2fa-recovery-codes-tip", "New codes replace the previous ones. Each one can be entered once instead of a verification code, keep them somewhere safe."),
     ].iter().cloned().collect();
 }
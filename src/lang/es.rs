@@ -699,5 +699,8 @@ pub static ref T: std::collections::HashMap<&'static str, &'static str> =
         ("Trackpad speed", "Velocidad de trackpad"),
         ("Default trackpad speed", "Velocidad predeterminada de trackpad"),
         ("Numeric one-time password", ""),
+        ("Lossless text", ""),
+        ("Recovery codes", ""),
+        ("2fa-recovery-codes-tip", ""),
     ].iter().cloned().collect();
 }
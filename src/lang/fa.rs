@@ -699,5 +699,8 @@ pub static ref T: std::collections::HashMap<&'static str, &'static str> =
         ("Trackpad speed", "سرعت ترک‌پد"),
         ("Default trackpad speed", "سرعت پیش‌فرض ترک‌پد"),
         ("Numeric one-time password", "رمز عبور یک‌بار مصرف عددی"),
+        ("Lossless text", ""),
+        ("Recovery codes", ""),
+        ("2fa-recovery-codes-tip", ""),
     ].iter().cloned().collect();
 }
@@ -699,5 +699,8 @@ pub static ref T: std::collections::HashMap<&'static str, &'static str> =
         ("Trackpad speed", "트랙패드 속도"),
         ("Default trackpad speed", "기본 트랙패드 속도"),
         ("Numeric one-time password", "일회용 비밀번호"),
+        ("Lossless text", ""),
+        ("Recovery codes", ""),
+        ("2fa-recovery-codes-tip", ""),
     ].iter().cloned().collect();
 }
@@ -699,5 +699,8 @@ pub static ref T: std::collections::HashMap<&'static str, &'static str> =
         ("Trackpad speed", "Skārienpaliktņa ātrums"),
         ("Default trackpad speed", "Noklusējuma skārienpaliktņa ātrums"),
         ("Numeric one-time password", "Vienreiz lietojama ciparu parole"),
+        ("Lossless text", ""),
+        ("Recovery codes", ""),
+        ("2fa-recovery-codes-tip", ""),
     ].iter().cloned().collect();
 }
@@ -699,5 +699,8 @@ pub static ref T: std::collections::HashMap<&'static str, &'static str> =
         ("Trackpad speed", "Скорость трекпада"),
         ("Default trackpad speed", "Скорость трекпада по умолчанию"),
         ("Numeric one-time password", "Цифровой одноразовый пароль"),
+        ("Lossless text", ""),
+        ("Recovery codes", ""),
+        ("2fa-recovery-codes-tip", ""),
     ].iter().cloned().collect();
 }
@@ -10,6 +10,7 @@ pub use platform::{
 mod server;
 #[cfg(not(any(target_os = "ios")))]
 pub use self::server::*;
+mod audio_codec;
 mod client;
 mod lan;
 #[cfg(not(any(target_os = "ios")))]
@@ -47,6 +48,8 @@ mod custom_server;
 mod lang;
 #[cfg(not(any(target_os = "android", target_os = "ios")))]
 mod port_forward;
+#[cfg(not(target_os = "ios"))]
+mod tunnel;
 
 #[cfg(all(feature = "flutter", feature = "plugin_framework"))]
 #[cfg(not(any(target_os = "android", target_os = "ios")))]
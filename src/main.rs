@@ -40,12 +40,16 @@ fn main() {
     if !common::global_init() {
         return;
     }
-    use clap::App;
+    use clap::{App, SubCommand};
     use hbb_common::log;
     let args = format!(
-        "-p, --port-forward=[PORT-FORWARD-OPTIONS] 'Format: remote-id:local-port:remote-port[:remote-host]'
+        "-p, --port-forward=[PORT-FORWARD-OPTIONS] 'Format: remote-id[:local-port:remote-port[:remote-host]]'
+        -f, --forward=[RULE]... 'More forwards for --port-forward, format: [tcp:|udp:][bind-address:]local-port:remote-host:remote-port, reverse:peer-port:host:port to forward a port of the peer here, or socks:[bind-address:]local-port for a SOCKS5 proxy through the peer'
+        --rate-limit=[KBPS] 'Bandwidth cap of the port forward session in KB/s, 0 for none'
+        --idle-timeout=[SECONDS] 'Close forwarded connections idle for this long, 0 for never'
         -c, --connect=[REMOTE_ID] 'test only'
         -k, --key=[KEY] ''
+        --password-file=[FILE] 'Read the password from the first line of FILE, RUSTDESK_PASSWORD and RUSTDESK_PASSWORD_FILE also work'
        -s, --server=[] 'Start server'",
     );
     let matches = App::new("rustdesk")
@@ -53,42 +57,208 @@ fn main() {
         .author("Purslane Ltd<info@rustdesk.com>")
         .about("RustDesk command line tool")
         .args_from_usage(&args)
+        .subcommand(
+            SubCommand::with_name("info")
+                .about("Print the information of the peer")
+                .args_from_usage("<ID> 'Remote ID'"),
+        )
+        .subcommand(
+            SubCommand::with_name("screenshot")
+                .about("Save a PNG screenshot of the peer")
+                .args_from_usage(
+                    "<ID> 'Remote ID'
+                    <OUTPUT> 'PNG file to write'
+                    -d, --display=[DISPLAY] 'Display index, 0 by default'",
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("clipboard")
+                .about("Get or set the text clipboard of the peer")
+                .subcommand(SubCommand::with_name("get").args_from_usage(
+                    "<ID> 'Remote ID'
+                    --wait=[SECONDS] 'How long to wait for the clipboard, 5 by default'",
+                ))
+                .subcommand(SubCommand::with_name("set").args_from_usage(
+                    "<ID> 'Remote ID'
+                    <TEXT> 'Text to set'",
+                )),
+        )
+        .subcommand(
+            SubCommand::with_name("file")
+                .about("Copy files or directories from or to the peer")
+                .subcommand(SubCommand::with_name("push").args_from_usage(
+                    "<ID> 'Remote ID'
+                    <LOCAL> 'Local path'
+                    <REMOTE> 'Remote directory'
+                    --hidden 'Include hidden files'
+                    --overwrite 'Overwrite existing files'",
+                ))
+                .subcommand(SubCommand::with_name("pull").args_from_usage(
+                    "<ID> 'Remote ID'
+                    <REMOTE> 'Remote path'
+                    <LOCAL> 'Local directory'
+                    --hidden 'Include hidden files'
+                    --overwrite 'Overwrite existing files'",
+                )),
+        )
+        .subcommand(
+            SubCommand::with_name("forward")
+                .about("Forward ports through the peer, same rules as --forward")
+                .args_from_usage(
+                    "<ID> 'Remote ID'
+                    <RULE>... 'Forward rule'
+                    --rate-limit=[KBPS] 'Bandwidth cap in KB/s, 0 for none'
+                    --idle-timeout=[SECONDS] 'Close forwarded connections idle for this long, 0 for never'",
+                ),
+        )
         .get_matches();
     use hbb_common::{config::LocalConfig, env_logger::*};
     init_from_env(Env::default().filter_or(DEFAULT_FILTER_ENV, "info"));
-    if let Some(p) = matches.value_of("port-forward") {
-        let options: Vec<String> = p.split(":").map(|x| x.to_owned()).collect();
-        if options.len() < 3 {
-            log::error!("Wrong port-forward options");
+    let password = match cli::read_password(matches.value_of("password-file")) {
+        Ok(password) => password,
+        Err(err) => {
+            log::error!("{}", err);
             return;
         }
-        let mut port = 0;
-        if let Ok(v) = options[1].parse::<i32>() {
-            port = v;
-        } else {
-            log::error!("Wrong local-port");
-            return;
+    };
+    if let (name, Some(sub)) = matches.subcommand() {
+        let key = matches.value_of("key").unwrap_or("").to_owned();
+        let token = LocalConfig::get_option("access_token");
+        let id = sub
+            .subcommand()
+            .1
+            .unwrap_or(sub)
+            .value_of("ID")
+            .unwrap_or_default()
+            .to_owned();
+        let command = match sub.subcommand() {
+            ("get", Some(m)) if name == "clipboard" => cli::Command::ClipboardGet {
+                wait: m.value_of("wait").and_then(|x| x.parse().ok()).unwrap_or(5),
+            },
+            ("set", Some(m)) if name == "clipboard" => cli::Command::ClipboardSet {
+                text: m.value_of("TEXT").unwrap_or_default().to_owned(),
+            },
+            ("push", Some(m)) if name == "file" => cli::Command::FilePush {
+                local: m.value_of("LOCAL").unwrap_or_default().to_owned(),
+                remote: m.value_of("REMOTE").unwrap_or_default().to_owned(),
+                include_hidden: m.is_present("hidden"),
+                overwrite: m.is_present("overwrite"),
+            },
+            ("pull", Some(m)) if name == "file" => cli::Command::FilePull {
+                remote: m.value_of("REMOTE").unwrap_or_default().to_owned(),
+                local: m.value_of("LOCAL").unwrap_or_default().to_owned(),
+                include_hidden: m.is_present("hidden"),
+                overwrite: m.is_present("overwrite"),
+            },
+            _ if name == "info" => cli::Command::Info,
+            _ if name == "screenshot" => cli::Command::Screenshot {
+                display: sub
+                    .value_of("display")
+                    .and_then(|x| x.parse().ok())
+                    .unwrap_or(0),
+                output: sub.value_of("OUTPUT").unwrap_or_default().to_owned(),
+            },
+            _ if name == "forward" => {
+                let rules = sub
+                    .values_of("RULE")
+                    .into_iter()
+                    .flatten()
+                    .map(|x| x.to_owned())
+                    .collect();
+                let rate_limit =
+                    match cli::parse_number_arg(sub.value_of("rate-limit"), "rate-limit") {
+                        Ok(rate_limit) => rate_limit,
+                        Err(err) => {
+                            log::error!("{}", err);
+                            return;
+                        }
+                    };
+                let idle_timeout =
+                    match cli::parse_number_arg(sub.value_of("idle-timeout"), "idle-timeout") {
+                        Ok(idle_timeout) => idle_timeout,
+                        Err(err) => {
+                            log::error!("{}", err);
+                            return;
+                        }
+                    };
+                cli::start_port_forward(id, rules, rate_limit, idle_timeout, password, key, token);
+                common::global_clean();
+                return;
+            }
+            _ => {
+                log::error!("{}", sub.usage());
+                return;
+            }
+        };
+        common::test_rendezvous_server();
+        common::test_nat_type();
+        let ok = cli::run(id, command, password, key, token);
+        common::global_clean();
+        if !ok {
+            std::process::exit(1);
         }
-        let mut remote_port = 0;
-        if let Ok(v) = options[2].parse::<i32>() {
-            remote_port = v;
-        } else {
-            log::error!("Wrong remote-port");
-            return;
+        return;
+    }
+    if let Some(p) = matches.value_of("port-forward") {
+        let options: Vec<String> = p.split(":").map(|x| x.to_owned()).collect();
+        let mut rules = Vec::new();
+        if options.len() > 1 {
+            if options.len() < 3 {
+                log::error!("Wrong port-forward options");
+                return;
+            }
+            let port = if let Ok(v) = options[1].parse::<u16>() {
+                v
+            } else {
+                log::error!("Wrong local-port");
+                return;
+            };
+            let remote_port = if let Ok(v) = options[2].parse::<u16>() {
+                v
+            } else {
+                log::error!("Wrong remote-port");
+                return;
+            };
+            let mut remote_host = "localhost".to_owned();
+            if options.len() > 3 {
+                remote_host = options[3].clone();
+            }
+            rules.push(format!("{}:[{}]:{}", port, remote_host, remote_port));
         }
-        let mut remote_host = "localhost".to_owned();
-        if options.len() > 3 {
-            remote_host = options[3].clone();
+        rules.extend(
+            matches
+                .values_of("forward")
+                .into_iter()
+                .flatten()
+                .map(|x| x.to_owned()),
+        );
+        if rules.is_empty() {
+            log::error!("No port-forward rules");
+            return;
         }
-        common::test_rendezvous_server();
-        common::test_nat_type();
+        let rate_limit = match cli::parse_number_arg(matches.value_of("rate-limit"), "rate-limit") {
+            Ok(rate_limit) => rate_limit,
+            Err(err) => {
+                log::error!("{}", err);
+                return;
+            }
+        };
+        let idle_timeout =
+            match cli::parse_number_arg(matches.value_of("idle-timeout"), "idle-timeout") {
+                Ok(idle_timeout) => idle_timeout,
+                Err(err) => {
+                    log::error!("{}", err);
+                    return;
+                }
+            };
         let key = matches.value_of("key").unwrap_or("").to_owned();
         let token = LocalConfig::get_option("access_token");
-        cli::start_one_port_forward(
+        cli::start_port_forward(
             options[0].clone(),
-            port,
-            remote_host,
-            remote_port,
+            rules,
+            rate_limit,
+            idle_timeout,
+            password,
             key,
             token,
         );
@@ -1,20 +1,272 @@
-use std::sync::{Arc, RwLock};
+use std::{
+    collections::HashMap,
+    fmt,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    str::FromStr,
+    sync::{Arc, RwLock},
+};
 
 use crate::client::*;
+use crate::tunnel::{self, FrameSender, Protocol, Throttle, TunnelFrame, TUNNEL_HOST};
+use bytes::Bytes;
 use hbb_common::{
     allow_err, bail,
-    config::READ_TIMEOUT,
+    config::{LocalConfig, READ_TIMEOUT},
     futures::{SinkExt, StreamExt},
     log,
     message_proto::*,
     protobuf::Message as _,
     rendezvous_proto::ConnType,
     tcp, timeout,
-    tokio::{self, net::TcpStream, sync::mpsc},
+    tokio::{
+        self,
+        io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+        net::{TcpStream, UdpSocket},
+        sync::{mpsc, oneshot},
+        task::JoinHandle,
+        time::{self, Duration, Instant},
+    },
     tokio_util::codec::{BytesCodec, Framed},
     ResultType, Stream,
 };
 
+/// Local option with the address forwards listen on when a rule names none.
+pub const OPTION_BIND_ADDRESS: &str = "port-forward-bind-address";
+/// Local option capping the traffic of a port forward session, in KB/s.
+pub const OPTION_RATE_LIMIT: &str = "port-forward-rate-limit";
+/// Local option closing forwarded connections idle for this many seconds.
+pub const OPTION_IDLE_TIMEOUT: &str = "port-forward-idle-timeout";
+
+/// How often an idle tunnel is pinged so the peer does not time it out.
+const TUNNEL_KEEPALIVE: Duration = Duration::from_secs(30);
+/// UDP has no close, a flow is forgotten after this long without traffic.
+const UDP_IDLE_TIMEOUT: Duration = Duration::from_secs(120);
+const UDP_BUFFER_SIZE: usize = 64 * 1024;
+/// How often traffic stats are reported and idle connections closed.
+const STATS_INTERVAL: Duration = Duration::from_secs(5);
+/// How long a SOCKS client has to send its request, in milliseconds.
+const SOCKS_HANDSHAKE_TIMEOUT: u64 = 10_000;
+/// Connections and datagrams of the forwarded ports waiting for the forwarder,
+/// the listeners stop reading while it is full.
+const EVENT_QUEUE_SIZE: usize = 64;
+/// Returned by `connect_and_login` when the peer predates tunnels.
+const TUNNEL_UNSUPPORTED: &str = "The peer does not support port forwarding tunnels";
+
+const SOCKS_VERSION: u8 = 5;
+const SOCKS_NO_AUTH: u8 = 0;
+const SOCKS_NO_ACCEPTABLE_METHOD: u8 = 0xff;
+const SOCKS_CONNECT: u8 = 1;
+const SOCKS_SUCCEEDED: u8 = 0;
+const SOCKS_HOST_UNREACHABLE: u8 = 4;
+const SOCKS_COMMAND_NOT_SUPPORTED: u8 = 7;
+const SOCKS_ADDRESS_NOT_SUPPORTED: u8 = 8;
+
+/// Where forwards listen by default: loopback unless configured otherwise,
+/// so a forward is not reachable from the network by accident.
+pub fn default_bind_address() -> IpAddr {
+    LocalConfig::get_option(OPTION_BIND_ADDRESS)
+        .parse()
+        .unwrap_or(IpAddr::V4(Ipv4Addr::LOCALHOST))
+}
+
+/// Limits of a port forward session.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TunnelLimits {
+    /// KB/s for both directions of the session together, 0 for unlimited
+    pub rate_limit: u64,
+    pub idle_timeout: Option<Duration>,
+}
+
+impl TunnelLimits {
+    pub fn from_options() -> Self {
+        let idle_timeout = LocalConfig::get_option(OPTION_IDLE_TIMEOUT)
+            .trim()
+            .parse()
+            .unwrap_or(0);
+        Self {
+            rate_limit: LocalConfig::get_option(OPTION_RATE_LIMIT)
+                .trim()
+                .parse()
+                .unwrap_or(0),
+            idle_timeout: (idle_timeout > 0).then(|| Duration::from_secs(idle_timeout)),
+        }
+    }
+}
+
+/// One local port forwarded to a target reachable from the peer.
+///
+/// Written as `[tcp:|udp:][bind-address:]local-port:remote-host:remote-port`,
+/// IPv6 addresses in brackets, e.g. `udp:[::1]:5353:dns.lan:53`.
+///
+/// A reverse rule, `reverse:peer-port:host:port`, works the other way round:
+/// the peer listens on `local_port` of its loopback and connections are
+/// forwarded to `remote_host:remote_port` as reached from this side. The peer
+/// has to allow the port, see [`tunnel::OPTION_REVERSE_PORTS`].
+///
+/// A dynamic rule, `socks:[bind-address:]local-port`, runs a SOCKS5 proxy on
+/// the local port. Each CONNECT is tunnelled to the target it names, which
+/// the peer resolves.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ForwardRule {
+    pub protocol: Protocol,
+    pub bind: IpAddr,
+    pub local_port: u16,
+    pub remote_host: String,
+    pub remote_port: u16,
+    pub kind: ForwardKind,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ForwardKind {
+    /// Listen here, connect from the peer.
+    Local,
+    /// Listen on the peer, connect from here.
+    Reverse,
+    /// SOCKS5 proxy here, connect from the peer.
+    Dynamic,
+}
+
+impl ForwardRule {
+    pub fn tcp(local_port: u16, remote_host: String, remote_port: u16) -> Self {
+        Self {
+            protocol: Protocol::Tcp,
+            bind: default_bind_address(),
+            local_port,
+            remote_host: if remote_host.is_empty() {
+                "localhost".to_owned()
+            } else {
+                remote_host
+            },
+            remote_port,
+            kind: ForwardKind::Local,
+        }
+    }
+
+    pub fn socks(bind: IpAddr, local_port: u16) -> Self {
+        Self {
+            protocol: Protocol::Tcp,
+            bind,
+            local_port,
+            remote_host: String::new(),
+            remote_port: 0,
+            kind: ForwardKind::Dynamic,
+        }
+    }
+
+    fn local_addr(&self) -> SocketAddr {
+        SocketAddr::new(self.bind, self.local_port)
+    }
+}
+
+impl FromStr for ForwardRule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = split_rule(s)?;
+        let port = |p: &str| match p.parse::<u16>() {
+            Ok(port) if port > 0 => Ok(port),
+            _ => Err(format!("Invalid port {} in {}", p, s)),
+        };
+        if parts.first().map(|x| x.eq_ignore_ascii_case("socks")) == Some(true) {
+            return match parts.len() {
+                2 => Ok(Self::socks(default_bind_address(), port(&parts[1])?)),
+                3 => Ok(Self::socks(
+                    parts[1]
+                        .parse()
+                        .map_err(|_| format!("Invalid bind address in {}", s))?,
+                    port(&parts[2])?,
+                )),
+                _ => Err(format!("Invalid forward rule {}", s)),
+            };
+        }
+        let reverse = parts.first().map(|x| x.eq_ignore_ascii_case("reverse")) == Some(true);
+        if reverse {
+            parts.remove(0);
+        }
+        let mut protocol = Protocol::Tcp;
+        if let Some(first) = parts.first() {
+            match first.to_lowercase().as_str() {
+                "tcp" => {
+                    parts.remove(0);
+                }
+                "udp" => {
+                    protocol = Protocol::Udp;
+                    parts.remove(0);
+                }
+                _ => {}
+            }
+        }
+        if reverse && (protocol != Protocol::Tcp || parts.len() == 4) {
+            return Err(format!(
+                "Reverse forwards are TCP on the peer's loopback only: {}",
+                s
+            ));
+        }
+        let bind = match parts.len() {
+            3 => default_bind_address(),
+            4 => parts
+                .remove(0)
+                .parse()
+                .map_err(|_| format!("Invalid bind address in {}", s))?,
+            _ => return Err(format!("Invalid forward rule {}", s)),
+        };
+        let mut rule = Self::tcp(port(&parts[0])?, parts[1].clone(), port(&parts[2])?);
+        rule.protocol = protocol;
+        rule.bind = bind;
+        if reverse {
+            rule.kind = ForwardKind::Reverse;
+        }
+        Ok(rule)
+    }
+}
+
+impl fmt::Display for ForwardRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let host = if self.remote_host.contains(':') {
+            format!("[{}]", self.remote_host)
+        } else {
+            self.remote_host.clone()
+        };
+        match self.kind {
+            ForwardKind::Reverse => {
+                return write!(
+                    f,
+                    "reverse:{}:{}:{}",
+                    self.local_port, host, self.remote_port
+                )
+            }
+            ForwardKind::Dynamic => return write!(f, "socks:{}", self.local_addr()),
+            ForwardKind::Local => {}
+        }
+        write!(
+            f,
+            "{}:{}:{}:{}",
+            self.protocol,
+            self.local_addr(),
+            host,
+            self.remote_port
+        )
+    }
+}
+
+/// Split on colons outside of brackets and drop the brackets.
+fn split_rule(s: &str) -> Result<Vec<String>, String> {
+    let mut parts = vec![String::new()];
+    let mut in_brackets = false;
+    for c in s.chars() {
+        match c {
+            '[' if !in_brackets => in_brackets = true,
+            ']' if in_brackets => in_brackets = false,
+            ':' if !in_brackets => parts.push(String::new()),
+            c => parts.last_mut().unwrap().push(c),
+        }
+    }
+    if in_brackets {
+        return Err(format!("Unbalanced brackets in {}", s));
+    }
+    Ok(parts)
+}
+
 fn run_rdp(port: u16) {
     std::process::Command::new("cmdkey")
         .arg("/delete:localhost")
@@ -54,7 +306,10 @@ pub async fn listen(
     remote_host: String,
     remote_port: i32,
 ) -> ResultType<()> {
-    let listener = tcp::new_listener(format!("0.0.0.0:{}", port), true).await?;
+    // Only RDP still takes this path, other forwards go through `run_tunnel`.
+    // mstsc is started here and connects to localhost, so the listener stays
+    // on loopback instead of exposing the peer's RDP on all interfaces.
+    let listener = tcp::new_listener(format!("127.0.0.1:{}", port), true).await?;
     let addr = listener.local_addr()?;
     log::info!("listening on port {:?}", addr);
     let is_rdp = port == 0;
@@ -62,6 +317,7 @@ pub async fn listen(
         run_rdp(addr.port());
     }
     let mut ui_receiver = ui_receiver;
+    let limits = TunnelLimits::from_options();
     loop {
         tokio::select! {
             Ok((forward, addr)) = listener.accept() => {
@@ -70,11 +326,11 @@ pub async fn listen(
                 let id = id.clone();
                 let password = password.clone();
                 let mut forward = Framed::new(forward, BytesCodec::new());
-                match connect_and_login(&id, &password, &mut ui_receiver, interface.clone(), &mut forward, key, token, is_rdp).await {
+                match connect_and_login(&id, &password, &mut ui_receiver, interface.clone(), Some(&mut forward), key, token, is_rdp).await {
                     Ok(Some(stream)) => {
                         let interface = interface.clone();
                         tokio::spawn(async move {
-                            if let Err(err) = run_forward(forward, stream).await {
+                            if let Err(err) = run_forward(forward, stream, limits).await {
                                 interface.msgbox("error", "Error", &err.to_string(), "");
                             }
                             log::info!("connection from {:?} closed", addr);
@@ -108,7 +364,7 @@ async fn connect_and_login(
     password: &str,
     ui_receiver: &mut mpsc::UnboundedReceiver<Data>,
     interface: impl Interface,
-    forward: &mut Framed<TcpStream, BytesCodec>,
+    mut forward: Option<&mut Framed<TcpStream, BytesCodec>>,
     key: &str,
     token: &str,
     is_rdp: bool,
@@ -118,6 +374,7 @@ async fn connect_and_login(
     } else {
         ConnType::PORT_FORWARD
     };
+    let tunnel = forward.is_none();
     let ((mut stream, direct, _pk), (feedback, rendezvous_server)) =
         Client::start(id, key, token, conn_type, interface.clone()).await?;
     interface.update_direct(Some(direct));
@@ -144,6 +401,11 @@ async fn connect_and_login(
                         }
                         Some(message::Union::LoginResponse(lr)) => match lr.union {
                             Some(login_response::Union::Error(err)) => {
+                                // A peer without tunnels tries to connect to the
+                                // tunnel host itself, which always fails on port 0.
+                                if tunnel && err.starts_with("Failed to access remote") && err.contains(TUNNEL_HOST) {
+                                    bail!(TUNNEL_UNSUPPORTED);
+                                }
                                 if !interface.handle_login_error(&err) {
                                     return Ok(None);
                                 }
@@ -178,7 +440,7 @@ async fn connect_and_login(
                     _ => {}
                 }
             },
-            res = forward.next() => {
+            res = next_forward(&mut forward) => {
                 if let Some(Ok(bytes)) = res {
                     buffer.extend(bytes);
                 } else {
@@ -187,34 +449,995 @@ async fn connect_and_login(
             },
         }
     }
-    stream.set_raw();
+    // A tunnel keeps the session framed and encrypted.
+    if forward.is_some() {
+        stream.set_raw();
+    }
     if !buffer.is_empty() {
         allow_err!(stream.send_bytes(buffer.into()).await);
     }
     Ok(Some(stream))
 }
 
-async fn run_forward(forward: Framed<TcpStream, BytesCodec>, stream: Stream) -> ResultType<()> {
+/// Data arriving on the local socket while logging in, never ready for a tunnel.
+async fn next_forward(
+    forward: &mut Option<&mut Framed<TcpStream, BytesCodec>>,
+) -> Option<Result<bytes::BytesMut, std::io::Error>> {
+    match forward {
+        Some(forward) => forward.next().await,
+        None => std::future::pending().await,
+    }
+}
+
+async fn run_forward(
+    forward: Framed<TcpStream, BytesCodec>,
+    stream: Stream,
+    limits: TunnelLimits,
+) -> ResultType<()> {
     log::info!("new port forwarding connection started");
     let mut forward = forward;
     let mut stream = stream;
+    let mut throttle = Throttle::new(limits.rate_limit);
+    let started = Instant::now();
+    let mut last_active = started;
+    let (mut sent, mut received) = (0u64, 0u64);
+    let mut timer = time::interval(STATS_INTERVAL);
     loop {
         tokio::select! {
-            res = forward.next() => {
+            res = forward.next(), if throttle.is_ready() => {
                 if let Some(Ok(bytes)) = res {
+                    last_active = Instant::now();
+                    sent += bytes.len() as u64;
+                    throttle.consume(bytes.len());
                     allow_err!(stream.send_bytes(bytes.into()).await);
                 } else {
                     break;
                 }
             },
-            res = stream.next() => {
+            res = stream.next(), if throttle.is_ready() => {
                 if let Some(Ok(bytes)) = res {
+                    last_active = Instant::now();
+                    received += bytes.len() as u64;
+                    throttle.consume(bytes.len());
                     allow_err!(forward.send(bytes).await);
                 } else {
                     break;
                 }
             },
+            _ = throttle.wait() => {}
+            _ = timer.tick() => {
+                if limits.idle_timeout.map_or(false, |timeout| last_active.elapsed() >= timeout) {
+                    log::info!("Closing idle port forwarding connection");
+                    break;
+                }
+            }
         }
     }
+    log::info!(
+        "port forwarding connection closed after {}s, {} bytes sent, {} bytes received",
+        started.elapsed().as_secs(),
+        sent,
+        received
+    );
     Ok(())
 }
+
+/// Negotiate with a SOCKS5 client up to its request and return the target of
+/// its CONNECT. Only CONNECT without authentication is supported, the proxy
+/// listens on loopback unless configured otherwise.
+async fn socks5_handshake<S: AsyncRead + AsyncWrite + Unpin>(
+    socket: &mut S,
+) -> ResultType<(String, u16)> {
+    let mut head = [0u8; 2];
+    socket.read_exact(&mut head).await?;
+    if head[0] != SOCKS_VERSION {
+        bail!("Not a SOCKS5 client");
+    }
+    let mut methods = vec![0u8; head[1] as usize];
+    socket.read_exact(&mut methods).await?;
+    if !methods.contains(&SOCKS_NO_AUTH) {
+        socket
+            .write_all(&[SOCKS_VERSION, SOCKS_NO_ACCEPTABLE_METHOD])
+            .await?;
+        bail!("SOCKS client requires authentication");
+    }
+    socket.write_all(&[SOCKS_VERSION, SOCKS_NO_AUTH]).await?;
+    let mut request = [0u8; 4];
+    socket.read_exact(&mut request).await?;
+    if request[0] != SOCKS_VERSION {
+        bail!("Invalid SOCKS request");
+    }
+    let host = match request[3] {
+        1 => {
+            let mut ip = [0u8; 4];
+            socket.read_exact(&mut ip).await?;
+            Ipv4Addr::from(ip).to_string()
+        }
+        3 => {
+            let mut len = [0u8; 1];
+            socket.read_exact(&mut len).await?;
+            let mut name = vec![0u8; len[0] as usize];
+            socket.read_exact(&mut name).await?;
+            String::from_utf8(name)?
+        }
+        4 => {
+            let mut ip = [0u8; 16];
+            socket.read_exact(&mut ip).await?;
+            Ipv6Addr::from(ip).to_string()
+        }
+        atyp => {
+            socket
+                .write_all(&socks5_reply(SOCKS_ADDRESS_NOT_SUPPORTED))
+                .await?;
+            bail!("Unsupported SOCKS address type {}", atyp);
+        }
+    };
+    let mut port = [0u8; 2];
+    socket.read_exact(&mut port).await?;
+    if request[1] != SOCKS_CONNECT {
+        socket
+            .write_all(&socks5_reply(SOCKS_COMMAND_NOT_SUPPORTED))
+            .await?;
+        bail!("Unsupported SOCKS command {}", request[1]);
+    }
+    Ok((host, u16::from_be_bytes(port)))
+}
+
+/// Reply to a SOCKS5 request. The bound address is left empty, the target is
+/// connected from the peer.
+fn socks5_reply(code: u8) -> [u8; 10] {
+    [SOCKS_VERSION, code, 0, 1, 0, 0, 0, 0, 0, 0]
+}
+
+/// Read the request of a new SOCKS client and hand it to the forwarder.
+async fn socks_accept(
+    rule: Arc<ForwardRule>,
+    mut socket: TcpStream,
+    addr: SocketAddr,
+    event_tx: mpsc::Sender<LocalEvent>,
+) {
+    match timeout(SOCKS_HANDSHAKE_TIMEOUT, socks5_handshake(&mut socket)).await {
+        Ok(Ok((host, port))) => {
+            event_tx
+                .send(LocalEvent::Socks {
+                    rule,
+                    socket,
+                    addr,
+                    host,
+                    port,
+                })
+                .await
+                .ok();
+        }
+        Ok(Err(err)) => log::debug!("SOCKS request from {:?} failed: {}", addr, err),
+        Err(_) => log::debug!("SOCKS request from {:?} timed out", addr),
+    }
+}
+
+/// Answer a SOCKS client once the peer has connected its channel, or failed
+/// to, then forward its data.
+async fn socks_connect(
+    channel: u32,
+    mut socket: TcpStream,
+    connected: oneshot::Receiver<()>,
+    from_tunnel: tunnel::DataReceiver,
+    to_tunnel: FrameSender,
+) {
+    // The sender is dropped when the peer closes the channel instead.
+    if connected.await.is_err() {
+        socket
+            .write_all(&socks5_reply(SOCKS_HOST_UNREACHABLE))
+            .await
+            .ok();
+        return;
+    }
+    if socket
+        .write_all(&socks5_reply(SOCKS_SUCCEEDED))
+        .await
+        .is_err()
+    {
+        to_tunnel.send(TunnelFrame::Close { channel }).await.ok();
+        return;
+    }
+    tunnel::pump_tcp(channel, socket, from_tunnel, to_tunnel).await;
+}
+
+/// Where the data of a channel goes on the local side of a tunnel.
+enum ChannelSink {
+    Tcp(tunnel::DataSender),
+    Udp {
+        socket: Arc<UdpSocket>,
+        peer: SocketAddr,
+        flow: (SocketAddr, SocketAddr),
+    },
+}
+
+/// A connection or UDP flow on the local side of a tunnel.
+struct LocalChannel {
+    sink: ChannelSink,
+    /// The rule as displayed, its stats are kept under this name
+    rule: String,
+    opened: Instant,
+    last_active: Instant,
+}
+
+/// Traffic on the forwarded local ports.
+enum LocalEvent {
+    Accepted(Arc<ForwardRule>, TcpStream, SocketAddr),
+    Datagram {
+        rule: Arc<ForwardRule>,
+        socket: Arc<UdpSocket>,
+        peer: SocketAddr,
+        data: Bytes,
+    },
+    /// A SOCKS client that has sent its CONNECT request.
+    Socks {
+        rule: Arc<ForwardRule>,
+        socket: TcpStream,
+        addr: SocketAddr,
+        host: String,
+        port: u16,
+    },
+}
+
+/// Forward all `rules` through one tunnel session with the peer.
+///
+/// The session is opened on the first connection or datagram and reopened the
+/// same way after it drops. Peers without tunnels get one session per TCP
+/// connection instead, like before; other rules do not work with them. With reverse rules it is opened right away and
+/// reopened by the keepalive timer, since the peer listens only while it is up.
+/// `ui_receiver` also adds and removes rules with `Data::AddPortForward` and
+/// `Data::RemovePortForward`.
+///
+/// The traffic of each rule is reported with `Interface::update_port_forward_stats`.
+pub async fn run_tunnel(
+    id: String,
+    password: String,
+    rules: Vec<ForwardRule>,
+    limits: TunnelLimits,
+    interface: impl Interface,
+    ui_receiver: mpsc::UnboundedReceiver<Data>,
+    key: &str,
+    token: &str,
+    lc: Arc<RwLock<LoginConfigHandler>>,
+) -> ResultType<()> {
+    let mut ui_receiver = ui_receiver;
+    let (event_tx, mut event_rx) = mpsc::channel(EVENT_QUEUE_SIZE);
+    let (frame_tx, mut frame_rx) = tunnel::frame_channel();
+    let mut forwarder = Forwarder {
+        id,
+        password,
+        interface,
+        key: key.to_owned(),
+        token: token.to_owned(),
+        lc,
+        limits,
+        throttle: Throttle::new(limits.rate_limit),
+        idle_timeout: limits.idle_timeout,
+        legacy: false,
+        event_tx,
+        frame_tx,
+        listeners: Vec::new(),
+        reverse_rules: Vec::new(),
+        reverse_listeners: HashMap::new(),
+        socks_pending: HashMap::new(),
+        stream: None,
+        channels: HashMap::new(),
+        udp_flows: HashMap::new(),
+        stats: HashMap::new(),
+        stats_changed: false,
+        next_channel: 0,
+    };
+    for rule in rules {
+        forwarder.add_rule(rule).await;
+    }
+    if !forwarder.reverse_rules.is_empty() {
+        forwarder.ensure_tunnel(&mut ui_receiver).await;
+    }
+    let mut timer = time::interval(TUNNEL_KEEPALIVE);
+    let mut stats_timer = time::interval(STATS_INTERVAL);
+    loop {
+        tokio::select! {
+            Some(event) = event_rx.recv(), if forwarder.throttle.is_ready() => {
+                forwarder.on_local_event(event, &mut ui_receiver).await;
+            }
+            Some(frame) = frame_rx.recv(), if forwarder.throttle.is_ready() => {
+                forwarder.send_frame(frame).await;
+            }
+            res = next_tunnel(&mut forwarder.stream), if forwarder.throttle.is_ready() => {
+                match res {
+                    Some(Ok(bytes)) => {
+                        forwarder.throttle.consume(bytes.len());
+                        match TunnelFrame::decode(bytes) {
+                            Ok(frame) => forwarder.on_tunnel_frame(frame).await,
+                            Err(err) => log::debug!("Ignoring invalid tunnel frame: {}", err),
+                        }
+                    }
+                    Some(Err(err)) => forwarder.close_tunnel(&err.to_string()),
+                    None => forwarder.close_tunnel("Reset by the peer"),
+                }
+            }
+            _ = forwarder.throttle.wait() => {}
+            _ = timer.tick() => {
+                forwarder.on_timer(&mut ui_receiver).await;
+            }
+            _ = stats_timer.tick() => {
+                forwarder.close_idle().await;
+                forwarder.report_stats();
+            }
+            d = ui_receiver.recv() => {
+                match d {
+                    Some(Data::AddPortForward((port, remote_host, remote_port))) => {
+                        if port > 0 && port <= u16::MAX as i32 && remote_port > 0 && remote_port <= u16::MAX as i32 {
+                            forwarder.add_rule(ForwardRule::tcp(port as _, remote_host, remote_port as _)).await;
+                        }
+                    }
+                    Some(Data::RemovePortForward(port)) => {
+                        forwarder.remove_port(port).await;
+                    }
+                    Some(Data::Close) | None => {
+                        break;
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+    forwarder.remove_all();
+    forwarder.report_stats();
+    Ok(())
+}
+
+async fn next_tunnel(
+    stream: &mut Option<Stream>,
+) -> Option<Result<bytes::BytesMut, std::io::Error>> {
+    match stream {
+        Some(stream) => stream.next().await,
+        None => std::future::pending().await,
+    }
+}
+
+struct Forwarder<T: Interface> {
+    id: String,
+    password: String,
+    interface: T,
+    key: String,
+    token: String,
+    lc: Arc<RwLock<LoginConfigHandler>>,
+    limits: TunnelLimits,
+    /// Pauses reading the tunnel and the local ports, see `Throttle`
+    throttle: Throttle,
+    idle_timeout: Option<Duration>,
+    /// The peer has no tunnels, TCP connections get a session each
+    legacy: bool,
+    event_tx: mpsc::Sender<LocalEvent>,
+    frame_tx: FrameSender,
+    listeners: Vec<(Arc<ForwardRule>, JoinHandle<()>)>,
+    reverse_rules: Vec<Arc<ForwardRule>>,
+    /// Listeners the peer runs for reverse rules in this session, by id
+    reverse_listeners: HashMap<u32, Arc<ForwardRule>>,
+    /// SOCKS channels waiting for the peer to connect them
+    socks_pending: HashMap<u32, oneshot::Sender<()>>,
+    stream: Option<Stream>,
+    channels: HashMap<u32, LocalChannel>,
+    /// (local address of the rule, UDP client) to channel
+    udp_flows: HashMap<(SocketAddr, SocketAddr), u32>,
+    /// By rule, kept across tunnel sessions
+    stats: HashMap<String, PortForwardStats>,
+    stats_changed: bool,
+    next_channel: u32,
+}
+
+impl<T: Interface> Forwarder<T> {
+    async fn add_rule(&mut self, rule: ForwardRule) {
+        let rule = Arc::new(rule);
+        self.stats
+            .entry(rule.to_string())
+            .or_insert_with(|| PortForwardStats {
+                rule: rule.to_string(),
+                local_port: rule.local_port,
+                ..Default::default()
+            });
+        self.stats_changed = true;
+        if rule.kind == ForwardKind::Reverse {
+            log::info!("Forwarding {}", rule);
+            self.reverse_rules.push(rule.clone());
+            if self.stream.is_some() {
+                self.listen(rule).await;
+            }
+            return;
+        }
+        let event_tx = self.event_tx.clone();
+        let handle = match rule.protocol {
+            Protocol::Tcp => match tcp::new_listener(rule.local_addr(), true).await {
+                Ok(listener) => {
+                    let rule = rule.clone();
+                    tokio::spawn(async move {
+                        while let Ok((socket, addr)) = listener.accept().await {
+                            if rule.kind == ForwardKind::Dynamic {
+                                tokio::spawn(socks_accept(
+                                    rule.clone(),
+                                    socket,
+                                    addr,
+                                    event_tx.clone(),
+                                ));
+                            } else if event_tx
+                                .send(LocalEvent::Accepted(rule.clone(), socket, addr))
+                                .await
+                                .is_err()
+                            {
+                                break;
+                            }
+                        }
+                    })
+                }
+                Err(err) => {
+                    self.interface.on_error(&format!(
+                        "Failed to listen on {}: {}",
+                        rule.local_addr(),
+                        err
+                    ));
+                    return;
+                }
+            },
+            Protocol::Udp => match UdpSocket::bind(rule.local_addr()).await {
+                Ok(socket) => {
+                    let socket = Arc::new(socket);
+                    let rule = rule.clone();
+                    tokio::spawn(async move {
+                        let mut buf = vec![0u8; UDP_BUFFER_SIZE];
+                        loop {
+                            // Errors are ICMP replies to an earlier send, keep listening.
+                            if let Ok((n, peer)) = socket.recv_from(&mut buf).await {
+                                let event = LocalEvent::Datagram {
+                                    rule: rule.clone(),
+                                    socket: socket.clone(),
+                                    peer,
+                                    data: Bytes::copy_from_slice(&buf[..n]),
+                                };
+                                if event_tx.send(event).await.is_err() {
+                                    break;
+                                }
+                            }
+                        }
+                    })
+                }
+                Err(err) => {
+                    self.interface.on_error(&format!(
+                        "Failed to listen on udp {}: {}",
+                        rule.local_addr(),
+                        err
+                    ));
+                    return;
+                }
+            },
+        };
+        log::info!("Forwarding {}", rule);
+        self.listeners.push((rule, handle));
+    }
+
+    /// Stop listening on `port`, here or on the peer for reverse rules.
+    /// Connections already forwarded keep running.
+    async fn remove_port(&mut self, port: i32) {
+        self.listeners.retain(|(rule, handle)| {
+            if rule.local_port as i32 == port {
+                log::info!("Stopped forwarding {}", rule);
+                handle.abort();
+                false
+            } else {
+                true
+            }
+        });
+        self.reverse_rules
+            .retain(|rule| rule.local_port as i32 != port);
+        self.stats
+            .retain(|_, stats| stats.local_port as i32 != port);
+        self.stats_changed = true;
+        let closed: Vec<u32> = self
+            .reverse_listeners
+            .iter()
+            .filter(|(_, rule)| rule.local_port as i32 == port)
+            .map(|(listener, _)| *listener)
+            .collect();
+        for listener in closed {
+            if let Some(rule) = self.reverse_listeners.remove(&listener) {
+                log::info!("Stopped forwarding {}", rule);
+            }
+            self.send_frame(TunnelFrame::Close { channel: listener })
+                .await;
+        }
+    }
+
+    fn remove_all(&mut self) {
+        for (_, handle) in self.listeners.drain(..) {
+            handle.abort();
+        }
+        self.close_tunnel("Closed");
+    }
+
+    /// Log in to the peer unless the tunnel is up already.
+    async fn ensure_tunnel(&mut self, ui_receiver: &mut mpsc::UnboundedReceiver<Data>) -> bool {
+        if self.stream.is_some() {
+            return true;
+        }
+        if self.legacy {
+            return false;
+        }
+        self.lc.write().unwrap().port_forward = (TUNNEL_HOST.to_owned(), 0);
+        match connect_and_login(
+            &self.id,
+            &self.password,
+            ui_receiver,
+            self.interface.clone(),
+            None,
+            &self.key,
+            &self.token,
+            false,
+        )
+        .await
+        {
+            Ok(Some(stream)) => {
+                log::info!("Port forwarding tunnel to {} established", self.id);
+                self.stream = Some(stream);
+                for rule in self.reverse_rules.clone() {
+                    self.listen(rule).await;
+                }
+                self.stream.is_some()
+            }
+            Ok(None) => false,
+            Err(err) if err.to_string() == TUNNEL_UNSUPPORTED => {
+                self.on_tunnel_unsupported();
+                false
+            }
+            Err(err) => {
+                self.interface
+                    .on_establish_connection_error(err.to_string());
+                false
+            }
+        }
+    }
+
+    /// Switch to one session per TCP connection for a peer without tunnels.
+    fn on_tunnel_unsupported(&mut self) {
+        log::info!(
+            "{} does not support tunnels, forwarding TCP connections one by one",
+            self.id
+        );
+        self.legacy = true;
+        let unsupported: Vec<String> = self
+            .listeners
+            .iter()
+            .map(|(rule, _)| rule)
+            .filter(|rule| rule.kind != ForwardKind::Local || rule.protocol != Protocol::Tcp)
+            .chain(self.reverse_rules.iter())
+            .map(|rule| rule.to_string())
+            .collect();
+        self.reverse_rules.clear();
+        if !unsupported.is_empty() {
+            self.interface.on_error(&format!(
+                "The peer needs to be updated for {}",
+                unsupported.join(", ")
+            ));
+        }
+    }
+
+    /// Forward a TCP connection through a session of its own, for peers
+    /// without tunnels. Datagrams and SOCKS requests are dropped.
+    async fn forward_legacy(
+        &mut self,
+        event: LocalEvent,
+        ui_receiver: &mut mpsc::UnboundedReceiver<Data>,
+    ) {
+        let LocalEvent::Accepted(rule, socket, addr) = event else {
+            return;
+        };
+        log::info!("new connection from {:?} for {}", addr, rule);
+        self.lc.write().unwrap().port_forward = (rule.remote_host.clone(), rule.remote_port as _);
+        let mut forward = Framed::new(socket, BytesCodec::new());
+        match connect_and_login(
+            &self.id,
+            &self.password,
+            ui_receiver,
+            self.interface.clone(),
+            Some(&mut forward),
+            &self.key,
+            &self.token,
+            false,
+        )
+        .await
+        {
+            Ok(Some(stream)) => {
+                if let Some(stats) = self.stats.get_mut(&rule.to_string()) {
+                    stats.connections += 1;
+                    self.stats_changed = true;
+                }
+                let interface = self.interface.clone();
+                let limits = self.limits;
+                tokio::spawn(async move {
+                    if let Err(err) = run_forward(forward, stream, limits).await {
+                        interface.msgbox("error", "Error", &err.to_string(), "");
+                    }
+                    log::info!("connection from {:?} closed", addr);
+                });
+            }
+            Ok(None) => {}
+            Err(err) => self
+                .interface
+                .on_establish_connection_error(err.to_string()),
+        }
+    }
+
+    fn close_tunnel(&mut self, reason: &str) {
+        if self.stream.take().is_some() {
+            log::info!("Port forwarding tunnel to {} closed: {}", self.id, reason);
+        }
+        // Dropping the senders ends the channel tasks.
+        let channels: Vec<u32> = self.channels.keys().copied().collect();
+        for channel in channels {
+            self.remove_channel(channel);
+        }
+        // The peer stops listening when the session ends.
+        self.reverse_listeners.clear();
+    }
+
+    /// Ids for channels and listeners, the peer's channels have
+    /// `tunnel::REVERSE_CHANNEL` set instead.
+    fn alloc_channel(&mut self) -> u32 {
+        let channel = self.next_channel;
+        self.next_channel = (self.next_channel + 1) & !tunnel::REVERSE_CHANNEL;
+        channel
+    }
+
+    /// Ask the peer to listen for a reverse `rule`.
+    async fn listen(&mut self, rule: Arc<ForwardRule>) {
+        let channel = self.alloc_channel();
+        let port = rule.local_port;
+        self.reverse_listeners.insert(channel, rule);
+        self.send_frame(TunnelFrame::Listen { channel, port }).await;
+    }
+
+    /// Open a channel to `host:port` on the peer's side, before any of its
+    /// data is sent.
+    async fn open_channel(&mut self, protocol: Protocol, host: String, port: u16) -> u32 {
+        let channel = self.alloc_channel();
+        self.send_frame(TunnelFrame::Open {
+            channel,
+            protocol,
+            host,
+            port,
+        })
+        .await;
+        channel
+    }
+
+    fn insert_channel(&mut self, channel: u32, rule: &ForwardRule, sink: ChannelSink) {
+        let rule = rule.to_string();
+        if let Some(stats) = self.stats.get_mut(&rule) {
+            stats.connections += 1;
+            stats.active += 1;
+            self.stats_changed = true;
+        }
+        let now = Instant::now();
+        self.channels.insert(
+            channel,
+            LocalChannel {
+                sink,
+                rule,
+                opened: now,
+                last_active: now,
+            },
+        );
+    }
+
+    async fn on_local_event(
+        &mut self,
+        event: LocalEvent,
+        ui_receiver: &mut mpsc::UnboundedReceiver<Data>,
+    ) {
+        if !self.ensure_tunnel(ui_receiver).await {
+            if self.legacy {
+                self.forward_legacy(event, ui_receiver).await;
+            }
+            return;
+        }
+        match event {
+            LocalEvent::Accepted(rule, socket, addr) => {
+                log::info!("new connection from {:?} for {}", addr, rule);
+                let channel = self
+                    .open_channel(rule.protocol, rule.remote_host.clone(), rule.remote_port)
+                    .await;
+                if self.stream.is_none() {
+                    return;
+                }
+                let (data_tx, data_rx) = tunnel::data_channel();
+                self.insert_channel(channel, &rule, ChannelSink::Tcp(data_tx));
+                tokio::spawn(tunnel::pump_tcp(
+                    channel,
+                    socket,
+                    data_rx,
+                    self.frame_tx.clone(),
+                ));
+            }
+            LocalEvent::Socks {
+                rule,
+                socket,
+                addr,
+                host,
+                port,
+            } => {
+                log::info!(
+                    "SOCKS connection from {:?} to {}:{} for {}",
+                    addr,
+                    host,
+                    port,
+                    rule
+                );
+                let channel = self.open_channel(Protocol::Tcp, host, port).await;
+                if self.stream.is_none() {
+                    return;
+                }
+                let (data_tx, data_rx) = tunnel::data_channel();
+                let (connected_tx, connected_rx) = oneshot::channel();
+                self.insert_channel(channel, &rule, ChannelSink::Tcp(data_tx));
+                self.socks_pending.insert(channel, connected_tx);
+                tokio::spawn(socks_connect(
+                    channel,
+                    socket,
+                    connected_rx,
+                    data_rx,
+                    self.frame_tx.clone(),
+                ));
+            }
+            LocalEvent::Datagram {
+                rule,
+                socket,
+                peer,
+                data,
+            } => {
+                let flow = (rule.local_addr(), peer);
+                let channel = match self.udp_flows.get(&flow) {
+                    Some(channel) => *channel,
+                    None => {
+                        let channel = self
+                            .open_channel(rule.protocol, rule.remote_host.clone(), rule.remote_port)
+                            .await;
+                        if self.stream.is_none() {
+                            return;
+                        }
+                        self.udp_flows.insert(flow, channel);
+                        self.insert_channel(
+                            channel,
+                            &rule,
+                            ChannelSink::Udp { socket, peer, flow },
+                        );
+                        channel
+                    }
+                };
+                self.send_frame(TunnelFrame::Data { channel, data }).await;
+            }
+        }
+    }
+
+    async fn on_tunnel_frame(&mut self, frame: TunnelFrame) {
+        match frame {
+            TunnelFrame::Data { channel, data } => {
+                let Some(local) = self.channels.get_mut(&channel) else {
+                    return;
+                };
+                local.last_active = Instant::now();
+                if let Some(stats) = self.stats.get_mut(&local.rule) {
+                    stats.bytes_received += data.len() as u64;
+                    self.stats_changed = true;
+                }
+                match &local.sink {
+                    // Waits while the connection's queue is full, the session is not read meanwhile.
+                    ChannelSink::Tcp(sender) => {
+                        sender.send(data).await.ok();
+                    }
+                    ChannelSink::Udp { socket, peer, .. } => {
+                        allow_err!(socket.send_to(&data, *peer).await);
+                    }
+                }
+            }
+            TunnelFrame::Close { channel } => match self.reverse_listeners.remove(&channel) {
+                Some(rule) => log::info!("Peer stopped listening for {}", rule),
+                None => self.remove_channel(channel),
+            },
+            TunnelFrame::Accept { channel, listener } => {
+                let rule = match self.reverse_listeners.get(&listener) {
+                    Some(rule)
+                        if channel & tunnel::REVERSE_CHANNEL != 0
+                            && !self.channels.contains_key(&channel) =>
+                    {
+                        rule.clone()
+                    }
+                    _ => {
+                        self.send_frame(TunnelFrame::Close { channel }).await;
+                        return;
+                    }
+                };
+                let (data_tx, data_rx) = tunnel::data_channel();
+                self.insert_channel(channel, &rule, ChannelSink::Tcp(data_tx));
+                tunnel::spawn_connect(
+                    channel,
+                    Protocol::Tcp,
+                    rule.remote_host.clone(),
+                    rule.remote_port,
+                    data_rx,
+                    self.frame_tx.clone(),
+                );
+            }
+            TunnelFrame::Connected { channel } => {
+                if let Some(connected) = self.socks_pending.remove(&channel) {
+                    connected.send(()).ok();
+                }
+            }
+            TunnelFrame::Refused { channel, reason } => {
+                if let Some(rule) = self.reverse_listeners.remove(&channel) {
+                    // Asking again after a reconnect would be refused the same way.
+                    self.reverse_rules.retain(|x| !Arc::ptr_eq(x, &rule));
+                    self.interface
+                        .on_error(&format!("The peer refused to forward {}: {}", rule, reason));
+                }
+            }
+            frame => log::debug!("Unexpected tunnel frame {:?}", frame),
+        }
+    }
+
+    fn remove_channel(&mut self, channel: u32) {
+        self.socks_pending.remove(&channel);
+        let Some(local) = self.channels.remove(&channel) else {
+            return;
+        };
+        if let ChannelSink::Udp { flow, .. } = local.sink {
+            self.udp_flows.remove(&flow);
+        }
+        if let Some(stats) = self.stats.get_mut(&local.rule) {
+            stats.active = stats.active.saturating_sub(1);
+            stats.duration += local.opened.elapsed().as_secs();
+            self.stats_changed = true;
+        }
+    }
+
+    async fn send_frame(&mut self, frame: TunnelFrame) {
+        match &frame {
+            TunnelFrame::Close { channel } => self.remove_channel(*channel),
+            TunnelFrame::Data { channel, data } => {
+                if let Some(local) = self.channels.get_mut(channel) {
+                    local.last_active = Instant::now();
+                    if let Some(stats) = self.stats.get_mut(&local.rule) {
+                        stats.bytes_sent += data.len() as u64;
+                        self.stats_changed = true;
+                    }
+                }
+                self.throttle.consume(data.len());
+            }
+            _ => {}
+        }
+        let Some(stream) = self.stream.as_mut() else {
+            return;
+        };
+        if let Err(err) = stream.send_bytes(frame.encode()).await {
+            self.close_tunnel(&err.to_string());
+        }
+    }
+
+    async fn on_timer(&mut self, ui_receiver: &mut mpsc::UnboundedReceiver<Data>) {
+        if self.stream.is_none() {
+            if !self.reverse_rules.is_empty() {
+                self.ensure_tunnel(ui_receiver).await;
+            }
+            return;
+        }
+        self.send_frame(TunnelFrame::Ping).await;
+    }
+
+    /// Close connections idle longer than the idle timeout, and UDP flows
+    /// idle longer than `UDP_IDLE_TIMEOUT` in any case.
+    async fn close_idle(&mut self) {
+        let idle: Vec<u32> = self
+            .channels
+            .iter()
+            .filter(|(_, local)| {
+                let elapsed = local.last_active.elapsed();
+                self.idle_timeout
+                    .map_or(false, |timeout| elapsed >= timeout)
+                    || matches!(local.sink, ChannelSink::Udp { .. }) && elapsed >= UDP_IDLE_TIMEOUT
+            })
+            .map(|(channel, _)| *channel)
+            .collect();
+        for channel in idle {
+            log::info!("Closing idle port forwarding channel {}", channel);
+            self.send_frame(TunnelFrame::Close { channel }).await;
+        }
+    }
+
+    fn report_stats(&mut self) {
+        if !self.stats_changed {
+            return;
+        }
+        self.stats_changed = false;
+        let mut stats: Vec<PortForwardStats> = self.stats.values().cloned().collect();
+        stats.sort_by(|a, b| a.rule.cmp(&b.rule));
+        self.interface.update_port_forward_stats(&stats);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_forward_rule() {
+        let rule: ForwardRule = "8080:example.com:80".parse().unwrap();
+        assert_eq!(rule.protocol, Protocol::Tcp);
+        assert_eq!(rule.bind, default_bind_address());
+        assert_eq!(
+            (rule.local_port, rule.remote_host.as_str(), rule.remote_port),
+            (8080, "example.com", 80)
+        );
+
+        let rule: ForwardRule = "udp:0.0.0.0:5353:10.0.0.1:53".parse().unwrap();
+        assert_eq!(rule.protocol, Protocol::Udp);
+        assert_eq!(rule.bind, IpAddr::V4(Ipv4Addr::UNSPECIFIED));
+        assert_eq!(rule.remote_host, "10.0.0.1");
+
+        let rule: ForwardRule = "TCP:[::1]:2222:[fd00::2]:22".parse().unwrap();
+        assert_eq!(rule.bind, "::1".parse::<IpAddr>().unwrap());
+        assert_eq!(rule.remote_host, "fd00::2");
+        assert_eq!(rule.to_string(), "tcp:[::1]:2222:[fd00::2]:22");
+
+        let rule: ForwardRule = "reverse:8080:localhost:3000".parse().unwrap();
+        assert_eq!(rule.kind, ForwardKind::Reverse);
+        assert_eq!(
+            (rule.local_port, rule.remote_host.as_str(), rule.remote_port),
+            (8080, "localhost", 3000)
+        );
+        assert_eq!(rule.to_string(), "reverse:8080:localhost:3000");
+
+        let rule: ForwardRule = "socks:1080".parse().unwrap();
+        assert_eq!(rule.kind, ForwardKind::Dynamic);
+        assert_eq!((rule.bind, rule.local_port), (default_bind_address(), 1080));
+        let rule: ForwardRule = "socks:[::1]:1080".parse().unwrap();
+        assert_eq!(rule.to_string(), "socks:[::1]:1080");
+    }
+
+    #[test]
+    fn test_parse_invalid_forward_rule() {
+        assert!("8080:example.com".parse::<ForwardRule>().is_err());
+        assert!("0:example.com:80".parse::<ForwardRule>().is_err());
+        assert!("8080:example.com:http".parse::<ForwardRule>().is_err());
+        assert!("host:8080:example.com:80".parse::<ForwardRule>().is_err());
+        assert!("[::1:8080:example.com:80".parse::<ForwardRule>().is_err());
+        assert!("reverse:udp:5353:localhost:53"
+            .parse::<ForwardRule>()
+            .is_err());
+        assert!("reverse:0.0.0.0:8080:localhost:80"
+            .parse::<ForwardRule>()
+            .is_err());
+        assert!("socks:1080:localhost:80".parse::<ForwardRule>().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_socks5_handshake() {
+        let (mut client, mut server) = tokio::io::duplex(64);
+        client.write_all(&[5, 1, 0, 5, 1, 0, 3, 11]).await.unwrap();
+        client.write_all(b"example.com").await.unwrap();
+        client.write_all(&[0, 80]).await.unwrap();
+        let target = socks5_handshake(&mut server).await.unwrap();
+        assert_eq!(target, ("example.com".to_owned(), 80));
+        let mut reply = [0u8; 2];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply, [SOCKS_VERSION, SOCKS_NO_AUTH]);
+
+        let (mut client, mut server) = tokio::io::duplex(64);
+        client
+            .write_all(&[5, 1, 0, 5, 2, 0, 1, 10, 0, 0, 1, 0, 80])
+            .await
+            .unwrap();
+        assert!(socks5_handshake(&mut server).await.is_err());
+        let mut reply = [0u8; 12];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply[3], SOCKS_COMMAND_NOT_SUPPORTED);
+    }
+}
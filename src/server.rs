@@ -33,6 +33,7 @@ use video_service::VideoSource;
 use crate::ipc::Data;
 
 pub mod audio_service;
+pub mod audit;
 cfg_if::cfg_if! {
 if #[cfg(not(target_os = "ios"))] {
 mod clipboard_service;
@@ -60,15 +61,21 @@ pub mod input_service {
     pub const NAME_CURSOR: &'static str = "";
     pub const NAME_POS: &'static str = "";
     pub const NAME_WINDOW_FOCUS: &'static str = "";
+
+    pub fn latest_cursor_pos() -> Option<(i32, i32)> {
+        None
+    }
 }
 
 mod connection;
 pub mod display_service;
+pub mod login_failure;
 #[cfg(windows)]
 pub mod portable_service;
 mod service;
 mod video_qos;
 pub mod video_service;
+pub mod whitelist;
 
 #[cfg(all(target_os = "windows", feature = "flutter"))]
 pub mod printer_service;
@@ -13,17 +13,29 @@
 // https://github.com/krruzic/pulsectl
 
 use super::*;
+use crate::audio_codec::{AudioProfile, Encoder, EncoderConfig};
 #[cfg(not(any(target_os = "linux", target_os = "android")))]
 use hbb_common::anyhow::anyhow;
-use magnum_opus::{Application::*, Channels::*, Encoder};
 use std::sync::atomic::{AtomicBool, Ordering};
 
 pub const NAME: &'static str = "audio";
 pub const AUDIO_DATA_SIZE_U8: usize = 960 * 4; // 10ms in 48000 stereo
+/// The options the service restarts on.
+const OPTIONS: [&str; 7] = [
+    "audio-input",
+    "audio-profile",
+    "audio-bitrate",
+    "audio-fec",
+    "audio-dtx",
+    "audio-multichannel",
+    "audio-gate-threshold",
+];
 static RESTARTING: AtomicBool = AtomicBool::new(false);
 
 lazy_static::lazy_static! {
     static ref VOICE_CALL_INPUT_DEVICE: Arc::<Mutex::<Option<String>>> = Default::default();
+    // Whether the peer of each connection decodes more than 2 channels.
+    static ref MULTICHANNEL_PEERS: Mutex<HashMap<i32, bool>> = Default::default();
 }
 
 #[cfg(not(any(target_os = "linux", target_os = "android")))]
@@ -67,6 +79,72 @@ fn get_audio_input() -> String {
         .unwrap_or(Config::get_option("audio-input"))
 }
 
+#[inline]
+pub fn option_values() -> Vec<String> {
+    OPTIONS.iter().map(|k| Config::get_option(k)).collect()
+}
+
+// "voice" or "music", the low delay profile else.
+// "audio-bitrate" is in kbps, the encoder's choice if not set.
+fn encoder_config(sample_rate: u32, channels: u16) -> EncoderConfig {
+    EncoderConfig {
+        sample_rate,
+        channels,
+        profile: AudioProfile::from_option(&Config::get_option("audio-profile")),
+        bitrate: Config::get_option("audio-bitrate")
+            .parse::<u32>()
+            .unwrap_or(0)
+            .saturating_mul(1000),
+        fec: Config::get_option("audio-fec") == "Y",
+        dtx: Config::get_option("audio-dtx") == "Y",
+    }
+}
+
+// All connections share one stream, so more than 2 channels are only sent
+// while every peer decodes them.
+fn multichannel_peers(peers: &HashMap<i32, bool>) -> bool {
+    !peers.is_empty() && peers.values().all(|v| *v)
+}
+
+/// Track the peer version of connection `id`, the service restarts when the
+/// channels it may send change.
+pub fn add_peer(id: i32, version: &str) {
+    update_peers(|peers| {
+        peers.insert(id, crate::common::is_support_multichannel_audio(version));
+    });
+}
+
+pub fn remove_peer(id: i32) {
+    update_peers(|peers| {
+        peers.remove(&id);
+    });
+}
+
+fn update_peers(f: impl FnOnce(&mut HashMap<i32, bool>)) {
+    let mut peers = MULTICHANNEL_PEERS.lock().unwrap();
+    let before = multichannel_peers(&peers);
+    f(&mut peers);
+    let changed = before != multichannel_peers(&peers);
+    drop(peers);
+    if changed && Config::get_option("audio-multichannel") == "Y" {
+        restart();
+    }
+}
+
+// Peers without the multistream decoder only play mono and stereo, more
+// channels are opt-in.
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn encode_channels(device_channels: u16) -> u16 {
+    if device_channels > 2
+        && Config::get_option("audio-multichannel") == "Y"
+        && multichannel_peers(&MULTICHANNEL_PEERS.lock().unwrap())
+    {
+        device_channels.min(crate::audio_codec::MAX_CHANNELS)
+    } else {
+        device_channels.clamp(1, 2)
+    }
+}
+
 pub fn restart() {
     log::info!("restart the audio service, freezing now...");
     if RESTARTING.load(Ordering::SeqCst) {
@@ -97,10 +175,8 @@ mod pa_impl {
         RESTARTING.store(false, Ordering::SeqCst);
         #[cfg(target_os = "linux")]
         let mut stream = crate::ipc::connect(1000, "_pa").await?;
-        unsafe {
-            AUDIO_ZERO_COUNT = 0;
-        }
-        let mut encoder = Encoder::new(crate::platform::PA_SAMPLE_RATE, Stereo, LowDelay)?;
+        let mut encoder = Encoder::new(&encoder_config(crate::platform::PA_SAMPLE_RATE, 2))?;
+        let mut gate = NoiseGate::new(crate::platform::PA_SAMPLE_RATE, 2);
         #[cfg(target_os = "linux")]
         allow_err!(
             stream
@@ -123,7 +199,7 @@ mod pa_impl {
             #[cfg(target_os = "linux")]
             if let Ok(data) = stream.next_raw().await {
                 if data.len() == 0 {
-                    send_f32(&zero_audio_frame, &mut encoder, &sp);
+                    send_f32(&zero_audio_frame, &mut encoder, &mut gate, &sp);
                     continue;
                 }
 
@@ -135,7 +211,7 @@ mod pa_impl {
                 let data = unsafe {
                     std::slice::from_raw_parts::<f32>(data.as_ptr() as _, data.len() / 4)
                 };
-                send_f32(data, &mut encoder, &sp);
+                send_f32(data, &mut encoder, &mut gate, &sp);
             }
 
             #[cfg(target_os = "android")]
@@ -147,7 +223,7 @@ mod pa_impl {
                         android_data.len() / 4,
                     )
                 };
-                send_f32(data, &mut encoder, &sp);
+                send_f32(data, &mut encoder, &mut gate, &sp);
             } else {
                 hbb_common::sleep(0.1).await;
             }
@@ -241,6 +317,7 @@ mod cpal_impl {
         device_channel: u16,
         encode_channel: u16,
         encoder: &mut Encoder,
+        gate: &mut NoiseGate,
         sp: &GenericService,
     ) {
         let mut data = data;
@@ -256,7 +333,7 @@ mod cpal_impl {
                 encode_channel,
             )
         }
-        send_f32(&data, encoder, sp);
+        send_f32(&data, encoder, gate, sp);
     }
 
     #[cfg(feature = "screencapturekit")]
@@ -363,7 +440,7 @@ mod cpal_impl {
         } else {
             48000
         };
-        let ch = if config.channels() > 1 { Stereo } else { Mono };
+        let ch = super::encode_channels(config.channels());
         let stream = match config.sample_format() {
             I8 => build_input_stream::<i8>(device, &config, sp, sample_rate, ch)?,
             I16 => build_input_stream::<i16>(device, &config, sp, sample_rate, ch)?,
@@ -380,7 +457,7 @@ mod cpal_impl {
         stream.play()?;
         Ok((
             Box::new(stream),
-            Arc::new(create_format_msg(sample_rate, ch as _)),
+            Arc::new(create_format_msg(sample_rate, ch)),
         ))
     }
 
@@ -389,7 +466,7 @@ mod cpal_impl {
         config: &cpal::SupportedStreamConfig,
         sp: GenericService,
         sample_rate: u32,
-        encode_channel: magnum_opus::Channels,
+        encode_channel: u16,
     ) -> ResultType<cpal::Stream>
     where
         T: cpal::SizedSample + dasp::sample::ToSample<f32>,
@@ -400,11 +477,11 @@ mod cpal_impl {
         };
         let sample_rate_0 = config.sample_rate().0;
         log::debug!("Audio sample rate : {}", sample_rate);
-        unsafe {
-            AUDIO_ZERO_COUNT = 0;
-        }
         let device_channel = config.channels();
-        let mut encoder = Encoder::new(sample_rate, encode_channel, LowDelay)?;
+        let encoder_config = encoder_config(sample_rate, encode_channel);
+        log::info!("Audio encoder: {:?}", encoder_config);
+        let mut encoder = Encoder::new(&encoder_config)?;
+        let mut gate = NoiseGate::new(sample_rate, encode_channel);
         // https://www.opus-codec.org/docs/html_api/group__opusencoder.html#gace941e4ef26ed844879fde342ffbe546
         // https://chromium.googlesource.com/chromium/deps/opus/+/1.1.1/include/opus.h
         // Do not set `frame_size = sample_rate as usize / 100;`
@@ -433,8 +510,9 @@ mod cpal_impl {
                         sample_rate_0,
                         sample_rate,
                         device_channel,
-                        encode_channel as _,
+                        encode_channel,
                         &mut encoder,
+                        &mut gate,
                         &sp,
                     );
                 }
@@ -459,28 +537,58 @@ fn create_format_msg(sample_rate: u32, channels: u16) -> Message {
     msg
 }
 
-// use AUDIO_ZERO_COUNT for the Noise(Zero) Gate Attack Time
-// every audio data length is set to 480
-// MAX_AUDIO_ZERO_COUNT=800 is similar as Gate Attack Time 3~5s(Linux) || 6~8s(Windows)
-const MAX_AUDIO_ZERO_COUNT: u16 = 800;
-static mut AUDIO_ZERO_COUNT: u16 = 0;
+// How long the level must stay below the threshold before the gate closes,
+// short pauses in speech or music are still sent.
+const GATE_HOLD_MS: usize = 4000;
+
+// Stops sending once the peak level stays below "audio-gate-threshold" (dBFS)
+// for GATE_HOLD_MS, opens on the first frame above it. Only digital silence is
+// gated without the option.
+struct NoiseGate {
+    threshold: f32,
+    hold: usize,
+    below: usize,
+}
+
+impl NoiseGate {
+    fn new(sample_rate: u32, channels: u16) -> Self {
+        let threshold = Config::get_option("audio-gate-threshold")
+            .parse::<f32>()
+            .map(|db| 10f32.powf(db.min(0.) / 20.))
+            .unwrap_or(0.);
+        Self::with_threshold(threshold, sample_rate, channels)
+    }
 
-fn send_f32(data: &[f32], encoder: &mut Encoder, sp: &GenericService) {
-    if data.iter().filter(|x| **x != 0.).next().is_some() {
-        unsafe {
-            AUDIO_ZERO_COUNT = 0;
+    fn with_threshold(threshold: f32, sample_rate: u32, channels: u16) -> Self {
+        Self {
+            threshold,
+            hold: sample_rate as usize * channels as usize * GATE_HOLD_MS / 1000,
+            below: 0,
         }
-    } else {
-        unsafe {
-            if AUDIO_ZERO_COUNT > MAX_AUDIO_ZERO_COUNT {
-                if AUDIO_ZERO_COUNT == MAX_AUDIO_ZERO_COUNT + 1 {
-                    log::debug!("Audio Zero Gate Attack");
-                    AUDIO_ZERO_COUNT += 1;
-                }
-                return;
+    }
+
+    fn pass(&mut self, data: &[f32]) -> bool {
+        if data.iter().any(|x| x.abs() > self.threshold) {
+            if self.below > self.hold {
+                log::debug!("Audio noise gate open");
             }
-            AUDIO_ZERO_COUNT += 1;
+            self.below = 0;
+            return true;
+        }
+        if self.below > self.hold {
+            return false;
+        }
+        self.below += data.len();
+        if self.below > self.hold {
+            log::debug!("Audio noise gate closed");
         }
+        true
+    }
+}
+
+fn send_f32(data: &[f32], encoder: &mut Encoder, gate: &mut NoiseGate, sp: &GenericService) {
+    if !gate.pass(data) {
+        return;
     }
     #[cfg(target_os = "android")]
     {
@@ -525,3 +633,28 @@ fn send_f32(data: &[f32], encoder: &mut Encoder, sp: &GenericService) {
         Err(_) => {}
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_noise_gate() {
+        // 10 ms frames of 48000 stereo.
+        let hold_frames = GATE_HOLD_MS / 10;
+        let quiet = vec![0.0001; 960];
+        let loud = vec![0.1; 960];
+        let mut gate = NoiseGate::with_threshold(0.001, 48000, 2);
+        for _ in 0..=hold_frames {
+            assert!(gate.pass(&quiet));
+        }
+        assert!(!gate.pass(&quiet));
+        assert!(gate.pass(&loud));
+        assert!(gate.pass(&quiet));
+        // Without a threshold only silence is gated.
+        let mut gate = NoiseGate::with_threshold(0., 48000, 2);
+        for _ in 0..hold_frames * 2 {
+            assert!(gate.pass(&quiet));
+        }
+    }
+}
@@ -0,0 +1,482 @@
+use hbb_common::{config::Config, get_time, log, ResultType};
+use serde_json::{json, Value};
+use std::{
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    path::PathBuf,
+    sync::{
+        mpsc::{sync_channel, Receiver, RecvTimeoutError, SyncSender, TrySendError},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+/// Path of a local JSON-lines audit log, appended to; empty (default) for none.
+pub const OPTION_AUDIT_FILE: &str = "audit-log-file";
+/// "Y" to also write audits to syslog, picked up by journald on systemd.
+pub const OPTION_AUDIT_SYSLOG: &str = "audit-syslog";
+
+const QUEUE_FILE: &str = "audit_queue.jsonl";
+// Oldest queued audits are dropped beyond this, once the queue has grown
+// `COMPACT_SLACK` more so that it is not rewritten on every audit.
+const MAX_QUEUED: usize = 10_000;
+const COMPACT_SLACK: usize = 1_000;
+const RETRY_INTERVAL: Duration = Duration::from_secs(30);
+// Audits waiting for the thread, newer ones are dropped while it is full.
+const CHANNEL_SIZE: usize = 1_024;
+
+lazy_static::lazy_static! {
+    static ref SENDER: Mutex<Option<SyncSender<Audit>>> = Default::default();
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuditKind {
+    Conn,
+    File,
+    Alarm,
+}
+
+impl AuditKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            AuditKind::Conn => "conn",
+            AuditKind::File => "file",
+            AuditKind::Alarm => "alarm",
+        }
+    }
+}
+
+/// One audit, `body` is what the api server expects for `kind`.
+#[derive(Debug, Clone)]
+pub struct Audit {
+    pub kind: AuditKind,
+    pub time: i64,
+    pub body: Value,
+}
+
+/// Destination of audits, written in order from a dedicated thread.
+pub trait AuditSink: Send {
+    fn name(&self) -> &'static str;
+    fn write(&mut self, audit: &Audit) -> ResultType<()>;
+    /// Called periodically to resend what failed before.
+    fn retry(&mut self) {}
+}
+
+/// Hand `body` to the configured sinks.
+pub fn post(kind: AuditKind, body: Value) {
+    let audit = Audit {
+        kind,
+        time: get_time(),
+        body,
+    };
+    let mut lock = SENDER.lock().unwrap();
+    let tx = lock.get_or_insert_with(|| {
+        let (tx, rx) = sync_channel(CHANNEL_SIZE);
+        std::thread::spawn(move || run(rx));
+        tx
+    });
+    match tx.try_send(audit) {
+        Ok(()) => {}
+        Err(TrySendError::Full(audit)) => {
+            log::error!("Audit queue is full, dropped {:?}", audit);
+        }
+        Err(TrySendError::Disconnected(_)) => {
+            log::error!("Audit thread is gone");
+            lock.take();
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct SinkOptions {
+    file: String,
+    syslog: bool,
+    api: String,
+    custom: String,
+}
+
+impl SinkOptions {
+    fn load() -> Self {
+        Self {
+            file: Config::get_option(OPTION_AUDIT_FILE),
+            syslog: Config::get_option(OPTION_AUDIT_SYSLOG) == "Y",
+            api: Config::get_option("api-server"),
+            custom: Config::get_option("custom-rendezvous-server"),
+        }
+    }
+
+    fn sinks(&self) -> Vec<Box<dyn AuditSink>> {
+        let mut sinks: Vec<Box<dyn AuditSink>> = vec![Box::new(HttpSink::new(
+            self.api.clone(),
+            self.custom.clone(),
+        ))];
+        if !self.file.is_empty() {
+            sinks.push(Box::new(FileSink {
+                path: PathBuf::from(&self.file),
+            }));
+        }
+        #[cfg(unix)]
+        if self.syslog {
+            sinks.push(Box::new(SyslogSink::new()));
+        }
+        sinks
+    }
+}
+
+fn run(rx: Receiver<Audit>) {
+    let mut options = SinkOptions::load();
+    let mut sinks = options.sinks();
+    let mut next_retry = Instant::now() + RETRY_INTERVAL;
+    loop {
+        match rx.recv_timeout(next_retry.saturating_duration_since(Instant::now())) {
+            Ok(audit) => {
+                let new_options = SinkOptions::load();
+                if new_options != options {
+                    options = new_options;
+                    sinks = options.sinks();
+                }
+                for sink in sinks.iter_mut() {
+                    if let Err(err) = sink.write(&audit) {
+                        log::error!("Failed to write audit to {}: {}", sink.name(), err);
+                    }
+                }
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+        // Also under steady traffic, which never lets the receive time out.
+        if Instant::now() >= next_retry {
+            for sink in sinks.iter_mut() {
+                sink.retry();
+            }
+            next_retry = Instant::now() + RETRY_INTERVAL;
+        }
+    }
+}
+
+// Flat record of the local sinks, the api server bodies nest `info` as a string.
+fn record(audit: &Audit) -> Value {
+    let mut v = json!({
+        "time": audit.time,
+        "kind": audit.kind.name(),
+    });
+    if let Value::Object(map) = &audit.body {
+        for (k, x) in map {
+            let x = match x {
+                Value::String(s) if k == "info" => serde_json::from_str(s).unwrap_or(x.clone()),
+                _ => x.clone(),
+            };
+            v[k] = x;
+        }
+    }
+    v
+}
+
+/// Appends one JSON object per line.
+pub struct FileSink {
+    path: PathBuf,
+}
+
+impl AuditSink for FileSink {
+    fn name(&self) -> &'static str {
+        "file"
+    }
+
+    fn write(&mut self, audit: &Audit) -> ResultType<()> {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        writeln!(file, "{}", record(audit))?;
+        Ok(())
+    }
+}
+
+#[cfg(unix)]
+pub struct SyslogSink;
+
+#[cfg(unix)]
+impl SyslogSink {
+    fn new() -> Self {
+        unsafe {
+            hbb_common::libc::openlog(
+                b"rustdesk\0".as_ptr() as _,
+                hbb_common::libc::LOG_PID,
+                hbb_common::libc::LOG_AUTH,
+            );
+        }
+        Self
+    }
+}
+
+#[cfg(unix)]
+impl AuditSink for SyslogSink {
+    fn name(&self) -> &'static str {
+        "syslog"
+    }
+
+    fn write(&mut self, audit: &Audit) -> ResultType<()> {
+        let priority = match audit.kind {
+            AuditKind::Alarm => hbb_common::libc::LOG_WARNING,
+            _ => hbb_common::libc::LOG_INFO,
+        };
+        let text = std::ffi::CString::new(record(audit).to_string())?;
+        unsafe {
+            hbb_common::libc::syslog(priority, b"%s\0".as_ptr() as _, text.as_ptr());
+        }
+        Ok(())
+    }
+}
+
+// Audits not sent yet, one JSON object per line, oldest first.
+struct Queue {
+    path: PathBuf,
+    // Lines in the file, counted on first use.
+    len: Option<usize>,
+}
+
+impl Queue {
+    fn new(path: PathBuf) -> Self {
+        Self { path, len: None }
+    }
+
+    fn load(&self) -> Vec<Value> {
+        let Ok(file) = File::open(&self.path) else {
+            return vec![];
+        };
+        BufReader::new(file)
+            .lines()
+            .filter_map(|l| serde_json::from_str(&l.ok()?).ok())
+            .collect()
+    }
+
+    fn len(&mut self) -> usize {
+        if self.len.is_none() {
+            self.len = Some(self.load().len());
+        }
+        self.len.unwrap_or_default()
+    }
+
+    fn push(&mut self, v: &Value) -> ResultType<()> {
+        if self.len() >= MAX_QUEUED + COMPACT_SLACK {
+            let queued = self.load();
+            self.store(&queued)?;
+        }
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        writeln!(file, "{}", v)?;
+        self.len = self.len.map(|n| n + 1);
+        Ok(())
+    }
+
+    fn store(&mut self, queued: &[Value]) -> ResultType<()> {
+        self.len = None;
+        if queued.is_empty() {
+            if self.path.exists() {
+                std::fs::remove_file(&self.path)?;
+            }
+            self.len = Some(0);
+            return Ok(());
+        }
+        let skip = queued.len().saturating_sub(MAX_QUEUED);
+        let mut file = File::create(&self.path)?;
+        for v in &queued[skip..] {
+            writeln!(file, "{}", v)?;
+        }
+        self.len = Some(queued.len() - skip);
+        Ok(())
+    }
+
+    /// Hand the queued audits in order to `send` until it fails, returns how
+    /// many were sent and removed.
+    fn replay(&mut self, mut send: impl FnMut(&Value) -> bool) -> ResultType<usize> {
+        if self.len() == 0 {
+            return Ok(0);
+        }
+        let mut queued = self.load();
+        let n = queued.iter().take_while(|v| send(v)).count();
+        if n > 0 {
+            queued.drain(..n);
+            self.store(&queued)?;
+        }
+        Ok(n)
+    }
+}
+
+/// Posts to the audit api of the configured server, queueing on disk while it
+/// is unreachable so that the order is kept.
+pub struct HttpSink {
+    api: String,
+    custom: String,
+    queue: Queue,
+}
+
+impl HttpSink {
+    fn new(api: String, custom: String) -> Self {
+        Self {
+            api,
+            custom,
+            queue: Queue::new(Config::path(QUEUE_FILE)),
+        }
+    }
+
+    fn url(&self, kind: AuditKind) -> String {
+        crate::get_audit_server(
+            self.api.clone(),
+            self.custom.clone(),
+            kind.name().to_owned(),
+        )
+    }
+}
+
+impl AuditSink for HttpSink {
+    fn name(&self) -> &'static str {
+        "http"
+    }
+
+    fn write(&mut self, audit: &Audit) -> ResultType<()> {
+        let url = self.url(audit.kind);
+        if url.is_empty() {
+            return Ok(());
+        }
+        if self.queue.len() == 0
+            && crate::post_request_sync(url.clone(), audit.body.to_string(), "").is_ok()
+        {
+            return Ok(());
+        }
+        self.queue.push(&json!({ "url": url, "body": audit.body }))
+    }
+
+    fn retry(&mut self) {
+        let res = self.queue.replay(|v| {
+            let url = v["url"].as_str().unwrap_or_default().to_owned();
+            crate::post_request_sync(url, v["body"].to_string(), "").is_ok()
+        });
+        match res {
+            Ok(0) => {}
+            Ok(n) => log::info!("Sent {} queued audits", n),
+            Err(err) => log::error!("Failed to store the audit queue: {}", err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "rustdesk_audit_test_{}_{}",
+            std::process::id(),
+            name
+        ));
+        std::fs::remove_file(&path).ok();
+        path
+    }
+
+    #[test]
+    fn test_record() {
+        let audit = Audit {
+            kind: AuditKind::Alarm,
+            time: 1_700_000_000_000,
+            body: json!({
+                "id": "123456789",
+                "typ": 2,
+                "info": r#"{"ip":"10.0.0.1"}"#,
+            }),
+        };
+        assert_eq!(
+            record(&audit),
+            json!({
+                "time": 1_700_000_000_000i64,
+                "kind": "alarm",
+                "id": "123456789",
+                "typ": 2,
+                "info": { "ip": "10.0.0.1" },
+            })
+        );
+        // `info` is kept as it is if it is not JSON.
+        let audit = Audit {
+            kind: AuditKind::Conn,
+            body: json!({ "info": "text" }),
+            ..audit
+        };
+        assert_eq!(record(&audit)["info"], "text");
+        assert_eq!(record(&audit)["kind"], "conn");
+    }
+
+    #[test]
+    fn test_file_sink() {
+        let path = temp_path("file");
+        let mut sink = FileSink { path: path.clone() };
+        for i in 0..3 {
+            let audit = Audit {
+                kind: AuditKind::File,
+                time: i,
+                body: json!({ "path": format!("/tmp/{}", i) }),
+            };
+            sink.write(&audit).unwrap();
+        }
+        let lines: Vec<Value> = std::fs::read_to_string(&path)
+            .unwrap()
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 3);
+        for (i, v) in lines.iter().enumerate() {
+            assert_eq!(v["time"], i);
+            assert_eq!(v["kind"], "file");
+            assert_eq!(v["path"], format!("/tmp/{}", i));
+        }
+        std::fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn test_queue() {
+        let path = temp_path("queue");
+        let mut queue = Queue::new(path.clone());
+        assert_eq!(queue.len(), 0);
+        assert_eq!(queue.replay(|_| unreachable!()).unwrap(), 0);
+        for i in 0..5 {
+            queue.push(&json!({ "n": i })).unwrap();
+        }
+        assert_eq!(queue.len(), 5);
+        // Counted again from the file by a new sink.
+        assert_eq!(Queue::new(path.clone()).len(), 5);
+
+        // Stops at the first failure, the rest stays in order.
+        let mut sent = vec![];
+        let n = queue
+            .replay(|v| {
+                sent.push(v["n"].clone());
+                sent.len() <= 2
+            })
+            .unwrap();
+        assert_eq!(n, 2);
+        assert_eq!(sent, [0, 1, 2]);
+        queue.push(&json!({ "n": 5 })).unwrap();
+        let left: Vec<Value> = queue.load().iter().map(|v| v["n"].clone()).collect();
+        assert_eq!(left, [2, 3, 4, 5]);
+
+        let mut sent = vec![];
+        let n = queue
+            .replay(|v| {
+                sent.push(v["n"].clone());
+                true
+            })
+            .unwrap();
+        assert_eq!(n, 4);
+        assert_eq!(sent, [2, 3, 4, 5]);
+        assert_eq!(queue.len(), 0);
+        assert!(!path.exists());
+
+        // The oldest are dropped once the queue is full.
+        let queued: Vec<Value> = (0..MAX_QUEUED + 10).map(|i| json!({ "n": i })).collect();
+        queue.store(&queued).unwrap();
+        assert_eq!(queue.len(), MAX_QUEUED);
+        assert_eq!(queue.load()[0]["n"], 10);
+        std::fs::remove_file(&path).ok();
+    }
+}
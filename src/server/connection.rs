@@ -21,7 +21,6 @@ use crate::{
 };
 #[cfg(any(target_os = "android", target_os = "ios"))]
 use crate::{common::DEVICE_NAME, flutter::connection_manager::start_channel};
-use cidr_utils::cidr::IpCidr;
 #[cfg(target_os = "linux")]
 use hbb_common::platform::linux::run_cmds;
 #[cfg(target_os = "android")]
@@ -64,7 +63,6 @@ use std::collections::HashSet;
 pub type Sender = mpsc::UnboundedSender<(Instant, Arc<Message>)>;
 
 lazy_static::lazy_static! {
-    static ref LOGIN_FAILURES: [Arc::<Mutex<HashMap<String, (i32, i32, i32)>>>; 2] = Default::default();
     static ref SESSIONS: Arc::<Mutex<HashMap<SessionKey, Session>>> = Default::default();
     static ref ALIVE_CONNS: Arc::<Mutex<Vec<i32>>> = Default::default();
     pub static ref AUTHED_CONNS: Arc::<Mutex<Vec<AuthedConn>>> = Default::default();
@@ -163,6 +161,13 @@ struct StartCmIpcPara {
     tx_cm_stream_ready: mpsc::Sender<()>,
 }
 
+/// What a port forward login connected to.
+enum PortForwardSocket {
+    Tcp(Framed<TcpStream, BytesCodec>),
+    /// Channels to many targets opened by the peer, see `crate::tunnel`.
+    Tunnel,
+}
+
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
 pub enum AuthConnType {
     Remote,
@@ -182,7 +187,7 @@ pub struct Connection {
     file_timer: crate::RustDeskInterval,
     file_transfer: Option<(String, bool)>,
     view_camera: bool,
-    port_forward_socket: Option<Framed<TcpStream, BytesCodec>>,
+    port_forward_socket: Option<PortForwardSocket>,
     port_forward_address: String,
     tx_to_cm: mpsc::UnboundedSender<ipc::Data>,
     authorized: bool,
@@ -194,6 +199,8 @@ pub struct Connection {
     restart: bool,
     recording: bool,
     block_input: bool,
+    // by the whitelist rule that allowed the connection
+    restrictions: whitelist::Restrictions,
     last_test_delay: Option<Instant>,
     network_delay: u32,
     lock_after_session_end: bool,
@@ -215,8 +222,6 @@ pub struct Connection {
     tx_input: std_mpsc::Sender<MessageInput>,
     // handle input messages
     video_ack_required: bool,
-    server_audit_conn: String,
-    server_audit_file: String,
     lr: LoginRequest,
     session_last_recv_time: Option<Arc<Mutex<Instant>>>,
     chat_unanswered: bool,
@@ -351,6 +356,7 @@ impl Connection {
             restart: Connection::permission("enable-remote-restart"),
             recording: Connection::permission("enable-record-session"),
             block_input: Connection::permission("enable-block-input"),
+            restrictions: Default::default(),
             last_test_delay: None,
             network_delay: 0,
             lock_after_session_end: false,
@@ -366,8 +372,6 @@ impl Connection {
             disable_keyboard: false,
             tx_input,
             video_ack_required: false,
-            server_audit_conn: "".to_owned(),
-            server_audit_file: "".to_owned(),
             lr: Default::default(),
             session_last_recv_time: None,
             chat_unanswered: false,
@@ -514,6 +518,7 @@ impl Connection {
                         }
                         ipc::Data::SwitchPermission{name, enabled} => {
                             log::info!("Change permission {} -> {}", name, enabled);
+                            let enabled = enabled && conn.restrictions.allows(&name);
                             if &name == "keyboard" {
                                 conn.keyboard = enabled;
                                 conn.send_permission(Permission::Keyboard, enabled).await;
@@ -697,10 +702,12 @@ impl Connection {
                     if !conn.video_ack_required {
                         video_service::notify_video_frame_fetched(id, Some(instant.into()));
                     }
+                    let bytes = value.compute_size() as usize;
                     if let Err(err) = conn.stream.send(&value as &Message).await {
                         conn.on_close(&err.to_string(), false).await;
                         break;
                     }
+                    video_service::VIDEO_QOS.lock().unwrap().user_video_frame_sent(id, bytes, instant.elapsed());
                 },
                 Some((instant, value)) = rx.recv() => {
                     let latency = instant.elapsed().as_millis() as i64;
@@ -801,7 +808,7 @@ impl Connection {
                         let mut msg_out = Message::new();
                         msg_out.set_test_delay(TestDelay{
                             last_delay: conn.network_delay,
-                            target_bitrate: video_service::VIDEO_QOS.lock().unwrap().bitrate(),
+                            target_bitrate: video_service::VIDEO_QOS.lock().unwrap().user_bitrate(id),
                             ..Default::default()
                         });
                         conn.send(msg_out.into()).await;
@@ -967,41 +974,129 @@ impl Connection {
         &mut self,
         rx_from_cm: &mut mpsc::UnboundedReceiver<Data>,
     ) -> ResultType<()> {
+        if let Some(PortForwardSocket::Tunnel) = self.port_forward_socket {
+            self.port_forward_socket.take();
+            return self.port_forward_tunnel_loop(rx_from_cm).await;
+        }
         let mut last_recv_time = Instant::now();
-        if let Some(mut forward) = self.port_forward_socket.take() {
+        if let Some(PortForwardSocket::Tcp(mut forward)) = self.port_forward_socket.take() {
             log::info!("Running port forwarding loop");
             self.stream.set_raw();
             let mut hbbs_rx = crate::hbbs_http::sync::signal_receiver();
-            loop {
-                tokio::select! {
-                    Some(data) = rx_from_cm.recv() => {
-                        match data {
-                            ipc::Data::Close => {
-                                bail!("Close requested from connection manager");
+            let mut throttle = crate::tunnel::Throttle::from_option(&Config::get_option(
+                crate::tunnel::OPTION_RATE_LIMIT,
+            ));
+            let started = Instant::now();
+            let (mut sent, mut received) = (0u64, 0u64);
+            self.post_conn_audit(json!({
+                "action": "port_forward",
+                "event": "open",
+                "protocol": "tcp",
+                "target": self.port_forward_address,
+            }));
+            let res: ResultType<()> = async {
+                loop {
+                    tokio::select! {
+                        Some(data) = rx_from_cm.recv() => {
+                            check_port_forward_cm_data(data)?;
+                        }
+                        res = forward.next(), if throttle.is_ready() => {
+                            if let Some(res) = res {
+                                last_recv_time = Instant::now();
+                                let bytes = res?;
+                                sent += bytes.len() as u64;
+                                throttle.consume(bytes.len());
+                                self.stream.send_bytes(bytes.into()).await?;
+                            } else {
+                                bail!("Forward reset by the peer");
                             }
-                            ipc::Data::CmErr(e) => {
-                                log::error!("Connection manager error: {e}");
-                                bail!("{e}");
+                        },
+                        res = self.stream.next(), if throttle.is_ready() => {
+                            if let Some(res) = res {
+                                last_recv_time = Instant::now();
+                                let bytes = res?;
+                                received += bytes.len() as u64;
+                                throttle.consume(bytes.len());
+                                timeout(SEND_TIMEOUT_OTHER, forward.send(bytes)).await??;
+                            } else {
+                                bail!("Stream reset by the peer");
+                            }
+                        },
+                        _ = throttle.wait() => {}
+                        _ = self.timer.tick() => {
+                            if last_recv_time.elapsed() >= H1 {
+                                bail!("Timeout");
+                            }
+                        }
+                        Ok(conns) = hbbs_rx.recv() => {
+                            if conns.contains(&self.inner.id) {
+                                // todo: check reconnect
+                                bail!("Closed manually by the web console");
                             }
-                            _ => {}
                         }
                     }
-                    res = forward.next() => {
-                        if let Some(res) = res {
-                            last_recv_time = Instant::now();
-                            self.stream.send_bytes(res?.into()).await?;
-                        } else {
-                            bail!("Forward reset by the peer");
+                }
+            }
+            .await;
+            self.post_conn_audit(json!({
+                "action": "port_forward",
+                "event": "close",
+                "protocol": "tcp",
+                "target": self.port_forward_address,
+                "bytes_sent": sent,
+                "bytes_received": received,
+                "duration": started.elapsed().as_secs(),
+            }));
+            return res;
+        }
+        Ok(())
+    }
+
+    /// Like `try_port_forward_loop`, but the stream stays encrypted and
+    /// carries `TunnelFrame`s for any number of channels.
+    async fn port_forward_tunnel_loop(
+        &mut self,
+        rx_from_cm: &mut mpsc::UnboundedReceiver<Data>,
+    ) -> ResultType<()> {
+        log::info!("Running port forwarding tunnel");
+        let mut last_recv_time = Instant::now();
+        let mut tunnel = crate::tunnel::TunnelServer::new();
+        let mut hbbs_rx = crate::hbbs_http::sync::signal_receiver();
+        let mut throttle = crate::tunnel::Throttle::from_option(&Config::get_option(
+            crate::tunnel::OPTION_RATE_LIMIT,
+        ));
+        let started = Instant::now();
+        self.post_conn_audit(json!({
+            "action": "port_forward",
+            "event": "open",
+            "protocol": "tunnel",
+        }));
+        let res: ResultType<()> = async {
+            loop {
+                tokio::select! {
+                    Some(data) = rx_from_cm.recv() => {
+                        check_port_forward_cm_data(data)?;
+                    }
+                    Some(frame) = tunnel.next_frame(), if throttle.is_ready() => {
+                        if let crate::tunnel::TunnelFrame::Data { data, .. } = &frame {
+                            throttle.consume(data.len());
                         }
-                    },
-                    res = self.stream.next() => {
+                        self.stream.send_bytes(frame.encode()).await?;
+                    }
+                    res = self.stream.next(), if throttle.is_ready() => {
                         if let Some(res) = res {
                             last_recv_time = Instant::now();
-                            timeout(SEND_TIMEOUT_OTHER, forward.send(res?)).await??;
+                            let bytes = res?;
+                            throttle.consume(bytes.len());
+                            match crate::tunnel::TunnelFrame::decode(bytes) {
+                                Ok(frame) => tunnel.handle(frame).await,
+                                Err(err) => log::debug!("Ignoring invalid tunnel frame: {}", err),
+                            }
                         } else {
                             bail!("Stream reset by the peer");
                         }
                     },
+                    _ = throttle.wait() => {}
                     _ = self.timer.tick() => {
                         if last_recv_time.elapsed() >= H1 {
                             bail!("Timeout");
@@ -1009,14 +1104,52 @@ impl Connection {
                     }
                     Ok(conns) = hbbs_rx.recv() => {
                         if conns.contains(&self.inner.id) {
-                            // todo: check reconnect
                             bail!("Closed manually by the web console");
                         }
                     }
                 }
             }
         }
-        Ok(())
+        .await;
+        let summary = tunnel.close_all();
+        self.post_tunnel_audit(summary, started.elapsed());
+        res
+    }
+
+    /// One audit for a whole tunnel session, a busy tunnel opens far too
+    /// many channels to audit each.
+    fn post_tunnel_audit(&self, summary: crate::tunnel::TunnelSummary, duration: Duration) {
+        log::debug!("Port forwarding tunnel: {:?}", summary);
+        let targets: Vec<Value> = summary
+            .targets
+            .iter()
+            .map(|t| {
+                json!({
+                    "protocol": t.protocol.name(),
+                    "target": t.target,
+                    "reverse": t.reverse,
+                    "channels": t.channels,
+                    "bytes_sent": t.sent,
+                    "bytes_received": t.received,
+                    "duration": t.duration.as_secs(),
+                })
+            })
+            .collect();
+        let listens: Vec<Value> = summary
+            .listens
+            .iter()
+            .map(|(port, allowed, count)| json!({ "port": port, "allowed": allowed, "count": count }))
+            .collect();
+        self.post_conn_audit(json!({
+            "action": "port_forward",
+            "event": "close",
+            "protocol": "tunnel",
+            "bytes_sent": summary.targets.iter().map(|t| t.sent).sum::<u64>(),
+            "bytes_received": summary.targets.iter().map(|t| t.received).sum::<u64>(),
+            "duration": duration.as_secs(),
+            "targets": targets,
+            "listens": listens,
+        }));
     }
 
     async fn send_permission(&mut self, permission: Permission, enabled: bool) {
@@ -1042,32 +1175,35 @@ impl Connection {
     }
 
     async fn check_whitelist(&mut self, addr: &SocketAddr) -> bool {
-        let whitelist: Vec<String> = Config::get_option("whitelist")
-            .split(",")
-            .filter(|x| !x.is_empty())
-            .map(|x| x.to_owned())
-            .collect();
-        if !whitelist.is_empty()
-            && whitelist
-                .iter()
-                .filter(|x| x == &"0.0.0.0")
-                .next()
-                .is_none()
-            && whitelist
-                .iter()
-                .filter(|x| IpCidr::from_str(x).map_or(false, |y| y.contains(addr.ip())))
-                .next()
-                .is_none()
-        {
-            self.send_login_error("Your ip is blocked by the peer")
-                .await;
-            Self::post_alarm_audit(
-                AlarmAuditType::IpWhitelist, //"ip whitelist",
-                json!({ "ip":addr.ip() }),
-            );
-            return false;
+        let rules = whitelist::Rules::parse(&Config::get_option("whitelist"));
+        if rules.is_empty() {
+            return true;
+        }
+        for err in rules.errors.iter() {
+            log::warn!("Ignored whitelist entry, {}", err);
+        }
+        match rules.check(addr.ip(), whitelist::local_minute()).await {
+            whitelist::Decision::Allow(_, restrictions) => {
+                self.restrictions = restrictions;
+                self.keyboard &= !restrictions.keyboard;
+                self.clipboard &= !restrictions.clipboard;
+                self.file &= !restrictions.file;
+                self.audio &= !restrictions.audio;
+                self.restart &= !restrictions.restart;
+                self.recording &= !restrictions.recording;
+                self.block_input &= !restrictions.block_input;
+                true
+            }
+            whitelist::Decision::Deny(_) => {
+                self.send_login_error("Your ip is blocked by the peer")
+                    .await;
+                Self::post_alarm_audit(
+                    AlarmAuditType::IpWhitelist, //"ip whitelist",
+                    json!({ "ip":addr.ip() }),
+                );
+                false
+            }
         }
-        true
     }
 
     async fn on_open(&mut self, addr: SocketAddr) -> bool {
@@ -1086,7 +1222,6 @@ impl Connection {
         let mut msg_out = Message::new();
         msg_out.set_hash(self.hash.clone());
         self.send(msg_out).await;
-        self.get_api_server();
         self.post_conn_audit(json!({
             "ip": addr.ip(),
             "action": "new",
@@ -1094,32 +1229,13 @@ impl Connection {
         true
     }
 
-    fn get_api_server(&mut self) {
-        self.server_audit_conn = crate::get_audit_server(
-            Config::get_option("api-server"),
-            Config::get_option("custom-rendezvous-server"),
-            "conn".to_owned(),
-        );
-        self.server_audit_file = crate::get_audit_server(
-            Config::get_option("api-server"),
-            Config::get_option("custom-rendezvous-server"),
-            "file".to_owned(),
-        );
-    }
-
     fn post_conn_audit(&self, v: Value) {
-        if self.server_audit_conn.is_empty() {
-            return;
-        }
-        let url = self.server_audit_conn.clone();
         let mut v = v;
         v["id"] = json!(Config::get_id());
         v["uuid"] = json!(crate::encode64(hbb_common::get_uuid()));
         v["conn_id"] = json!(self.inner.id);
         v["session_id"] = json!(self.lr.session_id);
-        tokio::spawn(async move {
-            allow_err!(Self::post_audit_async(url, v).await);
-        });
+        audit::post(audit::AuditKind::Conn, v);
     }
 
     fn get_files_for_audit(job_type: fs::JobType, mut files: Vec<FileEntry>) -> Vec<(String, i64)> {
@@ -1145,10 +1261,6 @@ impl Connection {
         files: Vec<(String, i64)>,
         info: Value,
     ) {
-        if self.server_audit_file.is_empty() {
-            return;
-        }
-        let url = self.server_audit_file.clone();
         let file_num = files.len();
         let mut files = files;
         files.sort_by(|a, b| b.1.cmp(&a.1));
@@ -1168,33 +1280,16 @@ impl Connection {
             "is_file":is_file,
             "info":json!(info).to_string(),
         });
-        tokio::spawn(async move {
-            allow_err!(Self::post_audit_async(url, v).await);
-        });
+        audit::post(audit::AuditKind::File, v);
     }
 
     pub fn post_alarm_audit(typ: AlarmAuditType, info: Value) {
-        let url = crate::get_audit_server(
-            Config::get_option("api-server"),
-            Config::get_option("custom-rendezvous-server"),
-            "alarm".to_owned(),
-        );
-        if url.is_empty() {
-            return;
-        }
         let mut v = Value::default();
         v["id"] = json!(Config::get_id());
         v["uuid"] = json!(crate::encode64(hbb_common::get_uuid()));
         v["typ"] = json!(typ as i8);
         v["info"] = serde_json::Value::String(info.to_string());
-        tokio::spawn(async move {
-            allow_err!(Self::post_audit_async(url, v).await);
-        });
-    }
-
-    #[inline]
-    async fn post_audit_async(url: String, v: Value) -> ResultType<String> {
-        crate::post_request(url, v.to_string(), "").await
+        audit::post(audit::AuditKind::Alarm, v);
     }
 
     async fn send_logon_response(&mut self) {
@@ -1203,29 +1298,18 @@ impl Connection {
         }
         if self.require_2fa.is_some() && !self.is_recent_session(true) && !self.from_switch {
             self.require_2fa.as_ref().map(|totp| {
-                let bot = crate::auth_2fa::TelegramBot::get();
-                let bot = match bot {
-                    Ok(Some(bot)) => bot,
-                    Err(err) => {
-                        log::error!("Failed to get telegram bot: {}", err);
-                        return;
-                    }
-                    _ => return,
-                };
-                let code = totp.generate_current();
-                if let Ok(code) = code {
-                    let text = format!(
-                        "2FA code: {}\n\nA new connection has been established to your device with ID {}. The source IP address is {}.",
+                let notifiers = crate::auth_2fa::notifier::notifiers();
+                if notifiers.is_empty() {
+                    return;
+                }
+                if let Ok(code) = totp.generate_current() {
+                    let notification = crate::auth_2fa::notifier::Notification {
                         code,
-                        Config::get_id(),
-                        self.ip,
-                    );
+                        id: Config::get_id(),
+                        ip: self.ip.clone(),
+                    };
                     tokio::spawn(async move {
-                        if let Err(err) =
-                            crate::auth_2fa::send_2fa_code_to_telegram(&text, bot).await
-                        {
-                            log::error!("Failed to send 2fa code to telegram bot: {}", err);
-                        }
+                        crate::auth_2fa::notifier::notify(&notifiers, &notification).await;
                     });
                 }
             });
@@ -1543,6 +1627,7 @@ impl Connection {
                 if !self.audio_enabled() {
                     noperms.push(super::audio_service::NAME);
                 }
+                crate::audio_service::add_peer(self.inner.id(), &self.lr.version);
                 let mut s = s.write().unwrap();
                 #[cfg(not(any(target_os = "android", target_os = "ios")))]
                 let _h = try_start_record_cursor_pos();
@@ -1871,7 +1956,9 @@ impl Connection {
             }
             match lr.union {
                 Some(login_request::Union::FileTransfer(ft)) => {
-                    if !Connection::permission(keys::OPTION_ENABLE_FILE_TRANSFER) {
+                    if !Connection::permission(keys::OPTION_ENABLE_FILE_TRANSFER)
+                        || self.restrictions.file
+                    {
                         self.send_login_error("No permission of file transfer")
                             .await;
                         sleep(1.).await;
@@ -1889,7 +1976,7 @@ impl Connection {
                     self.view_camera = true;
                 }
                 Some(login_request::Union::PortForward(mut pf)) => {
-                    if !Connection::permission("enable-tunnel") {
+                    if !Connection::permission("enable-tunnel") || self.restrictions.tunnel {
                         self.send_login_error("No permission of IP tunneling").await;
                         sleep(1.).await;
                         return false;
@@ -1903,22 +1990,29 @@ impl Connection {
                     if pf.host.is_empty() {
                         pf.host = "localhost".to_owned();
                     }
-                    let mut addr = format!("{}:{}", pf.host, pf.port);
-                    self.port_forward_address = addr.clone();
-                    match timeout(3000, TcpStream::connect(&addr)).await {
-                        Ok(Ok(sock)) => {
-                            self.port_forward_socket = Some(Framed::new(sock, BytesCodec::new()));
-                        }
-                        _ => {
-                            if is_rdp {
-                                addr = "RDP".to_owned();
+                    if pf.host == crate::tunnel::TUNNEL_HOST && pf.port == 0 {
+                        self.port_forward_address = "tunnel".to_owned();
+                        self.port_forward_socket = Some(PortForwardSocket::Tunnel);
+                    } else {
+                        let mut addr = format!("{}:{}", pf.host, pf.port);
+                        self.port_forward_address = addr.clone();
+                        match timeout(3000, TcpStream::connect(&addr)).await {
+                            Ok(Ok(sock)) => {
+                                self.port_forward_socket = Some(PortForwardSocket::Tcp(
+                                    Framed::new(sock, BytesCodec::new()),
+                                ));
+                            }
+                            _ => {
+                                if is_rdp {
+                                    addr = "RDP".to_owned();
+                                }
+                                self.send_login_error(format!(
+                                    "Failed to access remote {}, please make sure if it is open",
+                                    addr
+                                ))
+                                .await;
+                                return false;
                             }
-                            self.send_login_error(format!(
-                                "Failed to access remote {}, please make sure if it is open",
-                                addr
-                            ))
-                            .await;
-                            return false;
                         }
                     }
                 }
@@ -2006,12 +2100,11 @@ impl Connection {
                     .await;
                 }
             } else {
-                let (failure, res) = self.check_failure(0).await;
-                if !res {
+                if !self.check_failure(login_failure::Kind::Password).await {
                     return true;
                 }
                 if !self.validate_password() {
-                    self.update_failure(failure, false, 0);
+                    self.update_failure(login_failure::Kind::Password, false);
                     if err_msg.is_empty() {
                         self.send_login_error(crate::client::LOGIN_MSG_PASSWORD_WRONG)
                             .await;
@@ -2023,7 +2116,7 @@ impl Connection {
                         .await;
                     }
                 } else {
-                    self.update_failure(failure, true, 0);
+                    self.update_failure(login_failure::Kind::Password, true);
                     if err_msg.is_empty() {
                         #[cfg(target_os = "linux")]
                         self.linux_headless_handle.wait_desktop_cm_ready().await;
@@ -2035,36 +2128,33 @@ impl Connection {
                 }
             }
         } else if let Some(message::Union::Auth2fa(tfa)) = msg.union {
-            let (failure, res) = self.check_failure(1).await;
-            if !res {
+            if !self.check_failure(login_failure::Kind::TwoFactor).await {
                 return true;
             }
             if let Some(totp) = self.require_2fa.as_ref() {
-                if let Ok(res) = totp.check_current(&tfa.code) {
-                    if res {
-                        self.update_failure(failure, true, 1);
-                        self.require_2fa.take();
-                        raii::AuthedConnID::set_session_2fa(self.session_key());
-                        self.send_logon_response().await;
-                        self.try_start_cm(
-                            self.lr.my_id.to_owned(),
-                            self.lr.my_name.to_owned(),
-                            self.authorized,
-                        );
-                        if !tfa.hwid.is_empty() && Self::enable_trusted_devices() {
-                            Config::add_trusted_device(TrustedDevice {
-                                hwid: tfa.hwid,
-                                time: hbb_common::get_time(),
-                                id: self.lr.my_id.clone(),
-                                name: self.lr.my_name.clone(),
-                                platform: self.lr.my_platform.clone(),
-                            });
-                        }
-                    } else {
-                        self.update_failure(failure, false, 1);
-                        self.send_login_error(crate::client::LOGIN_MSG_2FA_WRONG)
-                            .await;
+                if crate::auth_2fa::check_code(totp, &tfa.code) {
+                    self.update_failure(login_failure::Kind::TwoFactor, true);
+                    self.require_2fa.take();
+                    raii::AuthedConnID::set_session_2fa(self.session_key());
+                    self.send_logon_response().await;
+                    self.try_start_cm(
+                        self.lr.my_id.to_owned(),
+                        self.lr.my_name.to_owned(),
+                        self.authorized,
+                    );
+                    if !tfa.hwid.is_empty() && Self::enable_trusted_devices() {
+                        Config::add_trusted_device(TrustedDevice {
+                            hwid: tfa.hwid,
+                            time: hbb_common::get_time(),
+                            id: self.lr.my_id.clone(),
+                            name: self.lr.my_name.clone(),
+                            platform: self.lr.my_platform.clone(),
+                        });
                     }
+                } else {
+                    self.update_failure(login_failure::Kind::TwoFactor, false);
+                    self.send_login_error(crate::client::LOGIN_MSG_2FA_WRONG)
+                        .await;
                 }
             }
         } else if let Some(message::Union::TestDelay(t)) = msg.union {
@@ -2835,61 +2925,34 @@ impl Connection {
         true
     }
 
-    fn update_failure(&self, (mut failure, time): ((i32, i32, i32), i32), remove: bool, i: usize) {
-        if remove {
-            if failure.0 != 0 {
-                LOGIN_FAILURES[i].lock().unwrap().remove(&self.ip);
-            }
-            return;
-        }
-        if failure.0 == time {
-            failure.1 += 1;
-            failure.2 += 1;
-        } else {
-            failure.0 = time;
-            failure.1 = 1;
-            failure.2 += 1;
-        }
-        LOGIN_FAILURES[i]
-            .lock()
-            .unwrap()
-            .insert(self.ip.clone(), failure);
+    fn update_failure(&self, kind: login_failure::Kind, success: bool) {
+        login_failure::update(kind, &self.ip, &self.lr.my_id, success);
     }
 
-    async fn check_failure(&mut self, i: usize) -> (((i32, i32, i32), i32), bool) {
-        let failure = LOGIN_FAILURES[i]
-            .lock()
-            .unwrap()
-            .get(&self.ip)
-            .map(|x| x.clone())
-            .unwrap_or((0, 0, 0));
-        let time = (get_time() / 60_000) as i32;
-        let res = if failure.2 > 30 {
-            self.send_login_error("Too many wrong attempts").await;
-            Self::post_alarm_audit(
-                AlarmAuditType::ExceedThirtyAttempts,
-                json!({
-                            "ip": self.ip,
-                            "id": self.lr.my_id.clone(),
-                            "name": self.lr.my_name.clone(),
-                }),
-            );
-            false
-        } else if time == failure.0 && failure.1 > 6 {
-            self.send_login_error("Please try 1 minute later").await;
-            Self::post_alarm_audit(
-                AlarmAuditType::SixAttemptsWithinOneMinute,
-                json!({
-                            "ip": self.ip,
-                            "id": self.lr.my_id.clone(),
-                            "name": self.lr.my_name.clone(),
-                }),
-            );
-            false
-        } else {
-            true
-        };
-        ((failure, time), res)
+    async fn check_failure(&mut self, kind: login_failure::Kind) -> bool {
+        let info = json!({
+                    "ip": self.ip,
+                    "id": self.lr.my_id.clone(),
+                    "name": self.lr.my_name.clone(),
+        });
+        match login_failure::check(kind, &self.ip, &self.lr.my_id) {
+            login_failure::Verdict::Allowed => true,
+            login_failure::Verdict::Banned => {
+                self.send_login_error("Too many wrong attempts").await;
+                Self::post_alarm_audit(AlarmAuditType::ExceedThirtyAttempts, info);
+                false
+            }
+            login_failure::Verdict::TooFrequent => {
+                self.send_login_error("Please try 1 minute later").await;
+                Self::post_alarm_audit(AlarmAuditType::SixAttemptsWithinOneMinute, info);
+                false
+            }
+            login_failure::Verdict::BackOff(secs) => {
+                self.send_login_error(format!("Please try {} seconds later", secs))
+                    .await;
+                false
+            }
+        }
     }
 
     fn refresh_video_display(&self, display: Option<usize>) {
@@ -3490,6 +3553,7 @@ impl Connection {
         // We can add a (Vec<conn_id>, input device) to avoid this.
         // But it's not necessary now and we have to consider two audio services(client, server).
         crate::audio_service::set_voice_call_input_device(None, true);
+        crate::audio_service::remove_peer(self.inner.id());
         log::info!("#{} Connection closed: {}", self.inner.id(), reason);
         if lock && self.lock_after_session_end && self.keyboard {
             #[cfg(not(any(target_os = "android", target_os = "ios")))]
@@ -3767,6 +3831,21 @@ impl Connection {
     }
 }
 
+/// Connection manager messages that end a port forwarding session.
+fn check_port_forward_cm_data(data: ipc::Data) -> ResultType<()> {
+    match data {
+        ipc::Data::Close => {
+            bail!("Close requested from connection manager");
+        }
+        ipc::Data::CmErr(e) => {
+            log::error!("Connection manager error: {e}");
+            bail!("{e}");
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
 pub fn insert_switch_sides_uuid(id: String, uuid: uuid::Uuid) {
     SWITCH_SIDES_UUID
         .lock()
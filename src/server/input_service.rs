@@ -345,6 +345,16 @@ fn update_last_cursor_pos(x: i32, y: i32) {
     }
 }
 
+/// The latest system cursor position in desktop coordinates.
+pub fn latest_cursor_pos() -> Option<(i32, i32)> {
+    let (_, (x, y)) = *LATEST_SYS_CURSOR_POS.lock().unwrap();
+    if x == INVALID_CURSOR_POS || y == INVALID_CURSOR_POS {
+        None
+    } else {
+        Some((x, y))
+    }
+}
+
 fn run_pos(sp: EmptyExtraFieldService, state: &mut StatePos) -> ResultType<()> {
     let (_, (x, y)) = *LATEST_SYS_CURSOR_POS.lock().unwrap();
     if x == INVALID_CURSOR_POS || y == INVALID_CURSOR_POS {
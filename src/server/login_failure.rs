@@ -0,0 +1,424 @@
+use hbb_common::{
+    config::{self, Config},
+    get_time, log,
+};
+use serde_derive::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
+
+/// Wrong attempts allowed in one minute, 6 by default, 0 for no limit.
+pub const OPTION_FAILURES_PER_MINUTE: &str = "login-failures-per-minute";
+/// Wrong attempts before a source is banned, 30 by default, 0 for no limit.
+pub const OPTION_MAX_FAILURES: &str = "login-max-failures";
+/// Seconds a ban lasts after the last wrong attempt, a day by default, 0 for until unbanned.
+pub const OPTION_BAN_DURATION: &str = "login-ban-duration";
+/// Seconds to wait after the first wrong attempt, doubled on each following one, 0 (default) for none.
+/// The only limit on the peer id an attempt claims.
+pub const OPTION_BACKOFF: &str = "login-failure-backoff";
+/// Upper bound of the back-off in seconds, 3600 by default.
+pub const OPTION_BACKOFF_MAX: &str = "login-failure-backoff-max";
+
+const FILE_NAME: &str = "login_failures.toml";
+// Failures of sources that are not banned are forgotten after a day.
+const EXPIRE_MS: i64 = 24 * 3600 * 1000;
+// Changes within this delay are written to the file at once.
+const STORE_DELAY: Duration = Duration::from_secs(1);
+
+lazy_static::lazy_static! {
+    static ref RECORDS: Mutex<Option<Records>> = Default::default();
+}
+static STORE_PENDING: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    Password,
+    TwoFactor,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Policy {
+    pub per_minute: u32,
+    pub max_failures: u32,
+    pub ban_duration: u64,
+    pub backoff: u64,
+    pub backoff_max: u64,
+}
+
+impl Default for Policy {
+    fn default() -> Self {
+        Self {
+            per_minute: 6,
+            max_failures: 30,
+            ban_duration: 24 * 3600,
+            backoff: 0,
+            backoff_max: 3600,
+        }
+    }
+}
+
+impl Policy {
+    pub fn from_options() -> Self {
+        let default = Self::default();
+        let get = |key: &str, default: u64| {
+            let v = Config::get_option(key);
+            if v.is_empty() {
+                default
+            } else {
+                v.parse().unwrap_or(default)
+            }
+        };
+        Self {
+            per_minute: get(OPTION_FAILURES_PER_MINUTE, default.per_minute as _) as _,
+            max_failures: get(OPTION_MAX_FAILURES, default.max_failures as _) as _,
+            ban_duration: get(OPTION_BAN_DURATION, default.ban_duration),
+            backoff: get(OPTION_BACKOFF, default.backoff),
+            backoff_max: get(OPTION_BACKOFF_MAX, default.backoff_max),
+        }
+    }
+
+    fn banned(&self, failure: &Failure, now: i64) -> bool {
+        self.max_failures > 0
+            && failure.total > self.max_failures
+            && (self.ban_duration == 0
+                || now - failure.last < (self.ban_duration as i64).saturating_mul(1000))
+    }
+
+    fn verdict(&self, failure: &Failure, now: i64) -> Verdict {
+        if self.banned(failure, now) {
+            return Verdict::Banned;
+        }
+        if self.per_minute > 0
+            && failure.minute == now / 60_000
+            && failure.in_minute > self.per_minute
+        {
+            return Verdict::TooFrequent;
+        }
+        self.backoff(failure, now)
+    }
+
+    fn backoff(&self, failure: &Failure, now: i64) -> Verdict {
+        if self.backoff > 0 && failure.total > 0 {
+            let delay = self
+                .backoff
+                .saturating_mul(1 << (failure.total - 1).min(32))
+                .min(self.backoff_max)
+                * 1000;
+            let wait = failure.last + delay as i64 - now;
+            if wait > 0 {
+                return Verdict::BackOff(((wait + 999) / 1000) as _);
+            }
+        }
+        Verdict::Allowed
+    }
+}
+
+/// Why a login attempt is refused, or `Allowed`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Verdict {
+    Allowed,
+    /// Seconds left to wait.
+    BackOff(u64),
+    TooFrequent,
+    Banned,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Failure {
+    /// Minute of the last failure since the epoch.
+    pub minute: i64,
+    /// Failures within `minute`.
+    pub in_minute: u32,
+    /// Failures since the last success.
+    pub total: u32,
+    /// Time of the last failure in milliseconds.
+    pub last: i64,
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+struct Records {
+    #[serde(default)]
+    password: HashMap<String, Failure>,
+    #[serde(default)]
+    two_factor: HashMap<String, Failure>,
+}
+
+impl Records {
+    fn map(&mut self, kind: Kind) -> &mut HashMap<String, Failure> {
+        match kind {
+            Kind::Password => &mut self.password,
+            Kind::TwoFactor => &mut self.two_factor,
+        }
+    }
+
+    fn expire(&mut self, policy: &Policy, now: i64) {
+        for map in [&mut self.password, &mut self.two_factor] {
+            map.retain(|k, f| now - f.last < EXPIRE_MS || (is_ip_key(k) && policy.banned(f, now)));
+        }
+    }
+}
+
+fn with_records<R>(f: impl FnOnce(&mut Records) -> R) -> R {
+    let mut lock = RECORDS.lock().unwrap();
+    let records = lock.get_or_insert_with(|| config::load_path(Config::path(FILE_NAME)));
+    f(records)
+}
+
+// Write the records from a thread a moment later, so a burst of attempts
+// neither holds the lock on file writes nor rewrites the file every time.
+fn store_later() {
+    if STORE_PENDING.swap(true, Ordering::SeqCst) {
+        return;
+    }
+    std::thread::spawn(|| {
+        std::thread::sleep(STORE_DELAY);
+        STORE_PENDING.store(false, Ordering::SeqCst);
+        let policy = Policy::from_options();
+        let records = with_records(|records| {
+            records.expire(&policy, get_time());
+            records.clone()
+        });
+        if let Err(err) = config::store_path(Config::path(FILE_NAME), records) {
+            log::error!("Failed to store login failures: {}", err);
+        }
+    });
+}
+
+/// Key of the source ip of a login attempt.
+pub fn ip_key(ip: &str) -> String {
+    format!("ip:{}", ip)
+}
+
+/// Key of the peer id a login attempt claims.
+pub fn id_key(id: &str) -> String {
+    format!("id:{}", id)
+}
+
+fn is_ip_key(key: &str) -> bool {
+    key.starts_with("ip:")
+}
+
+fn keys(ip: &str, id: &str) -> Vec<String> {
+    let mut keys = vec![ip_key(ip)];
+    if !id.is_empty() {
+        keys.push(id_key(id));
+    }
+    keys
+}
+
+// Any client can claim a peer id, so the failures of an id only delay its
+// attempts with the back-off, they never ban it or lock it out for a minute.
+fn verdict(policy: &Policy, key: &str, failure: &Failure, now: i64) -> Verdict {
+    if is_ip_key(key) {
+        policy.verdict(failure, now)
+    } else {
+        policy.backoff(failure, now)
+    }
+}
+
+/// The most severe verdict of the source `ip` and the claimed peer `id`.
+pub fn check(kind: Kind, ip: &str, id: &str) -> Verdict {
+    let policy = Policy::from_options();
+    let now = get_time();
+    with_records(|records| {
+        let map = records.map(kind);
+        keys(ip, id)
+            .iter()
+            .filter_map(|k| map.get(k).map(|f| verdict(&policy, k, f, now)))
+            .max()
+            .unwrap_or(Verdict::Allowed)
+    })
+}
+
+/// Record the result of a login attempt from `ip` claiming the peer `id`, a
+/// success clears the failures of both.
+pub fn update(kind: Kind, ip: &str, id: &str, success: bool) {
+    let now = get_time();
+    let minute = now / 60_000;
+    let changed = with_records(|records| {
+        let map = records.map(kind);
+        let mut changed = false;
+        for key in keys(ip, id) {
+            if success {
+                changed |= map.remove(&key).is_some();
+                continue;
+            }
+            let f = map.entry(key).or_default();
+            if f.minute == minute {
+                f.in_minute += 1;
+            } else {
+                f.minute = minute;
+                f.in_minute = 1;
+            }
+            f.total += 1;
+            f.last = now;
+            changed = true;
+        }
+        changed
+    });
+    if changed {
+        store_later();
+    }
+}
+
+/// All tracked sources as a JSON array, for the admin view.
+pub fn list() -> String {
+    let policy = Policy::from_options();
+    let now = get_time();
+    with_records(|records| {
+        let mut v = Vec::new();
+        for (kind, map) in [
+            ("password", &records.password),
+            ("2fa", &records.two_factor),
+        ] {
+            for (key, f) in map {
+                v.push(serde_json::json!({
+                    "kind": kind,
+                    "key": key,
+                    "failures": f.total,
+                    "last": f.last,
+                    "verdict": format!("{:?}", verdict(&policy, key, f, now)),
+                }));
+            }
+        }
+        serde_json::to_string(&v).unwrap_or_default()
+    })
+}
+
+/// Forget the failures of `key` ("ip:..." or "id:..."), or of every source if it is empty.
+/// Returns the number of entries removed.
+pub fn unban(key: &str) -> usize {
+    let n = with_records(|records| {
+        let mut n = 0;
+        for map in [&mut records.password, &mut records.two_factor] {
+            let len = map.len();
+            map.retain(|k, _| !key.is_empty() && k != key);
+            n += len - map.len();
+        }
+        n
+    });
+    if n > 0 {
+        log::info!("Unbanned {} login failure entries of {:?}", n, key);
+        store_later();
+    }
+    n
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_verdict() {
+        let policy = Policy::default();
+        let now = 600_000;
+        let mut f = Failure {
+            minute: now / 60_000,
+            in_minute: 6,
+            total: 6,
+            last: now,
+        };
+        assert_eq!(policy.verdict(&f, now), Verdict::Allowed);
+        f.in_minute = 7;
+        f.total = 7;
+        assert_eq!(policy.verdict(&f, now), Verdict::TooFrequent);
+        assert_eq!(policy.verdict(&f, now + 60_000), Verdict::Allowed);
+        f.total = 31;
+        assert_eq!(policy.verdict(&f, now + 60_000), Verdict::Banned);
+        assert_eq!(policy.verdict(&f, now + EXPIRE_MS - 1), Verdict::Banned);
+        assert_eq!(policy.verdict(&f, now + EXPIRE_MS), Verdict::Allowed);
+        let forever = Policy {
+            ban_duration: 0,
+            ..Default::default()
+        };
+        assert_eq!(forever.verdict(&f, now + 10 * EXPIRE_MS), Verdict::Banned);
+
+        let policy = Policy {
+            backoff: 2,
+            backoff_max: 10,
+            ..Default::default()
+        };
+        f.in_minute = 1;
+        f.total = 1;
+        assert_eq!(policy.verdict(&f, now), Verdict::BackOff(2));
+        f.total = 3;
+        assert_eq!(policy.verdict(&f, now + 1_000), Verdict::BackOff(7));
+        f.total = 20;
+        assert_eq!(policy.verdict(&f, now), Verdict::BackOff(10));
+        assert_eq!(policy.verdict(&f, now + 10_000), Verdict::Allowed);
+    }
+
+    #[test]
+    fn test_id_verdict() {
+        let now = 600_000;
+        let f = Failure {
+            minute: now / 60_000,
+            in_minute: 40,
+            total: 40,
+            last: now,
+        };
+        let policy = Policy::default();
+        assert_eq!(
+            verdict(&policy, &ip_key("10.0.0.1"), &f, now),
+            Verdict::Banned
+        );
+        // A claimed id is never banned, only delayed by the back-off.
+        assert_eq!(
+            verdict(&policy, &id_key("123456789"), &f, now),
+            Verdict::Allowed
+        );
+        let policy = Policy {
+            backoff: 2,
+            backoff_max: 10,
+            ..Default::default()
+        };
+        assert_eq!(
+            verdict(&policy, &id_key("123456789"), &f, now),
+            Verdict::BackOff(10)
+        );
+        assert_eq!(keys("10.0.0.1", ""), [ip_key("10.0.0.1")]);
+        assert_eq!(
+            keys("10.0.0.1", "123456789"),
+            [ip_key("10.0.0.1"), id_key("123456789")]
+        );
+    }
+
+    #[test]
+    fn test_expire() {
+        let now = 10 * EXPIRE_MS;
+        let banned = Failure {
+            total: 31,
+            last: now - 1_000,
+            ..Default::default()
+        };
+        let failed = Failure { total: 1, ..banned };
+        let mut records = Records::default();
+        records.password.insert(ip_key("10.0.0.1"), banned);
+        records.password.insert(ip_key("10.0.0.2"), failed);
+        records.two_factor.insert(ip_key("10.0.0.1"), banned);
+        records.password.insert(id_key("123456789"), banned);
+        records.expire(&Policy::default(), now);
+        assert_eq!(records.password.len(), 3);
+        assert_eq!(records.two_factor.len(), 1);
+
+        // Only the bans of ips outlive a day, and only while they last.
+        let later = now + EXPIRE_MS;
+        let forever = Policy {
+            ban_duration: 0,
+            ..Default::default()
+        };
+        records.expire(&forever, later);
+        assert_eq!(
+            records.password.keys().collect::<Vec<_>>(),
+            [&ip_key("10.0.0.1")]
+        );
+        records.expire(&Policy::default(), later);
+        assert!(records.password.is_empty());
+        assert!(records.two_factor.is_empty());
+    }
+}
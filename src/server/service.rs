@@ -242,6 +242,23 @@ impl<T: Subscriber + From<ConnInner>> ServiceTmpl<T> {
         conn_ids
     }
 
+    // Send to the subscribers `include` accepts only, e.g. those of one encoding tier.
+    pub fn send_video_frame_to<F>(&self, msg: Message, include: F) -> HashSet<i32>
+    where
+        F: Fn(i32) -> bool,
+    {
+        let msg = Arc::new(msg);
+        let mut conn_ids = HashSet::new();
+        let mut lock = self.0.write().unwrap();
+        for s in lock.subscribes.values_mut() {
+            if include(s.id()) {
+                s.send(msg.clone());
+                conn_ids.insert(s.id());
+            }
+        }
+        conn_ids
+    }
+
     pub fn send_without(&self, msg: Message, sub: i32) {
         let mut lock = self.0.write().unwrap();
         let msg = Arc::new(msg);
@@ -1,20 +1,26 @@
 use super::*;
-use scrap::codec::{Quality, BR_BALANCED, BR_BEST, BR_SPEED};
+use scrap::codec::{Quality, BR_BALANCED, BR_BEST, BR_SPEED, IMAGE_QUALITY_LOSSLESS};
 use std::{
-    collections::VecDeque,
+    collections::{HashSet, VecDeque},
     time::{Duration, Instant},
 };
 
+mod bwe;
+#[cfg(test)]
+mod sim;
+
+pub use bwe::BweStats;
+
 /*
 FPS adjust:
 a. new user connected =>set to INIT_FPS
 b. TestDelay receive => update user's fps according to network delay
     When network delay < DELAY_THRESHOLD_150MS, set minimum fps according to image quality, and increase fps;
     When network delay >= DELAY_THRESHOLD_150MS, set minimum fps according to image quality, and decrease fps;
-c. second timeout / TestDelay receive => update real fps to the minimum fps from all users
+c. second timeout / TestDelay receive => update real fps to the minimum fps from all users of the tier
 
 ratio adjust:
-a. user set image quality => update to the maximum ratio of the latest quality
+a. user set image quality => update to the maximum ratio of the latest quality of the tier
 b. 3 seconds timeout => update ratio according to network delay
     When network delay < DELAY_THRESHOLD_150MS, increase ratio, max 150kbps;
     When network delay >= DELAY_THRESHOLD_150MS, decrease ratio;
@@ -25,6 +31,22 @@ adjust betwen FPS and ratio:
 
 delay:
     use delay minus RTT as the actual network delay
+
+bandwidth estimation:
+    every sent video frame feeds the estimator of its connection, see bwe.rs;
+    once any has an estimate, it replaces the delay thresholds for the ratio:
+    on overuse, lower the ratio to the lowest estimate within BWE_ADJUST_INTERVAL;
+    otherwise raise it up to the estimate if the encoder output is not limited by the screen
+
+tiers:
+    every display is encoded once per tier in use, fps and ratio above are adjusted
+    per tier from the users of the tier only, so a slow viewer does not slow down the others;
+    a user whose estimate is below LOW_TIER_ENTER of the best one of the high tier moves to
+    the low tier, and back once the low tier has almost caught up with the high one and its
+    estimate covers the bitrate of the high tier,
+    at most once per TIER_HOLD; the low tier never runs faster than the high one;
+    a user joining a tier needs a key frame, forced on the encoder of the tier,
+    which restarts only if it can not force one
 */
 
 // Constants
@@ -43,6 +65,28 @@ const HISTORY_DELAY_LEN: usize = 2;
 const ADJUST_RATIO_INTERVAL: usize = 3; // Adjust quality ratio every 3 seconds
 const DYNAMIC_SCREEN_THRESHOLD: usize = 2; // Allow increase quality ratio if encode more than 2 times in one second
 const DELAY_THRESHOLD_150MS: u32 = 150; // 150ms is the threshold for good network condition
+const BWE_ADJUST_INTERVAL: Duration = Duration::from_millis(200);
+const BWE_MIN_CHANGE: f32 = 0.05; // Ignore ratio changes below 5% to avoid resetting the encoder too often
+const BWE_APP_LIMITED: f64 = 0.5; // Sending below half of the encoder bitrate means the screen is the limit
+const LOW_TIER_ENTER: f64 = 0.75; // Move to the low tier below 3/4 of the best estimate of the high tier
+const LOW_TIER_LEAVE: f32 = 0.9; // Back to the high tier once the low one has almost caught up
+const TIER_HOLD: Duration = Duration::from_secs(10); // Every switch costs a key frame
+
+/// Encoding tier of a connection, every display is encoded once per tier in use.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Tier {
+    #[default]
+    High,
+    Low,
+}
+
+impl Tier {
+    pub const ALL: [Tier; 2] = [Tier::High, Tier::Low];
+
+    fn index(self) -> usize {
+        self as usize
+    }
+}
 
 #[derive(Default, Debug, Clone)]
 struct UserDelay {
@@ -92,37 +136,63 @@ struct UserData {
     custom_fps: Option<u32>,
     quality: Option<(i64, Quality)>, // (time, quality)
     delay: UserDelay,
+    bwe: bwe::BandwidthEstimator,
     record: bool,
+    tier: Tier,
+    tier_instant: Option<Instant>, // last tier switch
 }
 
 #[derive(Default, Debug, Clone)]
 struct DisplayData {
     send_counter: usize, // Number of times encode during period
     support_changing_quality: bool,
+    support_simulcast: Option<bool>, // None until the encoder is created
 }
 
-// Main QoS controller structure
-pub struct VideoQoS {
+// Encoding state of one tier
+#[derive(Debug, Clone)]
+struct TierData {
     fps: u32,
     ratio: f32,
-    users: HashMap<i32, UserData>,
-    displays: HashMap<String, DisplayData>,
     bitrate_store: u32,
+    bitrate_ratio: f32, // ratio when bitrate_store is stored
     adjust_ratio_instant: Instant,
+    bwe_adjust_instant: Option<Instant>,
+    joins: usize, // Number of users joined the tier
+}
+
+impl Default for TierData {
+    fn default() -> Self {
+        TierData {
+            fps: FPS,
+            ratio: BR_BALANCED,
+            bitrate_store: 0,
+            bitrate_ratio: BR_BALANCED,
+            adjust_ratio_instant: Instant::now(),
+            bwe_adjust_instant: None,
+            joins: 0,
+        }
+    }
+}
+
+// Main QoS controller structure
+pub struct VideoQoS {
+    tiers: [TierData; 2],
+    users: HashMap<i32, UserData>,
+    displays: HashMap<String, DisplayData>,
     abr_config: bool,
+    simulcast_config: bool,
     new_user_instant: Instant,
 }
 
 impl Default for VideoQoS {
     fn default() -> Self {
         VideoQoS {
-            fps: FPS,
-            ratio: BR_BALANCED,
+            tiers: Default::default(),
             users: Default::default(),
             displays: Default::default(),
-            bitrate_store: 0,
-            adjust_ratio_instant: Instant::now(),
             abr_config: true,
+            simulcast_config: true,
             new_user_instant: Instant::now(),
         }
     }
@@ -131,13 +201,13 @@ impl Default for VideoQoS {
 // Basic functionality
 impl VideoQoS {
     // Calculate seconds per frame based on current FPS
-    pub fn spf(&self) -> Duration {
-        Duration::from_secs_f32(1. / (self.fps() as f32))
+    pub fn spf(&self, tier: Tier) -> Duration {
+        Duration::from_secs_f32(1. / (self.fps(tier) as f32))
     }
 
     // Get current FPS within valid range
-    pub fn fps(&self) -> u32 {
-        let fps = self.fps;
+    pub fn fps(&self, tier: Tier) -> u32 {
+        let fps = self.tiers[tier.index()].fps;
         if fps >= MIN_FPS && fps <= MAX_FPS {
             fps
         } else {
@@ -146,21 +216,29 @@ impl VideoQoS {
     }
 
     // Store bitrate for later use
-    pub fn store_bitrate(&mut self, bitrate: u32) {
-        self.bitrate_store = bitrate;
+    pub fn store_bitrate(&mut self, tier: Tier, bitrate: u32) {
+        let data = &mut self.tiers[tier.index()];
+        data.bitrate_store = bitrate;
+        data.bitrate_ratio = data.ratio;
     }
 
     // Get stored bitrate
-    pub fn bitrate(&self) -> u32 {
-        self.bitrate_store
+    pub fn bitrate(&self, tier: Tier) -> u32 {
+        self.tiers[tier.index()].bitrate_store
+    }
+
+    // Get stored bitrate of the tier of a user
+    pub fn user_bitrate(&self, id: i32) -> u32 {
+        self.bitrate(self.user_tier(id))
     }
 
     // Get current bitrate ratio with bounds checking
-    pub fn ratio(&mut self) -> f32 {
-        if self.ratio < BR_MIN_HIGH_RESOLUTION || self.ratio > BR_MAX {
-            self.ratio = BR_BALANCED;
+    pub fn ratio(&mut self, tier: Tier) -> f32 {
+        let data = &mut self.tiers[tier.index()];
+        if data.ratio < BR_MIN_HIGH_RESOLUTION || data.ratio > BR_MAX {
+            data.ratio = BR_BALANCED;
         }
-        self.ratio
+        data.ratio
     }
 
     // Check if any user is in recording mode
@@ -168,16 +246,70 @@ impl VideoQoS {
         self.users.iter().any(|u| u.1.record)
     }
 
+    // Lossless is asked by the latest quality of the high tier, the low tier stays lossy
+    pub fn lossless(&self) -> bool {
+        self.latest_quality(Tier::High) == Quality::Lossless
+    }
+
     pub fn set_support_changing_quality(&mut self, video_service_name: &str, support: bool) {
         if let Some(display) = self.displays.get_mut(video_service_name) {
             display.support_changing_quality = support;
         }
     }
 
+    // Whether the display can encode the low tier besides the high one
+    pub fn set_support_simulcast(&mut self, video_service_name: &str, support: bool) {
+        if let Some(display) = self.displays.get_mut(video_service_name) {
+            display.support_simulcast = Some(support);
+        }
+        if !support {
+            self.assign_tiers(Instant::now());
+        }
+    }
+
     // Check if variable bitrate encoding is supported and enabled
     pub fn in_vbr_state(&self) -> bool {
         self.abr_config && self.displays.iter().all(|e| e.1.support_changing_quality)
     }
+
+    pub fn user_tier(&self, id: i32) -> Tier {
+        self.users.get(&id).map(|u| u.tier).unwrap_or_default()
+    }
+
+    // Connections receiving the frames of a tier
+    pub fn tier_users(&self, tier: Tier) -> HashSet<i32> {
+        self.users
+            .iter()
+            .filter(|u| u.1.tier == tier)
+            .map(|u| *u.0)
+            .collect()
+    }
+
+    // Changes when users join the tier, its encoders then send them a key frame
+    pub fn tier_joins(&self, tier: Tier) -> usize {
+        self.tiers[tier.index()].joins
+    }
+}
+
+fn convert_quality(q: i32) -> Quality {
+    if q == ImageQuality::Balanced.value() {
+        Quality::Balanced
+    } else if q == ImageQuality::Low.value() {
+        Quality::Low
+    } else if q == ImageQuality::Best.value() {
+        Quality::Best
+    } else if q & IMAGE_QUALITY_LOSSLESS != 0 {
+        Quality::Lossless
+    } else {
+        custom_quality(q)
+    }
+}
+
+// Hosts without lossless support only read these bits, so they see the
+// lossless flag as the custom quality below it.
+fn custom_quality(q: i32) -> Quality {
+    let b = ((q >> 8 & 0xFFF) * 2) as f32 / 100.0;
+    Quality::Custom(b.clamp(BR_MIN, BR_MAX))
 }
 
 // User session management
@@ -186,6 +318,7 @@ impl VideoQoS {
     pub fn on_connection_open(&mut self, id: i32) {
         self.users.insert(id, UserData::default());
         self.abr_config = Config::get_option("enable-abr") != "N";
+        self.simulcast_config = Config::get_option("enable-simulcast") != "N";
         self.new_user_instant = Instant::now();
     }
 
@@ -194,6 +327,8 @@ impl VideoQoS {
         self.users.remove(&id);
         if self.users.is_empty() {
             *self = Default::default();
+        } else {
+            self.assign_tiers(Instant::now());
         }
     }
 
@@ -216,24 +351,12 @@ impl VideoQoS {
     }
 
     pub fn user_image_quality(&mut self, id: i32, image_quality: i32) {
-        let convert_quality = |q: i32| -> Quality {
-            if q == ImageQuality::Balanced.value() {
-                Quality::Balanced
-            } else if q == ImageQuality::Low.value() {
-                Quality::Low
-            } else if q == ImageQuality::Best.value() {
-                Quality::Best
-            } else {
-                let b = ((q >> 8 & 0xFFF) * 2) as f32 / 100.0;
-                Quality::Custom(b.clamp(BR_MIN, BR_MAX))
-            }
-        };
-
         let quality = Some((hbb_common::get_time(), convert_quality(image_quality)));
         if let Some(user) = self.users.get_mut(&id) {
             user.quality = quality;
+            let tier = user.tier;
             // update ratio directly
-            self.ratio = self.latest_quality().ratio();
+            self.tiers[tier.index()].ratio = self.latest_quality(tier).ratio();
         }
     }
 
@@ -244,8 +367,9 @@ impl VideoQoS {
     }
 
     pub fn user_network_delay(&mut self, id: i32, delay: u32) {
-        let highest_fps = self.highest_fps();
-        let target_ratio = self.latest_quality().ratio();
+        let tier = self.user_tier(id);
+        let highest_fps = self.highest_fps(tier);
+        let target_ratio = self.latest_quality(tier).ratio();
 
         // For bad network, small fps means quick reaction and high quality
         let (min_fps, normal_fps) = if target_ratio >= BR_BEST {
@@ -261,12 +385,13 @@ impl VideoQoS {
 
         let mut adjust_ratio = false;
         if let Some(user) = self.users.get_mut(&id) {
+            user.bwe.on_rtt(delay);
             let delay = delay.max(10);
             let old_avg_delay = user.delay.avg_delay();
             user.delay.add_delay(delay);
             let mut avg_delay = user.delay.avg_delay();
             avg_delay = avg_delay.max(10);
-            let mut fps = self.fps;
+            let mut fps = self.tiers[tier.index()].fps;
 
             // Adaptive FPS adjustment based on network delay:
             if avg_delay < 50 {
@@ -327,12 +452,51 @@ impl VideoQoS {
             user.delay.fps = Some(fps);
         }
         self.adjust_fps();
-        if adjust_ratio && !cfg!(target_os = "linux") {
+        if adjust_ratio && !cfg!(target_os = "linux") && !self.bwe_active(tier) {
             //Reduce the possibility of vaapi being created twice
-            self.adjust_ratio(false);
+            self.adjust_ratio(tier, false);
+        }
+    }
+
+    pub fn user_video_frame_sent(&mut self, id: i32, bytes: usize, queue_delay: Duration) {
+        self.user_video_frame_sent_at(id, bytes, queue_delay, Instant::now());
+    }
+
+    fn user_video_frame_sent_at(
+        &mut self,
+        id: i32,
+        bytes: usize,
+        queue_delay: Duration,
+        now: Instant,
+    ) {
+        let Some(user) = self.users.get_mut(&id) else {
+            return;
+        };
+        let old_signal = user.bwe.signal();
+        user.bwe.on_frame_sent(now, bytes, queue_delay);
+        let overuse = user.bwe.signal() == bwe::Signal::Overuse;
+        if overuse && old_signal != bwe::Signal::Overuse {
+            log::debug!("bwe overuse of {}: {:?}", id, user.bwe.stats());
+        }
+        let tier = user.tier;
+        if (overuse && old_signal != bwe::Signal::Overuse)
+            || self.tiers[tier.index()]
+                .bwe_adjust_instant
+                .map_or(true, |t| now.duration_since(t) >= BWE_ADJUST_INTERVAL)
+        {
+            self.assign_tiers(now);
+            self.adjust_ratio_by_estimate(self.user_tier(id), now);
         }
     }
 
+    /// Bandwidth estimation state of every connection.
+    pub fn bwe_stats(&self) -> Vec<(i32, BweStats)> {
+        self.users
+            .iter()
+            .map(|(id, u)| (*id, u.bwe.stats()))
+            .collect()
+    }
+
     pub fn user_delay_response_elapsed(&mut self, id: i32, elapsed: u128) {
         if let Some(user) = self.users.get_mut(&id) {
             user.delay.response_delayed = elapsed > 2000;
@@ -362,23 +526,42 @@ impl VideoQoS {
         self.adjust_fps();
         let abr_enabled = self.in_vbr_state();
         if abr_enabled {
-            if self.adjust_ratio_instant.elapsed().as_secs() >= ADJUST_RATIO_INTERVAL as u64 {
-                let dynamic_screen = self
-                    .displays
-                    .iter()
-                    .any(|d| d.1.send_counter >= ADJUST_RATIO_INTERVAL * DYNAMIC_SCREEN_THRESHOLD);
+            let dynamic_screen = self
+                .displays
+                .iter()
+                .any(|d| d.1.send_counter >= ADJUST_RATIO_INTERVAL * DYNAMIC_SCREEN_THRESHOLD);
+            let mut reset_counter = false;
+            for tier in Tier::ALL {
+                if !self.users.values().any(|u| u.tier == tier) {
+                    continue;
+                }
+                if self.bwe_active(tier) {
+                    // The ratio follows the estimate as frames are sent.
+                    reset_counter = true;
+                } else if self.tiers[tier.index()]
+                    .adjust_ratio_instant
+                    .elapsed()
+                    .as_secs()
+                    >= ADJUST_RATIO_INTERVAL as u64
+                {
+                    reset_counter = true;
+                    self.adjust_ratio(tier, dynamic_screen);
+                }
+            }
+            if reset_counter {
                 self.displays.iter_mut().for_each(|d| {
                     d.1.send_counter = 0;
                 });
-                self.adjust_ratio(dynamic_screen);
             }
         } else {
-            self.ratio = self.latest_quality().ratio();
+            for tier in Tier::ALL {
+                self.tiers[tier.index()].ratio = self.latest_quality(tier).ratio();
+            }
         }
     }
 
     #[inline]
-    fn highest_fps(&self) -> u32 {
+    fn highest_fps(&self, tier: Tier) -> u32 {
         let user_fps = |u: &UserData| {
             let mut fps = u.custom_fps.unwrap_or(FPS);
             if let Some(auto_adjust_fps) = u.auto_adjust_fps {
@@ -392,6 +575,7 @@ impl VideoQoS {
         let fps = self
             .users
             .iter()
+            .filter(|(_, u)| u.tier == tier)
             .map(|(_, u)| user_fps(u))
             .filter(|u| *u >= MIN_FPS)
             .min()
@@ -400,10 +584,11 @@ impl VideoQoS {
         fps.clamp(MIN_FPS, MAX_FPS)
     }
 
-    // Get latest quality settings from all users
-    pub fn latest_quality(&self) -> Quality {
+    // Get latest quality settings from all users of a tier
+    pub fn latest_quality(&self, tier: Tier) -> Quality {
         self.users
             .iter()
+            .filter(|(_, u)| u.tier == tier)
             .map(|(_, u)| u.quality)
             .filter(|q| *q != None)
             .max_by(|a, b| a.unwrap_or_default().0.cmp(&b.unwrap_or_default().0))
@@ -412,21 +597,12 @@ impl VideoQoS {
             .1
     }
 
-    // Adjust quality ratio based on network delay and screen changes
-    fn adjust_ratio(&mut self, dynamic_screen: bool) {
-        if !self.in_vbr_state() {
-            return;
-        }
-        // Get maximum delay from all users
-        let max_delay = self.users.iter().map(|u| u.1.delay.avg_delay()).max();
-        let Some(max_delay) = max_delay else {
-            return;
-        };
-
-        let target_quality = self.latest_quality();
-        let target_ratio = self.latest_quality().ratio();
-        let current_ratio = self.ratio;
-        let current_bitrate = self.bitrate();
+    // Ratio range of the latest quality
+    fn ratio_bounds(&self, tier: Tier) -> (f32, f32) {
+        let target_quality = self.latest_quality(tier);
+        let target_ratio = self.latest_quality(tier).ratio();
+        let current_ratio = self.tiers[tier.index()].ratio;
+        let current_bitrate = self.bitrate(tier);
 
         // Calculate minimum ratio for high resolution (1Mbps baseline)
         let ratio_1mbps = if current_bitrate > 0 {
@@ -435,16 +611,9 @@ impl VideoQoS {
             None
         };
 
-        // Calculate ratio for adding 150kbps bandwidth
-        let ratio_add_150kbps = if current_bitrate > 0 {
-            Some((current_bitrate + 150) as f32 * current_ratio / current_bitrate as f32)
-        } else {
-            None
-        };
-
         // Set minimum ratio based on quality mode
         let min = match target_quality {
-            Quality::Best => {
+            Quality::Best | Quality::Lossless => {
                 // For Best quality, ensure minimum 1Mbps for high resolution
                 let mut min = BR_BEST / 2.5;
                 if let Some(ratio_1mbps) = ratio_1mbps {
@@ -467,6 +636,156 @@ impl VideoQoS {
             Quality::Custom(_) => BR_MIN_HIGH_RESOLUTION,
         };
         let max = target_ratio * MAX_BR_MULTIPLE;
+        (min, max)
+    }
+
+    fn bwe_active(&self, tier: Tier) -> bool {
+        self.users
+            .values()
+            .any(|u| u.tier == tier && u.bwe.estimate().is_some())
+    }
+
+    // Move users between the tiers according to their bandwidth estimates
+    fn assign_tiers(&mut self, now: Instant) {
+        let high_users = self.users.values().filter(|u| u.tier == Tier::High);
+        let unsupported = !self.simulcast_config
+            || self
+                .displays
+                .values()
+                .any(|d| d.support_simulcast == Some(false));
+        let mut moves = vec![];
+        if unsupported || high_users.clone().next().is_none() {
+            moves = self
+                .users
+                .iter()
+                .filter(|u| u.1.tier == Tier::Low)
+                .map(|u| (*u.0, Tier::High))
+                .collect();
+        } else if self
+            .displays
+            .values()
+            .all(|d| d.support_simulcast == Some(true))
+        {
+            let best = high_users
+                .filter_map(|u| u.bwe.estimate())
+                .max_by(|a, b| a.total_cmp(b));
+            let high_bitrate = self.bitrate(Tier::High) as f64;
+            let caught_up =
+                self.bitrate(Tier::Low) as f32 >= self.bitrate(Tier::High) as f32 * LOW_TIER_LEAVE;
+            for (id, u) in self.users.iter() {
+                if u.tier_instant
+                    .map_or(false, |t| now.duration_since(t) < TIER_HOLD)
+                {
+                    continue;
+                }
+                let Some(estimate) = u.bwe.estimate() else {
+                    continue;
+                };
+                let to = match u.tier {
+                    Tier::High if best.map_or(false, |b| estimate < b * LOW_TIER_ENTER) => {
+                        Tier::Low
+                    }
+                    Tier::Low if caught_up && estimate >= high_bitrate => Tier::High,
+                    _ => continue,
+                };
+                moves.push((*id, to));
+            }
+        }
+        if moves.is_empty() {
+            return;
+        }
+        let low_was_empty = !self.users.values().any(|u| u.tier == Tier::Low);
+        for (id, tier) in moves {
+            if let Some(u) = self.users.get_mut(&id) {
+                log::info!("move {} to the {:?} tier, {:?}", id, tier, u.bwe.stats());
+                u.tier = tier;
+                u.tier_instant = Some(now);
+                self.tiers[tier.index()].joins += 1;
+            }
+        }
+        let low_estimate = self
+            .users
+            .values()
+            .filter(|u| u.tier == Tier::Low)
+            .filter_map(|u| u.bwe.estimate())
+            .min_by(|a, b| a.total_cmp(b));
+        if !self.users.values().any(|u| u.tier == Tier::Low) {
+            let joins = self.tier_joins(Tier::Low);
+            self.tiers[Tier::Low.index()] = TierData {
+                joins,
+                ..Default::default()
+            };
+        } else if low_was_empty {
+            // Start the low tier at the estimate
+            let high = &self.tiers[Tier::High.index()];
+            if let (Some(estimate), true) = (low_estimate, high.bitrate_store > 0) {
+                let ratio = high.bitrate_ratio * estimate as f32 / high.bitrate_store as f32;
+                let (min, max) = self.ratio_bounds(Tier::Low);
+                self.tiers[Tier::Low.index()].ratio = ratio.clamp(min, max);
+            }
+        }
+        self.adjust_fps();
+    }
+
+    // Follow the lowest bandwidth estimate of all users of a tier
+    fn adjust_ratio_by_estimate(&mut self, tier: Tier, now: Instant) {
+        let data = &self.tiers[tier.index()];
+        if !self.in_vbr_state() || data.bitrate_store == 0 {
+            return;
+        }
+        let users = || self.users.values().filter(|u| u.tier == tier);
+        let estimates = users().filter_map(|u| Some((u.bwe.estimate()?, u.bwe.stats())));
+        let Some((estimate, stats)) = estimates.min_by(|a, b| a.0.total_cmp(&b.0)) else {
+            return;
+        };
+        let target = data.bitrate_ratio * estimate as f32 / data.bitrate_store as f32;
+        let overuse = users().any(|u| u.bwe.signal() == bwe::Signal::Overuse);
+        let app_limited =
+            (stats.send_rate_kbps as f64) < data.bitrate_store as f64 * BWE_APP_LIMITED;
+        let (min, max) = self.ratio_bounds(tier);
+        let current = data.ratio;
+        let data = &mut self.tiers[tier.index()];
+        data.bwe_adjust_instant = Some(now);
+        let v = if overuse {
+            target.min(current)
+        } else if !app_limited && target > current {
+            target
+        } else {
+            return;
+        }
+        .clamp(min, max);
+        if (v - current).abs() >= current * BWE_MIN_CHANGE {
+            data.ratio = v;
+        }
+    }
+
+    // Adjust quality ratio based on network delay and screen changes
+    fn adjust_ratio(&mut self, tier: Tier, dynamic_screen: bool) {
+        if !self.in_vbr_state() {
+            return;
+        }
+        // Get maximum delay from all users of the tier
+        let max_delay = self
+            .users
+            .iter()
+            .filter(|u| u.1.tier == tier)
+            .map(|u| u.1.delay.avg_delay())
+            .max();
+        let Some(max_delay) = max_delay else {
+            return;
+        };
+
+        let current_ratio = self.tiers[tier.index()].ratio;
+        let current_bitrate = self.bitrate(tier);
+
+        // Calculate ratio for adding 150kbps bandwidth
+        let ratio_add_150kbps = if current_bitrate > 0 {
+            Some((current_bitrate + 150) as f32 * current_ratio / current_bitrate as f32)
+        } else {
+            None
+        };
+
+        let (min, max) = self.ratio_bounds(tier);
 
         let mut v = current_ratio;
 
@@ -503,36 +822,43 @@ impl VideoQoS {
             }
         }
 
-        self.ratio = v.clamp(min, max);
-        self.adjust_ratio_instant = Instant::now();
+        let data = &mut self.tiers[tier.index()];
+        data.ratio = v.clamp(min, max);
+        data.adjust_ratio_instant = Instant::now();
     }
 
-    // Adjust fps based on network delay and user response time
+    // Adjust fps of every tier based on network delay and user response time
     fn adjust_fps(&mut self) {
-        let highest_fps = self.highest_fps();
-        // Get minimum fps from all users
-        let mut fps = self
-            .users
-            .iter()
-            .map(|u| u.1.delay.fps.unwrap_or(INIT_FPS))
-            .min()
-            .unwrap_or(INIT_FPS);
+        for tier in Tier::ALL {
+            let highest_fps = self.highest_fps(tier);
+            let users = || self.users.values().filter(|u| u.tier == tier);
+            // Get minimum fps from all users of the tier
+            let mut fps = users()
+                .map(|u| u.delay.fps.unwrap_or(INIT_FPS))
+                .min()
+                .unwrap_or(INIT_FPS);
+
+            if users().any(|u| u.delay.response_delayed) {
+                if fps > MIN_FPS + 1 {
+                    fps = MIN_FPS + 1;
+                }
+            }
 
-        if self.users.iter().any(|u| u.1.delay.response_delayed) {
-            if fps > MIN_FPS + 1 {
-                fps = MIN_FPS + 1;
+            // For new connections (within 1 second), cap fps to INIT_FPS to ensure stability
+            if self.new_user_instant.elapsed().as_secs() < 1 {
+                if fps > INIT_FPS {
+                    fps = INIT_FPS;
+                }
             }
-        }
 
-        // For new connections (within 1 second), cap fps to INIT_FPS to ensure stability
-        if self.new_user_instant.elapsed().as_secs() < 1 {
-            if fps > INIT_FPS {
-                fps = INIT_FPS;
+            // Ensure fps stays within valid range
+            let mut fps = fps.clamp(MIN_FPS, highest_fps);
+            // The low tier is encoded from the frames captured for the high tier
+            if tier == Tier::Low {
+                fps = fps.min(self.tiers[Tier::High.index()].fps);
             }
+            self.tiers[tier.index()].fps = fps;
         }
-
-        // Ensure fps stays within valid range
-        self.fps = fps.clamp(MIN_FPS, highest_fps);
     }
 }
 
@@ -593,3 +919,17 @@ impl RttCalculator {
         None
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_convert_quality() {
+        let lossless = IMAGE_QUALITY_LOSSLESS | 100 << 8;
+        assert_eq!(convert_quality(lossless), Quality::Lossless);
+        assert_eq!(custom_quality(lossless), Quality::Custom(2.0));
+        assert_eq!(convert_quality(100 << 8), Quality::Custom(2.0));
+        assert_eq!(convert_quality(ImageQuality::Best.value()), Quality::Best);
+    }
+}
@@ -0,0 +1,295 @@
+/*
+Bandwidth estimation of one connection, after GCC (draft-ietf-rmcat-gcc) with a
+BBR-like standing queue check.
+
+signals:
+    queue delay of every video frame, from queuing in the connection to the socket
+    accepting it, grows when the send rate exceeds the path; TestDelay rtt above the
+    minimum rtt shows a queue standing in the network.
+
+delay gradient:
+    a least squares trend over the smoothed queue delay of the last frames, in ms
+    per second, compared with an adaptive threshold => overuse / normal / underuse.
+
+rate control:
+    overuse => decrease to BETA * measured send rate
+    underuse => hold
+    normal => increase, multiplicative far from the rate of the last decrease,
+              additive near it, never above 1.5 * send rate + 200kbps
+*/
+
+use serde_derive::Serialize;
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+const RATE_WINDOW: Duration = Duration::from_millis(1000);
+const MIN_RATE_SPAN: Duration = Duration::from_millis(300);
+const MIN_FRAMES: usize = 10;
+const TREND_WINDOW: usize = 20;
+const SMOOTHING: f64 = 0.9;
+const INIT_THRESHOLD: f64 = 30.0; // ms/s
+const MIN_THRESHOLD: f64 = 10.0;
+const MAX_THRESHOLD: f64 = 300.0;
+const K_UP: f64 = 0.01; // per ms
+const K_DOWN: f64 = 0.00018;
+const OVERUSE_TIME: Duration = Duration::from_millis(100);
+// A queue delay above this is overuse whatever the gradient.
+const MAX_QUEUE_DELAY: f64 = 200.0;
+// A TestDelay rtt this much above the minimum is overuse.
+const MAX_RTT_INFLATION: u32 = 250;
+const BETA: f64 = 0.85;
+const DECREASE_INTERVAL: Duration = Duration::from_millis(300);
+const MULTIPLICATIVE_INCREASE: f64 = 1.08; // per second
+const ADDITIVE_INCREASE: f64 = 100.0; // kbps per second
+const MIN_ESTIMATE: f64 = 100.0; // kbps
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum Signal {
+    #[default]
+    Normal,
+    Overuse,
+    Underuse,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum RateState {
+    #[default]
+    Hold,
+    Increase,
+    Decrease,
+}
+
+/// What the estimator currently sees, for logs and tuning.
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct BweStats {
+    pub estimate_kbps: Option<u32>,
+    pub send_rate_kbps: u32,
+    pub queue_delay_ms: u32,
+    /// Trend of the queue delay in ms per second.
+    pub gradient: f64,
+    pub threshold: f64,
+    pub rtt_ms: Option<u32>,
+    pub min_rtt_ms: Option<u32>,
+    pub signal: Signal,
+    pub state: RateState,
+}
+
+#[derive(Debug, Clone)]
+pub struct BandwidthEstimator {
+    sent: VecDeque<(Instant, usize)>,
+    frames: usize,
+    start: Option<Instant>,
+    smoothed_delay: f64,
+    trend: VecDeque<(f64, f64)>, // (ms since start, smoothed delay)
+    queue_delay: f64,
+    gradient: f64,
+    threshold: f64,
+    last_sample: Option<Instant>,
+    overuse_since: Option<Instant>,
+    rtt: Option<u32>,
+    min_rtt: Option<u32>,
+    // Set by an inflated rtt sample until the decrease it causes.
+    rtt_overuse: bool,
+    signal: Signal,
+    state: RateState,
+    estimate: Option<f64>,
+    last_decrease_rate: Option<f64>,
+    last_update: Option<Instant>,
+    last_decrease: Option<Instant>,
+}
+
+impl Default for BandwidthEstimator {
+    fn default() -> Self {
+        Self {
+            sent: Default::default(),
+            frames: 0,
+            start: None,
+            smoothed_delay: 0.0,
+            trend: Default::default(),
+            queue_delay: 0.0,
+            gradient: 0.0,
+            threshold: INIT_THRESHOLD,
+            last_sample: None,
+            overuse_since: None,
+            rtt: None,
+            min_rtt: None,
+            rtt_overuse: false,
+            signal: Signal::Normal,
+            state: RateState::Hold,
+            estimate: None,
+            last_decrease_rate: None,
+            last_update: None,
+            last_decrease: None,
+        }
+    }
+}
+
+impl BandwidthEstimator {
+    /// A video frame of `bytes` was accepted by the socket `queue_delay` after being queued.
+    pub fn on_frame_sent(&mut self, now: Instant, bytes: usize, queue_delay: Duration) {
+        self.sent.push_back((now, bytes));
+        while let Some((t, _)) = self.sent.front() {
+            if now.duration_since(*t) > RATE_WINDOW {
+                self.sent.pop_front();
+            } else {
+                break;
+            }
+        }
+        self.frames += 1;
+        self.update_gradient(now, queue_delay.as_secs_f64() * 1000.0);
+        self.update_signal(now);
+        self.update_rate(now);
+    }
+
+    /// A TestDelay round trip in ms.
+    pub fn on_rtt(&mut self, rtt: u32) {
+        self.rtt = Some(rtt);
+        match self.min_rtt {
+            Some(min) if rtt > min + MAX_RTT_INFLATION => self.rtt_overuse = true,
+            Some(min) if rtt >= min => {}
+            _ => self.min_rtt = Some(rtt),
+        }
+    }
+
+    /// The estimated available bandwidth in kbps, `None` until enough frames are sent.
+    pub fn estimate(&self) -> Option<f64> {
+        self.estimate
+    }
+
+    pub fn signal(&self) -> Signal {
+        self.signal
+    }
+
+    /// Send rate over the last second in kbps.
+    pub fn send_rate(&self) -> Option<f64> {
+        let (first, _) = self.sent.front()?;
+        let (last, _) = self.sent.back()?;
+        let span = last.duration_since(*first);
+        if span < MIN_RATE_SPAN {
+            return None;
+        }
+        // The first frame was sent before the span.
+        let bytes: usize = self.sent.iter().skip(1).map(|(_, b)| b).sum();
+        Some(bytes as f64 * 8.0 / span.as_secs_f64() / 1000.0)
+    }
+
+    pub fn stats(&self) -> BweStats {
+        BweStats {
+            estimate_kbps: self.estimate.map(|x| x as u32),
+            send_rate_kbps: self.send_rate().unwrap_or_default() as u32,
+            queue_delay_ms: self.queue_delay as u32,
+            gradient: self.gradient,
+            threshold: self.threshold,
+            rtt_ms: self.rtt,
+            min_rtt_ms: self.min_rtt,
+            signal: self.signal,
+            state: self.state,
+        }
+    }
+
+    fn update_gradient(&mut self, now: Instant, delay: f64) {
+        let start = *self.start.get_or_insert(now);
+        self.queue_delay = delay;
+        self.smoothed_delay = SMOOTHING * self.smoothed_delay + (1.0 - SMOOTHING) * delay;
+        let x = now.duration_since(start).as_secs_f64() * 1000.0;
+        self.trend.push_back((x, self.smoothed_delay));
+        if self.trend.len() > TREND_WINDOW {
+            self.trend.pop_front();
+        }
+        if self.trend.len() < 2 {
+            return;
+        }
+        let n = self.trend.len() as f64;
+        let mean_x = self.trend.iter().map(|p| p.0).sum::<f64>() / n;
+        let mean_y = self.trend.iter().map(|p| p.1).sum::<f64>() / n;
+        let (mut num, mut den) = (0.0, 0.0);
+        for (x, y) in self.trend.iter() {
+            num += (x - mean_x) * (y - mean_y);
+            den += (x - mean_x) * (x - mean_x);
+        }
+        if den > 0.0 {
+            self.gradient = num / den * 1000.0;
+        }
+    }
+
+    fn update_signal(&mut self, now: Instant) {
+        let dt = self
+            .last_sample
+            .map(|t| now.duration_since(t).as_secs_f64() * 1000.0)
+            .unwrap_or_default()
+            .min(100.0);
+        self.last_sample = Some(now);
+        let g = self.gradient.abs();
+        // Outliers do not move the threshold.
+        if g - self.threshold < 60.0 {
+            let k = if g > self.threshold { K_UP } else { K_DOWN };
+            self.threshold = (self.threshold + k * (g - self.threshold) * dt)
+                .clamp(MIN_THRESHOLD, MAX_THRESHOLD);
+        }
+        let standing_queue = self.queue_delay > MAX_QUEUE_DELAY || self.rtt_overuse;
+        if self.gradient > self.threshold || standing_queue {
+            let since = *self.overuse_since.get_or_insert(now);
+            if now.duration_since(since) >= OVERUSE_TIME || standing_queue {
+                self.signal = Signal::Overuse;
+            }
+        } else {
+            self.overuse_since = None;
+            self.signal = if self.gradient < -self.threshold {
+                Signal::Underuse
+            } else {
+                Signal::Normal
+            };
+        }
+    }
+
+    fn update_rate(&mut self, now: Instant) {
+        let Some(send_rate) = self.send_rate() else {
+            return;
+        };
+        if self.frames < MIN_FRAMES {
+            return;
+        }
+        let dt = self
+            .last_update
+            .map(|t| now.duration_since(t).as_secs_f64())
+            .unwrap_or_default();
+        self.last_update = Some(now);
+        let estimate = *self.estimate.get_or_insert(send_rate);
+        self.state = match (self.signal, self.state) {
+            (Signal::Overuse, _) => RateState::Decrease,
+            (Signal::Underuse, _) => RateState::Hold,
+            (Signal::Normal, RateState::Decrease) => RateState::Hold,
+            (Signal::Normal, _) => RateState::Increase,
+        };
+        let estimate = match self.state {
+            RateState::Decrease => {
+                if self
+                    .last_decrease
+                    .map_or(true, |t| now.duration_since(t) >= DECREASE_INTERVAL)
+                {
+                    self.last_decrease = Some(now);
+                    self.last_decrease_rate = Some(send_rate);
+                    self.rtt_overuse = false;
+                    (BETA * send_rate).min(estimate)
+                } else {
+                    estimate
+                }
+            }
+            RateState::Hold => estimate,
+            RateState::Increase => {
+                let near = self
+                    .last_decrease_rate
+                    .map_or(false, |r| (estimate - r).abs() < r * 0.1);
+                let increased = if near {
+                    estimate + ADDITIVE_INCREASE * dt
+                } else {
+                    estimate * MULTIPLICATIVE_INCREASE.powf(dt)
+                };
+                increased.min(1.5 * send_rate + 200.0)
+            }
+        };
+        self.estimate = Some(estimate.max(MIN_ESTIMATE));
+    }
+}
@@ -0,0 +1,279 @@
+// Replays bandwidth traces through `VideoQoS` with a simulated encoder and path,
+// so that the estimator can be tuned offline.
+//
+// The path is a FIFO draining at the trace capacity behind a socket buffer: a
+// frame is written once it fits into the buffer, so the write, and with it the
+// queue delay seen by the estimator, blocks when the path is congested.
+// TestDelay probes queue behind everything written. Every viewer has its own path
+// and gets the frames of its tier.
+
+use super::*;
+
+const FIRST_CONN_ID: i32 = 1;
+const DISPLAY: &str = "sim";
+const SOCKET_BUFFER: f64 = 64.0 * 1024.0;
+const PROBE_INTERVAL: f64 = 1000.0;
+
+/// From `at` ms on, the path carries `kbps` with a base round trip of `rtt` ms.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TracePoint {
+    pub at: u64,
+    pub kbps: u32,
+    pub rtt: u32,
+}
+
+/// One `at kbps rtt` point per line, `#` starts a comment.
+pub fn parse_trace(s: &str) -> Vec<TracePoint> {
+    s.lines()
+        .filter_map(|l| {
+            let mut it = l.split('#').next()?.split_whitespace();
+            Some(TracePoint {
+                at: it.next()?.parse().ok()?,
+                kbps: it.next()?.parse().ok()?,
+                rtt: it.next()?.parse().ok()?,
+            })
+        })
+        .collect()
+}
+
+/// State after a frame is written.
+#[derive(Debug, Clone)]
+pub struct Sample {
+    pub at: u64,
+    pub capacity: u32,
+    pub bitrate: u32,
+    pub fps: u32,
+    pub queue_delay: u32,
+    pub tier: Tier,
+    pub stats: BweStats,
+}
+
+fn point_at(trace: &[TracePoint], t: f64) -> TracePoint {
+    trace
+        .iter()
+        .rev()
+        .find(|p| p.at as f64 <= t)
+        .or(trace.first())
+        .copied()
+        .unwrap_or(TracePoint {
+            at: 0,
+            kbps: 1000,
+            rtt: 50,
+        })
+}
+
+// The path of one viewer.
+struct Path<'a> {
+    trace: &'a [TracePoint],
+    backlog: f64, // bytes
+    backlog_time: f64,
+    write_done: f64,
+    next_probe: f64,
+}
+
+impl Path<'_> {
+    fn drain(&mut self, to: f64) {
+        let bytes_per_ms = point_at(self.trace, self.backlog_time).kbps as f64 / 8.0;
+        self.backlog = (self.backlog - (to - self.backlog_time).max(0.0) * bytes_per_ms).max(0.0);
+        self.backlog_time = self.backlog_time.max(to);
+    }
+
+    // Returns when the write is done.
+    fn write(&mut self, t: f64, bytes: f64) -> f64 {
+        let write_start = t.max(self.write_done);
+        self.drain(write_start);
+        let bytes_per_ms = point_at(self.trace, write_start).kbps as f64 / 8.0;
+        self.write_done =
+            write_start + (self.backlog + bytes - SOCKET_BUFFER).max(0.0) / bytes_per_ms;
+        self.drain(self.write_done);
+        self.backlog += bytes;
+        self.write_done
+    }
+}
+
+/// Run `trace` for `duration` ms with an encoder of `base_bitrate` kbps at ratio 1.
+pub fn simulate(trace: &[TracePoint], duration: u64, base_bitrate: u32) -> Vec<Sample> {
+    simulate_viewers(&[trace], duration, base_bitrate).remove(0)
+}
+
+/// Every viewer gets the frames of its tier over its own path, frames are
+/// captured at the fps of the high tier.
+pub fn simulate_viewers(
+    traces: &[&[TracePoint]],
+    duration: u64,
+    base_bitrate: u32,
+) -> Vec<Vec<Sample>> {
+    let start = Instant::now();
+    let mut qos = VideoQoS::default();
+    let ids: Vec<i32> = (FIRST_CONN_ID..).take(traces.len()).collect();
+    for id in ids.iter() {
+        qos.on_connection_open(*id);
+    }
+    qos.abr_config = true;
+    qos.simulcast_config = true;
+    qos.new_user_instant = start - Duration::from_secs(1);
+    qos.new_display(DISPLAY.to_owned());
+    qos.set_support_changing_quality(DISPLAY, true);
+    qos.set_support_simulcast(DISPLAY, true);
+
+    let mut paths: Vec<Path> = traces
+        .iter()
+        .map(|trace| Path {
+            trace,
+            backlog: 0.0,
+            backlog_time: 0.0,
+            write_done: 0.0,
+            next_probe: PROBE_INTERVAL,
+        })
+        .collect();
+    let mut samples = vec![vec![]; traces.len()];
+    // The encoder of a tier exists while the tier has users.
+    let mut ratios = [None; 2];
+    // Share of the captured frames the low tier encodes.
+    let mut low_credit = 1.0;
+    let mut last_low_frame = None;
+    let mut t = 0.0;
+    while t < duration as f64 {
+        for (path, id) in paths.iter_mut().zip(ids.iter()) {
+            while path.next_probe <= t {
+                let probe = path.next_probe;
+                path.drain(probe);
+                let p = point_at(path.trace, probe);
+                let rtt = p.rtt as f64 + path.backlog / (p.kbps as f64 / 8.0);
+                qos.user_network_delay(*id, rtt as u32);
+                path.next_probe += PROBE_INTERVAL;
+            }
+        }
+        for tier in Tier::ALL {
+            if qos.tier_users(tier).is_empty() {
+                ratios[tier.index()] = None;
+                last_low_frame = None;
+            } else if ratios[tier.index()] != Some(qos.ratio(tier)) {
+                let ratio = qos.ratio(tier);
+                ratios[tier.index()] = Some(ratio);
+                qos.store_bitrate(tier, (base_bitrate as f32 * ratio) as u32);
+            }
+        }
+        let fps = qos.fps(Tier::High);
+        let low_fps = qos.fps(Tier::Low);
+        let low_frame = low_credit >= 1.0;
+        let low_interval = if low_frame {
+            low_credit -= 1.0;
+            let interval = last_low_frame.map_or(1000.0 / low_fps as f64, |l| t - l);
+            last_low_frame = Some(t);
+            interval
+        } else {
+            0.0
+        };
+        low_credit = (low_credit + low_fps as f64 / fps as f64).min(1.0);
+
+        for ((path, id), samples) in paths.iter_mut().zip(ids.iter()).zip(samples.iter_mut()) {
+            let tier = qos.user_tier(*id);
+            if tier == Tier::Low && !low_frame {
+                continue;
+            }
+            let tier_fps = qos.fps(tier);
+            let interval = match tier {
+                Tier::High => 1000.0 / fps as f64,
+                Tier::Low => low_interval,
+            };
+            let bytes = qos.bitrate(tier) as f64 / 8.0 * interval;
+            let write_done = path.write(t, bytes);
+            let queue_delay = Duration::from_secs_f64((write_done - t) / 1000.0);
+            let now = start + Duration::from_secs_f64(write_done / 1000.0);
+            qos.user_video_frame_sent_at(*id, bytes as usize, queue_delay, now);
+            samples.push(Sample {
+                at: write_done as u64,
+                capacity: point_at(path.trace, write_done).kbps,
+                bitrate: qos.bitrate(tier),
+                fps: tier_fps,
+                queue_delay: queue_delay.as_millis() as u32,
+                tier,
+                stats: qos.users[id].bwe.stats(),
+            });
+        }
+        t += 1000.0 / fps as f64;
+    }
+    samples
+}
+
+const BASE_BITRATE: u32 = 2073; // 1080p
+
+fn window(samples: &[Sample], from: u64, to: u64) -> impl Iterator<Item = &Sample> {
+    samples.iter().filter(move |s| s.at >= from && s.at < to)
+}
+
+#[test]
+fn test_capacity_drop() {
+    let trace = parse_trace(
+        "0 8000 20
+        5000 1000 20 # congested
+        12000 8000 20",
+    );
+    let samples = simulate(&trace, 20_000, BASE_BITRATE);
+    let balanced = BASE_BITRATE as f32 * BR_BALANCED;
+    // Enough bandwidth, the ratio stays at the balanced quality.
+    let before = window(&samples, 3000, 5000).map(|s| s.bitrate).min();
+    assert!(before.unwrap() as f32 >= balanced * 0.95);
+    // Reacts once the socket buffer is full or the next probe sees the queue.
+    let first = window(&samples, 5000, 12000).find(|s| (s.bitrate as f32) < balanced * 0.9);
+    assert!(first.expect("no reaction").at < 6500);
+    // Settles around the capacity with a bounded queue.
+    for s in window(&samples, 9000, 12000) {
+        assert!(s.bitrate <= 1100, "{:?}", s);
+        assert!(s.queue_delay < 300, "{:?}", s);
+    }
+    // Recovers after the congestion.
+    let after = window(&samples, 18000, 20000).map(|s| s.bitrate).min();
+    assert!(after.unwrap() as f32 >= balanced * 0.9);
+}
+
+#[test]
+fn test_no_congestion() {
+    let trace = parse_trace("0 5000 30");
+    let samples = simulate(&trace, 5000, BASE_BITRATE);
+    assert!(samples
+        .iter()
+        .all(|s| s.stats.signal != bwe::Signal::Overuse));
+}
+
+#[test]
+fn test_slow_viewer() {
+    let fast = parse_trace("0 8000 20");
+    let slow = parse_trace(
+        "0 8000 20
+        5000 1000 20 # congested",
+    );
+    let samples = simulate_viewers(&[&fast, &slow], 30_000, BASE_BITRATE);
+    let balanced = BASE_BITRATE as f32 * BR_BALANCED;
+    // The slow viewer moves to the low tier and follows its own path,
+    let slow: Vec<_> = window(&samples[1], 20000, 30000).collect();
+    for s in slow.iter() {
+        assert_eq!(s.tier, Tier::Low, "{:?}", s);
+        assert!(s.queue_delay < 300, "{:?}", s);
+    }
+    let mean = slow.iter().map(|s| s.bitrate).sum::<u32>() / slow.len() as u32;
+    assert!(mean <= 1100, "{}", mean);
+    // so that the fast viewer gets the balanced quality again.
+    for s in window(&samples[0], 20000, 30000) {
+        assert_eq!(s.tier, Tier::High, "{:?}", s);
+        assert!(s.bitrate as f32 >= balanced * 0.9, "{:?}", s);
+    }
+}
+
+// RUSTDESK_QOS_TRACE=trace.txt cargo test video_qos::sim::replay -- --ignored --nocapture
+#[test]
+#[ignore]
+fn replay() {
+    let Ok(path) = std::env::var("RUSTDESK_QOS_TRACE") else {
+        return;
+    };
+    let trace = parse_trace(&std::fs::read_to_string(path).unwrap());
+    let duration = trace.last().map(|p| p.at + 10_000).unwrap_or(10_000);
+    for s in simulate(&trace, duration, BASE_BITRATE) {
+        println!(
+            "{}\t{}\t{}\t{}\t{}\t{:?}",
+            s.at, s.capacity, s.bitrate, s.fps, s.queue_delay, s.stats
+        );
+    }
+}
@@ -18,7 +18,12 @@
 // to-do:
 // https://slhck.info/video/2017/03/01/rate-control.html
 
-use super::{display_service::check_display_changed, service::ServiceTmpl, video_qos::VideoQoS, *};
+use super::{
+    display_service::check_display_changed,
+    service::ServiceTmpl,
+    video_qos::{Tier, VideoQoS},
+    *,
+};
 #[cfg(target_os = "linux")]
 use crate::common::SimpleCallOnReturn;
 #[cfg(target_os = "linux")]
@@ -48,6 +53,7 @@ use scrap::{
     aom::AomEncoderConfig,
     codec::{Encoder, EncoderCfg},
     record::{Recorder, RecorderContext},
+    region::DirtyTracker,
     vpxcodec::{VpxEncoderConfig, VpxVideoCodecId},
     CodecFormat, Display, EncodeInput, TraitCapturer, TraitPixelBuffer,
 };
@@ -494,8 +500,9 @@ fn run(vs: VideoService) -> ResultType<()> {
         c.set_gdi();
     }
     let mut video_qos = VIDEO_QOS.lock().unwrap();
-    let mut spf = video_qos.spf();
-    let mut quality = video_qos.ratio();
+    let mut spf = video_qos.spf(Tier::High);
+    let mut quality = video_qos.ratio(Tier::High);
+    let lossless = video_qos.lossless();
     let record_incoming = config::option2bool(
         "allow-auto-record-incoming",
         &Config::get_option("allow-auto-record-incoming"),
@@ -506,6 +513,7 @@ fn run(vs: VideoService) -> ResultType<()> {
         &c,
         sp.name(),
         quality,
+        lossless,
         client_record,
         record_incoming,
         last_portable_service_running,
@@ -526,6 +534,7 @@ fn run(vs: VideoService) -> ResultType<()> {
                 &c,
                 sp.name(),
                 quality,
+                lossless,
                 client_record,
                 record_incoming,
                 last_portable_service_running,
@@ -543,12 +552,24 @@ fn run(vs: VideoService) -> ResultType<()> {
             bail!(e);
         }
     }
-    VIDEO_QOS.lock().unwrap().store_bitrate(encoder.bitrate());
+    VIDEO_QOS
+        .lock()
+        .unwrap()
+        .store_bitrate(Tier::High, encoder.bitrate());
     VIDEO_QOS
         .lock()
         .unwrap()
         .set_support_changing_quality(&sp.name(), encoder.support_changing_quality());
-    log::info!("initial quality: {quality:?}");
+    let support_simulcast =
+        low_tier_config(&encoder_cfg, quality).is_some() && encoder.support_changing_quality();
+    VIDEO_QOS
+        .lock()
+        .unwrap()
+        .set_support_simulcast(&sp.name(), support_simulcast);
+    let mut high_tier_joins = VIDEO_QOS.lock().unwrap().tier_joins(Tier::High);
+    log::info!(
+        "initial quality: {quality:?}, lossless: {lossless}, simulcast: {support_simulcast}"
+    );
 
     if sp.is_option_true(OPTION_REFRESH) {
         sp.set_option_bool(OPTION_REFRESH, false);
@@ -575,7 +596,12 @@ fn run(vs: VideoService) -> ResultType<()> {
     let mut first_frame = true;
     let capture_width = c.width;
     let capture_height = c.height;
+    let capture_origin = c.origin;
+    let mut dirty_tracker = DirtyTracker::new();
+    let mut region_hints = true;
+    let mut last_encoded_frame = None;
     let (mut second_instant, mut send_counter) = (Instant::now(), 0);
+    let mut low_tier: Option<LowTier> = None;
 
     while sp.ok() {
         #[cfg(windows)]
@@ -584,11 +610,14 @@ fn run(vs: VideoService) -> ResultType<()> {
             &mut encoder,
             &mut quality,
             &mut spf,
+            lossless,
             client_record,
             &mut send_counter,
             &mut second_instant,
+            &mut high_tier_joins,
             &sp.name(),
         )?;
+        check_low_tier(&mut low_tier, &encoder_cfg, use_i444, &sp.name());
         if sp.is_option_true(OPTION_REFRESH) {
             if vs.source.is_monitor() {
                 let _ = try_broadcast_display_changed(&sp, display_idx, &c, true);
@@ -644,76 +673,109 @@ fn run(vs: VideoService) -> ResultType<()> {
         let ms = (time.as_secs() * 1000 + time.subsec_millis() as u64) as i64;
         let res = match c.frame(spf) {
             Ok(frame) => {
-                repeat_encode_counter = 0;
-                if frame.valid() {
-                    let screenshot = SCREENSHOTS.lock().unwrap().remove(&display_idx);
-                    if let Some(mut screenshot) = screenshot {
-                        let restore_vram = screenshot.restore_vram;
-                        let (msg, w, h, data) = match &frame {
-                            scrap::Frame::PixelBuffer(f) => match get_rgba_from_pixelbuf(f) {
-                                Ok(rgba) => ("".to_owned(), f.width(), f.height(), rgba),
-                                Err(e) => {
-                                    let serr = e.to_string();
-                                    log::error!(
-                                        "Failed to convert the pix format into rgba, {}",
-                                        &serr
-                                    );
-                                    (format!("Convert pixfmt: {}", serr), 0, 0, vec![])
-                                }
-                            },
-                            scrap::Frame::Texture(_) => {
-                                if restore_vram {
-                                    // Already set one time, just ignore to break infinite loop.
-                                    // Though it's unreachable, this branch is kept to avoid infinite loop.
-                                    (
-                                        "Please change codec and try again.".to_owned(),
-                                        0,
-                                        0,
-                                        vec![],
-                                    )
-                                } else {
-                                    #[cfg(all(windows, feature = "vram"))]
-                                    VRamEncoder::set_not_use(sp.name(), true);
-                                    screenshot.restore_vram = true;
-                                    SCREENSHOTS.lock().unwrap().insert(display_idx, screenshot);
-                                    _raii.try_vram = false;
-                                    bail!("SWITCH");
+                let changed_blocks = if frame.valid() && vs.source.is_monitor() {
+                    update_dirty_tracker(&mut dirty_tracker, &frame, capture_origin)
+                } else {
+                    None
+                };
+                if changed_blocks == Some(0)
+                    && !SCREENSHOTS.lock().unwrap().contains_key(&display_idx)
+                {
+                    // Nothing changed, handled like no new frame.
+                    Err(WouldBlock.into())
+                } else {
+                    repeat_encode_counter = 0;
+                    if frame.valid() {
+                        let screenshot = SCREENSHOTS.lock().unwrap().remove(&display_idx);
+                        if let Some(mut screenshot) = screenshot {
+                            let restore_vram = screenshot.restore_vram;
+                            let (msg, w, h, data) = match &frame {
+                                scrap::Frame::PixelBuffer(f) => match get_rgba_from_pixelbuf(f) {
+                                    Ok(rgba) => ("".to_owned(), f.width(), f.height(), rgba),
+                                    Err(e) => {
+                                        let serr = e.to_string();
+                                        log::error!(
+                                            "Failed to convert the pix format into rgba, {}",
+                                            &serr
+                                        );
+                                        (format!("Convert pixfmt: {}", serr), 0, 0, vec![])
+                                    }
+                                },
+                                scrap::Frame::Texture(_) => {
+                                    if restore_vram {
+                                        // Already set one time, just ignore to break infinite loop.
+                                        // Though it's unreachable, this branch is kept to avoid infinite loop.
+                                        (
+                                            "Please change codec and try again.".to_owned(),
+                                            0,
+                                            0,
+                                            vec![],
+                                        )
+                                    } else {
+                                        #[cfg(all(windows, feature = "vram"))]
+                                        VRamEncoder::set_not_use(sp.name(), true);
+                                        screenshot.restore_vram = true;
+                                        SCREENSHOTS.lock().unwrap().insert(display_idx, screenshot);
+                                        _raii.try_vram = false;
+                                        bail!("SWITCH");
+                                    }
                                 }
+                            };
+                            std::thread::spawn(move || {
+                                handle_screenshot(screenshot, msg, w, h, data);
+                            });
+                            if restore_vram {
+                                bail!("SWITCH");
                             }
-                        };
-                        std::thread::spawn(move || {
-                            handle_screenshot(screenshot, msg, w, h, data);
-                        });
-                        if restore_vram {
-                            bail!("SWITCH");
                         }
-                    }
 
-                    let frame = frame.to(encoder.yuvfmt(), &mut yuv, &mut mid_data)?;
-                    let send_conn_ids = handle_one_frame(
-                        display_idx,
-                        &sp,
-                        frame,
-                        ms,
-                        &mut encoder,
-                        recorder.clone(),
-                        &mut encode_fail_counter,
-                        &mut first_frame,
-                        capture_width,
-                        capture_height,
-                    )?;
-                    frame_controller.set_send(now, send_conn_ids);
-                    send_counter += 1;
-                }
-                #[cfg(windows)]
-                {
-                    #[cfg(feature = "vram")]
-                    if try_gdi == 1 && !c.is_gdi() {
-                        VRamEncoder::set_fallback_gdi(sp.name(), false);
+                        let tracked = changed_blocks.is_some();
+                        if tracked && region_hints {
+                            let hints = dirty_tracker.hints(last_encoded_frame);
+                            if let Err(e) = encoder.set_region_hints(&hints) {
+                                log::error!("Failed to set region hints: {e:?}");
+                                region_hints = false;
+                            }
+                        }
+                        let frame = frame.to(encoder.yuvfmt(), &mut yuv, &mut mid_data)?;
+                        let mut send_conn_ids = handle_one_frame(
+                            display_idx,
+                            &sp,
+                            frame,
+                            ms,
+                            &mut encoder,
+                            recorder.clone(),
+                            &mut encode_fail_counter,
+                            &mut first_frame,
+                            capture_width,
+                            capture_height,
+                            low_tier.as_ref().map(|l| &l.conn_ids),
+                        )?;
+                        if tracked {
+                            last_encoded_frame = Some(dirty_tracker.frame_no());
+                        }
+                        send_conn_ids.extend(handle_low_tier_frame(
+                            display_idx,
+                            &sp,
+                            &mut low_tier,
+                            &yuv,
+                            ms,
+                            spf,
+                            tracked.then_some(&dirty_tracker),
+                        ));
+                        frame_controller.set_send(now, send_conn_ids);
+                        send_counter += 1;
+                    }
+                    #[cfg(windows)]
+                    {
+                        #[cfg(feature = "vram")]
+                        if try_gdi == 1 && !c.is_gdi() {
+                            VRamEncoder::set_fallback_gdi(sp.name(), false);
+                        }
+                        try_gdi = 0;
                     }
-                    try_gdi = 0;
+                    Ok(())
                 }
-                Ok(())
             }
             Err(err) => Err(err),
         };
@@ -749,7 +811,7 @@ fn run(vs: VideoService) -> ResultType<()> {
                     // yun.len() > 0 means the frame is not texture.
                     if repeat_encode_counter < repeat_encode_max {
                         repeat_encode_counter += 1;
-                        let send_conn_ids = handle_one_frame(
+                        let mut send_conn_ids = handle_one_frame(
                             display_idx,
                             &sp,
                             EncodeInput::YUV(&yuv),
@@ -760,7 +822,17 @@ fn run(vs: VideoService) -> ResultType<()> {
                             &mut first_frame,
                             capture_width,
                             capture_height,
+                            low_tier.as_ref().map(|l| &l.conn_ids),
                         )?;
+                        send_conn_ids.extend(handle_low_tier_frame(
+                            display_idx,
+                            &sp,
+                            &mut low_tier,
+                            &yuv,
+                            ms,
+                            spf,
+                            None,
+                        ));
                         frame_controller.set_send(now, send_conn_ids);
                         send_counter += 1;
                     }
@@ -847,6 +919,7 @@ fn setup_encoder(
     c: &CapturerInfo,
     name: String,
     quality: f32,
+    lossless: bool,
     client_record: bool,
     record_incoming: bool,
     last_portable_service_running: bool,
@@ -863,6 +936,7 @@ fn setup_encoder(
         &c,
         name.to_string(),
         quality,
+        lossless,
         client_record || record_incoming,
         last_portable_service_running,
         source,
@@ -879,6 +953,7 @@ fn get_encoder_config(
     c: &CapturerInfo,
     _name: String,
     quality: f32,
+    lossless: bool,
     record: bool,
     _portable_service: bool,
     _source: VideoSource,
@@ -941,6 +1016,7 @@ fn get_encoder_config(
             height: c.height as _,
             quality,
             keyframe_interval,
+            lossless,
         }),
         _ => EncoderCfg::VPX(VpxEncoderConfig {
             width: c.width as _,
@@ -952,6 +1028,21 @@ fn get_encoder_config(
     }
 }
 
+// The low tier is encoded in software only: a second hardware session may not be
+// available, and a failing hardware encoder disables hardware encoding altogether.
+fn low_tier_config(cfg: &EncoderCfg, quality: f32) -> Option<EncoderCfg> {
+    match cfg {
+        EncoderCfg::VPX(c) => Some(EncoderCfg::VPX(VpxEncoderConfig { quality, ..*c })),
+        EncoderCfg::AOM(c) => Some(EncoderCfg::AOM(AomEncoderConfig {
+            quality,
+            lossless: false,
+            ..*c
+        })),
+        #[allow(unreachable_patterns)]
+        _ => None,
+    }
+}
+
 fn get_recorder(
     record_incoming: bool,
     display_idx: usize,
@@ -1058,6 +1149,7 @@ fn handle_one_frame(
     first_frame: &mut bool,
     width: usize,
     height: usize,
+    low_tier_conn_ids: Option<&HashSet<i32>>,
 ) -> ResultType<HashSet<i32>> {
     sp.snapshot(|sps| {
         // so that new sub and old sub share the same encoder after switch
@@ -1082,7 +1174,10 @@ fn handle_one_frame(
                 .unwrap()
                 .as_mut()
                 .map(|r| r.write_message(&msg, width, height));
-            send_conn_ids = sp.send_video_frame(msg);
+            send_conn_ids = match low_tier_conn_ids {
+                Some(ids) => sp.send_video_frame_to(msg, |id| !ids.contains(&id)),
+                None => sp.send_video_frame(msg),
+            };
         }
         Err(e) => {
             *encode_fail_counter += 1;
@@ -1118,6 +1213,80 @@ fn handle_one_frame(
     Ok(send_conn_ids)
 }
 
+// Encode the frame converted for the high tier for the low tier users as well,
+// paced to the fps of the low tier.
+fn handle_low_tier_frame(
+    display: usize,
+    sp: &GenericService,
+    low_tier: &mut Option<LowTier>,
+    yuv: &[u8],
+    ms: i64,
+    spf: Duration,
+    dirty_tracker: Option<&DirtyTracker>,
+) -> HashSet<i32> {
+    let Some(low) = low_tier.as_mut() else {
+        return Default::default();
+    };
+    let due = low.credit >= 1.0;
+    if due {
+        low.credit -= 1.0;
+    }
+    low.credit = (low.credit + spf.as_secs_f32() / low.spf.as_secs_f32()).min(1.0);
+    if !due || yuv.is_empty() {
+        return Default::default();
+    }
+    if let Some(tracker) = dirty_tracker.filter(|_| low.region_hints) {
+        // Blocks are static only if unchanged since the last frame of this encoder.
+        let hints = tracker.hints(low.last_encoded_frame);
+        if let Err(e) = low.encoder.set_region_hints(&hints) {
+            log::error!("Failed to set low tier region hints: {e:?}");
+            low.region_hints = false;
+        }
+    }
+    low.last_encoded_frame = dirty_tracker.map(|t| t.frame_no());
+    match low.encoder.encode_to_message(EncodeInput::YUV(yuv), ms) {
+        Ok(mut vf) => {
+            low.fail_counter = 0;
+            vf.display = display as _;
+            let mut msg = Message::new();
+            msg.set_video_frame(vf);
+            sp.send_video_frame_to(msg, |id| low.conn_ids.contains(&id))
+        }
+        Err(e) => {
+            low.fail_counter += 1;
+            log::error!("low tier encode fail: {e:?}, times: {}", low.fail_counter);
+            if low.fail_counter >= 3 {
+                *low_tier = None;
+                VIDEO_QOS
+                    .lock()
+                    .unwrap()
+                    .set_support_simulcast(&sp.name(), false);
+            }
+            Default::default()
+        }
+    }
+}
+
+// Returns the number of changed blocks, `None` if the frame is not tracked.
+fn update_dirty_tracker(
+    tracker: &mut DirtyTracker,
+    frame: &scrap::Frame,
+    origin: (i32, i32),
+) -> Option<usize> {
+    let scrap::Frame::PixelBuffer(f) = frame else {
+        return None;
+    };
+    if !matches!(f.pixfmt(), scrap::Pixfmt::BGRA | scrap::Pixfmt::RGBA) {
+        return None;
+    }
+    let stride = *f.stride().first()?;
+    let cursor = input_service::latest_cursor_pos().and_then(|(x, y)| {
+        let (x, y) = (x - origin.0, y - origin.1);
+        (x >= 0 && y >= 0).then_some((x as usize, y as usize))
+    });
+    Some(tracker.update(f.data(), f.width(), f.height(), stride, cursor))
+}
+
 #[inline]
 pub fn refresh() {
     #[cfg(target_os = "android")]
@@ -1232,21 +1401,23 @@ fn check_qos(
     encoder: &mut Encoder,
     ratio: &mut f32,
     spf: &mut Duration,
+    lossless: bool,
     client_record: bool,
     send_counter: &mut usize,
     second_instant: &mut Instant,
+    high_tier_joins: &mut usize,
     name: &str,
 ) -> ResultType<()> {
     let mut video_qos = VIDEO_QOS.lock().unwrap();
-    *spf = video_qos.spf();
-    if *ratio != video_qos.ratio() {
-        *ratio = video_qos.ratio();
+    *spf = video_qos.spf(Tier::High);
+    if *ratio != video_qos.ratio(Tier::High) {
+        *ratio = video_qos.ratio(Tier::High);
         if encoder.support_changing_quality() {
             allow_err!(encoder.set_quality(*ratio));
-            video_qos.store_bitrate(encoder.bitrate());
+            video_qos.store_bitrate(Tier::High, encoder.bitrate());
         } else {
             // Now only vaapi doesn't support changing quality
-            if !video_qos.in_vbr_state() && !video_qos.latest_quality().is_custom() {
+            if !video_qos.in_vbr_state() && !video_qos.latest_quality(Tier::High).is_custom() {
                 log::info!("switch to change quality");
                 bail!("SWITCH");
             }
@@ -1256,6 +1427,19 @@ fn check_qos(
         log::info!("switch due to record changed");
         bail!("SWITCH");
     }
+    if lossless != video_qos.lossless() {
+        log::info!("switch due to lossless changed");
+        bail!("SWITCH");
+    }
+    if *high_tier_joins != video_qos.tier_joins(Tier::High) {
+        // a key frame for the users just joined, from a new encoder if it can not be forced
+        *high_tier_joins = video_qos.tier_joins(Tier::High);
+        if !encoder.request_key_frame() {
+            log::info!("switch due to users joined the high tier");
+            bail!("SWITCH");
+        }
+        log::info!("key frame for users joined the high tier");
+    }
     if second_instant.elapsed() > Duration::from_secs(1) {
         *second_instant = Instant::now();
         video_qos.update_display_data(&name, *send_counter);
@@ -1265,6 +1449,84 @@ fn check_qos(
     Ok(())
 }
 
+// Encoder of the low tier, fed with the frames converted for the high tier.
+struct LowTier {
+    encoder: Encoder,
+    ratio: f32,
+    spf: Duration,
+    credit: f32, // frames due, gains spf of the high tier / spf of the low tier per frame
+    joins: usize,
+    conn_ids: HashSet<i32>,
+    fail_counter: usize,
+    region_hints: bool,
+    last_encoded_frame: Option<u64>,
+}
+
+fn check_low_tier(
+    low_tier: &mut Option<LowTier>,
+    encoder_cfg: &EncoderCfg,
+    use_i444: bool,
+    name: &str,
+) {
+    let mut video_qos = VIDEO_QOS.lock().unwrap();
+    let conn_ids = video_qos.tier_users(Tier::Low);
+    if conn_ids.is_empty() {
+        if low_tier.take().is_some() {
+            log::info!("stop low tier encoder of {name}");
+        }
+        return;
+    }
+    let joins = video_qos.tier_joins(Tier::Low);
+    let ratio = video_qos.ratio(Tier::Low);
+    let spf = video_qos.spf(Tier::Low);
+    if let Some(low) = low_tier.as_mut().filter(|l| l.joins != joins) {
+        if low.encoder.request_key_frame() {
+            low.joins = joins;
+        }
+    }
+    if let Some(low) = low_tier.as_mut().filter(|l| l.joins == joins) {
+        low.conn_ids = conn_ids;
+        low.spf = spf;
+        if low.ratio != ratio {
+            low.ratio = ratio;
+            allow_err!(low.encoder.set_quality(ratio));
+            video_qos.store_bitrate(Tier::Low, low.encoder.bitrate());
+        }
+        return;
+    }
+    drop(video_qos);
+    // A new encoder starts with a key frame for the users just joined, if the
+    // encoder could not force one.
+    let Some(cfg) = low_tier_config(encoder_cfg, ratio) else {
+        return;
+    };
+    match Encoder::new(cfg, use_i444) {
+        Ok(encoder) => {
+            log::info!("new low tier encoder of {name}, ratio: {ratio}");
+            VIDEO_QOS
+                .lock()
+                .unwrap()
+                .store_bitrate(Tier::Low, encoder.bitrate());
+            *low_tier = Some(LowTier {
+                encoder,
+                ratio,
+                spf,
+                credit: 1.0,
+                joins,
+                conn_ids,
+                fail_counter: 0,
+                region_hints: true,
+                last_encoded_frame: None,
+            });
+        }
+        Err(e) => {
+            log::error!("Failed to create low tier encoder of {name}: {e:?}");
+            *low_tier = None;
+            VIDEO_QOS.lock().unwrap().set_support_simulcast(name, false);
+        }
+    }
+}
+
 pub fn set_take_screenshot(display_idx: usize, sid: String, tx: Sender) {
     SCREENSHOTS.lock().unwrap().insert(
         display_idx,
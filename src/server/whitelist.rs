@@ -0,0 +1,396 @@
+use cidr_utils::cidr::IpCidr;
+use hbb_common::{futures::future::join_all, log, timeout, tokio};
+use std::{net::IpAddr, str::FromStr};
+
+// Hostname rules are resolved on every check, all at once.
+const RESOLVE_TIMEOUT: u64 = 3_000;
+
+/// Permissions a rule takes away from the connections it allows.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Restrictions {
+    pub keyboard: bool,
+    pub clipboard: bool,
+    pub file: bool,
+    pub audio: bool,
+    pub tunnel: bool,
+    pub restart: bool,
+    pub recording: bool,
+    pub block_input: bool,
+}
+
+impl Restrictions {
+    /// Everything but seeing the screen.
+    pub fn view_only() -> Self {
+        Self {
+            keyboard: true,
+            clipboard: true,
+            file: true,
+            audio: true,
+            tunnel: true,
+            restart: true,
+            recording: true,
+            block_input: true,
+        }
+    }
+
+    /// Whether the permission `name` of `ipc::Data::SwitchPermission` may be enabled.
+    pub fn allows(&self, name: &str) -> bool {
+        match name {
+            "keyboard" => !self.keyboard,
+            "clipboard" => !self.clipboard,
+            "file" => !self.file,
+            "audio" => !self.audio,
+            "restart" => !self.restart,
+            "recording" => !self.recording,
+            "block_input" => !self.block_input,
+            _ => true,
+        }
+    }
+
+    fn names(&self) -> Vec<&'static str> {
+        [
+            (self.keyboard, "keyboard"),
+            (self.clipboard, "clipboard"),
+            (self.file, "file"),
+            (self.audio, "audio"),
+            (self.tunnel, "tunnel"),
+            (self.restart, "restart"),
+            (self.recording, "recording"),
+            (self.block_input, "block-input"),
+        ]
+        .iter()
+        .filter_map(|(x, name)| x.then_some(*name))
+        .collect()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Target {
+    Any,
+    Cidr(IpCidr),
+    Host(String),
+}
+
+/// One entry of the `whitelist` option: `[!]target[@modifier]...`.
+///
+/// The target is an IPv4 or IPv6 address or prefix, a hostname, or `*` and the
+/// legacy `0.0.0.0` for any. `!` makes it a deny rule. Modifiers are a
+/// `HH:MM-HH:MM` local time window and the restrictions `view-only`,
+/// `no-keyboard`, `no-clipboard`, `no-file`, `no-audio`, `no-tunnel`,
+/// `no-restart`, `no-recording` and `no-block-input`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Rule {
+    pub deny: bool,
+    target: Target,
+    restrictions: Restrictions,
+    // Minutes of the day, the window wraps past midnight if start > end.
+    window: Option<(u32, u32)>,
+    text: String,
+}
+
+impl FromStr for Rule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let text = s.trim();
+        let (deny, rest) = match text.strip_prefix('!') {
+            Some(rest) => (true, rest),
+            None => (false, text),
+        };
+        let mut parts = rest.split('@');
+        let target = match parts.next().unwrap_or_default() {
+            "" => return Err(format!("Empty rule: {}", text)),
+            "*" | "0.0.0.0" => Target::Any,
+            x => match IpCidr::from_str(x) {
+                Ok(cidr) => Target::Cidr(cidr),
+                Err(_) if is_hostname(x) => Target::Host(x.to_lowercase()),
+                Err(_) => return Err(format!("Invalid IP: {}", x)),
+            },
+        };
+        let mut restrictions = Restrictions::default();
+        let mut window = None;
+        for m in parts {
+            match m {
+                "view-only" => restrictions = Restrictions::view_only(),
+                "no-keyboard" => restrictions.keyboard = true,
+                "no-clipboard" => restrictions.clipboard = true,
+                "no-file" => restrictions.file = true,
+                "no-audio" => restrictions.audio = true,
+                "no-tunnel" => restrictions.tunnel = true,
+                "no-restart" => restrictions.restart = true,
+                "no-recording" => restrictions.recording = true,
+                "no-block-input" => restrictions.block_input = true,
+                _ => match parse_window(m) {
+                    Some(w) => window = Some(w),
+                    None => return Err(format!("Invalid modifier {} in {}", m, text)),
+                },
+            }
+        }
+        Ok(Self {
+            deny,
+            target,
+            restrictions,
+            window,
+            text: text.to_owned(),
+        })
+    }
+}
+
+impl std::fmt::Display for Rule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.text)
+    }
+}
+
+impl Rule {
+    fn in_window(&self, minute: u32) -> bool {
+        match self.window {
+            Some((start, end)) if start <= end => minute >= start && minute < end,
+            Some((start, end)) => minute >= start || minute < end,
+            None => true,
+        }
+    }
+
+    // `addrs` are the resolved addresses of a hostname target.
+    fn matches(&self, ip: IpAddr, minute: u32, addrs: &[IpAddr]) -> bool {
+        self.in_window(minute)
+            && match &self.target {
+                Target::Any => true,
+                Target::Cidr(cidr) => cidr.contains(ip),
+                Target::Host(_) => addrs.contains(&ip),
+            }
+    }
+
+    async fn resolve(&self, minute: u32) -> Vec<IpAddr> {
+        let Target::Host(host) = &self.target else {
+            return vec![];
+        };
+        if !self.in_window(minute) {
+            return vec![];
+        }
+        let addrs = tokio::net::lookup_host((host.as_str(), 0));
+        match timeout(RESOLVE_TIMEOUT, addrs).await {
+            Ok(Ok(addrs)) => addrs.map(|a| normalize(a.ip())).collect(),
+            Ok(Err(err)) => {
+                log::warn!("Failed to resolve whitelist host {}: {}", host, err);
+                vec![]
+            }
+            Err(_) => {
+                log::warn!("Timeout resolving whitelist host {}", host);
+                vec![]
+            }
+        }
+    }
+}
+
+/// Result of checking an address against the rules, with the index of the
+/// deciding rule, `None` for the default.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Decision {
+    Allow(Option<usize>, Restrictions),
+    Deny(Option<usize>),
+}
+
+/// The ordered rules of the `whitelist` option.
+#[derive(Debug, Default, Clone)]
+pub struct Rules {
+    pub rules: Vec<Rule>,
+    /// Entries that failed to parse.
+    pub errors: Vec<String>,
+    // Unmatched addresses are denied as soon as there is an allow entry, valid or not.
+    default_deny: bool,
+}
+
+impl Rules {
+    pub fn parse(option: &str) -> Self {
+        let mut rules = Self::default();
+        for entry in option.split(|c: char| c == ',' || c.is_whitespace()) {
+            if entry.is_empty() {
+                continue;
+            }
+            if !entry.starts_with('!') {
+                rules.default_deny = true;
+            }
+            match entry.parse() {
+                Ok(rule) => rules.rules.push(rule),
+                Err(err) => rules.errors.push(err),
+            }
+        }
+        rules
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty() && self.errors.is_empty()
+    }
+
+    /// First matching rule wins. `minute` is the local minute of the day.
+    pub async fn check(&self, ip: IpAddr, minute: u32) -> Decision {
+        let ip = normalize(ip);
+        // Hostnames after the first matching address rule cannot decide, the
+        // ones before it are resolved concurrently.
+        let end = self
+            .rules
+            .iter()
+            .position(|r| !matches!(r.target, Target::Host(_)) && r.matches(ip, minute, &[]))
+            .map_or(self.rules.len(), |i| i + 1);
+        let rules = &self.rules[..end];
+        let resolved = join_all(rules.iter().map(|r| r.resolve(minute))).await;
+        for (i, (rule, addrs)) in rules.iter().zip(resolved).enumerate() {
+            if rule.matches(ip, minute, &addrs) {
+                return if rule.deny {
+                    Decision::Deny(Some(i))
+                } else {
+                    Decision::Allow(Some(i), rule.restrictions)
+                };
+            }
+        }
+        if self.default_deny {
+            Decision::Deny(None)
+        } else {
+            Decision::Allow(None, Restrictions::default())
+        }
+    }
+}
+
+/// How `ip` is handled by the rules of `option` at `minute`, for the rule tester.
+#[tokio::main(flavor = "current_thread")]
+pub async fn describe(option: &str, ip: IpAddr, minute: u32) -> String {
+    let rules = Rules::parse(option);
+    let mut lines: Vec<String> = rules
+        .errors
+        .iter()
+        .map(|e| format!("ignored: {}", e))
+        .collect();
+    let by = |i: Option<usize>| match i {
+        Some(i) => format!("rule {} ({})", i + 1, rules.rules[i]),
+        None => "default".to_owned(),
+    };
+    lines.push(match rules.check(ip, minute).await {
+        Decision::Allow(i, r) => {
+            let denied = r.names();
+            if denied.is_empty() {
+                format!("allow by {}", by(i))
+            } else {
+                format!("allow by {} without {}", by(i), denied.join(", "))
+            }
+        }
+        Decision::Deny(i) => format!("deny by {}", by(i)),
+    });
+    lines.join("\n")
+}
+
+pub fn local_minute() -> u32 {
+    use chrono::Timelike;
+    let now = chrono::Local::now();
+    now.hour() * 60 + now.minute()
+}
+
+// Dual-stack listeners see IPv4 peers as mapped IPv6 addresses.
+fn normalize(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(ip),
+        _ => ip,
+    }
+}
+
+pub fn parse_window(s: &str) -> Option<(u32, u32)> {
+    let parse = |t: &str| {
+        let (h, m) = t.split_once(':')?;
+        let (h, m) = (h.parse::<u32>().ok()?, m.parse::<u32>().ok()?);
+        (h <= 24 && m < 60 && h * 60 + m <= 24 * 60).then(|| h * 60 + m)
+    };
+    let (start, end) = s.split_once('-')?;
+    Some((parse(start)?, parse(end)?))
+}
+
+fn is_hostname(s: &str) -> bool {
+    s.len() <= 253
+        && !s.chars().all(|c| c.is_ascii_digit() || c == '.')
+        && s.split('.').all(|l| {
+            !l.is_empty()
+                && l.len() <= 63
+                && !l.starts_with('-')
+                && !l.ends_with('-')
+                && l.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_rules() {
+        let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+        let rules = Rules::parse("!10.0.0.5,10.0.0.0/8,192.168.1.0/24@view-only,fe80::/10");
+        assert!(rules.errors.is_empty());
+        assert_eq!(
+            rules.check(ip("10.0.0.5"), 0).await,
+            Decision::Deny(Some(0))
+        );
+        assert_eq!(
+            rules.check(ip("::ffff:10.1.2.3"), 0).await,
+            Decision::Allow(Some(1), Restrictions::default())
+        );
+        match rules.check(ip("192.168.1.7"), 0).await {
+            Decision::Allow(Some(2), r) => {
+                assert_eq!(r, Restrictions::view_only());
+                for name in [
+                    "keyboard",
+                    "clipboard",
+                    "file",
+                    "audio",
+                    "restart",
+                    "recording",
+                    "block_input",
+                ] {
+                    assert!(!r.allows(name), "{}", name);
+                }
+                assert!(r.tunnel);
+            }
+            d => panic!("{:?}", d),
+        }
+        let restricted = Rules::parse("*@no-audio@no-restart@no-block-input");
+        match restricted.check(ip("8.8.8.8"), 0).await {
+            Decision::Allow(Some(0), r) => {
+                assert!(!r.allows("audio") && !r.allows("restart") && !r.allows("block_input"));
+                assert!(r.allows("keyboard") && r.allows("file") && r.allows("recording"));
+            }
+            d => panic!("{:?}", d),
+        }
+        assert!(matches!(
+            rules.check(ip("fe80::1"), 0).await,
+            Decision::Allow(Some(3), _)
+        ));
+        assert_eq!(rules.check(ip("8.8.8.8"), 0).await, Decision::Deny(None));
+
+        let rules = Rules::parse("!172.16.0.0/12");
+        assert!(matches!(
+            rules.check(ip("8.8.8.8"), 0).await,
+            Decision::Allow(None, _)
+        ));
+        let rules = Rules::parse("0.0.0.0");
+        assert!(matches!(
+            rules.check(ip("8.8.8.8"), 0).await,
+            Decision::Allow(Some(0), _)
+        ));
+
+        let rules = Rules::parse("*@22:00-06:00");
+        assert!(matches!(
+            rules.check(ip("8.8.8.8"), 23 * 60).await,
+            Decision::Allow(..)
+        ));
+        assert!(matches!(
+            rules.check(ip("8.8.8.8"), 5 * 60).await,
+            Decision::Allow(..)
+        ));
+        assert_eq!(
+            rules.check(ip("8.8.8.8"), 12 * 60).await,
+            Decision::Deny(None)
+        );
+
+        let rules = Rules::parse("1.2.3.400,10.0.0.1@never");
+        assert_eq!(rules.errors.len(), 2);
+        assert_eq!(rules.check(ip("10.0.0.1"), 0).await, Decision::Deny(None));
+    }
+}
@@ -0,0 +1,943 @@
+//! Several port forwards multiplexed over one authenticated session.
+//!
+//! A port forward login for [`TUNNEL_HOST`] with port 0 does not connect the
+//! controlled side anywhere. The session stays framed and encrypted, and every
+//! frame on it carries one [`TunnelFrame`]. The controlling side opens a
+//! channel per forwarded TCP connection or UDP flow, and the controlled side
+//! connects the channel to the requested target.
+//!
+//! For reverse forwards the controlling side asks the controlled side to
+//! listen on a port instead, like `ssh -R`. Connections accepted there open
+//! channels the other way, and the controlling side connects them to a target
+//! reachable from it. Only ports allowed by [`OPTION_REVERSE_PORTS`] can be
+//! listened on, and only on loopback.
+
+use bytes::{BufMut, Bytes, BytesMut};
+use hbb_common::{
+    bail,
+    config::Config,
+    log, tcp, timeout,
+    tokio::{
+        self,
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{lookup_host, TcpStream, UdpSocket},
+        sync::mpsc::{self, error::TrySendError},
+        task::JoinHandle,
+    },
+    ResultType,
+};
+use std::{
+    collections::HashMap,
+    fmt,
+    net::{Ipv4Addr, SocketAddr},
+    time::{Duration, Instant},
+};
+
+/// `PortForward::host` of a tunnel login, sent with port 0 like "RDP".
+pub const TUNNEL_HOST: &str = "TUNNEL";
+
+/// Option of the controlled side with the ports a peer may listen on, e.g.
+/// "8080,9000-9010". Reverse forwarding is off while it is empty.
+pub const OPTION_REVERSE_PORTS: &str = "reverse-port-forward-ports";
+
+/// Option of the controlled side capping the traffic of each port forward
+/// session, both directions together, in KB/s. Unlimited when empty or 0.
+pub const OPTION_RATE_LIMIT: &str = "tunnel-rate-limit";
+
+/// Set in the id of channels opened by the controlled side, so they never
+/// collide with the ids the controlling side picks.
+pub const REVERSE_CHANNEL: u32 = 1 << 31;
+
+/// How long the controlled side tries to connect a TCP channel, in milliseconds.
+const CONNECT_TIMEOUT: u64 = 3_000;
+/// Frames from channel tasks waiting to be sent on the session.
+const FRAME_QUEUE_SIZE: usize = 64;
+const READ_BUFFER_SIZE: usize = 64 * 1024;
+/// Data frames from the peer waiting for one channel, up to 1 MB with full
+/// reads. The session is not read while a TCP channel's queue is full, UDP
+/// datagrams are dropped instead.
+pub const CHANNEL_WINDOW: usize = 16;
+/// Reverse forward connections waiting for a channel, the listener stops
+/// accepting while it is full.
+const ACCEPT_QUEUE_SIZE: usize = 16;
+
+const FRAME_OPEN: u8 = 1;
+const FRAME_DATA: u8 = 2;
+const FRAME_CLOSE: u8 = 3;
+const FRAME_PING: u8 = 4;
+const FRAME_LISTEN: u8 = 5;
+const FRAME_ACCEPT: u8 = 6;
+const FRAME_REFUSED: u8 = 7;
+const FRAME_CONNECTED: u8 = 8;
+/// Kind byte and channel id.
+const FRAME_HEADER_LEN: usize = 5;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Protocol {
+    Tcp,
+    Udp,
+}
+
+impl Protocol {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Protocol::Tcp => "tcp",
+            Protocol::Udp => "udp",
+        }
+    }
+}
+
+impl fmt::Display for Protocol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// One frame on a tunnel session.
+///
+/// Layout: kind (1 byte), channel id (4 bytes, big-endian), then
+/// - `Open`: protocol (1 byte, 0 tcp / 1 udp), port (2 bytes), host (utf-8)
+/// - `Data`: payload, one datagram for UDP channels
+/// - `Close`, `Ping`, `Connected`: nothing
+/// - `Listen`: port (2 bytes)
+/// - `Accept`: listener id (4 bytes, big-endian)
+/// - `Refused`: reason (utf-8)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TunnelFrame {
+    Open {
+        channel: u32,
+        protocol: Protocol,
+        host: String,
+        port: u16,
+    },
+    Data {
+        channel: u32,
+        data: Bytes,
+    },
+    Close {
+        channel: u32,
+    },
+    /// Keeps an idle session from timing out.
+    Ping,
+    /// Ask the controlled side to listen on `port`, `channel` names the
+    /// listener in `Accept`, `Refused` and `Close`.
+    Listen {
+        channel: u32,
+        port: u16,
+    },
+    /// A connection accepted by `listener`, carried on the new `channel`.
+    Accept {
+        channel: u32,
+        listener: u32,
+    },
+    /// The controlled side could not listen for `channel`.
+    Refused {
+        channel: u32,
+        reason: String,
+    },
+    /// A TCP channel reached its target, sent before any of its data.
+    Connected {
+        channel: u32,
+    },
+}
+
+impl TunnelFrame {
+    pub fn encode(&self) -> Bytes {
+        let mut buf = BytesMut::with_capacity(FRAME_HEADER_LEN);
+        match self {
+            TunnelFrame::Open {
+                channel,
+                protocol,
+                host,
+                port,
+            } => {
+                buf.put_u8(FRAME_OPEN);
+                buf.put_u32(*channel);
+                buf.put_u8(match protocol {
+                    Protocol::Tcp => 0,
+                    Protocol::Udp => 1,
+                });
+                buf.put_u16(*port);
+                buf.put_slice(host.as_bytes());
+            }
+            TunnelFrame::Data { channel, data } => {
+                buf.reserve(data.len());
+                buf.put_u8(FRAME_DATA);
+                buf.put_u32(*channel);
+                buf.put_slice(data);
+            }
+            TunnelFrame::Close { channel } => {
+                buf.put_u8(FRAME_CLOSE);
+                buf.put_u32(*channel);
+            }
+            TunnelFrame::Ping => {
+                buf.put_u8(FRAME_PING);
+                buf.put_u32(0);
+            }
+            TunnelFrame::Listen { channel, port } => {
+                buf.put_u8(FRAME_LISTEN);
+                buf.put_u32(*channel);
+                buf.put_u16(*port);
+            }
+            TunnelFrame::Accept { channel, listener } => {
+                buf.put_u8(FRAME_ACCEPT);
+                buf.put_u32(*channel);
+                buf.put_u32(*listener);
+            }
+            TunnelFrame::Refused { channel, reason } => {
+                buf.put_u8(FRAME_REFUSED);
+                buf.put_u32(*channel);
+                buf.put_slice(reason.as_bytes());
+            }
+            TunnelFrame::Connected { channel } => {
+                buf.put_u8(FRAME_CONNECTED);
+                buf.put_u32(*channel);
+            }
+        }
+        buf.freeze()
+    }
+
+    pub fn decode(mut bytes: BytesMut) -> ResultType<Self> {
+        if bytes.len() < FRAME_HEADER_LEN {
+            bail!("Tunnel frame too short: {} bytes", bytes.len());
+        }
+        let header = bytes.split_to(FRAME_HEADER_LEN);
+        let channel = u32::from_be_bytes([header[1], header[2], header[3], header[4]]);
+        Ok(match header[0] {
+            FRAME_OPEN => {
+                if bytes.len() < 3 {
+                    bail!("Tunnel open frame too short");
+                }
+                let protocol = match bytes[0] {
+                    0 => Protocol::Tcp,
+                    1 => Protocol::Udp,
+                    p => bail!("Unknown tunnel protocol {}", p),
+                };
+                let port = u16::from_be_bytes([bytes[1], bytes[2]]);
+                let host = String::from_utf8(bytes[3..].to_vec())?;
+                TunnelFrame::Open {
+                    channel,
+                    protocol,
+                    host,
+                    port,
+                }
+            }
+            FRAME_DATA => TunnelFrame::Data {
+                channel,
+                data: bytes.freeze(),
+            },
+            FRAME_CLOSE => TunnelFrame::Close { channel },
+            FRAME_PING => TunnelFrame::Ping,
+            FRAME_LISTEN => {
+                if bytes.len() < 2 {
+                    bail!("Tunnel listen frame too short");
+                }
+                TunnelFrame::Listen {
+                    channel,
+                    port: u16::from_be_bytes([bytes[0], bytes[1]]),
+                }
+            }
+            FRAME_ACCEPT => {
+                if bytes.len() < 4 {
+                    bail!("Tunnel accept frame too short");
+                }
+                TunnelFrame::Accept {
+                    channel,
+                    listener: u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]),
+                }
+            }
+            FRAME_REFUSED => TunnelFrame::Refused {
+                channel,
+                reason: String::from_utf8_lossy(&bytes).into_owned(),
+            },
+            FRAME_CONNECTED => TunnelFrame::Connected { channel },
+            kind => bail!("Unknown tunnel frame kind {}", kind),
+        })
+    }
+}
+
+/// Sender for frames going out on the session, shared by all channel tasks.
+pub type FrameSender = mpsc::Sender<TunnelFrame>;
+/// Sender for the data of one channel, see [`CHANNEL_WINDOW`].
+pub type DataSender = mpsc::Sender<Bytes>;
+pub type DataReceiver = mpsc::Receiver<Bytes>;
+
+pub fn frame_channel() -> (FrameSender, mpsc::Receiver<TunnelFrame>) {
+    mpsc::channel(FRAME_QUEUE_SIZE)
+}
+
+pub fn data_channel() -> (DataSender, DataReceiver) {
+    mpsc::channel(CHANNEL_WINDOW)
+}
+
+/// Copy between a TCP socket and a tunnel channel until either side closes,
+/// then tell the other end of the tunnel.
+///
+/// Both directions run on their own: the session waits for `from_tunnel` to
+/// have room, so it has to drain even while `to_tunnel` is full.
+pub async fn pump_tcp(
+    channel: u32,
+    socket: TcpStream,
+    mut from_tunnel: DataReceiver,
+    to_tunnel: FrameSender,
+) {
+    let (mut reader, mut writer) = socket.into_split();
+    let upload = async {
+        let mut buf = vec![0u8; READ_BUFFER_SIZE];
+        loop {
+            match reader.read(&mut buf).await {
+                Ok(n) if n > 0 => {
+                    let data = Bytes::copy_from_slice(&buf[..n]);
+                    if to_tunnel
+                        .send(TunnelFrame::Data { channel, data })
+                        .await
+                        .is_err()
+                    {
+                        return false;
+                    }
+                }
+                _ => return true,
+            }
+        }
+    };
+    let download = async {
+        while let Some(data) = from_tunnel.recv().await {
+            if writer.write_all(&data).await.is_err() {
+                break;
+            }
+        }
+        true
+    };
+    let notify = tokio::select! {
+        notify = upload => notify,
+        notify = download => notify,
+    };
+    if notify {
+        to_tunnel.send(TunnelFrame::Close { channel }).await.ok();
+    }
+}
+
+/// Connect `channel` to `host:port` in the background, the peer is told with
+/// `Close` if that fails. Data for the channel waits in `from_tunnel` meanwhile.
+pub fn spawn_connect(
+    channel: u32,
+    protocol: Protocol,
+    host: String,
+    port: u16,
+    from_tunnel: DataReceiver,
+    to_tunnel: FrameSender,
+) {
+    tokio::spawn(async move {
+        log::info!("Tunnel channel {}: {} {}:{}", channel, protocol, host, port);
+        let res = match protocol {
+            Protocol::Tcp => {
+                connect_tcp(channel, &host, port, from_tunnel, to_tunnel.clone()).await
+            }
+            Protocol::Udp => {
+                connect_udp(channel, &host, port, from_tunnel, to_tunnel.clone()).await
+            }
+        };
+        if let Err(err) = res {
+            log::warn!(
+                "Tunnel channel {} to {}:{} failed: {}",
+                channel,
+                host,
+                port,
+                err
+            );
+            to_tunnel.send(TunnelFrame::Close { channel }).await.ok();
+        }
+    });
+}
+
+/// Whether `port` is in a list like "8080,9000-9010".
+pub fn port_allowed(list: &str, port: u16) -> bool {
+    list.split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|x| !x.is_empty())
+        .any(|item| match item.split_once('-') {
+            Some((start, end)) => match (start.trim().parse::<u16>(), end.trim().parse::<u16>()) {
+                (Ok(start), Ok(end)) => (start..=end).contains(&port),
+                _ => false,
+            },
+            None => item.parse::<u16>() == Ok(port),
+        })
+}
+
+/// Token bucket for the bytes of one session, allowing a burst of one second.
+pub struct RateLimiter {
+    /// Bytes per second
+    rate: f64,
+    allowance: f64,
+    last: Instant,
+}
+
+impl RateLimiter {
+    /// `None` for 0, which means unlimited.
+    pub fn new(kbps: u64) -> Option<Self> {
+        let rate = kbps as f64 * 1024.;
+        (kbps > 0).then(|| Self {
+            rate,
+            allowance: rate,
+            last: Instant::now(),
+        })
+    }
+
+    /// Parse an option value in KB/s.
+    pub fn from_option(value: &str) -> Option<Self> {
+        Self::new(value.trim().parse().unwrap_or(0))
+    }
+
+    /// How long to wait at `now` before `n` more bytes may pass.
+    fn reserve(&mut self, n: usize, now: Instant) -> Duration {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.allowance = (self.allowance + elapsed * self.rate).min(self.rate);
+        self.last = now;
+        self.allowance -= n as f64;
+        if self.allowance < 0. {
+            Duration::from_secs_f64(-self.allowance / self.rate)
+        } else {
+            Duration::ZERO
+        }
+    }
+}
+
+/// Rate limit of a session loop. Sleeping in a `select!` branch would stall
+/// every other branch, timers and control messages included, so the loop
+/// disables its reads while [`Throttle::is_ready`] is false and waits on
+/// [`Throttle::wait`] instead.
+#[derive(Default)]
+pub struct Throttle {
+    limiter: Option<RateLimiter>,
+    resume: Option<tokio::time::Instant>,
+}
+
+impl Throttle {
+    pub fn new(kbps: u64) -> Self {
+        Self {
+            limiter: RateLimiter::new(kbps),
+            resume: None,
+        }
+    }
+
+    /// Parse an option value in KB/s.
+    pub fn from_option(value: &str) -> Self {
+        Self {
+            limiter: RateLimiter::from_option(value),
+            resume: None,
+        }
+    }
+
+    pub fn is_ready(&self) -> bool {
+        self.resume.is_none()
+    }
+
+    /// Count `n` bytes that have passed, reads pause until they are paid for.
+    pub fn consume(&mut self, n: usize) {
+        if let Some(limiter) = &mut self.limiter {
+            let delay = limiter.reserve(n, Instant::now());
+            if !delay.is_zero() {
+                self.resume = Some(tokio::time::Instant::now() + delay);
+            }
+        }
+    }
+
+    /// Resolves when reads may resume, never while they are not paused.
+    pub async fn wait(&mut self) {
+        match self.resume {
+            Some(resume) => {
+                tokio::time::sleep_until(resume).await;
+                self.resume = None;
+            }
+            None => std::future::pending().await,
+        }
+    }
+}
+
+/// Traffic of a tunnel session to one target, summed over its channels.
+#[derive(Debug, Clone)]
+pub struct TargetStats {
+    pub protocol: Protocol,
+    pub target: String,
+    /// Connections accepted by a reverse forward listener on `target`
+    pub reverse: bool,
+    pub channels: u64,
+    /// Bytes sent to the peer
+    pub sent: u64,
+    pub received: u64,
+    /// Total time of the channels
+    pub duration: Duration,
+}
+
+/// What the peer did with a tunnel session, audited once when it ends
+/// rather than per channel.
+#[derive(Debug, Clone, Default)]
+pub struct TunnelSummary {
+    pub targets: Vec<TargetStats>,
+    /// Reverse forward ports asked for, whether allowed, and how often
+    pub listens: Vec<(u16, bool, u64)>,
+}
+
+/// A channel of a `TunnelServer`.
+struct ServerChannel {
+    sender: DataSender,
+    protocol: Protocol,
+    target: String,
+    reverse: bool,
+    opened: Instant,
+    sent: u64,
+    received: u64,
+}
+
+impl ServerChannel {
+    fn new(sender: DataSender, protocol: Protocol, target: String, reverse: bool) -> Self {
+        Self {
+            sender,
+            protocol,
+            target,
+            reverse,
+            opened: Instant::now(),
+            sent: 0,
+            received: 0,
+        }
+    }
+}
+
+/// A connection accepted by a reverse forward listener.
+struct Accepted {
+    listener: u32,
+    socket: TcpStream,
+    addr: SocketAddr,
+}
+
+/// Controlled side of a tunnel: connects channels opened by the peer and
+/// runs the listeners of its reverse forwards.
+pub struct TunnelServer {
+    channels: HashMap<u32, ServerChannel>,
+    /// Reverse forward listeners and their ports
+    listeners: HashMap<u32, (u16, JoinHandle<()>)>,
+    /// By protocol, target and direction
+    targets: HashMap<(Protocol, String, bool), TargetStats>,
+    /// By port and whether it was allowed
+    listens: HashMap<(u16, bool), u64>,
+    next_channel: u32,
+    tx: FrameSender,
+    rx: mpsc::Receiver<TunnelFrame>,
+    accepted_tx: mpsc::Sender<Accepted>,
+    accepted_rx: mpsc::Receiver<Accepted>,
+}
+
+impl Default for TunnelServer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TunnelServer {
+    pub fn new() -> Self {
+        let (tx, rx) = frame_channel();
+        let (accepted_tx, accepted_rx) = mpsc::channel(ACCEPT_QUEUE_SIZE);
+        Self {
+            channels: HashMap::new(),
+            listeners: HashMap::new(),
+            targets: HashMap::new(),
+            listens: HashMap::new(),
+            next_channel: 0,
+            tx,
+            rx,
+            accepted_tx,
+            accepted_rx,
+        }
+    }
+
+    /// Next frame to send to the peer.
+    pub async fn next_frame(&mut self) -> Option<TunnelFrame> {
+        loop {
+            tokio::select! {
+                frame = self.rx.recv() => {
+                    match &frame {
+                        Some(TunnelFrame::Data { channel, data }) => {
+                            if let Some(c) = self.channels.get_mut(channel) {
+                                c.sent += data.len() as u64;
+                            }
+                        }
+                        Some(TunnelFrame::Close { channel }) => {
+                            self.remove_channel(*channel);
+                        }
+                        Some(TunnelFrame::Refused { channel, .. }) => {
+                            self.listeners.remove(channel);
+                        }
+                        _ => {}
+                    }
+                    return frame;
+                }
+                Some(accepted) = self.accepted_rx.recv() => {
+                    if let Some(frame) = self.on_accepted(accepted) {
+                        return Some(frame);
+                    }
+                }
+            }
+        }
+    }
+
+    /// End every channel and sum up the session for the audit log.
+    pub fn close_all(&mut self) -> TunnelSummary {
+        let channels: Vec<u32> = self.channels.keys().copied().collect();
+        for channel in channels {
+            self.remove_channel(channel);
+        }
+        let mut targets: Vec<TargetStats> = self.targets.drain().map(|(_, stats)| stats).collect();
+        targets.sort_by(|a, b| a.target.cmp(&b.target));
+        let mut listens: Vec<(u16, bool, u64)> = self
+            .listens
+            .drain()
+            .map(|((port, allowed), count)| (port, allowed, count))
+            .collect();
+        listens.sort();
+        TunnelSummary { targets, listens }
+    }
+
+    fn add_channel(
+        &mut self,
+        channel: u32,
+        sender: DataSender,
+        protocol: Protocol,
+        target: String,
+        reverse: bool,
+    ) {
+        self.targets
+            .entry((protocol, target.clone(), reverse))
+            .or_insert_with(|| TargetStats {
+                protocol,
+                target: target.clone(),
+                reverse,
+                channels: 0,
+                sent: 0,
+                received: 0,
+                duration: Duration::ZERO,
+            })
+            .channels += 1;
+        self.channels.insert(
+            channel,
+            ServerChannel::new(sender, protocol, target, reverse),
+        );
+    }
+
+    fn remove_channel(&mut self, channel: u32) {
+        if let Some(c) = self.channels.remove(&channel) {
+            if let Some(stats) = self.targets.get_mut(&(c.protocol, c.target, c.reverse)) {
+                stats.sent += c.sent;
+                stats.received += c.received;
+                stats.duration += c.opened.elapsed();
+            }
+        }
+    }
+
+    /// Give an accepted connection a channel. The returned `Accept` goes out
+    /// before anything the connection sends, which is queued behind it.
+    fn on_accepted(&mut self, accepted: Accepted) -> Option<TunnelFrame> {
+        let Accepted {
+            listener,
+            socket,
+            addr,
+        } = accepted;
+        // The peer may have closed the listener in the meantime.
+        let port = self.listeners.get(&listener)?.0;
+        let channel = REVERSE_CHANNEL | self.next_channel;
+        self.next_channel = (self.next_channel + 1) & !REVERSE_CHANNEL;
+        log::info!(
+            "Reverse tunnel channel {}: connection from {} on listener {}",
+            channel,
+            addr,
+            listener
+        );
+        let target = format!("{}:{}", Ipv4Addr::LOCALHOST, port);
+        let (data_tx, data_rx) = data_channel();
+        self.add_channel(channel, data_tx, Protocol::Tcp, target, true);
+        tokio::spawn(pump_tcp(channel, socket, data_rx, self.tx.clone()));
+        Some(TunnelFrame::Accept { channel, listener })
+    }
+
+    fn listen(&mut self, listener: u32, port: u16) {
+        if self.listeners.contains_key(&listener) {
+            log::warn!("Tunnel listener {} opened twice", listener);
+            return;
+        }
+        let tx = self.tx.clone();
+        let allowed = port_allowed(&Config::get_option(OPTION_REVERSE_PORTS), port);
+        *self.listens.entry((port, allowed)).or_default() += 1;
+        if !allowed {
+            log::warn!("Refused reverse port forward on port {}", port);
+            tokio::spawn(async move {
+                let reason = format!("Port {} is not allowed", port);
+                tx.send(TunnelFrame::Refused {
+                    channel: listener,
+                    reason,
+                })
+                .await
+                .ok();
+            });
+            return;
+        }
+        let accepted_tx = self.accepted_tx.clone();
+        let handle = tokio::spawn(async move {
+            let addr = SocketAddr::from((Ipv4Addr::LOCALHOST, port));
+            let socket = match tcp::new_listener(addr, false).await {
+                Ok(socket) => socket,
+                Err(err) => {
+                    log::warn!("Reverse port forward failed to listen on {}: {}", addr, err);
+                    let reason = err.to_string();
+                    tx.send(TunnelFrame::Refused {
+                        channel: listener,
+                        reason,
+                    })
+                    .await
+                    .ok();
+                    return;
+                }
+            };
+            log::info!("Reverse port forward listening on {}", addr);
+            while let Ok((socket, addr)) = socket.accept().await {
+                let accepted = Accepted {
+                    listener,
+                    socket,
+                    addr,
+                };
+                if accepted_tx.send(accepted).await.is_err() {
+                    break;
+                }
+            }
+        });
+        self.listeners.insert(listener, (port, handle));
+    }
+
+    /// Handle a frame received from the peer. Waits while the queue of the
+    /// TCP channel the data is for is full.
+    pub async fn handle(&mut self, frame: TunnelFrame) {
+        match frame {
+            TunnelFrame::Open {
+                channel,
+                protocol,
+                host,
+                port,
+            } => {
+                if channel & REVERSE_CHANNEL != 0 || self.channels.contains_key(&channel) {
+                    log::warn!("Tunnel channel {} is in use or invalid", channel);
+                    return;
+                }
+                let target = if host.contains(':') {
+                    format!("[{}]:{}", host, port)
+                } else {
+                    format!("{}:{}", host, port)
+                };
+                // Data may follow before the connection is up, it waits in the queue.
+                let (data_tx, data_rx) = data_channel();
+                self.add_channel(channel, data_tx, protocol, target, false);
+                spawn_connect(channel, protocol, host, port, data_rx, self.tx.clone());
+            }
+            TunnelFrame::Data { channel, data } => {
+                if let Some(c) = self.channels.get_mut(&channel) {
+                    c.received += data.len() as u64;
+                    let closed = match c.protocol {
+                        Protocol::Tcp => c.sender.send(data).await.is_err(),
+                        Protocol::Udp => {
+                            matches!(c.sender.try_send(data), Err(TrySendError::Closed(_)))
+                        }
+                    };
+                    if closed {
+                        self.remove_channel(channel);
+                    }
+                }
+            }
+            TunnelFrame::Close { channel } => {
+                if let Some((_, handle)) = self.listeners.remove(&channel) {
+                    log::info!("Tunnel listener {} closed", channel);
+                    handle.abort();
+                }
+                self.remove_channel(channel);
+            }
+            TunnelFrame::Listen { channel, port } => self.listen(channel, port),
+            TunnelFrame::Ping | TunnelFrame::Connected { .. } => {}
+            frame => log::debug!("Unexpected tunnel frame {:?}", frame),
+        }
+    }
+}
+
+impl Drop for TunnelServer {
+    fn drop(&mut self) {
+        // Listeners must not outlive the session that asked for them.
+        for (_, (_, handle)) in self.listeners.drain() {
+            handle.abort();
+        }
+    }
+}
+
+async fn connect_tcp(
+    channel: u32,
+    host: &str,
+    port: u16,
+    from_tunnel: DataReceiver,
+    to_tunnel: FrameSender,
+) -> ResultType<()> {
+    let socket = timeout(CONNECT_TIMEOUT, TcpStream::connect((host, port))).await??;
+    if to_tunnel
+        .send(TunnelFrame::Connected { channel })
+        .await
+        .is_err()
+    {
+        return Ok(());
+    }
+    pump_tcp(channel, socket, from_tunnel, to_tunnel).await;
+    Ok(())
+}
+
+async fn connect_udp(
+    channel: u32,
+    host: &str,
+    port: u16,
+    mut from_tunnel: DataReceiver,
+    to_tunnel: FrameSender,
+) -> ResultType<()> {
+    let target = match lookup_host((host, port)).await?.next() {
+        Some(target) => target,
+        None => bail!("No address for {}", host),
+    };
+    let local: SocketAddr = if target.is_ipv4() {
+        "0.0.0.0:0".parse()?
+    } else {
+        "[::]:0".parse()?
+    };
+    let socket = UdpSocket::bind(local).await?;
+    socket.connect(target).await?;
+    let mut buf = vec![0u8; READ_BUFFER_SIZE];
+    loop {
+        tokio::select! {
+            res = socket.recv(&mut buf) => {
+                // ICMP errors surface here, the flow stays usable.
+                if let Ok(n) = res {
+                    let data = Bytes::copy_from_slice(&buf[..n]);
+                    if to_tunnel.send(TunnelFrame::Data { channel, data }).await.is_err() {
+                        break;
+                    }
+                }
+            }
+            data = from_tunnel.recv() => match data {
+                Some(data) => {
+                    socket.send(&data).await.ok();
+                }
+                None => break,
+            },
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(frame: TunnelFrame) {
+        let bytes = BytesMut::from(&frame.encode()[..]);
+        assert_eq!(TunnelFrame::decode(bytes).unwrap(), frame);
+    }
+
+    #[test]
+    fn test_frame_round_trip() {
+        round_trip(TunnelFrame::Open {
+            channel: 7,
+            protocol: Protocol::Udp,
+            host: "8.8.8.8".to_owned(),
+            port: 53,
+        });
+        round_trip(TunnelFrame::Data {
+            channel: u32::MAX,
+            data: Bytes::from_static(b"hello"),
+        });
+        round_trip(TunnelFrame::Close { channel: 1 });
+        round_trip(TunnelFrame::Ping);
+        round_trip(TunnelFrame::Listen {
+            channel: 3,
+            port: 8080,
+        });
+        round_trip(TunnelFrame::Accept {
+            channel: REVERSE_CHANNEL | 1,
+            listener: 3,
+        });
+        round_trip(TunnelFrame::Refused {
+            channel: 3,
+            reason: "Port 8080 is not allowed".to_owned(),
+        });
+        round_trip(TunnelFrame::Connected { channel: 5 });
+    }
+
+    #[test]
+    fn test_rate_limiter() {
+        assert!(RateLimiter::from_option("").is_none());
+        assert!(RateLimiter::from_option("0").is_none());
+        let mut limiter = RateLimiter::from_option("1").unwrap();
+        let start = limiter.last;
+        // One second of burst, then the rest has to wait.
+        assert_eq!(limiter.reserve(1024, start), Duration::ZERO);
+        assert_eq!(limiter.reserve(512, start), Duration::from_millis(500));
+        assert_eq!(
+            limiter.reserve(512, start + Duration::from_secs(1)),
+            Duration::ZERO
+        );
+        // Idle time does not add up beyond the burst.
+        assert_eq!(
+            limiter.reserve(2048, start + Duration::from_secs(10)),
+            Duration::from_secs(1)
+        );
+    }
+
+    #[test]
+    fn test_summary() {
+        let mut server = TunnelServer::new();
+        for channel in 0..3 {
+            let (data_tx, _) = data_channel();
+            server.add_channel(
+                channel,
+                data_tx,
+                Protocol::Tcp,
+                "10.0.0.1:22".to_owned(),
+                false,
+            );
+        }
+        let (data_tx, _) = data_channel();
+        server.add_channel(
+            REVERSE_CHANNEL,
+            data_tx,
+            Protocol::Tcp,
+            "127.0.0.1:8080".to_owned(),
+            true,
+        );
+        server.channels.get_mut(&0).unwrap().sent = 10;
+        server.channels.get_mut(&1).unwrap().received = 5;
+        server.remove_channel(0);
+        *server.listens.entry((8080, true)).or_default() += 2;
+
+        let summary = server.close_all();
+        assert_eq!(summary.targets.len(), 2);
+        let ssh = &summary.targets[0];
+        assert_eq!((ssh.target.as_str(), ssh.reverse), ("10.0.0.1:22", false));
+        assert_eq!((ssh.channels, ssh.sent, ssh.received), (3, 10, 5));
+        assert_eq!(summary.targets[1].channels, 1);
+        assert!(summary.targets[1].reverse);
+        assert_eq!(summary.listens, vec![(8080, true, 2)]);
+        assert!(server.close_all().targets.is_empty());
+    }
+
+    #[test]
+    fn test_port_allowed() {
+        assert!(!port_allowed("", 8080));
+        assert!(port_allowed("22, 8080", 8080));
+        assert!(port_allowed("9000-9010,22", 9005));
+        assert!(!port_allowed("9000-9010", 9011));
+        assert!(!port_allowed("abc,9000-", 9000));
+    }
+
+    #[test]
+    fn test_frame_rejects_garbage() {
+        assert!(TunnelFrame::decode(BytesMut::from(&[FRAME_DATA, 0, 0][..])).is_err());
+        assert!(TunnelFrame::decode(BytesMut::from(&[9u8, 0, 0, 0, 1][..])).is_err());
+    }
+}
@@ -291,6 +291,10 @@ impl InvokeUiSession for SciterHandler {
         );
     }
 
+    fn update_port_forward_stats(&self, _stats: &[PortForwardStats]) {
+        // Ignore for sciter version.
+    }
+
     fn set_platform_additions(&self, _data: &str) {
         // Ignore for sciter version.
     }
@@ -414,7 +414,7 @@ pub fn set_option(key: String, value: String) {
                 return;
             }
         }
-    } else if &key == "audio-input" {
+    } else if key.starts_with("audio-") {
         #[cfg(not(target_os = "ios"))]
         crate::audio_service::restart();
     }
@@ -1442,6 +1442,17 @@ pub fn verify2fa(code: String) -> bool {
     res
 }
 
+/// New one-time recovery codes of the enabled 2FA, one per line, empty on failure.
+pub fn generate2fa_recovery_codes() -> String {
+    match crate::auth_2fa::generate_recovery_codes(Some(get_option("2fa"))) {
+        Ok(codes) => codes.join("\n"),
+        Err(err) => {
+            log::error!("Failed to generate 2FA recovery codes: {}", err);
+            "".to_owned()
+        }
+    }
+}
+
 pub fn has_valid_bot() -> bool {
     crate::auth_2fa::TelegramBot::get().map_or(false, |bot| bot.is_some())
 }
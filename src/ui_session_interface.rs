@@ -36,7 +36,7 @@ use crate::client::io_loop::Remote;
 use crate::client::{
     check_if_retry, handle_hash, handle_login_error, handle_login_from_ui, handle_test_delay,
     input_os_password, send_mouse, send_pointer_device_event, FileManager, Key, LoginConfigHandler,
-    QualityStatus, KEY_MAP,
+    PortForwardStats, QualityStatus, KEY_MAP,
 };
 #[cfg(not(any(target_os = "android", target_os = "ios")))]
 use crate::common::GrabState;
@@ -1552,6 +1552,7 @@ pub trait InvokeUiSession: Send + Sync + Clone + 'static + Sized + Default {
     fn set_permission(&self, name: &str, value: bool);
     fn close_success(&self);
     fn update_quality_status(&self, qs: QualityStatus);
+    fn update_port_forward_stats(&self, stats: &[PortForwardStats]);
     fn set_connection_type(&self, is_secured: bool, direct: bool);
     fn set_fingerprint(&self, fingerprint: String);
     fn job_error(&self, id: i32, err: String, file_num: i32);
@@ -1649,6 +1650,10 @@ impl<T: InvokeUiSession> Interface for Session<T> {
         self.ui_handler.set_multiple_windows_session(sessions);
     }
 
+    fn update_port_forward_stats(&self, stats: &[PortForwardStats]) {
+        self.ui_handler.update_port_forward_stats(stats);
+    }
+
     fn handle_peer_info(&self, mut pi: PeerInfo) {
         log::debug!("handle_peer_info :{:?}", pi);
         self.lc.write().unwrap().peer_info = Some(pi.clone());
@@ -1796,10 +1801,7 @@ impl<T: InvokeUiSession> Session<T> {
 
 #[tokio::main(flavor = "current_thread")]
 pub async fn io_loop<T: InvokeUiSession>(handler: Session<T>, round: u32) {
-    #[cfg(any(target_os = "android", target_os = "ios"))]
     let (sender, receiver) = mpsc::unbounded_channel::<Data>();
-    #[cfg(not(any(target_os = "android", target_os = "ios")))]
-    let (sender, mut receiver) = mpsc::unbounded_channel::<Data>();
     *handler.sender.write().unwrap() = Some(sender.clone());
     let token = LocalConfig::get_option("access_token");
     let key = crate::get_key(false).await;
@@ -1820,72 +1822,46 @@ pub async fn io_loop<T: InvokeUiSession>(handler: Session<T>, round: u32) {
             );
             log::info!("Remote rdp port: {}", port);
             start_one_port_forward(handler, 0, "".to_owned(), port, receiver, &key, &token).await;
-        } else if handler.args.len() == 0 {
-            let pfs = handler.lc.read().unwrap().port_forwards.clone();
-            let mut queues = HashMap::<i32, mpsc::UnboundedSender<Data>>::new();
-            for d in pfs {
-                sender.send(Data::AddPortForward(d)).ok();
-            }
-            loop {
-                match receiver.recv().await {
-                    Some(Data::AddPortForward((port, remote_host, remote_port))) => {
-                        if port <= 0 || remote_port <= 0 {
-                            continue;
-                        }
-                        let (sender, receiver) = mpsc::unbounded_channel::<Data>();
-                        queues.insert(port, sender);
-                        let handler = handler.clone();
-                        let key = key.clone();
-                        let token = token.clone();
-                        tokio::spawn(async move {
-                            start_one_port_forward(
-                                handler,
-                                port,
-                                remote_host,
-                                remote_port,
-                                receiver,
-                                &key,
-                                &token,
-                            )
-                            .await;
-                        });
-                    }
-                    Some(Data::RemovePortForward(port)) => {
-                        if let Some(s) = queues.remove(&port) {
-                            s.send(Data::Close).ok();
-                        }
-                    }
-                    Some(Data::Close) => {
-                        break;
-                    }
-                    Some(d) => {
-                        for (_, s) in queues.iter() {
-                            s.send(d.clone()).ok();
-                        }
+        } else {
+            let rules = if handler.args.is_empty() {
+                handler
+                    .lc
+                    .read()
+                    .unwrap()
+                    .port_forwards
+                    .iter()
+                    .filter(|(port, _, remote_port)| {
+                        *port > 0 && *port <= u16::MAX as i32 && *remote_port > 0 && *remote_port <= u16::MAX as i32
+                    })
+                    .map(|(port, remote_host, remote_port)| {
+                        crate::port_forward::ForwardRule::tcp(*port as _, remote_host.clone(), *remote_port as _)
+                    })
+                    .collect()
+            } else {
+                match parse_port_forward_args(&handler.args) {
+                    Ok(rules) => rules,
+                    Err(err) => {
+                        handler.on_error(&format!("{}<br><br>Usage: rustdesk --port-forward remote-id listen-port remote-host remote-port, or rustdesk --port-forward remote-id RULE... with RULE as [tcp:|udp:][bind-address:]local-port:remote-host:remote-port", err));
+                        return;
                     }
-                    _ => {}
                 }
-            }
-        } else {
-            let port = handler.args[0].parse::<i32>().unwrap_or(0);
-            if handler.args.len() != 3
-                || handler.args[2].parse::<i32>().unwrap_or(0) <= 0
-                || port <= 0
-            {
-                handler.on_error("Invalid arguments, usage:<br><br> rustdesk --port-forward remote-id listen-port remote-host remote-port");
-            }
-            let remote_host = handler.args[1].clone();
-            let remote_port = handler.args[2].parse::<i32>().unwrap_or(0);
-            start_one_port_forward(
-                handler,
-                port,
-                remote_host,
-                remote_port,
+            };
+            if let Err(err) = crate::port_forward::run_tunnel(
+                handler.get_id(),
+                handler.password.clone(),
+                rules,
+                crate::port_forward::TunnelLimits::from_options(),
+                handler.clone(),
                 receiver,
                 &key,
                 &token,
+                handler.lc.clone(),
             )
-            .await;
+            .await
+            {
+                handler.on_error(&format!("Port forwarding failed: {}", err));
+            }
+            log::info!("port forward tunnel to {} exit", handler.get_id());
         }
         return;
     }
@@ -1923,6 +1899,23 @@ async fn start_one_port_forward<T: InvokeUiSession>(
     log::info!("port forward (:{}) exit", port);
 }
 
+/// `listen-port remote-host remote-port` as before, or one forward rule per argument.
+#[cfg(not(any(target_os = "android", target_os = "ios")))]
+fn parse_port_forward_args(args: &[String]) -> Result<Vec<crate::port_forward::ForwardRule>, String> {
+    if let [port, remote_host, remote_port] = args {
+        if let (Ok(port), Ok(remote_port)) = (port.parse::<u16>(), remote_port.parse::<u16>()) {
+            if port > 0 && remote_port > 0 {
+                return Ok(vec![crate::port_forward::ForwardRule::tcp(
+                    port,
+                    remote_host.clone(),
+                    remote_port,
+                )]);
+            }
+        }
+    }
+    args.iter().map(|arg| arg.parse()).collect()
+}
+
 #[tokio::main(flavor = "current_thread")]
 async fn send_note(url: String, id: String, sid: u64, note: String) {
     let body = serde_json::json!({ "id": id, "session_id": sid, "note": note });